@@ -19,6 +19,26 @@ fn trace(b: &mut test::Bencher) {
     b.iter(the_function);
 }
 
+#[bench]
+#[cfg(feature = "std")]
+fn trace_deep(b: &mut test::Bencher) {
+    #[inline(never)]
+    fn recurse(depth: usize) {
+        if depth == 0 {
+            backtrace::trace(|frame| {
+                let ip = frame.ip();
+                test::black_box(ip);
+                true
+            });
+        } else {
+            recurse(test::black_box(depth - 1));
+            // Keeps the call from being turned into a loop.
+            test::black_box(depth);
+        }
+    }
+    b.iter(|| recurse(200));
+}
+
 #[bench]
 #[cfg(feature = "std")]
 fn trace_and_resolve_callback(b: &mut test::Bencher) {
@@ -90,3 +110,92 @@ fn new_unresolved_and_resolve_separate(b: &mut test::Bencher) {
     }
     b.iter(the_function);
 }
+
+#[bench]
+#[cfg(feature = "std")]
+fn resolve_deep_stack(b: &mut test::Bencher) {
+    #[inline(never)]
+    fn recurse(depth: usize) -> Backtrace {
+        if depth == 0 {
+            Backtrace::new_unresolved()
+        } else {
+            test::black_box(recurse(depth - 1))
+        }
+    }
+    let bt = recurse(300);
+    b.iter(|| {
+        let mut bt = bt.clone();
+        bt.resolve();
+        test::black_box(bt);
+    });
+}
+
+#[bench]
+#[cfg(feature = "std")]
+fn resolve_offset_only_deep_stack(b: &mut test::Bencher) {
+    #[inline(never)]
+    fn recurse(depth: usize) -> Backtrace {
+        if depth == 0 {
+            Backtrace::new_unresolved()
+        } else {
+            test::black_box(recurse(depth - 1))
+        }
+    }
+    let bt = recurse(300);
+    let map = backtrace::ModuleMap::capture();
+    b.iter(|| {
+        let mut bt = bt.clone();
+        bt.resolve_offset_only(&map);
+        test::black_box(bt);
+    });
+}
+
+#[cfg(windows)]
+extern "system" {
+    fn GetCurrentThread() -> *mut std::ffi::c_void;
+}
+
+#[bench]
+#[cfg(windows)]
+fn trace_thread(b: &mut test::Bencher) {
+    let thread = backtrace::ThreadHandle::new(unsafe { GetCurrentThread() }).unwrap();
+    b.iter(|| unsafe {
+        backtrace::trace_thread_unsynchronized(thread, |frame| {
+            test::black_box(frame.ip());
+            true
+        })
+    });
+}
+
+#[bench]
+#[cfg(windows)]
+fn trace_thread_with_context(b: &mut test::Bencher) {
+    let thread = backtrace::ThreadHandle::new(unsafe { GetCurrentThread() }).unwrap();
+    let mut buffer = Box::new(backtrace::ContextBuffer::new());
+    b.iter(|| unsafe {
+        backtrace::trace_thread_with_context(thread, &mut buffer, |frame| {
+            test::black_box(frame.ip());
+            true
+        })
+    });
+}
+
+#[bench]
+#[cfg(windows)]
+fn trace_deep_lazy_base(b: &mut test::Bencher) {
+    #[inline(never)]
+    fn recurse(depth: usize) {
+        if depth == 0 {
+            backtrace::trace_lazy_base(|frame| {
+                let ip = frame.ip();
+                test::black_box(ip);
+                true
+            });
+        } else {
+            recurse(test::black_box(depth - 1));
+            // Keeps the call from being turned into a loop.
+            test::black_box(depth);
+        }
+    }
+    b.iter(|| recurse(200));
+}
@@ -1,7 +1,6 @@
 // Windows only currently
-use std::os::windows::prelude::AsRawHandle;
 use backtrace::{Backtrace, BacktraceFrame};
-
+use std::os::windows::prelude::AsRawHandle;
 
 fn worker() {
     foo();
@@ -27,29 +26,36 @@ fn main() {
         let thread = std::thread::spawn(|| {
             worker();
         });
-        let os_handle = thread.as_raw_handle();
+        let os_handle = backtrace::ThreadHandle::new(thread.as_raw_handle()).unwrap();
 
         // Allow the thread to start
         std::thread::sleep(std::time::Duration::from_millis(500));
 
         let mut frames = Vec::new();
-        unsafe {
+        let result = unsafe {
             backtrace::trace_thread_unsynchronized(os_handle, |frame| {
                 frames.push(BacktraceFrame::from(frame.clone()));
                 true
-            });
+            })
+        };
+        if let Err(e) = result {
+            println!("{index}: trace failed: {:?}", e);
         }
         let len = frames.len();
         let mut bt = Backtrace::from(frames);
         bt.resolve();
 
-        if let Some(symbol) = bt.frames().last().unwrap().symbols()[0].name() {
-            let name = symbol.to_string();
-            if name != "RtlUserThreadStart" || index == 0 {
-                println!("{index}:{}, {:?}", len, bt);
-            }
-        } else {
-            panic!("no good");
+        // The walk may have failed before producing any frames, and the
+        // outermost frame may not have been resolved.
+        let outermost = bt
+            .frames()
+            .last()
+            .and_then(|frame| frame.symbols().first())
+            .and_then(|symbol| symbol.name())
+            .map(|name| name.to_string());
+        match outermost {
+            Some(name) if name == "RtlUserThreadStart" && index != 0 => {}
+            _ => println!("{index}:{}, {:?}", len, bt),
         }
     }
 }
@@ -12,8 +12,17 @@
 #![allow(bad_style)]
 
 use super::super::{dbghelp, windows::*};
+use super::TraceError;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::char;
 use core::ffi::c_void;
+use core::fmt::{self, Write};
 use core::mem;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering::Relaxed};
+use core::time::Duration;
 
 #[derive(Clone, Copy)]
 pub enum StackFrame {
@@ -21,10 +30,24 @@ pub enum StackFrame {
     Old(STACKFRAME64),
 }
 
-#[derive(Clone, Copy)]
 pub struct Frame {
     pub(crate) stack_frame: StackFrame,
-    base_address: *mut c_void,
+    // The module base, or `LAZY_BASE` until it's first asked for if the frame
+    // comes from `trace_lazy_base`.
+    base_address: AtomicUsize,
+}
+
+// `base_address` of frames whose module base hasn't been looked up yet. No
+// module can be loaded at the very top of the address space.
+const LAZY_BASE: usize = !0;
+
+impl Clone for Frame {
+    fn clone(&self) -> Frame {
+        Frame {
+            stack_frame: self.stack_frame,
+            base_address: AtomicUsize::new(self.base_address.load(Relaxed)),
+        }
+    }
 }
 
 // we're just sending around raw pointers and reading them, never interpreting
@@ -46,7 +69,45 @@ impl Frame {
     }
 
     pub fn module_base_address(&self) -> Option<*mut c_void> {
-        Some(self.base_address)
+        let mut base = self.base_address.load(Relaxed);
+        if base == LAZY_BASE {
+            base = lookup_module_base(self.addr_pc().Offset) as usize;
+            self.base_address.store(base, Relaxed);
+        }
+        Some(base as *mut c_void)
+    }
+
+    pub fn function_table_entry(&self) -> usize {
+        match self.stack_frame {
+            StackFrame::New(ref new) => new.FuncTableEntry as usize,
+            StackFrame::Old(ref old) => old.FuncTableEntry as usize,
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        match self.stack_frame {
+            // The low byte of the inline context is the frame type.
+            StackFrame::New(ref new) => new.InlineFrameContext & 0xff == STACK_FRAME_TYPE_INLINE,
+            StackFrame::Old(_) => false,
+        }
+    }
+
+    pub fn inline_context(&self) -> u32 {
+        match self.stack_frame {
+            StackFrame::New(ref new) => new.InlineFrameContext,
+            StackFrame::Old(_) => 0,
+        }
+    }
+
+    // Values which uniquely identify this frame within a stack walk. Inline
+    // frames share the same `ip` and `sp` as their physical frame, so the inline
+    // context is included as well.
+    fn identity(&self) -> (DWORD64, DWORD64, DWORD) {
+        (
+            self.addr_pc().Offset,
+            self.addr_stack().Offset,
+            self.inline_context(),
+        )
     }
 
     fn addr_pc(&self) -> &ADDRESS64 {
@@ -56,173 +117,2526 @@ impl Frame {
         }
     }
 
-    fn addr_pc_mut(&mut self) -> &mut ADDRESS64 {
-        match self.stack_frame {
-            StackFrame::New(ref mut new) => &mut new.AddrPC,
-            StackFrame::Old(ref mut old) => &mut old.AddrPC,
+    fn addr_pc_mut(&mut self) -> &mut ADDRESS64 {
+        match self.stack_frame {
+            StackFrame::New(ref mut new) => &mut new.AddrPC,
+            StackFrame::Old(ref mut old) => &mut old.AddrPC,
+        }
+    }
+
+    #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+    fn addr_frame(&self) -> &ADDRESS64 {
+        match self.stack_frame {
+            StackFrame::New(ref new) => &new.AddrFrame,
+            StackFrame::Old(ref old) => &old.AddrFrame,
+        }
+    }
+
+    fn addr_frame_mut(&mut self) -> &mut ADDRESS64 {
+        match self.stack_frame {
+            StackFrame::New(ref mut new) => &mut new.AddrFrame,
+            StackFrame::Old(ref mut old) => &mut old.AddrFrame,
+        }
+    }
+
+    fn addr_stack(&self) -> &ADDRESS64 {
+        match self.stack_frame {
+            StackFrame::New(ref new) => &new.AddrStack,
+            StackFrame::Old(ref old) => &old.AddrStack,
+        }
+    }
+
+    fn addr_stack_mut(&mut self) -> &mut ADDRESS64 {
+        match self.stack_frame {
+            StackFrame::New(ref mut new) => &mut new.AddrStack,
+            StackFrame::Old(ref mut old) => &mut old.AddrStack,
+        }
+    }
+}
+
+// Frame type of the virtual frames `StackWalkEx` yields for inlined calls.
+const STACK_FRAME_TYPE_INLINE: DWORD = 0x02;
+
+#[repr(C, align(16))] // required by `CONTEXT`, is a FIXME in winapi right now
+#[derive(Clone, Copy)]
+struct MyContext(CONTEXT);
+
+/// Space for the registers of a thread, reused across traces, see
+/// `super::ContextBuffer`.
+pub struct ContextBuffer(MyContext);
+
+impl ContextBuffer {
+    pub fn new() -> ContextBuffer {
+        ContextBuffer(unsafe { mem::zeroed() })
+    }
+}
+
+// Counters backing `trace_stats`. These are purely informational so relaxed
+// ordering is all we need.
+static TRACES: AtomicUsize = AtomicUsize::new(0);
+static SUSPENSIONS: AtomicUsize = AtomicUsize::new(0);
+static SUSPEND_FAILURES: AtomicUsize = AtomicUsize::new(0);
+static CONTEXT_FAILURES: AtomicUsize = AtomicUsize::new(0);
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
+static SINGLE_PROCESSOR_SUSPENSIONS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn stats() -> super::TraceStats {
+    super::TraceStats {
+        traces: TRACES.load(Relaxed),
+        suspensions: SUSPENSIONS.load(Relaxed),
+        suspend_failures: SUSPEND_FAILURES.load(Relaxed),
+        context_failures: CONTEXT_FAILURES.load(Relaxed),
+        frames: FRAMES.load(Relaxed),
+        single_processor_suspensions: SINGLE_PROCESSOR_SUSPENSIONS.load(Relaxed),
+    }
+}
+
+pub fn reset_stats() {
+    TRACES.store(0, Relaxed);
+    SUSPENSIONS.store(0, Relaxed);
+    SUSPEND_FAILURES.store(0, Relaxed);
+    CONTEXT_FAILURES.store(0, Relaxed);
+    FRAMES.store(0, Relaxed);
+    SINGLE_PROCESSOR_SUSPENSIONS.store(0, Relaxed);
+}
+
+pub fn available_processors() -> Option<usize> {
+    let mut process = 0;
+    let mut system = 0;
+    let ok = unsafe { GetProcessAffinityMask(GetCurrentProcess(), &mut process, &mut system) };
+    if ok == TRUE && process != 0 {
+        Some(process.count_ones() as usize)
+    } else {
+        None
+    }
+}
+
+// Whether to yield before suspending a thread when the process can only run
+// on one processor, see `set_yield_on_single_processor`.
+static YIELD_ON_SINGLE_PROCESSOR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_yield_on_single_processor(enabled: bool) {
+    YIELD_ON_SINGLE_PROCESSOR.store(enabled, Relaxed);
+}
+
+//#[inline(always)]
+pub unsafe fn trace(
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+    thread: *mut c_void,
+) -> Result<(), TraceError> {
+    trace_as(cb, thread, None, 0, false)
+}
+
+pub unsafe fn trace_with_machine(
+    thread: super::RawThreadHandle,
+    machine: WORD,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    trace_as(cb, thread, Some(machine), 0, false)
+}
+
+pub unsafe fn trace_with_walk_flags(
+    thread: super::RawThreadHandle,
+    flags: DWORD,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    trace_as(cb, thread, None, flags, false)
+}
+
+pub unsafe fn trace_lazy_base(cb: &mut dyn FnMut(&super::Frame) -> bool) -> Result<(), TraceError> {
+    trace_as(cb, GetCurrentThread(), None, 0, true)
+}
+
+/// Looks up the base of the module containing `addr` for a frame of
+/// `trace_lazy_base`, the same way `walk_context` would have during the walk.
+fn lookup_module_base(addr: DWORD64) -> DWORD64 {
+    #[cfg(target_pointer_width = "64")]
+    {
+        if let Some(lookup) = rtl_lookup_function_entry() {
+            let mut base = 0;
+            unsafe { lookup(addr, &mut base, ptr::null_mut()) };
+            return base;
+        }
+    }
+    match dbghelp::init() {
+        Ok(dbghelp) => unsafe { dbghelp.SymGetModuleBase64()(GetCurrentProcess(), addr) },
+        Err(_) => 0,
+    }
+}
+
+/// Walks the stack of `thread` like `trace`, telling dbghelp it belongs to
+/// `machine` instead of the architecture this was compiled for if given,
+/// passing `flags` on to `StackWalkEx` and leaving the module bases of the
+/// frames to be looked up on demand if `lazy_base` is set.
+unsafe fn trace_as(
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+    thread: *mut c_void,
+    machine: Option<WORD>,
+    flags: DWORD,
+    lazy_base: bool,
+) -> Result<(), TraceError> {
+    let mut context = mem::zeroed::<MyContext>();
+    trace_in(cb, thread, machine, flags, lazy_base, None, &mut context)
+}
+
+pub unsafe fn trace_thread_with_context(
+    thread: super::RawThreadHandle,
+    buffer: &mut ContextBuffer,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    trace_in(cb, thread, None, 0, false, None, &mut buffer.0)
+}
+
+/// Implementation of `trace_as`, capturing the registers of `thread` into
+/// `context`, whose previous contents are overwritten.
+///
+/// If `report_state` is given the registers are read with
+/// `CONTEXT_EXCEPTION_REQUEST`, and the flags `GetThreadContext` reports for
+/// a suspended thread are passed to it before the walk, see
+/// `take_context_state`.
+unsafe fn trace_in(
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+    thread: *mut c_void,
+    machine: Option<WORD>,
+    flags: DWORD,
+    lazy_base: bool,
+    report_state: Option<&mut dyn FnMut(DWORD)>,
+    context: &mut MyContext,
+) -> Result<(), TraceError> {
+    TRACES.fetch_add(1, Relaxed);
+
+    let (dbghelp, expand_inline) = init_for_walk()?;
+
+    let context_flags = match report_state {
+        Some(_) => CONTEXT_EXCEPTION_REQUEST,
+        None => 0,
+    };
+    let do_resume = capture_context_into(thread, context, context_flags)?;
+    if let Some(report_state) = report_state {
+        if do_resume {
+            report_state(take_context_state(&mut context.0));
+        }
+    }
+    let result = walk_context(
+        &dbghelp,
+        expand_inline,
+        &mut context.0,
+        thread,
+        None,
+        machine,
+        flags,
+        lazy_base,
+        cb,
+    );
+
+    if do_resume {
+        ResumeThread(thread);
+    }
+    result
+}
+
+// Frame records followed by `trace_reconciled`, bounded so they fit on the
+// stack, as nothing may be allocated while the thread is suspended.
+#[cfg(target_pointer_width = "64")]
+const MAX_RECONCILED_RECORDS: usize = 256;
+
+pub unsafe fn trace_reconciled(
+    thread: super::RawThreadHandle,
+    cb: &mut dyn FnMut(&super::Frame, super::FrameConfidence) -> bool,
+) -> Result<(), TraceError> {
+    TRACES.fetch_add(1, Relaxed);
+
+    let (dbghelp, expand_inline) = init_for_walk()?;
+    let (mut context, do_resume) = suspend_thread_and_capture_context(thread, 0)?;
+
+    // The chain is followed before the unwinder runs, which updates the
+    // context as it goes.
+    #[cfg(target_pointer_width = "64")]
+    let mut records = [(0, 0, 0); MAX_RECONCILED_RECORDS];
+    #[cfg(target_pointer_width = "64")]
+    let records = {
+        let mut len = 0;
+        if let Some((_, high)) = thread_stack_bounds(thread) {
+            // Only the part of the stack in use, above the stack pointer, is
+            // read, so the guard page below it is never touched. The frame
+            // pointer may be any value in optimized code, so the records are
+            // read with `ReadProcessMemory`, which fails instead of faulting.
+            let low = stack_pointer(&context.0);
+            let mut read = |addr: DWORD64| {
+                if addr >= low && addr.checked_add(8)? <= high {
+                    read_stack_word(addr)
+                } else {
+                    None
+                }
+            };
+            walk_frame_records(frame_pointer(&context.0), &mut read, &mut |ip, sp, fp| {
+                records[len] = (ip, sp, fp);
+                len += 1;
+                len < records.len()
+            });
+        }
+        &records[..len]
+    };
+
+    let mut last_sp = 0;
+    let mut stopped = false;
+    let mut result = walk_context(
+        &dbghelp,
+        expand_inline,
+        &mut context.0,
+        thread,
+        None,
+        None,
+        0,
+        false,
+        &mut |frame| {
+            #[cfg(target_pointer_width = "64")]
+            let confirmed = records.iter().any(|r| r.0 == frame.inner.addr_pc().Offset);
+            #[cfg(not(target_pointer_width = "64"))]
+            let confirmed = false;
+            let confidence = if confirmed {
+                super::FrameConfidence::Confirmed
+            } else {
+                super::FrameConfidence::Unwound
+            };
+            last_sp = frame.inner.addr_stack().Offset;
+            stopped = !cb(frame, confidence);
+            !stopped
+        },
+    );
+
+    // Records of the callers of the last frame the unwinder found lie above
+    // its stack pointer, those are the frames it missed.
+    #[cfg(target_pointer_width = "64")]
+    {
+        let walked = match result {
+            Ok(()) | Err(TraceError::NoFrames) => !stopped,
+            Err(_) => false,
+        };
+        if walked {
+            for &(ip, sp, fp) in records.iter().filter(|r| r.1 > last_sp) {
+                let frame = frame_from_record(&dbghelp, ip, sp, fp);
+                // The chain may well be intact when the unwinder fails.
+                result = Ok(());
+                if !cb(&frame, super::FrameConfidence::FramePointer) {
+                    break;
+                }
+            }
+        }
+    }
+    #[cfg(not(target_pointer_width = "64"))]
+    let _ = (last_sp, stopped);
+
+    if do_resume {
+        ResumeThread(thread);
+    }
+    result
+}
+
+/// Reads the word at `addr` of a stack of this process for `trace_reconciled`,
+/// returning `None` if it isn't readable.
+#[cfg(target_pointer_width = "64")]
+unsafe fn read_stack_word(addr: DWORD64) -> Option<DWORD64> {
+    let mut word: DWORD64 = 0;
+    let mut read = 0;
+    let size = mem::size_of_val(&word);
+    if ReadProcessMemory(
+        GetCurrentProcess(),
+        addr as LPCVOID,
+        (&mut word as *mut DWORD64).cast(),
+        size,
+        &mut read,
+    ) == FALSE
+        || read != size
+    {
+        return None;
+    }
+    Some(word)
+}
+
+pub unsafe fn trace_thread(
+    thread: super::RawThreadHandle,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    trace(cb, thread)
+}
+
+// How far below the base of the stack the last frame found by `StackWalkEx`
+// may be before `trace_best_effort` suspects it stopped early. The outermost
+// frames of a thread are small, so a walk reaching them ends well within this.
+const BEST_EFFORT_STACK_SLACK: DWORD64 = 16 * 1024;
+
+pub unsafe fn trace_best_effort(
+    thread: super::RawThreadHandle,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<super::StackWalkApi, TraceError> {
+    TRACES.fetch_add(1, Relaxed);
+
+    let (dbghelp, expand_inline) = init_for_walk()?;
+    let has_ex = expand_inline && (*dbghelp.dbghelp()).StackWalkEx().is_some();
+    let (context, do_resume) = suspend_thread_and_capture_context(thread, 0)?;
+
+    // Walks a copy of the context without yielding anything, returning the
+    // number of physical frames found and the stack pointer of the last one.
+    // Nothing may be allocated while the thread is suspended, so the frames
+    // can't be kept and the winning walk is repeated for `cb` instead.
+    let count = |expand_inline| {
+        let mut context = context;
+        let mut frames = 0;
+        let mut last_sp = 0;
+        let _ = walk_context(
+            &dbghelp,
+            expand_inline,
+            &mut context.0,
+            thread,
+            None,
+            None,
+            0,
+            false,
+            &mut |frame| {
+                if !frame.inner.is_inline() {
+                    frames += 1;
+                    last_sp = frame.inner.addr_stack().Offset;
+                }
+                true
+            },
+        );
+        (frames, last_sp)
+    };
+
+    let mut api = if has_ex {
+        super::StackWalkApi::StackWalkEx
+    } else {
+        super::StackWalkApi::StackWalk64
+    };
+    if has_ex {
+        let (frames, last_sp) = count(true);
+        let stopped_early = match thread_stack_bounds(thread) {
+            Some((_, high)) => high.saturating_sub(last_sp) > BEST_EFFORT_STACK_SLACK,
+            None => false,
+        };
+        if (frames <= 1 || stopped_early) && count(false).0 > frames {
+            api = super::StackWalkApi::StackWalk64;
+        }
+    }
+
+    let mut context = context;
+    let result = walk_context(
+        &dbghelp,
+        api == super::StackWalkApi::StackWalkEx,
+        &mut context.0,
+        thread,
+        None,
+        None,
+        0,
+        false,
+        cb,
+    );
+
+    if do_resume {
+        ResumeThread(thread);
+    }
+    result.map(|()| api)
+}
+
+/// Returns whether `thread` refers to the calling thread the way `trace`
+/// recognizes it, which captures the context directly rather than suspending.
+///
+/// Besides the pseudo handle this also matches real handles to the calling
+/// thread, e.g. duplicated or opened ones, by comparing thread ids: suspending
+/// ourselves would never return. A handle without query access can't be
+/// matched this way and is treated as another thread.
+pub fn is_current_thread(thread: HANDLE) -> bool {
+    if thread.is_null() || thread == unsafe { GetCurrentThread() } {
+        return true;
+    }
+    let id = unsafe { GetThreadId(thread) };
+    id != 0 && id == unsafe { GetCurrentThreadId() }
+}
+
+pub fn thread_id(thread: HANDLE) -> Option<u32> {
+    let id = unsafe {
+        if is_current_thread(thread) {
+            GetCurrentThreadId()
+        } else {
+            GetThreadId(thread)
+        }
+    };
+    if id == 0 {
+        None
+    } else {
+        Some(id)
+    }
+}
+
+pub fn teb_address(thread: HANDLE) -> Option<*mut c_void> {
+    unsafe { thread_teb(thread) }
+}
+
+pub fn stack_bounds(thread: HANDLE) -> Option<super::ThreadStackBounds> {
+    let (low, high) = unsafe { thread_stack_bounds(thread)? };
+    Some(super::ThreadStackBounds {
+        base: high as usize,
+        limit: low as usize,
+    })
+}
+
+pub fn validate_thread_handle(thread: HANDLE) -> Result<(), TraceError> {
+    unsafe {
+        // The pseudo handle of the current thread always has full access.
+        if thread == GetCurrentThread() {
+            return Ok(());
+        }
+        if thread.is_null() || thread == INVALID_HANDLE_VALUE {
+            return Err(TraceError::InvalidHandle(ERROR_INVALID_HANDLE));
+        }
+        let mut flags = 0;
+        if GetHandleInformation(thread, &mut flags) == FALSE {
+            return Err(TraceError::InvalidHandle(GetLastError()));
+        }
+        let required = THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT;
+        match granted_access(thread) {
+            Some(access) if access & required != required => Err(TraceError::AccessDenied),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub unsafe fn trace_with_memory_reader(
+    reader: super::MemoryReader,
+    context: *mut c_void,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    TRACES.fetch_add(1, Relaxed);
+
+    let (dbghelp, expand_inline) = init_for_walk()?;
+    walk_context(
+        &dbghelp,
+        expand_inline,
+        &mut *(context as *mut CONTEXT),
+        ptr::null_mut(),
+        Some(reader),
+        None,
+        0,
+        false,
+        cb,
+    )
+}
+
+pub unsafe fn trace_exception(
+    pointers: *mut c_void,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    TRACES.fetch_add(1, Relaxed);
+
+    let (dbghelp, expand_inline) = init_for_walk()?;
+    // The walk updates the context, so walk a copy to leave the exception
+    // record intact for whoever handles the exception.
+    let pointers = &*(pointers as *const EXCEPTION_POINTERS);
+    let mut context = MyContext(*pointers.ContextRecord);
+    walk_context(
+        &dbghelp,
+        expand_inline,
+        &mut context.0,
+        ptr::null_mut(),
+        None,
+        None,
+        0,
+        false,
+        cb,
+    )
+}
+
+// A request of `trace_thread_cooperative` for a thread to park at its next
+// safe point. The requests waiting for their thread form a list, which is
+// only accessed with `PARK_LOCK` held.
+struct ParkRequest {
+    thread_id: DWORD,
+    next: *mut ParkRequest,
+    // Set by the thread once it's captured its registers into `context`.
+    parked: HANDLE,
+    // Set by the tracer once it's done walking the thread, after which the
+    // thread frees the request and closes both events.
+    released: HANDLE,
+    context: MyContext,
+    marker: usize,
+}
+
+static mut PARK_LOCK: SRWLOCK = SRWLOCK { Ptr: 0 as PVOID };
+static PARK_REQUESTS: AtomicPtr<ParkRequest> = AtomicPtr::new(ptr::null_mut());
+
+/// A thread parked at a safe point by `park_thread`, released when dropped.
+pub struct ParkedThread {
+    // Released by this and then freed by the parked thread, or null for the
+    // calling thread, which doesn't park.
+    request: *mut ParkRequest,
+    marker: usize,
+}
+
+pub unsafe fn park_thread(
+    thread_id: u32,
+    timeout: Duration,
+    marker: *const u8,
+) -> Result<ParkedThread, TraceError> {
+    if thread_id == GetCurrentThreadId() {
+        return Ok(ParkedThread {
+            request: ptr::null_mut(),
+            marker: marker as usize,
+        });
+    }
+
+    let parked = CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null());
+    if parked.is_null() {
+        return Err(TraceError::RendezvousFailed(GetLastError()));
+    }
+    let released = CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null());
+    if released.is_null() {
+        let err = GetLastError();
+        CloseHandle(parked);
+        return Err(TraceError::RendezvousFailed(err));
+    }
+    // The request is boxed as the thread accesses it through the list.
+    let request = Box::into_raw(Box::new(ParkRequest {
+        thread_id,
+        next: ptr::null_mut(),
+        parked,
+        released,
+        context: mem::zeroed(),
+        marker: 0,
+    }));
+
+    AcquireSRWLockExclusive(ptr::addr_of_mut!(PARK_LOCK));
+    (*request).next = PARK_REQUESTS.load(Relaxed);
+    PARK_REQUESTS.store(request, Relaxed);
+    ReleaseSRWLockExclusive(ptr::addr_of_mut!(PARK_LOCK));
+
+    // `INFINITE` is reserved, so longer timeouts are capped just below it.
+    let millis = timeout.as_millis().min(u128::from(INFINITE - 1)) as DWORD;
+    if WaitForSingleObject(parked, millis) != WAIT_OBJECT_0 {
+        AcquireSRWLockExclusive(ptr::addr_of_mut!(PARK_LOCK));
+        let pending = take_park_request(|r| r == request).is_some();
+        ReleaseSRWLockExclusive(ptr::addr_of_mut!(PARK_LOCK));
+        if pending {
+            CloseHandle(parked);
+            CloseHandle(released);
+            drop(Box::from_raw(request));
+            return Err(TraceError::TimedOut);
+        }
+        // The thread took the request just as the wait timed out, so it's
+        // about to park.
+        WaitForSingleObject(parked, INFINITE);
+    }
+    Ok(ParkedThread { request, marker: 0 })
+}
+
+// Removes the first request for which `matches` returns true from the list.
+// `PARK_LOCK` has to be held.
+unsafe fn take_park_request(
+    mut matches: impl FnMut(*mut ParkRequest) -> bool,
+) -> Option<*mut ParkRequest> {
+    let mut prev: *mut ParkRequest = ptr::null_mut();
+    let mut cur = PARK_REQUESTS.load(Relaxed);
+    while !cur.is_null() {
+        if matches(cur) {
+            if prev.is_null() {
+                PARK_REQUESTS.store((*cur).next, Relaxed);
+            } else {
+                (*prev).next = (*cur).next;
+            }
+            return Some(cur);
+        }
+        prev = cur;
+        cur = (*cur).next;
+    }
+    None
+}
+
+impl ParkedThread {
+    pub unsafe fn trace(
+        &mut self,
+        cb: &mut dyn FnMut(&super::Frame) -> bool,
+    ) -> Result<(), TraceError> {
+        if self.request.is_null() {
+            let limit = self.marker;
+            return trace(
+                &mut |frame| (frame.sp() as usize) < limit || cb(frame),
+                GetCurrentThread(),
+            );
+        }
+        let request = &mut *self.request;
+        TRACES.fetch_add(1, Relaxed);
+
+        let (dbghelp, expand_inline) = init_for_walk()?;
+        // The frames of `safe_point` are below its marker, so the walk starts
+        // at its caller.
+        let limit = request.marker;
+        walk_context(
+            &dbghelp,
+            expand_inline,
+            &mut request.context.0,
+            ptr::null_mut(),
+            None,
+            None,
+            0,
+            false,
+            &mut |frame| (frame.sp() as usize) < limit || cb(frame),
+        )
+    }
+}
+
+impl Drop for ParkedThread {
+    fn drop(&mut self) {
+        if self.request.is_null() {
+            return;
+        }
+        // The thread is waiting for `released`, so the events can't be
+        // closed here without pulling the handle out from under its wait. It
+        // frees the request and closes them itself once it's woken up, so the
+        // request mustn't be touched after this.
+        unsafe {
+            SetEvent((*self.request).released);
+        }
+    }
+}
+
+pub fn safe_point(marker: *const u8) {
+    // Checking the list without the lock keeps safe points cheap while nobody
+    // is tracing. A request added concurrently is found at the next one.
+    if PARK_REQUESTS.load(Relaxed).is_null() {
+        return;
+    }
+    unsafe {
+        let id = GetCurrentThreadId();
+        AcquireSRWLockExclusive(ptr::addr_of_mut!(PARK_LOCK));
+        let request = take_park_request(|r| (*r).thread_id == id);
+        ReleaseSRWLockExclusive(ptr::addr_of_mut!(PARK_LOCK));
+        let request = match request {
+            Some(request) => request,
+            None => return,
+        };
+        (*request).marker = marker as usize;
+        RtlCaptureContext(&mut (*request).context.0);
+        // This frame has to stay on the stack while the tracer walks from the
+        // captured registers. Signaling and starting to wait in one step means
+        // the tracer can't release the thread before it's waiting.
+        SignalObjectAndWait((*request).parked, (*request).released, INFINITE, FALSE);
+        // The tracer is done with the request, and left freeing it to this
+        // thread, see `ParkedThread::drop`.
+        let request = Box::from_raw(request);
+        CloseHandle(request.parked);
+        CloseHandle(request.released);
+    }
+}
+
+// Whether walks may skip initializing the symbol handler, see
+// `set_deferred_symbol_init`.
+static DEFER_SYMBOL_INIT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_deferred_symbol_init(enabled: bool) {
+    DEFER_SYMBOL_INIT.store(enabled, Relaxed);
+}
+
+/// Loads dbghelp for a walk, also returning whether the walk should expand
+/// inlined calls.
+///
+/// The symbol handler is only initialized if the walk needs it. `StackWalkEx`
+/// can't expand inlined calls without it, so those walks use `StackWalk64`
+/// instead, to not depend on whether something else happened to initialize
+/// it already.
+unsafe fn init_for_walk() -> Result<(dbghelp::Init, bool), TraceError> {
+    let deferred = DEFER_SYMBOL_INIT.load(Relaxed) && !walk_needs_symbol_handler();
+    let init = if deferred {
+        dbghelp::load()
+    } else {
+        dbghelp::init()
+    };
+    match init {
+        Ok(dbghelp) => Ok((dbghelp, !deferred)),
+        Err(e) => Err(TraceError::DbghelpUnavailable(e)),
+    }
+}
+
+/// Returns whether the function table and module base callbacks picked by
+/// `walk_context` rely on the symbol handler, which is the case for the `Sym*`
+/// functions.
+fn walk_needs_symbol_handler() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(target_pointer_width = "64")] {
+            rtl_lookup_function_entry().is_none()
+                || DBGHELP_FUNCTION_TABLE_ACCESS.load(Relaxed) != 0
+        } else {
+            true
+        }
+    }
+}
+
+/// Walks the stack described by `context`, which is updated as the walk
+/// proceeds, reading memory through `read_memory` if given and directly from
+/// the current process otherwise. Inlined calls are expanded into frames of
+/// their own if `expand_inline` is set and `StackWalkEx` is available. The
+/// walk is done for the `IMAGE_FILE_MACHINE_*` type `machine` if given, and
+/// for the architecture this was compiled for otherwise. `flags` are passed
+/// on to `StackWalkEx`, and ignored if `StackWalk64` is used. If `lazy_base`
+/// is set the module bases of the frames are left to be looked up on demand,
+/// see `trace_lazy_base`.
+unsafe fn walk_context(
+    dbghelp: &dbghelp::Init,
+    expand_inline: bool,
+    context: &mut CONTEXT,
+    thread: HANDLE,
+    read_memory: PREAD_PROCESS_MEMORY_ROUTINE64,
+    machine: Option<WORD>,
+    flags: DWORD,
+    lazy_base: bool,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    // A thread created suspended hasn't pushed anything on its stack yet, the
+    // only frame it has is the routine it's about to start.
+    if read_memory.is_none() && machine.is_none() {
+        if let Some(start) = unstarted_thread_start(context) {
+            cb(&frame_from_record(
+                dbghelp,
+                start,
+                stack_pointer(context),
+                0,
+            ));
+            return Ok(());
+        }
+    }
+
+    // On x86_64 and ARM64 we opt to not use the default `Sym*` functions from
+    // dbghelp for getting the function table and module base. Instead we use
+    // the `RtlLookupFunctionEntry` function in kernel32 which will account for
+    // JIT compiler frames as well. These should be equivalent, but using
+    // `Rtl*` allows us to backtrace through JIT frames.
+    //
+    // Note that `RtlLookupFunctionEntry` only works for in-process backtraces,
+    // but that's all we support anyway, so it all lines up well.
+    //
+    // `RtlLookupFunctionEntry` is loaded dynamically, and if it's not
+    // available we fall back to the `Sym*` functions. We lose the ability to
+    // walk through JIT frames in that case but can otherwise still walk.
+    cfg_if::cfg_if! {
+        if #[cfg(target_pointer_width = "64")] {
+            unsafe extern "system" fn function_table_access(process: HANDLE, addr: DWORD64) -> PVOID {
+                let lookup = match rtl_lookup_function_entry() {
+                    Some(lookup) => lookup,
+                    None => return ptr::null_mut(),
+                };
+                let mut base = 0;
+                let entry = lookup(addr, &mut base, ptr::null_mut());
+                if entry.is_null() {
+                    // Code the OS doesn't know about may still be described by
+                    // a callback registered with dbghelp, which only
+                    // `SymFunctionTableAccess64` consults.
+                    let fallback = DBGHELP_FUNCTION_TABLE_ACCESS.load(Relaxed);
+                    if fallback != 0 {
+                        let fallback = mem::transmute::<usize, dbghelp::SymFunctionTableAccess64>(fallback);
+                        return fallback(process, addr);
+                    }
+                }
+                entry.cast()
+            }
+
+            unsafe extern "system" fn get_module_base(_process: HANDLE, addr: DWORD64) -> DWORD64 {
+                let lookup = match rtl_lookup_function_entry() {
+                    Some(lookup) => lookup,
+                    None => return 0,
+                };
+                cached_module_base(addr, || {
+                    let mut base = 0;
+                    lookup(addr, &mut base, ptr::null_mut());
+                    base
+                })
+            }
+            clear_module_bases();
+
+            let (function_table_access, get_module_base) = match rtl_lookup_function_entry() {
+                Some(_) => (
+                    function_table_access as dbghelp::SymFunctionTableAccess64,
+                    get_module_base as dbghelp::SymGetModuleBase64,
+                ),
+                None => (dbghelp.SymFunctionTableAccess64(), dbghelp.SymGetModuleBase64()),
+            };
+        } else {
+            let function_table_access = dbghelp.SymFunctionTableAccess64();
+            let get_module_base = dbghelp.SymGetModuleBase64();
+        }
+    }
+
+    let (mut frame, walk) = new_walk(dbghelp, expand_inline);
+    let native = init_frame(&mut frame.inner, context);
+    let image = machine.unwrap_or(native);
+    let mut walker = DbghelpWalker {
+        walk,
+        image: image as DWORD,
+        process: GetCurrentProcess(),
+        thread,
+        context: (context as *mut CONTEXT).cast(),
+        read_memory,
+        function_table_access,
+        get_module_base,
+        flags,
+        lazy_base,
+    };
+    walk_stack(&mut walker, &mut frame, cb)
+}
+
+/// Sets up the first frame of a walk, which still has to be pointed at the
+/// registers the walk starts from, and picks the function walking it.
+unsafe fn new_walk(dbghelp: &dbghelp::Init, expand_inline: bool) -> (super::Frame, Walk) {
+    // Attempt to use `StackWalkEx` if we can, but fall back to `StackWalk64`
+    // since it's in theory supported on more systems.
+    let stack_walk_ex = if expand_inline {
+        (*dbghelp.dbghelp()).StackWalkEx()
+    } else {
+        None
+    };
+    let (stack_frame, walk) = match stack_walk_ex {
+        Some(StackWalkEx) => {
+            let mut inner: STACKFRAME_EX = mem::zeroed();
+            inner.StackFrameSize = mem::size_of::<STACKFRAME_EX>() as DWORD;
+            (StackFrame::New(inner), Walk::Ex(StackWalkEx))
+        }
+        None => (
+            StackFrame::Old(mem::zeroed()),
+            Walk::Old(dbghelp.StackWalk64()),
+        ),
+    };
+    let frame = super::Frame {
+        inner: Frame {
+            stack_frame,
+            base_address: AtomicUsize::new(0),
+        },
+    };
+    (frame, walk)
+}
+
+// The size of the largest `CONTEXT` record of the architectures
+// `trace_guest_context` supports, that of x86_64.
+const GUEST_CONTEXT_SIZE: usize = 0x4d0;
+
+#[repr(C, align(16))]
+struct GuestContext([u8; GUEST_CONTEXT_SIZE]);
+
+pub unsafe fn trace_guest_context(
+    arch: super::GuestArch,
+    registers: &super::GuestRegisters,
+    reader: Option<super::MemoryReader>,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    TRACES.fetch_add(1, Relaxed);
+
+    let (dbghelp, expand_inline) = init_for_walk()?;
+    let mut context = GuestContext([0; GUEST_CONTEXT_SIZE]);
+    let machine = guest_context(arch, registers, &mut context.0);
+
+    let (mut frame, walk) = new_walk(&dbghelp, expand_inline);
+    frame.inner.addr_pc_mut().Offset = registers.pc;
+    frame.inner.addr_pc_mut().Mode = AddrModeFlat;
+    frame.inner.addr_stack_mut().Offset = registers.sp;
+    frame.inner.addr_stack_mut().Mode = AddrModeFlat;
+    frame.inner.addr_frame_mut().Offset = registers.fp;
+    frame.inner.addr_frame_mut().Mode = AddrModeFlat;
+
+    // `RtlLookupFunctionEntry` only knows the unwind information of the
+    // native architecture, so unlike `walk_context` this always relies on
+    // dbghelp, which reads it from the modules for the walked machine type.
+    let mut walker = DbghelpWalker {
+        walk,
+        image: machine as DWORD,
+        process: GetCurrentProcess(),
+        thread: ptr::null_mut(),
+        context: context.0.as_mut_ptr().cast(),
+        read_memory: reader,
+        function_table_access: dbghelp.SymFunctionTableAccess64(),
+        get_module_base: dbghelp.SymGetModuleBase64(),
+        flags: 0,
+        lazy_base: false,
+    };
+    walk_stack(&mut walker, &mut frame, cb)
+}
+
+/// Lays out `registers` in `context` as the `CONTEXT` record of `arch`, which
+/// differs from the native one for guests of another architecture, returning
+/// the machine type to walk it as. The `ContextFlags` claim the control and
+/// integer registers, all of which other than the given ones are zero.
+fn guest_context(
+    arch: super::GuestArch,
+    registers: &super::GuestRegisters,
+    context: &mut [u8; GUEST_CONTEXT_SIZE],
+) -> WORD {
+    use super::GuestArch;
+
+    fn put32(context: &mut [u8], offset: usize, value: u64) {
+        context[offset..offset + 4].copy_from_slice(&(value as u32).to_le_bytes());
+    }
+    fn put64(context: &mut [u8], offset: usize, value: u64) {
+        context[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let r = registers;
+    match arch {
+        GuestArch::X86 => {
+            put32(context, 0x00, 0x0001_0003); // ContextFlags
+            put32(context, 0xb4, r.fp); // Ebp
+            put32(context, 0xb8, r.pc); // Eip
+            put32(context, 0xc4, r.sp); // Esp
+            IMAGE_FILE_MACHINE_I386
+        }
+        GuestArch::X86_64 => {
+            put32(context, 0x30, 0x0010_0003); // ContextFlags
+            put64(context, 0x98, r.sp); // Rsp
+            put64(context, 0xa0, r.fp); // Rbp
+            put64(context, 0xf8, r.pc); // Rip
+            IMAGE_FILE_MACHINE_AMD64
+        }
+        GuestArch::Arm64 => {
+            put32(context, 0x00, 0x0040_0003); // ContextFlags
+            put64(context, 0xf0, r.fp); // Fp
+            put64(context, 0xf8, r.lr); // Lr
+            put64(context, 0x100, r.sp); // Sp
+            put64(context, 0x108, r.pc); // Pc
+            IMAGE_FILE_MACHINE_ARM64
+        }
+        GuestArch::Arm => {
+            put32(context, 0x00, 0x0020_0003); // ContextFlags
+            put32(context, 0x30, r.fp); // R11
+            put32(context, 0x38, r.sp); // Sp
+            put32(context, 0x3c, r.lr); // Lr
+            put32(context, 0x40, r.pc); // Pc
+            put32(context, 0x44, 0x20); // Cpsr, in Thumb state
+            IMAGE_FILE_MACHINE_ARMNT
+        }
+    }
+}
+
+pub unsafe fn trace_all_threads(
+    options: &super::ThreadSnapshotOptions,
+    cb: &mut dyn FnMut(&super::ThreadInfo, &super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    for_each_thread(options, &mut |mut info| {
+        trace_listed_thread(options, &mut info, cb)
+    })
+}
+
+/// Enumerates the threads of the current process, passing the information
+/// gathered about each one to `f`.
+unsafe fn for_each_thread(
+    options: &super::ThreadSnapshotOptions,
+    f: &mut dyn FnMut(super::ThreadInfo),
+) -> Result<(), TraceError> {
+    let process = GetCurrentProcessId();
+    let current = GetCurrentThreadId();
+
+    // Thread states are read up front, as this allocates and must not happen
+    // while any thread is suspended.
+    let states = if options.scheduling {
+        query_thread_states()
+    } else {
+        None
+    };
+
+    // Note that the snapshot always includes the threads of all processes,
+    // the process id argument only applies to module and heap snapshots.
+    let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+    if snapshot == INVALID_HANDLE_VALUE {
+        return Err(TraceError::EnumerationFailed(GetLastError()));
+    }
+
+    let mut entry: THREADENTRY32 = mem::zeroed();
+    entry.dwSize = mem::size_of::<THREADENTRY32>() as DWORD;
+    let mut more = Thread32First(snapshot, &mut entry) == TRUE;
+    while more {
+        if entry.th32OwnerProcessID == process && options.includes(entry.th32ThreadID) {
+            let mut info = super::ThreadInfo {
+                id: entry.th32ThreadID,
+                is_current: entry.th32ThreadID == current,
+                priority: None,
+                state: None,
+                wait_reason: None,
+                kernel_time: None,
+                user_time: None,
+                stack_bounds: None,
+                teb_address: None,
+                in_system_call: None,
+                in_exception: None,
+                ideal_processor: None,
+                affinity: None,
+            };
+            if let Some(states) = &states {
+                if let Some(thread) = find_thread_state(states, process, info.id) {
+                    info.state = Some(thread.ThreadState);
+                    if thread.ThreadState == THREAD_STATE_WAITING {
+                        info.wait_reason = Some(thread.WaitReason);
+                    }
+                }
+            }
+            f(info);
+        }
+        more = Thread32Next(snapshot, &mut entry) == TRUE;
+    }
+    CloseHandle(snapshot);
+    Ok(())
+}
+
+pub unsafe fn list_threads(
+    options: &super::ThreadSnapshotOptions,
+) -> Result<Vec<super::ThreadInfo>, TraceError> {
+    let mut threads = Vec::new();
+    for_each_thread(options, &mut |mut info| {
+        let thread = open_listed_thread(options, &mut info);
+        if !thread.is_null() {
+            if options.cpu_times {
+                read_cpu_times(thread, &mut info);
+            }
+            if !info.is_current {
+                CloseHandle(thread);
+            }
+        }
+        threads.push(info);
+    })?;
+    Ok(threads)
+}
+
+/// Opens the thread `id` with the access needed to trace it and calls `f`
+/// with its handle, which is closed afterwards. Returns `false` if the thread
+/// couldn't be opened.
+pub unsafe fn with_thread(id: u32, f: &mut dyn FnMut(HANDLE)) -> bool {
+    if id == GetCurrentThreadId() {
+        f(GetCurrentThread());
+        return true;
+    }
+    let thread = OpenThread(
+        THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_INFORMATION,
+        FALSE,
+        id,
+    );
+    if thread.is_null() {
+        return false;
+    }
+    f(thread);
+    CloseHandle(thread);
+    true
+}
+
+/// Traces one thread found by `trace_all_threads`, skipping it if it can't be
+/// traced, for example because it exited after being enumerated.
+unsafe fn trace_listed_thread(
+    options: &super::ThreadSnapshotOptions,
+    info: &mut super::ThreadInfo,
+    cb: &mut dyn FnMut(&super::ThreadInfo, &super::Frame) -> bool,
+) {
+    let thread = open_listed_thread(options, info);
+    if thread.is_null() {
+        return;
+    }
+    if options.cpu_times {
+        read_cpu_times(thread, info);
+    }
+
+    if options.exception_request {
+        trace_reporting_state(thread, info, cb);
+    } else {
+        let info = &*info;
+        let _ = trace(&mut |frame| cb(info, frame), thread);
+    }
+    if !info.is_current {
+        CloseHandle(thread);
+    }
+}
+
+/// Walks the stack of `thread` like `trace`, reading its registers with
+/// `CONTEXT_EXCEPTION_REQUEST` and recording the state reported in `info`
+/// before any frame is yielded.
+unsafe fn trace_reporting_state(
+    thread: HANDLE,
+    info: &mut super::ThreadInfo,
+    cb: &mut dyn FnMut(&super::ThreadInfo, &super::Frame) -> bool,
+) {
+    // The state is recorded before the first frame is yielded, so the two
+    // callbacks never borrow `info` at the same time.
+    let info = core::cell::RefCell::new(info);
+    let mut context = mem::zeroed::<MyContext>();
+    let _ = trace_in(
+        &mut |frame| cb(&info.borrow(), frame),
+        thread,
+        None,
+        0,
+        false,
+        Some(&mut |flags| record_context_state(flags, &mut info.borrow_mut())),
+        &mut context,
+    );
+}
+
+/// Returns the flags `GetThreadContext` set in `context` to report the state
+/// of the thread when asked to with `CONTEXT_EXCEPTION_REQUEST`.
+///
+/// The reporting flags are cleared from `context`, so it only describes the
+/// registers it holds.
+fn take_context_state(context: &mut CONTEXT) -> DWORD {
+    let reporting = CONTEXT_EXCEPTION_REQUEST
+        | CONTEXT_EXCEPTION_REPORTING
+        | CONTEXT_SERVICE_ACTIVE
+        | CONTEXT_EXCEPTION_ACTIVE;
+    let flags = context.ContextFlags & reporting;
+    context.ContextFlags &= !reporting;
+    flags
+}
+
+/// Records in `info` whether the thread was in a system call or dispatching
+/// an exception, if `flags` taken with `take_context_state` report it.
+fn record_context_state(flags: DWORD, info: &mut super::ThreadInfo) {
+    if flags & CONTEXT_EXCEPTION_REPORTING != 0 {
+        info.in_system_call = Some(flags & CONTEXT_SERVICE_ACTIVE != 0);
+        info.in_exception = Some(flags & CONTEXT_EXCEPTION_ACTIVE != 0);
+    }
+}
+
+/// Opens a handle to a thread found by `for_each_thread` with the access
+/// needed to trace it, returning null if it can't be opened.
+///
+/// The handle of the calling thread is a pseudo handle which must not be
+/// closed, other handles must be closed by the caller.
+unsafe fn open_listed_thread(
+    options: &super::ThreadSnapshotOptions,
+    info: &mut super::ThreadInfo,
+) -> HANDLE {
+    let thread = if info.is_current {
+        GetCurrentThread()
+    } else {
+        OpenThread(
+            THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_INFORMATION,
+            FALSE,
+            info.id,
+        )
+    };
+    if thread.is_null() {
+        return thread;
+    }
+    info.stack_bounds = stack_bounds(thread);
+    info.teb_address = thread_teb(thread).map(|teb| teb as usize);
+    if options.scheduling {
+        let priority = GetThreadPriority(thread);
+        if priority as DWORD != THREAD_PRIORITY_ERROR_RETURN {
+            info.priority = Some(priority);
+        }
+    }
+    if options.affinity {
+        read_affinity(thread, info);
+    }
+    thread
+}
+
+/// Records the ideal processor and the affinity of `thread` in `info`.
+unsafe fn read_affinity(thread: HANDLE, info: &mut super::ThreadInfo) {
+    let mut ideal = mem::zeroed::<PROCESSOR_NUMBER>();
+    if GetThreadIdealProcessorEx(thread, &mut ideal) == TRUE {
+        info.ideal_processor = Some((ideal.Group, ideal.Number));
+    }
+    let mut affinity = mem::zeroed::<GROUP_AFFINITY>();
+    if GetThreadGroupAffinity(thread, &mut affinity) == TRUE {
+        info.affinity = Some((affinity.Group, affinity.Mask));
+    }
+}
+
+/// Records the CPU time consumed by `thread` so far in `info`.
+unsafe fn read_cpu_times(thread: HANDLE, info: &mut super::ThreadInfo) {
+    let mut creation = mem::zeroed();
+    let mut exit = mem::zeroed();
+    let mut kernel = mem::zeroed();
+    let mut user = mem::zeroed();
+    if GetThreadTimes(thread, &mut creation, &mut exit, &mut kernel, &mut user) == TRUE {
+        info.kernel_time = Some(filetime_duration(&kernel));
+        info.user_time = Some(filetime_duration(&user));
+    }
+}
+
+/// Converts a `FILETIME` holding a duration, in 100ns units, to a `Duration`.
+fn filetime_duration(time: &FILETIME) -> Duration {
+    let ticks = u64::from(time.dwHighDateTime) << 32 | u64::from(time.dwLowDateTime);
+    Duration::new(ticks / 10_000_000, (ticks % 10_000_000) as u32 * 100)
+}
+
+/// The registers and a copy of the innermost part of the stack of a thread,
+/// taken by `snapshot_all_threads`.
+pub struct CapturedThread {
+    info: super::ThreadInfo,
+    handle: HANDLE,
+    suspended: bool,
+    context: Option<MyContext>,
+    /// Address the copy of the stack starts at, the stack pointer of the
+    /// thread when it was suspended.
+    stack_start: DWORD64,
+    /// End of the memory region the stack pointer points into, any memory
+    /// between the end of the copy and this is the part of the stack that
+    /// wasn't copied.
+    stack_end: DWORD64,
+    stack: Vec<u8>,
+    frame_pointer_fallback: bool,
+}
+
+// The handle is only used by `snapshot_all_threads`, after that this is plain
+// data.
+unsafe impl Send for CapturedThread {}
+unsafe impl Sync for CapturedThread {}
+
+impl CapturedThread {
+    pub fn info(&self) -> &super::ThreadInfo {
+        &self.info
+    }
+
+    /// Returns the raw `CONTEXT` of the thread.
+    #[cfg(feature = "minidump")]
+    pub fn context_bytes(&self) -> Option<&[u8]> {
+        let context = &self.context.as_ref()?.0;
+        Some(unsafe {
+            slice::from_raw_parts(
+                context as *const CONTEXT as *const u8,
+                mem::size_of::<CONTEXT>(),
+            )
+        })
+    }
+
+    /// Returns the address the copy of the stack starts at, and the copy.
+    #[cfg(feature = "minidump")]
+    pub fn stack_copy(&self) -> (u64, &[u8]) {
+        (self.stack_start, &self.stack)
+    }
+}
+
+pub unsafe fn snapshot_all_threads(
+    options: &super::ThreadSnapshotOptions,
+) -> Result<super::AllThreadsSnapshot, TraceError> {
+    let mut threads = open_for_snapshot(options, &[])?;
+    let timestamp = if options.timestamp {
+        performance_counter()
+    } else {
+        None
+    };
+    capture_threads(options, &mut threads);
+
+    // Threads which exited between being enumerated and being captured have
+    // simply gone away, but others may have been in the middle of starting
+    // or the id was reused. Enumerating again tells them apart: threads
+    // which are still listed are captured once more, along with any threads
+    // which started in the meantime.
+    let mut unstable = take_uncaptured(&mut threads);
+    let mut retries = 0;
+    while !unstable.is_empty() && retries < options.retries {
+        retries += 1;
+        let mut captured = threads.iter().map(|t| t.info.id).collect::<Vec<_>>();
+        captured.sort_unstable();
+        let mut retried = open_for_snapshot(options, &captured)?;
+        capture_threads(options, &mut retried);
+        unstable = take_uncaptured(&mut retried);
+        threads.append(&mut retried);
+    }
+
+    Ok(super::AllThreadsSnapshot {
+        threads,
+        timestamp,
+        correlation_id: options.correlation_id,
+        retries,
+        unstable,
+    })
+}
+
+/// Enumerates and opens the threads to capture for `snapshot_all_threads`,
+/// skipping those in the sorted `captured`. The ids of threads which can't be
+/// opened are recorded as threads without a context.
+///
+/// Everything which allocates happens here, as nothing may allocate once the
+/// first thread is suspended.
+unsafe fn open_for_snapshot(
+    options: &super::ThreadSnapshotOptions,
+    captured: &[u32],
+) -> Result<Vec<CapturedThread>, TraceError> {
+    let mut threads = Vec::new();
+    for_each_thread(options, &mut |mut info| {
+        if captured.binary_search(&info.id).is_ok() {
+            return;
+        }
+        let handle = open_listed_thread(options, &mut info);
+        let mut stack = Vec::new();
+        if !handle.is_null() {
+            stack.resize(options.stack_copy_size, 0);
+        }
+        threads.push(CapturedThread {
+            info,
+            handle,
+            suspended: false,
+            context: None,
+            stack_start: 0,
+            stack_end: 0,
+            stack,
+            frame_pointer_fallback: options.frame_pointer_fallback,
+        });
+    })?;
+    Ok(threads)
+}
+
+/// Captures `threads` in batches of `ThreadSnapshotOptions::batch_size`.
+unsafe fn capture_threads(options: &super::ThreadSnapshotOptions, threads: &mut [CapturedThread]) {
+    let batch_size = match options.batch_size {
+        0 => threads.len().max(1),
+        n => n,
+    };
+    for batch in threads.chunks_mut(batch_size) {
+        capture_batch(options, batch);
+    }
+}
+
+/// Removes the threads whose registers couldn't be captured from `threads`,
+/// returning their ids.
+fn take_uncaptured(threads: &mut Vec<CapturedThread>) -> Vec<u32> {
+    let uncaptured = threads
+        .iter()
+        .filter(|t| t.context.is_none())
+        .map(|t| t.info.id)
+        .collect();
+    threads.retain(|t| t.context.is_some());
+    uncaptured
+}
+
+// Declared here rather than in `windows.rs`, as winapi declares these with the
+// `LARGE_INTEGER` union, which `ffi!` can't check against.
+extern "system" {
+    fn QueryPerformanceCounter(lpPerformanceCount: *mut i64) -> BOOL;
+    fn QueryPerformanceFrequency(lpFrequency: *mut i64) -> BOOL;
+}
+
+/// Returns the current value of the performance counter and its frequency.
+fn performance_counter() -> Option<(u64, u64)> {
+    let mut counter = 0;
+    let mut frequency = 0;
+    unsafe {
+        if QueryPerformanceCounter(&mut counter) == FALSE
+            || QueryPerformanceFrequency(&mut frequency) == FALSE
+        {
+            return None;
+        }
+    }
+    Some((counter as u64, frequency as u64))
+}
+
+/// Suspends the threads of `batch`, captures their registers and stacks, and
+/// resumes and closes them again.
+unsafe fn capture_batch(options: &super::ThreadSnapshotOptions, batch: &mut [CapturedThread]) {
+    for thread in batch.iter_mut().filter(|t| !t.info.is_current) {
+        if thread.handle.is_null() {
+            continue;
+        }
+        if SuspendThread(thread.handle) as i32 == -1 {
+            SUSPEND_FAILURES.fetch_add(1, Relaxed);
+        } else {
+            SUSPENSIONS.fetch_add(1, Relaxed);
+            thread.suspended = true;
+        }
+    }
+
+    for thread in batch.iter_mut() {
+        let mut context = mem::zeroed::<MyContext>();
+        if thread.info.is_current {
+            RtlCaptureContext(&mut context.0);
+        } else if !thread.suspended {
+            continue;
+        } else {
+            context.0.ContextFlags = CONTEXT_CONTROL | CONTEXT_INTEGER;
+            if options.exception_request {
+                context.0.ContextFlags |= CONTEXT_EXCEPTION_REQUEST;
+            }
+            if GetThreadContext(thread.handle, &mut context.0) == 0 {
+                CONTEXT_FAILURES.fetch_add(1, Relaxed);
+                continue;
+            }
+            record_context_state(take_context_state(&mut context.0), &mut thread.info);
+            if !context_is_plausible(thread.handle, &context.0) {
+                continue;
+            }
+        }
+        thread.context = Some(context);
+        copy_stack(thread, stack_pointer(&context.0));
+        if options.cpu_times {
+            read_cpu_times(thread.handle, &mut thread.info);
+        }
+    }
+
+    for thread in batch.iter_mut() {
+        if thread.suspended {
+            ResumeThread(thread.handle);
+        }
+        if !thread.info.is_current && !thread.handle.is_null() {
+            CloseHandle(thread.handle);
+        }
+        thread.handle = ptr::null_mut();
+    }
+}
+
+/// Copies the stack of a suspended `thread` starting at `sp` into its
+/// preallocated buffer, without allocating.
+unsafe fn copy_stack(thread: &mut CapturedThread, sp: DWORD64) {
+    let mut region: MEMORY_BASIC_INFORMATION = mem::zeroed();
+    let size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+    if VirtualQuery(sp as LPCVOID, &mut region, size) != size {
+        thread.stack.clear();
+        return;
+    }
+    let end = region.BaseAddress as DWORD64 + region.RegionSize as DWORD64;
+    let len = (end.saturating_sub(sp) as usize).min(thread.stack.len());
+    let mut read = 0;
+    if ReadProcessMemory(
+        GetCurrentProcess(),
+        sp as LPCVOID,
+        thread.stack.as_mut_ptr().cast(),
+        len,
+        &mut read,
+    ) == FALSE
+    {
+        read = 0;
+    }
+    thread.stack.truncate(read);
+    thread.stack_start = sp;
+    thread.stack_end = end;
+}
+
+// The thread being walked by `trace_captured`, read by `read_captured_stack`.
+// Walks are serialized by the dbghelp lock, which is held throughout.
+static CAPTURED_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+pub unsafe fn trace_captured(
+    thread: &CapturedThread,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    TRACES.fetch_add(1, Relaxed);
+
+    let (dbghelp, expand_inline) = init_for_walk()?;
+    // The walk updates the context, copy it so the snapshot can be walked
+    // again.
+    let mut context = match thread.context {
+        Some(context) => context,
+        None => return Ok(()),
+    };
+    let prev = CAPTURED_THREAD.swap(thread as *const _ as usize, Relaxed);
+    let mut last_fp = None;
+    let mut stopped = false;
+    #[cfg_attr(not(target_arch = "aarch64"), allow(unused_mut))]
+    let mut result = walk_context(
+        &dbghelp,
+        expand_inline,
+        &mut context.0,
+        ptr::null_mut(),
+        Some(read_captured_stack),
+        None,
+        0,
+        false,
+        &mut |frame| {
+            last_fp = Some(frame.inner.addr_frame().Offset);
+            stopped = !cb(frame);
+            !stopped
+        },
+    );
+    CAPTURED_THREAD.store(prev, Relaxed);
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        let walked = match result {
+            Ok(()) | Err(TraceError::NoFrames) => !stopped,
+            Err(_) => false,
+        };
+        if thread.frame_pointer_fallback && walked {
+            let mut found = false;
+            follow_captured_frame_records(&dbghelp, thread, last_fp, &mut |frame| {
+                found = true;
+                cb(frame)
+            });
+            if found {
+                result = Ok(());
+            }
+        }
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = (last_fp, thread.frame_pointer_fallback);
+    result
+}
+
+/// Continues a walk of `thread` which the unwinder gave up on by following the
+/// frame records on its copied stack, starting from the frame pointer of the
+/// last frame found, `last_fp`. If the unwinder found no frames at all the walk
+/// starts from the captured registers instead.
+#[cfg(target_arch = "aarch64")]
+unsafe fn follow_captured_frame_records(
+    dbghelp: &dbghelp::Init,
+    thread: &CapturedThread,
+    last_fp: Option<DWORD64>,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) {
+    let mut yield_frame =
+        |ip: DWORD64, sp: DWORD64, fp: DWORD64| cb(&frame_from_record(dbghelp, ip, sp, fp));
+
+    let fp = match (last_fp, thread.context) {
+        (Some(fp), _) => fp,
+        (None, Some(context)) => {
+            let context = &context.0;
+            let fp = context.u.s().Fp as DWORD64;
+            if !yield_frame(context.Pc as DWORD64, context.Sp as DWORD64, fp) {
+                return;
+            }
+            fp
+        }
+        (None, None) => return,
+    };
+    walk_frame_records(
+        fp,
+        &mut |addr| read_captured_word(thread, addr),
+        &mut yield_frame,
+    );
+}
+
+/// Creates a frame for a return address found by `walk_frame_records`.
+unsafe fn frame_from_record(
+    dbghelp: &dbghelp::Init,
+    ip: DWORD64,
+    sp: DWORD64,
+    fp: DWORD64,
+) -> super::Frame {
+    let get_module_base = dbghelp.SymGetModuleBase64();
+    let mut frame = super::Frame {
+        inner: Frame {
+            stack_frame: StackFrame::Old(mem::zeroed()),
+            base_address: AtomicUsize::new(get_module_base(GetCurrentProcess(), ip) as usize),
+        },
+    };
+    frame.inner.addr_pc_mut().Offset = ip;
+    frame.inner.addr_stack_mut().Offset = sp;
+    frame.inner.addr_frame_mut().Offset = fp;
+    FRAMES.fetch_add(1, Relaxed);
+    frame
+}
+
+/// Reads the 8 bytes at `addr` from the copy of the stack of `thread`,
+/// returning `None` if they weren't copied.
+#[cfg(target_arch = "aarch64")]
+fn read_captured_word(thread: &CapturedThread, addr: DWORD64) -> Option<DWORD64> {
+    let offset = addr.checked_sub(thread.stack_start)? as usize;
+    let bytes = thread.stack.get(offset..offset.checked_add(8)?)?;
+    let mut word = [0; 8];
+    word.copy_from_slice(bytes);
+    Some(DWORD64::from_le_bytes(word))
+}
+
+/// Follows the chain of frame records starting at `fp`, yielding the return
+/// address, stack pointer and frame pointer of each caller to `cb`.
+///
+/// Each record holds the caller's frame pointer followed by the return
+/// address, the layout used by ARM64 and by x64 code built with frame
+/// pointers. `read` returns the 8 bytes at an address, or `None` if they're
+/// outside of the stack. The chain has to move strictly towards the base of
+/// the stack, so the walk ends even if the stack is corrupt.
+#[cfg(target_pointer_width = "64")]
+fn walk_frame_records(
+    mut fp: DWORD64,
+    read: &mut dyn FnMut(DWORD64) -> Option<DWORD64>,
+    cb: &mut dyn FnMut(DWORD64, DWORD64, DWORD64) -> bool,
+) {
+    for _ in 0..MAX_FRAMES {
+        if fp == 0 || fp % 8 != 0 {
+            return;
+        }
+        let (next_fp, ret) = match (read(fp), read(fp + 8)) {
+            (Some(next_fp), Some(ret)) => (next_fp, ret),
+            _ => return,
+        };
+        if ret == 0 || !cb(ret, fp + 16, next_fp) || next_fp <= fp {
+            return;
+        }
+        fp = next_fp;
+    }
+}
+
+/// Reads memory for `trace_captured`, serving the stack from the copy taken
+/// while the thread was suspended.
+///
+/// Reads of the part of the stack that wasn't copied fail, ending the walk,
+/// while any other memory, such as code, is read from the live process.
+unsafe extern "system" fn read_captured_stack(
+    process: HANDLE,
+    addr: DWORD64,
+    buffer: PVOID,
+    size: DWORD,
+    read: LPDWORD,
+) -> BOOL {
+    let thread = match CAPTURED_THREAD.load(Relaxed) {
+        0 => return FALSE,
+        thread => &*(thread as *const CapturedThread),
+    };
+    let start = thread.stack_start;
+    let copied_end = start + thread.stack.len() as DWORD64;
+    let end = addr.saturating_add(size as DWORD64);
+    if addr >= start && end <= copied_end {
+        let offset = (addr - start) as usize;
+        ptr::copy_nonoverlapping(
+            thread.stack.as_ptr().add(offset),
+            buffer.cast(),
+            size as usize,
+        );
+        *read = size;
+        return TRUE;
+    }
+    if addr < thread.stack_end && end > start {
+        return FALSE;
+    }
+    let mut n = 0;
+    let ok = ReadProcessMemory(process, addr as LPCVOID, buffer, size as SIZE_T, &mut n);
+    *read = n as DWORD;
+    ok
+}
+
+/// Checks that the registers of the suspended `thread` describe a stack which
+/// can be walked.
+///
+/// A thread which has been created but hasn't started running yet has no
+/// meaningful instruction or stack pointer, and walking from those yields a
+/// single garbage frame. If the bounds of the thread's stack can be read from
+/// its TEB, which needs `THREAD_QUERY_INFORMATION` access, the stack pointer
+/// has to be within them. The frame pointer isn't checked since optimized code
+/// uses it as a general purpose register.
+unsafe fn context_is_plausible(thread: HANDLE, ctx: &CONTEXT) -> bool {
+    let sp = stack_pointer(ctx);
+    if instruction_pointer(ctx) == 0 || sp == 0 {
+        return false;
+    }
+    match thread_stack_bounds(thread) {
+        Some((low, high)) => low <= sp && sp < high,
+        None => true,
+    }
+}
+
+/// Returns the address of the start routine of a thread which was created
+/// suspended and hasn't run yet, given its registers, and `None` for any other
+/// thread.
+///
+/// Such a thread is about to enter `RtlUserThreadStart` with its start routine
+/// as the first argument, and walking from there would yield whatever happens
+/// to be above its stack pointer.
+unsafe fn unstarted_thread_start(ctx: &CONTEXT) -> Option<DWORD64> {
+    use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    // 0 means we haven't looked yet, and 1 means we looked but didn't find
+    // anything, as in `rtl_lookup_function_entry`.
+    const MISSING: usize = 1;
+    static ENTRY: AtomicUsize = AtomicUsize::new(0);
+
+    let mut entry = ENTRY.load(SeqCst);
+    if entry == 0 {
+        let ntdll = GetModuleHandleA(b"ntdll.dll\0".as_ptr() as *const i8);
+        entry = if ntdll.is_null() {
+            MISSING
+        } else {
+            match GetProcAddress(ntdll, b"RtlUserThreadStart\0".as_ptr() as *const i8) as usize {
+                0 => MISSING,
+                n => n,
+            }
+        };
+        ENTRY.store(entry, SeqCst);
+    }
+    if entry == MISSING || instruction_pointer(ctx) != entry as DWORD64 {
+        return None;
+    }
+    match first_argument(ctx) {
+        0 => None,
+        start => Some(start),
+    }
+}
+
+/// Returns the lowest and highest address of the stack of `thread`.
+///
+/// The top of the stack is read from the `NT_TIB` at the start of the thread's
+/// TEB. Its `StackLimit` is only the lowest committed page of the stack, which
+/// moves as the stack grows, so the bottom is taken to be the start of the
+/// whole reservation instead.
+unsafe fn thread_stack_bounds(thread: HANDLE) -> Option<(DWORD64, DWORD64)> {
+    let teb = thread_teb(thread)?;
+
+    // `ExceptionList`, `StackBase` and `StackLimit`.
+    let mut tib = [0usize; 3];
+    let mut read = 0;
+    let size = mem::size_of_val(&tib);
+    if ReadProcessMemory(
+        GetCurrentProcess(),
+        teb as LPCVOID,
+        tib.as_mut_ptr().cast(),
+        size,
+        &mut read,
+    ) == FALSE
+        || read != size
+    {
+        return None;
+    }
+    let high = tib[1] as DWORD64;
+
+    let mut region: MEMORY_BASIC_INFORMATION = mem::zeroed();
+    let size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+    if high == 0 || VirtualQuery((high - 1) as LPCVOID, &mut region, size) != size {
+        return None;
+    }
+    Some((region.AllocationBase as DWORD64, high))
+}
+
+/// Returns the address of the TEB of `thread`, found with
+/// `NtQueryInformationThread`, which needs `THREAD_QUERY_INFORMATION` access.
+unsafe fn thread_teb(thread: HANDLE) -> Option<PVOID> {
+    let query = nt_query_information_thread()?;
+    let mut info: THREAD_BASIC_INFORMATION = mem::zeroed();
+    let status = query(
+        thread,
+        ThreadBasicInformation,
+        &mut info as *mut _ as PVOID,
+        mem::size_of_val(&info) as ULONG,
+        ptr::null_mut(),
+    );
+    if status < 0 || info.TebBaseAddress.is_null() {
+        return None;
+    }
+    Some(info.TebBaseAddress)
+}
+
+/// Looks up `NtQueryInformationThread` in ntdll, once.
+///
+/// `thread_teb` runs while a thread is suspended, which could be holding the
+/// loader lock that `GetModuleHandleA` and `GetProcAddress` may take, so
+/// `capture_context_into` looks this up before suspending anything.
+fn nt_query_information_thread() -> Option<NtQueryInformationThread> {
+    use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    // 0 means we haven't looked yet, and 1 means we looked but didn't find
+    // anything, as in `rtl_lookup_function_entry`.
+    const MISSING: usize = 1;
+    static QUERY: AtomicUsize = AtomicUsize::new(0);
+
+    let mut query = QUERY.load(SeqCst);
+    if query == 0 {
+        unsafe {
+            let ntdll = GetModuleHandleA(b"ntdll.dll\0".as_ptr() as *const i8);
+            query = if ntdll.is_null() {
+                MISSING
+            } else {
+                match GetProcAddress(ntdll, b"NtQueryInformationThread\0".as_ptr() as *const i8)
+                    as usize
+                {
+                    0 => MISSING,
+                    n => n,
+                }
+            };
+        }
+        QUERY.store(query, SeqCst);
+    }
+    if query == MISSING {
+        None
+    } else {
+        Some(unsafe { mem::transmute::<usize, NtQueryInformationThread>(query) })
+    }
+}
+
+// Definitions for `NtQueryInformationThread`, which is only partially
+// documented and isn't in winapi.
+type NtQueryInformationThread = unsafe extern "system" fn(
+    ThreadHandle: HANDLE,
+    ThreadInformationClass: ULONG,
+    ThreadInformation: PVOID,
+    ThreadInformationLength: ULONG,
+    ReturnLength: *mut ULONG,
+) -> LONG;
+
+const ThreadBasicInformation: ULONG = 0;
+
+#[repr(C)]
+struct THREAD_BASIC_INFORMATION {
+    ExitStatus: LONG,
+    TebBaseAddress: PVOID,
+    ClientId: CLIENT_ID,
+    AffinityMask: usize,
+    Priority: LONG,
+    BasePriority: LONG,
+}
+
+// Definitions for `NtQueryObject`, which is only partially documented and
+// isn't in winapi.
+type NtQueryObject = unsafe extern "system" fn(
+    Handle: HANDLE,
+    ObjectInformationClass: ULONG,
+    ObjectInformation: PVOID,
+    ObjectInformationLength: ULONG,
+    ReturnLength: *mut ULONG,
+) -> LONG;
+
+const ObjectBasicInformation: ULONG = 0;
+
+#[repr(C)]
+struct PUBLIC_OBJECT_BASIC_INFORMATION {
+    Attributes: ULONG,
+    GrantedAccess: DWORD,
+    HandleCount: ULONG,
+    PointerCount: ULONG,
+    Reserved: [ULONG; 10],
+}
+
+/// Returns the access rights granted to `handle`, or `None` if they can't be
+/// queried.
+unsafe fn granted_access(handle: HANDLE) -> Option<DWORD> {
+    let ntdll = GetModuleHandleA(b"ntdll.dll\0".as_ptr() as *const i8);
+    if ntdll.is_null() {
+        return None;
+    }
+    let query = GetProcAddress(ntdll, b"NtQueryObject\0".as_ptr() as *const i8);
+    if query.is_null() {
+        return None;
+    }
+    let query = mem::transmute::<FARPROC, NtQueryObject>(query);
+    let mut info: PUBLIC_OBJECT_BASIC_INFORMATION = mem::zeroed();
+    let status = query(
+        handle,
+        ObjectBasicInformation,
+        &mut info as *mut _ as PVOID,
+        mem::size_of_val(&info) as ULONG,
+        ptr::null_mut(),
+    );
+    if status < 0 {
+        return None;
+    }
+    Some(info.GrantedAccess)
+}
+
+// Definitions for `NtQuerySystemInformation`, which isn't part of the
+// documented Win32 API and as such isn't in winapi either.
+type NtQuerySystemInformation = unsafe extern "system" fn(
+    SystemInformationClass: ULONG,
+    SystemInformation: PVOID,
+    SystemInformationLength: ULONG,
+    ReturnLength: *mut ULONG,
+) -> LONG;
+
+const SystemProcessInformation: ULONG = 5;
+const STATUS_INFO_LENGTH_MISMATCH: LONG = 0xC0000004u32 as LONG;
+const THREAD_STATE_WAITING: ULONG = 5;
+
+#[repr(C)]
+struct UNICODE_STRING {
+    Length: WORD,
+    MaximumLength: WORD,
+    Buffer: *mut WCHAR,
+}
+
+#[repr(C)]
+struct SYSTEM_PROCESS_INFORMATION {
+    NextEntryOffset: ULONG,
+    NumberOfThreads: ULONG,
+    WorkingSetPrivateSize: i64,
+    HardFaultCount: ULONG,
+    NumberOfThreadsHighWatermark: ULONG,
+    CycleTime: u64,
+    CreateTime: i64,
+    UserTime: i64,
+    KernelTime: i64,
+    ImageName: UNICODE_STRING,
+    BasePriority: LONG,
+    UniqueProcessId: HANDLE,
+    InheritedFromUniqueProcessId: HANDLE,
+    HandleCount: ULONG,
+    SessionId: ULONG,
+    UniqueProcessKey: usize,
+    PeakVirtualSize: usize,
+    VirtualSize: usize,
+    PageFaultCount: ULONG,
+    PeakWorkingSetSize: usize,
+    WorkingSetSize: usize,
+    QuotaPeakPagedPoolUsage: usize,
+    QuotaPagedPoolUsage: usize,
+    QuotaPeakNonPagedPoolUsage: usize,
+    QuotaNonPagedPoolUsage: usize,
+    PagefileUsage: usize,
+    PeakPagefileUsage: usize,
+    PrivatePageCount: usize,
+    ReadOperationCount: i64,
+    WriteOperationCount: i64,
+    OtherOperationCount: i64,
+    ReadTransferCount: i64,
+    WriteTransferCount: i64,
+    OtherTransferCount: i64,
+    // Followed by `NumberOfThreads` `SYSTEM_THREAD_INFORMATION`s.
+}
+
+#[repr(C)]
+struct CLIENT_ID {
+    UniqueProcess: HANDLE,
+    UniqueThread: HANDLE,
+}
+
+#[repr(C)]
+struct SYSTEM_THREAD_INFORMATION {
+    KernelTime: i64,
+    UserTime: i64,
+    CreateTime: i64,
+    WaitTime: ULONG,
+    StartAddress: PVOID,
+    ClientId: CLIENT_ID,
+    Priority: LONG,
+    BasePriority: LONG,
+    ContextSwitches: ULONG,
+    ThreadState: ULONG,
+    WaitReason: ULONG,
+}
+
+/// Reads the process and thread list of the system with
+/// `NtQuerySystemInformation`, returning `None` if that fails.
+///
+/// The buffer is made of `u64`s to align the structures within it.
+unsafe fn query_thread_states() -> Option<Vec<u64>> {
+    let ntdll = GetModuleHandleA(b"ntdll.dll\0".as_ptr() as *const i8);
+    if ntdll.is_null() {
+        return None;
+    }
+    let query = GetProcAddress(ntdll, b"NtQuerySystemInformation\0".as_ptr() as *const i8);
+    if query.is_null() {
+        return None;
+    }
+    let query = mem::transmute::<FARPROC, NtQuerySystemInformation>(query);
+
+    // The list can grow between calls, so retry a few times with the size
+    // reported by the previous attempt plus some slack.
+    let mut buffer = Vec::<u64>::new();
+    for _ in 0..4 {
+        let mut needed = 0;
+        let size = buffer.len() * mem::size_of::<u64>();
+        let status = query(
+            SystemProcessInformation,
+            buffer.as_mut_ptr().cast(),
+            size as ULONG,
+            &mut needed,
+        );
+        match status {
+            0 => return Some(buffer),
+            STATUS_INFO_LENGTH_MISMATCH => {
+                let len = (needed as usize + 64 * 1024) / mem::size_of::<u64>();
+                buffer.resize(len, 0);
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Finds thread `thread` of process `process` in the output of
+/// `query_thread_states`.
+unsafe fn find_thread_state(
+    states: &[u64],
+    process: DWORD,
+    thread: DWORD,
+) -> Option<&SYSTEM_THREAD_INFORMATION> {
+    let mut entry = states.as_ptr() as *const u8;
+    loop {
+        let info = &*(entry as *const SYSTEM_PROCESS_INFORMATION);
+        if info.UniqueProcessId as usize == process as usize {
+            let threads = slice::from_raw_parts(
+                entry.add(mem::size_of::<SYSTEM_PROCESS_INFORMATION>())
+                    as *const SYSTEM_THREAD_INFORMATION,
+                info.NumberOfThreads as usize,
+            );
+            return threads
+                .iter()
+                .find(|t| t.ClientId.UniqueThread as usize == thread as usize);
+        }
+        if info.NextEntryOffset == 0 {
+            return None;
+        }
+        entry = entry.add(info.NextEntryOffset as usize);
+    }
+}
+
+// The maximum number of frames we'll ever walk. This is only a safety net
+// against walkers which never terminate on corrupt stacks, legitimate stacks
+// are expected to be much shallower than this.
+const MAX_FRAMES: usize = 16 * 1024;
+
+/// A source of stack frames for `walk_stack`.
+///
+/// In practice this is always `DbghelpWalker`, but abstracting over it allows
+/// the walk loop to be tested against scripted stacks.
+trait StackWalker {
+    /// Advances `frame` to the next frame on the stack, returning `false` once
+    /// there are no more frames.
+    unsafe fn next(&mut self, frame: &mut Frame) -> bool;
+
+    /// Returns the base address of the module containing `addr`.
+    unsafe fn module_base(&mut self, addr: DWORD64) -> DWORD64;
+}
+
+enum Walk {
+    Ex(dbghelp::StackWalkEx),
+    Old(dbghelp::StackWalk64),
+}
+
+struct DbghelpWalker {
+    walk: Walk,
+    image: DWORD,
+    process: HANDLE,
+    thread: HANDLE,
+    // A `CONTEXT` record for the `image` machine type.
+    context: PVOID,
+    read_memory: PREAD_PROCESS_MEMORY_ROUTINE64,
+    function_table_access: dbghelp::SymFunctionTableAccess64,
+    get_module_base: dbghelp::SymGetModuleBase64,
+    flags: DWORD,
+    // Whether to leave the module bases of the frames to `lookup_module_base`.
+    lazy_base: bool,
+}
+
+impl StackWalker for DbghelpWalker {
+    unsafe fn next(&mut self, frame: &mut Frame) -> bool {
+        let ret = match (&self.walk, &mut frame.stack_frame) {
+            (Walk::Ex(walk), StackFrame::New(stack_frame)) => walk(
+                self.image,
+                self.process,
+                self.thread,
+                stack_frame,
+                self.context.cast(),
+                self.read_memory,
+                Some(self.function_table_access),
+                Some(self.get_module_base),
+                None,
+                self.flags,
+            ),
+            (Walk::Old(walk), StackFrame::Old(stack_frame)) => walk(
+                self.image,
+                self.process,
+                self.thread,
+                stack_frame,
+                self.context.cast(),
+                self.read_memory,
+                Some(self.function_table_access),
+                Some(self.get_module_base),
+                None,
+            ),
+            _ => unreachable!(),
+        };
+        ret == TRUE
+    }
+
+    unsafe fn module_base(&mut self, addr: DWORD64) -> DWORD64 {
+        if self.lazy_base {
+            return LAZY_BASE as DWORD64;
         }
+        (self.get_module_base)(self.process, addr)
     }
+}
 
-    fn addr_frame_mut(&mut self) -> &mut ADDRESS64 {
-        match self.stack_frame {
-            StackFrame::New(ref mut new) => &mut new.AddrFrame,
-            StackFrame::Old(ref mut old) => &mut old.AddrFrame,
+/// Drives `walker` to completion, yielding each frame to `cb`.
+///
+/// Besides stopping when the walker runs out of frames or `cb` returns
+/// `false`, this also stops if the walker yields the exact same frame twice in
+/// a row, which happens on some corrupt stacks and would otherwise loop
+/// forever, and after `MAX_FRAMES` frames. Those two cases are reported as
+/// `TraceError::Truncated`.
+unsafe fn walk_stack(
+    walker: &mut dyn StackWalker,
+    frame: &mut super::Frame,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    let mut prev = None;
+    for _ in 0..MAX_FRAMES {
+        if !walker.next(&mut frame.inner) {
+            // Even a thread which just started has the frame of the code it's
+            // executing, so not getting that means the walk failed.
+            return match prev {
+                Some(_) => Ok(()),
+                None => Err(TraceError::NoFrames),
+            };
         }
-    }
+        let this = frame.inner.identity();
+        if prev == Some(this) {
+            return Err(TraceError::Truncated);
+        }
+        prev = Some(this);
 
-    fn addr_stack(&self) -> &ADDRESS64 {
-        match self.stack_frame {
-            StackFrame::New(ref new) => &new.AddrStack,
-            StackFrame::Old(ref old) => &old.AddrStack,
+        *frame.inner.base_address.get_mut() = walker.module_base(frame.ip() as _) as usize;
+        FRAMES.fetch_add(1, Relaxed);
+
+        if !cb(frame) {
+            return Ok(());
         }
     }
+    Err(TraceError::Truncated)
+}
 
-    fn addr_stack_mut(&mut self) -> &mut ADDRESS64 {
-        match self.stack_frame {
-            StackFrame::New(ref mut new) => &mut new.AddrStack,
-            StackFrame::Old(ref mut old) => &mut old.AddrStack,
+// Recent results of `get_module_base` in `walk_context`, as pairs of an
+// address and the base of the module containing it. dbghelp looks up the base
+// of each frame while unwinding it, and `walk_stack` needs the same base for
+// `base_address`, so remembering the last few saves a lookup per frame. Walks
+// are serialized by the dbghelp lock and the cache is cleared at the start of
+// each, so modules being unloaded in between don't matter.
+#[cfg(target_pointer_width = "64")]
+static MODULE_BASES: [AtomicUsize; 8] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+#[cfg(target_pointer_width = "64")]
+static NEXT_MODULE_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the module base of `addr` from `MODULE_BASES`, calling `lookup` and
+/// remembering its result if it isn't there.
+#[cfg(target_pointer_width = "64")]
+fn cached_module_base(addr: DWORD64, lookup: impl FnOnce() -> DWORD64) -> DWORD64 {
+    let key = addr as usize;
+    if key != 0 {
+        for pair in MODULE_BASES.chunks(2) {
+            if pair[0].load(Relaxed) == key {
+                return pair[1].load(Relaxed) as DWORD64;
+            }
         }
     }
+    let base = lookup();
+    let slot = NEXT_MODULE_BASE.fetch_add(1, Relaxed) % (MODULE_BASES.len() / 2);
+    MODULE_BASES[2 * slot].store(key, Relaxed);
+    MODULE_BASES[2 * slot + 1].store(base as usize, Relaxed);
+    base
 }
 
-#[repr(C, align(16))] // required by `CONTEXT`, is a FIXME in winapi right now
-struct MyContext(CONTEXT);
+#[cfg(target_pointer_width = "64")]
+fn clear_module_bases() {
+    for entry in MODULE_BASES.iter() {
+        entry.store(0, Relaxed);
+    }
+}
 
-//#[inline(always)]
-pub unsafe fn trace(cb: &mut dyn FnMut(&super::Frame) -> bool, thread: *mut c_void) {
-    // Ensure this process's symbols are initialized
+#[cfg(target_pointer_width = "64")]
+type RtlLookupFunctionEntry = unsafe extern "system" fn(
+    ControlPc: DWORD64,
+    ImageBase: PDWORD64,
+    HistoryTable: PUNWIND_HISTORY_TABLE,
+) -> PRUNTIME_FUNCTION;
+
+// `SymFunctionTableAccess64`, stored once a function entry callback has been
+// registered and zero until then. See `function_table_access` in `trace`.
+#[cfg(target_pointer_width = "64")]
+static DBGHELP_FUNCTION_TABLE_ACCESS: AtomicUsize = AtomicUsize::new(0);
+
+pub unsafe fn register_function_entry_callback(
+    callback: super::FunctionEntryCallback,
+    user_context: u64,
+) -> bool {
     let dbghelp = match dbghelp::init() {
         Ok(dbghelp) => dbghelp,
-        Err(()) => return, // oh well...
+        Err(_) => return false,
     };
+    let register = dbghelp.SymRegisterFunctionEntryCallback64();
+    if register(GetCurrentProcess(), Some(callback), user_context) == FALSE {
+        return false;
+    }
+    #[cfg(target_pointer_width = "64")]
+    DBGHELP_FUNCTION_TABLE_ACCESS.store(dbghelp.SymFunctionTableAccess64() as usize, Relaxed);
+    true
+}
 
-    // On x86_64 and ARM64 we opt to not use the default `Sym*` functions from
-    // dbghelp for getting the function table and module base. Instead we use
-    // the `RtlLookupFunctionEntry` function in kernel32 which will account for
-    // JIT compiler frames as well. These should be equivalent, but using
-    // `Rtl*` allows us to backtrace through JIT frames.
-    //
-    // Note that `RtlLookupFunctionEntry` only works for in-process backtraces,
-    // but that's all we support anyway, so it all lines up well.
-    cfg_if::cfg_if! {
-        if #[cfg(target_pointer_width = "64")] {
-            use core::ptr;
-
-            unsafe extern "system" fn function_table_access(_process: HANDLE, addr: DWORD64) -> PVOID {
-                let mut base = 0;
-                RtlLookupFunctionEntry(addr, &mut base, ptr::null_mut()).cast()
-            }
+pub fn with_dbghelp(
+    f: &mut dyn FnMut(HANDLE, &super::DbghelpApi),
+) -> Result<(), super::DbghelpError> {
+    let dbghelp = dbghelp::init()?;
+    let api = super::DbghelpApi {
+        module: unsafe { (*dbghelp.dbghelp()).dll() }.cast(),
+    };
+    f(unsafe { GetCurrentProcess() }, &api);
+    drop(dbghelp);
+    Ok(())
+}
 
-            unsafe extern "system" fn get_module_base(_process: HANDLE, addr: DWORD64) -> DWORD64 {
-                let mut base = 0;
-                RtlLookupFunctionEntry(addr, &mut base, ptr::null_mut());
-                base
-            }
-        } else {
-            let function_table_access = dbghelp.SymFunctionTableAccess64();
-            let get_module_base = dbghelp.SymGetModuleBase64();
-        }
+pub fn dbghelp_function(module: *mut c_void, name: &str) -> Option<*mut c_void> {
+    // `GetProcAddress` wants a nul-terminated name, and dbghelp's names are
+    // all short.
+    let mut buf = [0u8; 128];
+    if name.len() >= buf.len() || name.contains('\0') {
+        return None;
+    }
+    buf[..name.len()].copy_from_slice(name.as_bytes());
+    let function = unsafe { GetProcAddress(module.cast(), buf.as_ptr().cast()) };
+    if function.is_null() {
+        None
+    } else {
+        Some(function.cast())
     }
+}
 
-    let process_handle = GetCurrentProcess();
+/// Loads `RtlLookupFunctionEntry` from `ntdll.dll`, returning `None` if this
+/// version of Windows doesn't provide it.
+///
+/// Like the functions in `dbghelp.dll` this is looked up dynamically rather
+/// than imported, so a missing function doesn't prevent the binary from
+/// loading at all. The result of the lookup is cached.
+#[cfg(target_pointer_width = "64")]
+fn rtl_lookup_function_entry() -> Option<RtlLookupFunctionEntry> {
+    use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
 
-    // Attempt to use `StackWalkEx` if we can, but fall back to `StackWalk64`
-    // since it's in theory supported on more systems.
-    match (*dbghelp.dbghelp()).StackWalkEx() {
-        Some(StackWalkEx) => {
-            let mut inner: STACKFRAME_EX = mem::zeroed();
-            inner.StackFrameSize = mem::size_of::<STACKFRAME_EX>() as DWORD;
-            let mut frame = super::Frame {
-                inner: Frame {
-                    stack_frame: StackFrame::New(inner),
-                    base_address: 0 as _,
-                },
-            };
+    // 0 means we haven't looked yet, and 1 means we looked but didn't find
+    // anything. Neither can ever be a valid function pointer.
+    const MISSING: usize = 1;
+    static LOOKUP: AtomicUsize = AtomicUsize::new(0);
 
-            let (mut context, do_resume) = match suspend_thread_and_capture_context(thread) {
-                Some((context, do_resume)) => (context, do_resume),
-                None => return,
+    let mut lookup = LOOKUP.load(SeqCst);
+    if lookup == 0 {
+        unsafe {
+            let ntdll = GetModuleHandleA(b"ntdll.dll\0".as_ptr() as *const i8);
+            lookup = if ntdll.is_null() {
+                MISSING
+            } else {
+                match GetProcAddress(ntdll, b"RtlLookupFunctionEntry\0".as_ptr() as *const i8)
+                    as usize
+                {
+                    0 => MISSING,
+                    n => n,
+                }
             };
+        }
+        LOOKUP.store(lookup, SeqCst);
+    }
+    if lookup == MISSING {
+        None
+    } else {
+        Some(unsafe { mem::transmute::<usize, RtlLookupFunctionEntry>(lookup) })
+    }
+}
 
-            let image = init_frame(&mut frame.inner, &context.0);
-            let frame_ptr = match &mut frame.inner.stack_frame {
-                StackFrame::New(ptr) => ptr as *mut STACKFRAME_EX,
-                _ => unreachable!(),
-            };
+/// Returns whether `frame` looks like it was entered through a tail call from
+/// the function `caller` called, see `Frame::is_tail_call`.
+///
+/// The instruction right before the return address of `caller` is decoded,
+/// and if it's a direct call its target is compared with the start of the
+/// function `frame` is in, as found by `RtlLookupFunctionEntry`. A single
+/// jump thunk at the target, as emitted by incremental linking, is followed.
+/// Indirect calls, functions without unwind information and code which
+/// can't be read are all treated as not being tail calls.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn is_tail_call(frame: &super::Frame, caller: &super::Frame) -> bool {
+    if frame.is_inline() {
+        return false;
+    }
+    let target = match direct_call_target(caller.ip() as DWORD64) {
+        Some(target) => target,
+        None => return false,
+    };
+    // The return address of a call to a function which doesn't return may be
+    // the first byte of the next function, so the byte before it is looked up
+    // as well.
+    let ip = frame.ip() as DWORD64;
+    let starts = [function_start(ip), function_start(ip.wrapping_sub(1))];
+    if starts == [None, None] || starts.contains(&Some(target)) {
+        return false;
+    }
+    match jump_target(target) {
+        Some(thunk) => !starts.contains(&Some(thunk)),
+        None => true,
+    }
+}
 
-            while StackWalkEx(
-                image as DWORD,
-                process_handle,
-                thread,
-                frame_ptr,
-                &mut context.0 as *mut CONTEXT as *mut _,
-                None,
-                Some(function_table_access),
-                Some(get_module_base),
-                None,
-                0,
-            ) == TRUE
-            {
-                frame.inner.base_address = get_module_base(process_handle, frame.ip() as _) as _;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn is_tail_call(_frame: &super::Frame, _caller: &super::Frame) -> bool {
+    false
+}
 
-                if !cb(&frame) {
-                    break;
-                }
-            }
-            if do_resume {
-                ResumeThread(thread);
-            }
+/// Checks with `VirtualQuery` that the instruction pointer of `frame` is in
+/// committed memory which may be executed. Guard pages are never executable,
+/// as touching them raises an exception first.
+pub fn address_is_mapped(frame: &super::Frame) -> bool {
+    const EXECUTABLE: DWORD =
+        PAGE_EXECUTE | PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY;
+    unsafe {
+        let mut region: MEMORY_BASIC_INFORMATION = mem::zeroed();
+        let size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+        if VirtualQuery(frame.ip() as LPCVOID, &mut region, size) != size {
+            return false;
         }
-        None => {
-            let mut frame = super::Frame {
-                inner: Frame {
-                    stack_frame: StackFrame::Old(mem::zeroed()),
-                    base_address: 0 as _,
-                },
-            };
+        region.State == MEM_COMMIT
+            && region.Protect & EXECUTABLE != 0
+            && region.Protect & PAGE_GUARD == 0
+    }
+}
 
-            let (mut context, do_resume) = match suspend_thread_and_capture_context(thread) {
-                Some((context, do_resume)) => (context, do_resume),
-                None => return,
-            };
+/// Returns the address of the start of the function containing `addr`,
+/// following chained unwind information back to the primary entry.
+#[cfg(target_arch = "x86_64")]
+fn function_start(addr: DWORD64) -> Option<DWORD64> {
+    // `UNW_FLAG_CHAININFO` in the flags of an `UNWIND_INFO`, which is then
+    // followed by the `RUNTIME_FUNCTION` of the code it's a fragment of.
+    const UNW_FLAG_CHAININFO: u8 = 0x4;
 
-            let image = init_frame(&mut frame.inner, &context.0);
-            let frame_ptr = match &mut frame.inner.stack_frame {
-                StackFrame::Old(ptr) => ptr as *mut STACKFRAME64,
-                _ => unreachable!(),
-            };
+    let lookup = rtl_lookup_function_entry()?;
+    let mut base = 0;
+    let entry = unsafe { lookup(addr, &mut base, ptr::null_mut()) };
+    if entry.is_null() {
+        return None;
+    }
+    let mut entry = entry as DWORD64;
+    // Chains are short in practice, the bound only guards against garbage.
+    for _ in 0..32 {
+        let mut function = [0; 12];
+        if !read_code(entry, &mut function) {
+            return None;
+        }
+        let unwind_data =
+            u32::from_le_bytes([function[8], function[9], function[10], function[11]]);
+        // The lowest bit of `UnwindData` is set if it refers to another
+        // `RUNTIME_FUNCTION` rather than to an `UNWIND_INFO`.
+        let unwind_info = base + (unwind_data & !1) as DWORD64;
+        let mut header = [0; 4];
+        if unwind_data & 1 == 0 && !read_code(unwind_info, &mut header) {
+            return None;
+        }
+        if unwind_data & 1 == 1 {
+            entry = unwind_info;
+        } else if (header[0] >> 3) & UNW_FLAG_CHAININFO != 0 {
+            // Unwind codes are two bytes each, padded to an even count.
+            let codes = (header[2] as DWORD64 + 1) & !1;
+            entry = unwind_info + 4 + codes * 2;
+        } else {
+            let begin = u32::from_le_bytes([function[0], function[1], function[2], function[3]]);
+            return Some(base + begin as DWORD64);
+        }
+    }
+    None
+}
 
-            while dbghelp.StackWalk64()(
-                image as DWORD,
-                process_handle,
-                thread,
-                frame_ptr,
-                &mut context.0 as *mut CONTEXT as *mut _,
-                None,
-                Some(function_table_access),
-                Some(get_module_base),
-                None,
-            ) == TRUE
-            {
-                frame.inner.base_address = get_module_base(process_handle, frame.ip() as _) as _;
+/// Returns the address of the start of the function containing `addr`.
+#[cfg(target_arch = "aarch64")]
+fn function_start(addr: DWORD64) -> Option<DWORD64> {
+    // The low two bits of `UnwindData` are the kind of unwind data, and
+    // fragments of a function, which don't start where it does, are marked
+    // as such.
+    const PACKED_FRAGMENT: u32 = 2;
 
-                if !cb(&frame) {
-                    break;
-                }
-            }
-            if do_resume {
-                ResumeThread(thread);
-            }
-        }
+    let lookup = rtl_lookup_function_entry()?;
+    let mut base = 0;
+    let entry = unsafe { lookup(addr, &mut base, ptr::null_mut()) };
+    if entry.is_null() {
+        return None;
+    }
+    let mut function = [0; 8];
+    if !read_code(entry as DWORD64, &mut function) {
+        return None;
+    }
+    let unwind_data = u32::from_le_bytes([function[4], function[5], function[6], function[7]]);
+    if unwind_data & 3 == PACKED_FRAGMENT {
+        return None;
+    }
+    let begin = u32::from_le_bytes([function[0], function[1], function[2], function[3]]);
+    Some(base + begin as DWORD64)
+}
+
+/// Returns the target of the direct call ending at `ret`, if any.
+#[cfg(target_arch = "x86_64")]
+fn direct_call_target(ret: DWORD64) -> Option<DWORD64> {
+    // `call rel32`
+    let mut call = [0; 5];
+    if !read_code(ret.wrapping_sub(5), &mut call) || call[0] != 0xe8 {
+        return None;
+    }
+    let rel = i32::from_le_bytes([call[1], call[2], call[3], call[4]]);
+    Some(ret.wrapping_add(rel as i64 as DWORD64))
+}
+
+/// Returns the target of the direct call ending at `ret`, if any.
+#[cfg(target_arch = "aarch64")]
+fn direct_call_target(ret: DWORD64) -> Option<DWORD64> {
+    // `bl imm26`
+    let mut call = [0; 4];
+    let pc = ret.wrapping_sub(4);
+    if !read_code(pc, &mut call) {
+        return None;
+    }
+    let insn = u32::from_le_bytes(call);
+    if insn & 0xfc00_0000 != 0x9400_0000 {
+        return None;
+    }
+    Some(pc.wrapping_add(branch_offset(insn)))
+}
+
+/// Returns where the jump at `addr` goes, if there's a direct jump there.
+#[cfg(target_arch = "x86_64")]
+fn jump_target(addr: DWORD64) -> Option<DWORD64> {
+    // `jmp rel32`
+    let mut jump = [0; 5];
+    if !read_code(addr, &mut jump) || jump[0] != 0xe9 {
+        return None;
+    }
+    let rel = i32::from_le_bytes([jump[1], jump[2], jump[3], jump[4]]);
+    Some(addr.wrapping_add(5).wrapping_add(rel as i64 as DWORD64))
+}
+
+/// Returns where the jump at `addr` goes, if there's a direct jump there.
+#[cfg(target_arch = "aarch64")]
+fn jump_target(addr: DWORD64) -> Option<DWORD64> {
+    // `b imm26`
+    let mut jump = [0; 4];
+    if !read_code(addr, &mut jump) {
+        return None;
+    }
+    let insn = u32::from_le_bytes(jump);
+    if insn & 0xfc00_0000 != 0x1400_0000 {
+        return None;
     }
+    Some(addr.wrapping_add(branch_offset(insn)))
+}
+
+/// Returns the byte offset encoded in the `imm26` field of a `b` or `bl`.
+#[cfg(target_arch = "aarch64")]
+fn branch_offset(insn: u32) -> DWORD64 {
+    // Shifting the field to the top and back sign extends it.
+    (((insn << 6) as i32 >> 6) as i64 * 4) as DWORD64
+}
+
+/// Reads `buf.len()` bytes of code at `addr` of this process, returning
+/// `false` if they aren't all readable. The addresses come from stack walks
+/// which may have gone wrong, so they aren't dereferenced directly.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn read_code(addr: DWORD64, buf: &mut [u8]) -> bool {
+    let mut n = 0;
+    let ok = unsafe {
+        ReadProcessMemory(
+            GetCurrentProcess(),
+            addr as LPCVOID,
+            buf.as_mut_ptr().cast(),
+            buf.len() as SIZE_T,
+            &mut n,
+        )
+    };
+    ok == TRUE && n == buf.len() as SIZE_T
 }
 
-unsafe fn suspend_thread_and_capture_context(thread: *mut c_void) -> Option<(MyContext, bool)> {
+/// Suspends `thread` unless it's the calling thread and reads its registers,
+/// with `flags` added to the `ContextFlags` passed to `GetThreadContext`.
+unsafe fn suspend_thread_and_capture_context(
+    thread: *mut c_void,
+    flags: DWORD,
+) -> Result<(MyContext, bool), TraceError> {
     let mut context = mem::zeroed::<MyContext>();
-    if thread == GetCurrentThread() || thread.is_null() {
+    let do_resume = capture_context_into(thread, &mut context, flags)?;
+    Ok((context, do_resume))
+}
+
+/// Same as `suspend_thread_and_capture_context`, but reads the registers into
+/// `context` and only returns whether the thread has to be resumed.
+unsafe fn capture_context_into(
+    thread: *mut c_void,
+    context: &mut MyContext,
+    flags: DWORD,
+) -> Result<bool, TraceError> {
+    if is_current_thread(thread) {
         // Capture current thread, no synchronization needed.
         RtlCaptureContext(&mut context.0);
-        Some((context, false))
+        Ok(false)
     } else {
         // Capture non calling thread.
         // Thread must be suspended while capturing backtrace.
@@ -236,23 +2650,142 @@ unsafe fn suspend_thread_and_capture_context(thread: *mut c_void) -> Option<(MyC
         // There is most definitely more pitfalls i haven't thought
         // of or encountered. This is windows after all.
 
-        context.0.ContextFlags = CONTEXT_CONTROL | CONTEXT_INTEGER;
+        // Checking the context below needs this, and looking it up may take
+        // the loader lock.
+        nt_query_information_thread();
+
+        // With a single processor the suspended thread can't run at all until
+        // it's resumed, so anything it holds stays held. Giving it the rest of
+        // our time slice first lets it finish short critical sections.
+        let single_processor = available_processors() == Some(1);
+        if single_processor && YIELD_ON_SINGLE_PROCESSOR.load(Relaxed) {
+            SwitchToThread();
+        }
+
+        context.0.ContextFlags = CONTEXT_CONTROL | CONTEXT_INTEGER | flags;
         if SuspendThread(thread) as i32 == -1 {
             // TODO: I am unsure about when SuspendThread fails. Will it still increase suspend count?
             //ResumeThread(thread);
-            return None;
+            SUSPEND_FAILURES.fetch_add(1, Relaxed);
+            return Err(match GetLastError() {
+                ERROR_ACCESS_DENIED => TraceError::AccessDenied,
+                code => TraceError::SuspendFailed(code),
+            });
+        }
+        SUSPENSIONS.fetch_add(1, Relaxed);
+        if single_processor {
+            SINGLE_PROCESSOR_SUSPENSIONS.fetch_add(1, Relaxed);
         }
         let status = GetThreadContext(thread, &mut context.0);
         if status == 0 {
+            let code = GetLastError();
             ResumeThread(thread);
-            return None;
+            CONTEXT_FAILURES.fetch_add(1, Relaxed);
+            return Err(match code {
+                ERROR_ACCESS_DENIED => TraceError::AccessDenied,
+                code => TraceError::GetContextFailed(code),
+            });
+        }
+        if !context_is_plausible(thread, &context.0) {
+            ResumeThread(thread);
+            return Err(TraceError::InvalidContext);
         }
 
         // The thread must be resumed by the caller.
-        Some((context, true))
+        Ok(true)
+    }
+}
+
+/// Writes the file name of the module loaded at `base` to `f`, returning
+/// `false` without writing anything if it isn't known.
+///
+/// This uses a fixed buffer rather than allocating, so paths longer than
+/// `MAX_PATH` are treated as unknown.
+pub fn fmt_module_name(base: *mut c_void, f: &mut fmt::Formatter<'_>) -> Result<bool, fmt::Error> {
+    let mut buf = [0u16; MAX_PATH];
+    let len = unsafe { GetModuleFileNameW(base as HMODULE, buf.as_mut_ptr(), buf.len() as DWORD) };
+    let len = len as usize;
+    if len == 0 || len >= buf.len() {
+        return Ok(false);
+    }
+    let path = &buf[..len];
+    let start = path
+        .iter()
+        .rposition(|&c| c == b'\\' as u16 || c == b'/' as u16)
+        .map_or(0, |i| i + 1);
+    for c in char::decode_utf16(path[start..].iter().cloned()) {
+        f.write_char(c.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+    }
+    Ok(true)
+}
+
+/// Attempts to enable `SeDebugPrivilege` for the current process' token.
+pub fn enable_debug_privilege() -> bool {
+    unsafe {
+        let mut token = ptr::null_mut();
+        if OpenProcessToken(
+            GetCurrentProcess(),
+            TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY,
+            &mut token,
+        ) == FALSE
+        {
+            return false;
+        }
+
+        let mut name = [0u16; 17];
+        for (dst, c) in name.iter_mut().zip("SeDebugPrivilege".encode_utf16()) {
+            *dst = c;
+        }
+        let mut privileges = TOKEN_PRIVILEGES {
+            PrivilegeCount: 1,
+            Privileges: [LUID_AND_ATTRIBUTES {
+                Luid: mem::zeroed(),
+                Attributes: SE_PRIVILEGE_ENABLED,
+            }],
+        };
+        let mut ok = LookupPrivilegeValueW(
+            ptr::null(),
+            name.as_ptr(),
+            &mut privileges.Privileges[0].Luid,
+        ) == TRUE;
+        if ok {
+            ok = AdjustTokenPrivileges(
+                token,
+                FALSE,
+                &mut privileges,
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            ) == TRUE;
+            // `AdjustTokenPrivileges` succeeds even if the privilege couldn't
+            // be assigned, in which case it sets this error code instead.
+            ok = ok && GetLastError() != ERROR_NOT_ALL_ASSIGNED;
+        }
+        CloseHandle(token);
+        ok
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+fn instruction_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Rip as u64
+}
+
+#[cfg(target_arch = "x86_64")]
+fn stack_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Rsp as u64
+}
+
+#[cfg(target_arch = "x86_64")]
+fn frame_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Rbp as u64
+}
+
+#[cfg(target_arch = "x86_64")]
+fn first_argument(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Rcx as u64
+}
+
 #[cfg(target_arch = "x86_64")]
 fn init_frame(frame: &mut Frame, ctx: &CONTEXT) -> WORD {
     frame.addr_pc_mut().Offset = ctx.Rip as u64;
@@ -265,6 +2798,22 @@ fn init_frame(frame: &mut Frame, ctx: &CONTEXT) -> WORD {
     IMAGE_FILE_MACHINE_AMD64
 }
 
+#[cfg(target_arch = "x86")]
+fn instruction_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Eip as u64
+}
+
+#[cfg(target_arch = "x86")]
+fn stack_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Esp as u64
+}
+
+// `RtlUserThreadStart` takes its arguments in registers on x86 as well.
+#[cfg(target_arch = "x86")]
+fn first_argument(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Eax as u64
+}
+
 #[cfg(target_arch = "x86")]
 fn init_frame(frame: &mut Frame, ctx: &CONTEXT) -> WORD {
     frame.addr_pc_mut().Offset = ctx.Eip as u64;
@@ -277,6 +2826,26 @@ fn init_frame(frame: &mut Frame, ctx: &CONTEXT) -> WORD {
     IMAGE_FILE_MACHINE_I386
 }
 
+#[cfg(target_arch = "aarch64")]
+fn instruction_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Pc as u64
+}
+
+#[cfg(target_arch = "aarch64")]
+fn stack_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Sp as u64
+}
+
+#[cfg(target_arch = "aarch64")]
+fn frame_pointer(ctx: &CONTEXT) -> DWORD64 {
+    unsafe { ctx.u.s().Fp as u64 }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn first_argument(ctx: &CONTEXT) -> DWORD64 {
+    unsafe { ctx.u.s().X0 as u64 }
+}
+
 #[cfg(target_arch = "aarch64")]
 fn init_frame(frame: &mut Frame, ctx: &CONTEXT) -> WORD {
     frame.addr_pc_mut().Offset = ctx.Pc as u64;
@@ -290,6 +2859,21 @@ fn init_frame(frame: &mut Frame, ctx: &CONTEXT) -> WORD {
     IMAGE_FILE_MACHINE_ARM64
 }
 
+#[cfg(target_arch = "arm")]
+fn instruction_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Pc as u64
+}
+
+#[cfg(target_arch = "arm")]
+fn stack_pointer(ctx: &CONTEXT) -> DWORD64 {
+    ctx.Sp as u64
+}
+
+#[cfg(target_arch = "arm")]
+fn first_argument(ctx: &CONTEXT) -> DWORD64 {
+    ctx.R0 as u64
+}
+
 #[cfg(target_arch = "arm")]
 fn init_frame(frame: &mut Frame, ctx: &CONTEXT) -> WORD {
     frame.addr_pc_mut().Offset = ctx.Pc as u64;
@@ -302,3 +2886,291 @@ fn init_frame(frame: &mut Frame, ctx: &CONTEXT) -> WORD {
     frame.addr_frame_mut().Mode = AddrModeFlat;
     IMAGE_FILE_MACHINE_ARMNT
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::prelude::v1::*;
+
+    // Replays a scripted list of `(ip, sp)` pairs instead of walking a real
+    // stack.
+    struct Replay {
+        frames: Vec<(DWORD64, DWORD64)>,
+        next: usize,
+    }
+
+    impl StackWalker for Replay {
+        unsafe fn next(&mut self, frame: &mut Frame) -> bool {
+            let (ip, sp) = match self.frames.get(self.next) {
+                Some(pair) => *pair,
+                None => return false,
+            };
+            self.next += 1;
+            frame.addr_pc_mut().Offset = ip;
+            frame.addr_stack_mut().Offset = sp;
+            true
+        }
+
+        unsafe fn module_base(&mut self, addr: DWORD64) -> DWORD64 {
+            addr & !0xffff
+        }
+    }
+
+    fn replay(
+        frames: Vec<(DWORD64, DWORD64)>,
+        limit: usize,
+    ) -> (Vec<(usize, usize, usize)>, Result<(), TraceError>) {
+        let mut walker = Replay { frames, next: 0 };
+        let mut frame = super::super::Frame {
+            inner: Frame {
+                stack_frame: StackFrame::New(unsafe { mem::zeroed() }),
+                base_address: AtomicUsize::new(0),
+            },
+        };
+        let mut seen = Vec::new();
+        let result = unsafe {
+            walk_stack(&mut walker, &mut frame, &mut |frame| {
+                seen.push((
+                    frame.ip() as usize,
+                    frame.sp() as usize,
+                    frame.module_base_address().unwrap() as usize,
+                ));
+                seen.len() < limit
+            })
+        };
+        (seen, result)
+    }
+
+    #[test]
+    fn walk_yields_all_frames() {
+        let (frames, result) = replay(
+            vec![(0x1_0010, 0x100), (0x2_0020, 0x200), (0x3_0030, 0x300)],
+            !0,
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            frames,
+            [
+                (0x1_0010, 0x100, 0x1_0000),
+                (0x2_0020, 0x200, 0x2_0000),
+                (0x3_0030, 0x300, 0x3_0000),
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_without_frames() {
+        assert_eq!(
+            replay(Vec::new(), !0),
+            (Vec::new(), Err(TraceError::NoFrames))
+        );
+        let (frames, result) = replay(vec![(0x10, 0x100)], !0);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn walk_stops_when_callback_returns_false() {
+        let (frames, result) = replay(vec![(0x10, 0x100), (0x20, 0x200), (0x30, 0x300)], 2);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn walk_stops_on_repeated_frame() {
+        let (frames, result) = replay(
+            vec![(0x10, 0x100), (0x20, 0x200), (0x20, 0x200), (0x30, 0x300)],
+            !0,
+        );
+        assert_eq!(frames.len(), 2);
+        assert_eq!(result, Err(TraceError::Truncated));
+    }
+
+    #[test]
+    fn nt_layouts() {
+        // Sizes as defined by the Windows SDK, the thread list follows the
+        // process entry so its size has to be exact.
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(mem::size_of::<SYSTEM_PROCESS_INFORMATION>(), 0x100);
+            assert_eq!(mem::size_of::<SYSTEM_THREAD_INFORMATION>(), 0x50);
+        } else {
+            assert_eq!(mem::size_of::<SYSTEM_PROCESS_INFORMATION>(), 0xb8);
+            assert_eq!(mem::size_of::<SYSTEM_THREAD_INFORMATION>(), 0x40);
+        }
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn module_base_cache() {
+        // Walks by other tests use the cache while holding the dbghelp lock.
+        let _lock = dbghelp::load().unwrap();
+        let lookups = &core::cell::Cell::new(0);
+        let lookup = |base| {
+            move || {
+                lookups.set(lookups.get() + 1);
+                base
+            }
+        };
+
+        clear_module_bases();
+        assert_eq!(cached_module_base(0x1_0010, lookup(0x1_0000)), 0x1_0000);
+        assert_eq!(cached_module_base(0x1_0010, lookup(0xdead)), 0x1_0000);
+        assert_eq!(lookups.get(), 1);
+
+        // Older entries are evicted once the cache is full.
+        for addr in 1..=4 {
+            cached_module_base(0x2_0000 + addr, lookup(0x2_0000));
+        }
+        assert_eq!(cached_module_base(0x1_0010, lookup(0x3_0000)), 0x3_0000);
+        assert_eq!(lookups.get(), 6);
+
+        clear_module_bases();
+        assert_eq!(cached_module_base(0x2_0004, lookup(0x4_0000)), 0x4_0000);
+    }
+
+    #[test]
+    fn context_plausibility() {
+        unsafe {
+            let thread = GetCurrentThread();
+            let mut context = mem::zeroed::<MyContext>();
+            assert!(!context_is_plausible(thread, &context.0));
+
+            RtlCaptureContext(&mut context.0);
+            assert!(context_is_plausible(thread, &context.0));
+            let (low, high) = thread_stack_bounds(thread).unwrap();
+            let sp = stack_pointer(&context.0);
+            assert!(low <= sp && sp < high);
+        }
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn frame_records() {
+        // Records at 0x100 -> 0x120 -> 0x140, the last one ending the chain
+        // by pointing back down the stack.
+        let stack = [
+            (0x100, 0x120),
+            (0x108, 0xa),
+            (0x120, 0x140),
+            (0x128, 0xb),
+            (0x140, 0x110),
+            (0x148, 0xc),
+        ];
+        let mut read = |addr| stack.iter().find(|w| w.0 == addr).map(|w| w.1);
+        let mut seen = Vec::new();
+        walk_frame_records(0x100, &mut read, &mut |ip, sp, fp| {
+            seen.push((ip, sp, fp));
+            true
+        });
+        assert_eq!(
+            seen,
+            [
+                (0xa, 0x110, 0x120),
+                (0xb, 0x130, 0x140),
+                (0xc, 0x150, 0x110)
+            ]
+        );
+
+        // Reads outside of the stack and misaligned records end the walk.
+        seen.clear();
+        walk_frame_records(0x200, &mut read, &mut |ip, sp, fp| {
+            seen.push((ip, sp, fp));
+            true
+        });
+        walk_frame_records(0x104, &mut read, &mut |ip, sp, fp| {
+            seen.push((ip, sp, fp));
+            true
+        });
+        assert!(seen.is_empty());
+    }
+
+    #[test]
+    fn walk_is_bounded() {
+        let frames = (0..MAX_FRAMES as DWORD64 + 10)
+            .map(|i| (0x10 + i, 0x100 + i * 8))
+            .collect();
+        let (frames, result) = replay(frames, !0);
+        assert_eq!(frames.len(), MAX_FRAMES);
+        assert_eq!(result, Err(TraceError::Truncated));
+    }
+
+    #[test]
+    fn walk_with_memory_reader() {
+        static READS: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "system" fn read(
+            _process: HANDLE,
+            addr: DWORD64,
+            buffer: PVOID,
+            size: DWORD,
+            read: LPDWORD,
+        ) -> BOOL {
+            READS.fetch_add(1, Relaxed);
+            ptr::copy_nonoverlapping(addr as *const u8, buffer.cast(), size as usize);
+            *read = size;
+            TRUE
+        }
+
+        let mut frames = 0;
+        let result = unsafe {
+            let mut context = mem::zeroed::<MyContext>();
+            RtlCaptureContext(&mut context.0);
+            trace_with_memory_reader(
+                read,
+                &mut context.0 as *mut CONTEXT as *mut c_void,
+                &mut |_| {
+                    frames += 1;
+                    true
+                },
+            )
+        };
+        assert_eq!(result, Ok(()));
+        assert!(frames > 1);
+        assert!(READS.load(Relaxed) > 0);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const NATIVE_ARCH: super::super::GuestArch = super::super::GuestArch::X86_64;
+    #[cfg(target_arch = "aarch64")]
+    const NATIVE_ARCH: super::super::GuestArch = super::super::GuestArch::Arm64;
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn guest_context_has_native_layout() {
+        assert!(mem::size_of::<CONTEXT>() <= GUEST_CONTEXT_SIZE);
+        let registers = super::super::GuestRegisters::new(0x1110, 0x2220, 0x3330);
+        let mut context = GuestContext([0; GUEST_CONTEXT_SIZE]);
+        let machine = guest_context(NATIVE_ARCH, &registers, &mut context.0);
+        let native = unsafe { &*(context.0.as_ptr() as *const CONTEXT) };
+        assert_eq!(instruction_pointer(native), 0x1110);
+        assert_eq!(stack_pointer(native), 0x2220);
+        assert_eq!(frame_pointer(native), 0x3330);
+
+        let mut frame = Frame {
+            stack_frame: StackFrame::Old(unsafe { mem::zeroed() }),
+            base_address: AtomicUsize::new(0),
+        };
+        assert_eq!(init_frame(&mut frame, native), machine);
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn walk_guest_context() {
+        let mut frames = 0;
+        let result = unsafe {
+            let mut context = mem::zeroed::<MyContext>();
+            RtlCaptureContext(&mut context.0);
+            let registers = super::super::GuestRegisters::new(
+                instruction_pointer(&context.0),
+                stack_pointer(&context.0),
+                frame_pointer(&context.0),
+            );
+            trace_guest_context(NATIVE_ARCH, &registers, None, &mut |_| {
+                frames += 1;
+                true
+            })
+        };
+        assert_eq!(result, Ok(()));
+        assert!(frames > 1);
+    }
+}
@@ -0,0 +1,227 @@
+//! Cross-thread backtraces on macOS using Mach thread APIs.
+//!
+//! `_Unwind_Backtrace` can only unwind the calling thread, so to trace another
+//! thread we suspend it with `thread_suspend`, read its registers with
+//! `thread_get_state` and then follow the frame pointer chain starting from
+//! the captured `fp`/`pc`. Frame pointers are mandated by the platform ABI on
+//! both x86_64 and aarch64 macOS so this is generally reliable, although the
+//! caller of a leaf function which doesn't set up a frame may be missed.
+//!
+//! The frames yielded are the same cloned frames the libunwind backend
+//! produces, so symbolication goes through the usual gimli path.
+
+use super::libunwind::Frame;
+use super::TraceError;
+use core::ffi::c_void;
+use core::mem;
+
+#[allow(non_camel_case_types)]
+type kern_return_t = libc::c_int;
+#[allow(non_camel_case_types)]
+type natural_t = libc::c_uint;
+#[allow(non_camel_case_types)]
+type mach_port_t = libc::c_uint;
+
+const KERN_SUCCESS: kern_return_t = 0;
+const KERN_INVALID_ARGUMENT: kern_return_t = 4;
+const MACH_PORT_NULL: mach_port_t = 0;
+
+/// Upper bound on the number of frames yielded, guarding against loops in a
+/// corrupt frame pointer chain.
+const MAX_FRAMES: usize = 16 * 1024;
+
+extern "C" {
+    static mach_task_self_: mach_port_t;
+    fn mach_thread_self() -> mach_port_t;
+    fn mach_port_deallocate(task: mach_port_t, name: mach_port_t) -> kern_return_t;
+    fn thread_suspend(thread: mach_port_t) -> kern_return_t;
+    fn thread_resume(thread: mach_port_t) -> kern_return_t;
+    fn thread_get_state(
+        thread: mach_port_t,
+        flavor: libc::c_int,
+        state: *mut natural_t,
+        count: *mut natural_t,
+    ) -> kern_return_t;
+    fn pthread_from_mach_thread_np(thread: mach_port_t) -> libc::pthread_t;
+    fn pthread_get_stackaddr_np(thread: libc::pthread_t) -> *mut c_void;
+    fn pthread_get_stacksize_np(thread: libc::pthread_t) -> libc::size_t;
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        const THREAD_STATE_FLAVOR: libc::c_int = 4; // x86_THREAD_STATE64
+
+        #[repr(C)]
+        struct ThreadState {
+            rax: u64, rbx: u64, rcx: u64, rdx: u64,
+            rdi: u64, rsi: u64, rbp: u64, rsp: u64,
+            r8: u64, r9: u64, r10: u64, r11: u64,
+            r12: u64, r13: u64, r14: u64, r15: u64,
+            rip: u64, rflags: u64, cs: u64, fs: u64, gs: u64,
+        }
+
+        impl ThreadState {
+            fn registers(&self) -> (usize, usize, usize) {
+                (self.rip as usize, self.rsp as usize, self.rbp as usize)
+            }
+        }
+
+        fn strip_pointer_auth(addr: usize) -> usize {
+            addr
+        }
+    } else if #[cfg(target_arch = "aarch64")] {
+        const THREAD_STATE_FLAVOR: libc::c_int = 6; // ARM_THREAD_STATE64
+
+        #[repr(C)]
+        struct ThreadState {
+            x: [u64; 29],
+            fp: u64,
+            lr: u64,
+            sp: u64,
+            pc: u64,
+            cpsr: u32,
+            pad: u32,
+        }
+
+        impl ThreadState {
+            fn registers(&self) -> (usize, usize, usize) {
+                (
+                    strip_pointer_auth(self.pc as usize),
+                    self.sp as usize,
+                    self.fp as usize,
+                )
+            }
+        }
+
+        // Return addresses may be signed on arm64e, and user space addresses
+        // never use more than 47 bits.
+        fn strip_pointer_auth(addr: usize) -> usize {
+            addr & 0x0000_7fff_ffff_ffff
+        }
+    }
+}
+
+pub fn validate_thread_handle(thread: mach_port_t) -> Result<(), TraceError> {
+    if thread == MACH_PORT_NULL {
+        return Err(TraceError::InvalidHandle(KERN_INVALID_ARGUMENT as u32));
+    }
+    Ok(())
+}
+
+pub unsafe fn trace_thread(
+    thread: super::RawThreadHandle,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    // Suspending ourselves would never return, so trace the current thread
+    // with libunwind instead.
+    let me = mach_thread_self();
+    mach_port_deallocate(mach_task_self_, me);
+    if thread == me {
+        super::libunwind::trace(cb);
+        return Ok(());
+    }
+
+    // Looking up the pthread takes libpthread's lock on its list of threads,
+    // which the thread could be holding once it's suspended.
+    let stack = stack_bounds(thread);
+
+    let kr = thread_suspend(thread);
+    if kr != KERN_SUCCESS {
+        return Err(TraceError::SuspendFailed(kr as u32));
+    }
+
+    let mut state: ThreadState = mem::zeroed();
+    let mut count = (mem::size_of::<ThreadState>() / mem::size_of::<natural_t>()) as natural_t;
+    let kr = thread_get_state(
+        thread,
+        THREAD_STATE_FLAVOR,
+        &mut state as *mut _ as *mut natural_t,
+        &mut count,
+    );
+    if kr != KERN_SUCCESS {
+        thread_resume(thread);
+        return Err(TraceError::GetContextFailed(kr as u32));
+    }
+
+    let (pc, sp, fp) = state.registers();
+    // Frame pointers are dereferenced directly, so without the bounds of the
+    // stack a corrupt one could point anywhere and crash this thread while the
+    // target is still suspended. Only the frame of the registers is yielded
+    // then, by giving the walk an empty stack.
+    let (stack_low, stack_high) = match stack {
+        Some((low, high)) if sp >= low && sp < high => (sp, high),
+        _ => (sp, sp),
+    };
+    walk(pc, sp, fp, stack_low, stack_high, cb);
+
+    thread_resume(thread);
+    Ok(())
+}
+
+/// Returns the lowest and highest address of the stack of `thread`, or `None`
+/// if they aren't known.
+unsafe fn stack_bounds(thread: mach_port_t) -> Option<(usize, usize)> {
+    let pthread = pthread_from_mach_thread_np(thread);
+    if pthread != 0 as libc::pthread_t {
+        // `pthread_get_stackaddr_np` returns the top of the stack.
+        let high = pthread_get_stackaddr_np(pthread) as usize;
+        let size = pthread_get_stacksize_np(pthread);
+        if high > size {
+            return Some((high - size, high));
+        }
+    }
+    None
+}
+
+unsafe fn walk(
+    pc: usize,
+    sp: usize,
+    mut fp: usize,
+    stack_low: usize,
+    stack_high: usize,
+    cb: &mut dyn FnMut(&super::Frame) -> bool,
+) {
+    let mut frame = super::Frame {
+        inner: Frame::Cloned {
+            ip: pc as *mut c_void,
+            sp: sp as *mut c_void,
+            symbol_address: pc as *mut c_void,
+        },
+    };
+    let word = mem::size_of::<usize>();
+    for _ in 0..MAX_FRAMES {
+        if !cb(&frame) {
+            return;
+        }
+
+        // Each frame record is a pair of the caller's frame pointer and the
+        // return address. Records must be aligned, lie within the stack and
+        // grow towards its top, otherwise the chain is corrupt or finished.
+        let last_record = match stack_high.checked_sub(2 * word) {
+            Some(last) => last,
+            None => return,
+        };
+        if fp % word != 0 || fp < stack_low || fp > last_record {
+            return;
+        }
+        let next_fp = *(fp as *const usize);
+        let ip = strip_pointer_auth(*((fp + word) as *const usize));
+        if ip == 0 {
+            return;
+        }
+        frame = super::Frame {
+            inner: Frame::Cloned {
+                ip: ip as *mut c_void,
+                sp: (fp + 2 * word) as *mut c_void,
+                symbol_address: ip as *mut c_void,
+            },
+        };
+        if next_fp <= fp {
+            // Yield the last return address but don't follow the chain any
+            // further.
+            cb(&frame);
+            return;
+        }
+        fp = next_fp;
+    }
+}
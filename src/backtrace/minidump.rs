@@ -0,0 +1,471 @@
+//! Writer for minidumps of thread snapshots, see
+//! `AllThreadsSnapshot::write_minidump`.
+//!
+//! Only the streams needed to inspect the stacks of the threads in a debugger
+//! are written: the threads with their registers and stack memory, the loaded
+//! modules, and information about the system. The layout follows
+//! `minidumpapiset.h`, whose structures are packed to 4 bytes.
+
+#![allow(bad_style)]
+
+use super::super::windows::*;
+use super::dbghelp::CapturedThread;
+use alloc::vec::Vec;
+use core::mem;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MINIDUMP_SIGNATURE: u32 = 0x504d_444d; // "MDMP"
+const MINIDUMP_VERSION: u32 = 0xa793;
+
+const ThreadListStream: u32 = 3;
+const ModuleListStream: u32 = 4;
+const MemoryListStream: u32 = 5;
+const SystemInfoStream: u32 = 7;
+
+const HEADER_LEN: usize = 32;
+const DIRECTORY_ENTRY_LEN: usize = 12;
+const STREAM_COUNT: usize = 4;
+
+#[cfg(target_arch = "x86_64")]
+const PROCESSOR_ARCHITECTURE: u16 = 9; // PROCESSOR_ARCHITECTURE_AMD64
+#[cfg(target_arch = "aarch64")]
+const PROCESSOR_ARCHITECTURE: u16 = 12; // PROCESSOR_ARCHITECTURE_ARM64
+#[cfg(target_arch = "x86")]
+const PROCESSOR_ARCHITECTURE: u16 = 0; // PROCESSOR_ARCHITECTURE_INTEL
+
+const VER_PLATFORM_WIN32_NT: u32 = 2;
+const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+const IMAGE_DIRECTORY_ENTRY_DEBUG: usize = 6;
+
+// Upper bound for the CodeView record of a module, which is a small header
+// followed by the path of its PDB.
+const MAX_CV_RECORD_LEN: usize = 4096;
+
+/// A `MINIDUMP_LOCATION_DESCRIPTOR`, the size and offset of some data.
+#[derive(Clone, Copy, Default)]
+struct Location {
+    size: u32,
+    rva: u32,
+}
+
+/// The minidump being assembled in memory.
+struct Dump {
+    buf: Vec<u8>,
+}
+
+impl Dump {
+    fn rva(&self) -> io::Result<u32> {
+        if self.buf.len() > u32::max_value() as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "minidump exceeds 4GiB",
+            ));
+        }
+        Ok(self.buf.len() as u32)
+    }
+
+    fn align(&mut self, align: usize) {
+        while self.buf.len() % align != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn location(&mut self, loc: Location) {
+        self.u32(loc.size);
+        self.u32(loc.rva);
+    }
+
+    /// Appends `data` aligned to `align` bytes and returns where it went.
+    fn blob(&mut self, data: &[u8], align: usize) -> io::Result<Location> {
+        self.align(align);
+        let rva = self.rva()?;
+        self.buf.extend_from_slice(data);
+        Ok(Location {
+            size: data.len() as u32,
+            rva,
+        })
+    }
+
+    /// Appends a `MINIDUMP_STRING` holding `s` and returns its offset.
+    fn string(&mut self, s: &[u16]) -> io::Result<u32> {
+        self.align(4);
+        let rva = self.rva()?;
+        self.u32((s.len() * 2) as u32);
+        for &c in s {
+            self.u16(c);
+        }
+        self.u16(0);
+        Ok(rva)
+    }
+
+    fn patch_u32(&mut self, at: usize, v: u32) {
+        self.buf[at..at + 4].copy_from_slice(&v.to_le_bytes());
+    }
+}
+
+/// A thread as it's listed in the thread list.
+struct ThreadRecord {
+    id: u32,
+    priority: i32,
+    teb: u64,
+    stack_start: u64,
+    stack: Location,
+    context: Location,
+}
+
+/// A module as it's listed in the module list.
+struct ModuleRecord {
+    base: u64,
+    size: u32,
+    checksum: u32,
+    timestamp: u32,
+    name: u32,
+    cv_record: Location,
+}
+
+pub fn write(threads: &[CapturedThread], w: &mut dyn io::Write) -> io::Result<()> {
+    let mut dump = Dump { buf: Vec::new() };
+    dump.buf
+        .resize(HEADER_LEN + STREAM_COUNT * DIRECTORY_ENTRY_LEN, 0);
+
+    // The variable sized data goes first, so the fixed sized streams
+    // referring to it can be written in one go.
+    let mut records = Vec::new();
+    for thread in threads {
+        let context = match thread.context_bytes() {
+            Some(context) => context,
+            None => continue,
+        };
+        let context = dump.blob(context, 16)?;
+        let (stack_start, stack) = thread.stack_copy();
+        let stack = dump.blob(stack, 16)?;
+        records.push(ThreadRecord {
+            id: thread.info().id(),
+            priority: thread.info().priority().unwrap_or(0),
+            teb: thread.info().teb_address().map_or(0, |teb| teb as u64),
+            stack_start,
+            stack,
+            context,
+        });
+    }
+    let modules = unsafe { loaded_modules(&mut dump)? };
+    let version = unsafe { os_version() };
+    let csd_version = match &version {
+        Some(version) => {
+            let len = version.szCSDVersion.iter().position(|&c| c == 0);
+            dump.string(&version.szCSDVersion[..len.unwrap_or(0)])?
+        }
+        None => dump.string(&[])?,
+    };
+
+    let mut streams = [(0, Location::default()); STREAM_COUNT];
+
+    dump.align(4);
+    let start = dump.rva()?;
+    dump.u32(records.len() as u32);
+    for record in &records {
+        dump.u32(record.id);
+        dump.u32(0); // SuspendCount, the thread was resumed
+        dump.u32(0); // PriorityClass
+        dump.u32(record.priority as u32);
+        dump.u64(record.teb);
+        dump.u64(record.stack_start);
+        dump.location(record.stack);
+        dump.location(record.context);
+    }
+    streams[0] = (
+        ThreadListStream,
+        Location {
+            size: dump.rva()? - start,
+            rva: start,
+        },
+    );
+
+    dump.align(4);
+    let start = dump.rva()?;
+    dump.u32(modules.len() as u32);
+    for module in &modules {
+        dump.u64(module.base);
+        dump.u32(module.size);
+        dump.u32(module.checksum);
+        dump.u32(module.timestamp);
+        dump.u32(module.name);
+        dump.buf.extend_from_slice(&[0; 52]); // VersionInfo
+        dump.location(module.cv_record);
+        dump.location(Location::default()); // MiscRecord
+        dump.u64(0); // Reserved0
+        dump.u64(0); // Reserved1
+    }
+    streams[1] = (
+        ModuleListStream,
+        Location {
+            size: dump.rva()? - start,
+            rva: start,
+        },
+    );
+
+    // The stacks of the threads are all the memory in the dump.
+    dump.align(4);
+    let start = dump.rva()?;
+    dump.u32(records.len() as u32);
+    for record in &records {
+        dump.u64(record.stack_start);
+        dump.location(record.stack);
+    }
+    streams[2] = (
+        MemoryListStream,
+        Location {
+            size: dump.rva()? - start,
+            rva: start,
+        },
+    );
+
+    dump.align(4);
+    let start = dump.rva()?;
+    dump.u16(PROCESSOR_ARCHITECTURE);
+    dump.u16(0); // ProcessorLevel
+    dump.u16(0); // ProcessorRevision
+    dump.u8(super::dbghelp::available_processors().unwrap_or(1).min(255) as u8);
+    match &version {
+        Some(version) => {
+            dump.u8(version.wProductType);
+            dump.u32(version.dwMajorVersion);
+            dump.u32(version.dwMinorVersion);
+            dump.u32(version.dwBuildNumber);
+            dump.u32(version.dwPlatformId);
+            dump.u32(csd_version);
+            dump.u16(version.wSuiteMask);
+        }
+        None => {
+            dump.u8(0);
+            dump.u32(0);
+            dump.u32(0);
+            dump.u32(0);
+            dump.u32(VER_PLATFORM_WIN32_NT);
+            dump.u32(csd_version);
+            dump.u16(0);
+        }
+    }
+    dump.u16(0); // Reserved2
+    dump.buf.extend_from_slice(&[0; 24]); // Cpu
+    streams[3] = (
+        SystemInfoStream,
+        Location {
+            size: dump.rva()? - start,
+            rva: start,
+        },
+    );
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32);
+    dump.patch_u32(0, MINIDUMP_SIGNATURE);
+    dump.patch_u32(4, MINIDUMP_VERSION);
+    dump.patch_u32(8, STREAM_COUNT as u32);
+    dump.patch_u32(12, HEADER_LEN as u32);
+    dump.patch_u32(16, 0); // CheckSum
+    dump.patch_u32(20, timestamp);
+    // Flags are left 0, `MiniDumpNormal`.
+    for (i, &(kind, loc)) in streams.iter().enumerate() {
+        let at = HEADER_LEN + i * DIRECTORY_ENTRY_LEN;
+        dump.patch_u32(at, kind);
+        dump.patch_u32(at + 4, loc.size);
+        dump.patch_u32(at + 8, loc.rva);
+    }
+
+    w.write_all(&dump.buf)
+}
+
+/// Lists the modules loaded in the process, appending their names and
+/// CodeView records to `dump`.
+unsafe fn loaded_modules(dump: &mut Dump) -> io::Result<Vec<ModuleRecord>> {
+    let mut modules = Vec::new();
+    let snap = CreateToolhelp32Snapshot(TH32CS_SNAPMODULE, 0);
+    if snap == INVALID_HANDLE_VALUE {
+        return Ok(modules);
+    }
+    let mut entry: MODULEENTRY32W = mem::zeroed();
+    entry.dwSize = mem::size_of_val(&entry) as DWORD;
+    let mut more = Module32FirstW(snap, &mut entry) == TRUE;
+    let mut result = Ok(());
+    while more {
+        if let Err(e) = add_module(dump, &entry, &mut modules) {
+            result = Err(e);
+            break;
+        }
+        more = Module32NextW(snap, &mut entry) == TRUE;
+    }
+    CloseHandle(snap);
+    result.map(|()| modules)
+}
+
+unsafe fn add_module(
+    dump: &mut Dump,
+    entry: &MODULEENTRY32W,
+    modules: &mut Vec<ModuleRecord>,
+) -> io::Result<()> {
+    let base = entry.modBaseAddr as u64;
+    let len = entry.szExePath.iter().position(|&c| c == 0);
+    let name = dump.string(&entry.szExePath[..len.unwrap_or(entry.szExePath.len())])?;
+    let mut record = ModuleRecord {
+        base,
+        size: entry.modBaseSize,
+        checksum: 0,
+        timestamp: 0,
+        name,
+        cv_record: Location::default(),
+    };
+    if let Some(headers) = read_image_headers(base) {
+        record.checksum = headers.checksum;
+        record.timestamp = headers.timestamp;
+        if let Some(cv) = read_cv_record(base, headers.debug_directory) {
+            record.cv_record = dump.blob(&cv, 4)?;
+        }
+    }
+    modules.push(record);
+    Ok(())
+}
+
+/// The parts of the PE headers of a loaded image a debugger needs to find
+/// its symbols.
+struct ImageHeaders {
+    checksum: u32,
+    timestamp: u32,
+    /// Offset and size of the debug directory, relative to the image base.
+    debug_directory: (u32, u32),
+}
+
+unsafe fn read_image_headers(base: u64) -> Option<ImageHeaders> {
+    let nt = base + u64::from(read_u32(base + 0x3c)?);
+    if read_u32(nt)? != 0x0000_4550 {
+        // "PE\0\0"
+        return None;
+    }
+    let timestamp = read_u32(nt + 8)?;
+    let optional = nt + 24;
+    let directories = match read_u16(optional)? {
+        0x10b => optional + 96,  // PE32
+        0x20b => optional + 112, // PE32+
+        _ => return None,
+    };
+    let checksum = read_u32(optional + 64)?;
+    let debug = directories + 8 * IMAGE_DIRECTORY_ENTRY_DEBUG as u64;
+    Some(ImageHeaders {
+        checksum,
+        timestamp,
+        debug_directory: (read_u32(debug)?, read_u32(debug + 4)?),
+    })
+}
+
+/// Reads the CodeView record, which identifies the PDB of the image, from the
+/// debug directory of the image loaded at `base`.
+unsafe fn read_cv_record(base: u64, (offset, size): (u32, u32)) -> Option<Vec<u8>> {
+    const ENTRY_LEN: u32 = 28; // IMAGE_DEBUG_DIRECTORY
+    if offset == 0 {
+        return None;
+    }
+    for i in 0..size / ENTRY_LEN {
+        let entry = base + u64::from(offset + i * ENTRY_LEN);
+        if read_u32(entry + 12)? != IMAGE_DEBUG_TYPE_CODEVIEW {
+            continue;
+        }
+        let len = read_u32(entry + 16)? as usize;
+        let data = read_u32(entry + 20)?;
+        if data == 0 || len > MAX_CV_RECORD_LEN {
+            return None;
+        }
+        let mut cv = vec![0; len];
+        if !read_memory(base + u64::from(data), &mut cv) {
+            return None;
+        }
+        return Some(cv);
+    }
+    None
+}
+
+unsafe fn read_u16(addr: u64) -> Option<u16> {
+    let mut buf = [0; 2];
+    if read_memory(addr, &mut buf) {
+        Some(u16::from_le_bytes(buf))
+    } else {
+        None
+    }
+}
+
+unsafe fn read_u32(addr: u64) -> Option<u32> {
+    let mut buf = [0; 4];
+    if read_memory(addr, &mut buf) {
+        Some(u32::from_le_bytes(buf))
+    } else {
+        None
+    }
+}
+
+/// Reads memory of the process, which fails rather than faulting if the
+/// module was unloaded in the meantime.
+unsafe fn read_memory(addr: u64, buf: &mut [u8]) -> bool {
+    let mut read = 0;
+    ReadProcessMemory(
+        GetCurrentProcess(),
+        addr as LPCVOID,
+        buf.as_mut_ptr().cast(),
+        buf.len(),
+        &mut read,
+    ) == TRUE
+        && read == buf.len()
+}
+
+/// Returns the version of Windows, read with `RtlGetVersion` since
+/// `GetVersionExW` reports the version the application is manifested for.
+unsafe fn os_version() -> Option<RTL_OSVERSIONINFOEXW> {
+    let ntdll = GetModuleHandleA(b"ntdll.dll\0".as_ptr() as *const i8);
+    if ntdll.is_null() {
+        return None;
+    }
+    let get = GetProcAddress(ntdll, b"RtlGetVersion\0".as_ptr() as *const i8);
+    if get.is_null() {
+        return None;
+    }
+    let get = mem::transmute::<FARPROC, RtlGetVersion>(get);
+    let mut info: RTL_OSVERSIONINFOEXW = mem::zeroed();
+    info.dwOSVersionInfoSize = mem::size_of_val(&info) as ULONG;
+    if get(&mut info) < 0 {
+        return None;
+    }
+    Some(info)
+}
+
+// Definitions for `RtlGetVersion`, which isn't in winapi.
+type RtlGetVersion = unsafe extern "system" fn(*mut RTL_OSVERSIONINFOEXW) -> LONG;
+
+#[repr(C)]
+struct RTL_OSVERSIONINFOEXW {
+    dwOSVersionInfoSize: ULONG,
+    dwMajorVersion: ULONG,
+    dwMinorVersion: ULONG,
+    dwBuildNumber: ULONG,
+    dwPlatformId: ULONG,
+    szCSDVersion: [WCHAR; 128],
+    wServicePackMajor: u16,
+    wServicePackMinor: u16,
+    wSuiteMask: u16,
+    wProductType: u8,
+    wReserved: u8,
+}
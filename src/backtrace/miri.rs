@@ -54,6 +54,14 @@ impl Frame {
     pub fn module_base_address(&self) -> Option<*mut c_void> {
         None
     }
+
+    pub fn is_inline(&self) -> bool {
+        false
+    }
+
+    pub fn inline_context(&self) -> u32 {
+        0
+    }
 }
 
 pub fn trace<F: FnMut(&super::Frame) -> bool>(cb: F) {
@@ -1,5 +1,9 @@
 use core::ffi::c_void;
 use core::fmt;
+use core::time::Duration;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 /// Inspects the current call-stack, passing all active frames into the closure
 /// provided to calculate a stack trace.
@@ -66,65 +70,2133 @@ pub unsafe fn trace_unsynchronized<F: FnMut(&Frame) -> bool>(mut cb: F) {
     #[cfg(not(target_os = "windows"))]
     trace_imp(&mut cb);
     #[cfg(target_os = "windows")]
-    trace_imp(&mut cb, 0 as _);
+    let _ = trace_imp(&mut cb, 0 as _);
 }
 
-/// TODO docs
-#[cfg(target_os = "windows")]
-pub unsafe fn trace_thread_unsynchronized<F: FnMut(&Frame) -> bool>(
-    thread: *mut c_void,
-    mut cb: F,
-) {
-    trace_imp(&mut cb, thread)
+/// Same as `trace`, except that the module base of each frame isn't looked up
+/// during the walk but the first time `Frame::module_base_address` is called.
+///
+/// Walks otherwise look up the module base of every frame, which is wasted on
+/// capture-only workloads, such as sampling profilers recording only
+/// instruction pointers. The base is remembered once looked up, and clones of
+/// the frame made afterwards share the result. Note that if the module was
+/// unloaded before the base is asked for, it's reported as null.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(all(target_os = "windows", feature = "std"))]
+pub fn trace_lazy_base<F: FnMut(&Frame) -> bool>(cb: F) {
+    let _guard = crate::lock::lock();
+    unsafe { trace_lazy_base_unsynchronized(cb) }
 }
 
-/// A trait representing one frame of a backtrace, yielded to the `trace`
-/// function of this crate.
+/// Same as `trace_lazy_base`, only unsafe as it's unsynchronized.
 ///
-/// The tracing function's closure will be yielded frames, and the frame is
-/// virtually dispatched as the underlying implementation is not always known
-/// until runtime.
-#[derive(Clone)]
-pub struct Frame {
-    pub(crate) inner: FrameImp,
+/// See `trace_unsynchronized` for the caveats.
+#[cfg(target_os = "windows")]
+pub unsafe fn trace_lazy_base_unsynchronized<F: FnMut(&Frame) -> bool>(mut cb: F) {
+    let _ = trace_lazy_base_imp(&mut cb);
 }
 
-impl Frame {
-    /// Returns the current instruction pointer of this frame.
+/// The platform's raw representation of a thread, wrapped by `ThreadHandle`.
+///
+/// This is a thread `HANDLE` on Windows and a Mach thread port on macOS. Other
+/// platforms can't trace other threads, but the type is still defined there
+/// so code using the cross-thread functions compiles everywhere.
+#[cfg(not(target_os = "macos"))]
+pub type RawThreadHandle = *mut c_void;
+
+/// The platform's raw representation of a thread, wrapped by `ThreadHandle`.
+///
+/// This is a thread `HANDLE` on Windows and a Mach thread port on macOS. Other
+/// platforms can't trace other threads, but the type is still defined there
+/// so code using the cross-thread functions compiles everywhere.
+#[cfg(target_os = "macos")]
+pub type RawThreadHandle = libc::mach_port_t;
+
+/// A handle identifying a thread to trace, see `trace_thread_unsynchronized`.
+///
+/// This wraps a `RawThreadHandle` which has been checked to be usable for
+/// tracing by `ThreadHandle::new`. It doesn't own the raw handle, which has to
+/// stay open for as long as this is used.
+///
+/// # Examples
+///
+/// ```no_run
+/// # #[cfg(windows)] {
+/// use std::os::windows::io::AsRawHandle;
+///
+/// let thread = std::thread::spawn(|| loop {});
+/// let handle = backtrace::ThreadHandle::new(thread.as_raw_handle()).unwrap();
+/// unsafe {
+///     backtrace::trace_thread_unsynchronized(handle, |frame| {
+///         println!("{:?}", frame.ip());
+///         true
+///     })
+///     .unwrap();
+/// }
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ThreadHandle(RawThreadHandle);
+
+// The handle is only an identifier for the thread, which may be used from any
+// thread.
+unsafe impl Send for ThreadHandle {}
+unsafe impl Sync for ThreadHandle {}
+
+impl ThreadHandle {
+    /// Checks that `raw` can be used to trace a thread and wraps it.
     ///
-    /// This is normally the next instruction to execute in the frame, but not
-    /// all implementations list this with 100% accuracy (but it's generally
-    /// pretty close).
+    /// On Windows `raw` has to be an open handle, checked with
+    /// `GetHandleInformation`, with `THREAD_SUSPEND_RESUME` and
+    /// `THREAD_GET_CONTEXT` access. The pseudo handle returned by
+    /// `GetCurrentThread` is accepted as well. A real handle to the calling
+    /// thread is traced in place rather than suspended if it also has
+    /// `THREAD_QUERY_LIMITED_INFORMATION` access, which identifies the thread
+    /// by its id. On macOS `raw` must not be `MACH_PORT_NULL`.
     ///
-    /// It is recommended to pass this value to `backtrace::resolve` to turn it
-    /// into a symbol name.
-    pub fn ip(&self) -> *mut c_void {
-        self.inner.ip()
+    /// # Errors
+    ///
+    /// Returns `TraceError::InvalidHandle` if `raw` isn't a valid handle and
+    /// `TraceError::AccessDenied` if it lacks the required access rights. On
+    /// platforms which can't trace other threads this always returns
+    /// `TraceError::Unsupported`.
+    pub fn new(raw: RawThreadHandle) -> Result<ThreadHandle, TraceError> {
+        validate_thread_handle_imp(raw)?;
+        Ok(ThreadHandle(raw))
     }
 
-    /// Returns the current stack pointer of this frame.
+    /// Wraps `raw` without checking it.
     ///
-    /// In the case that a backend cannot recover the stack pointer for this
-    /// frame, a null pointer is returned.
-    pub fn sp(&self) -> *mut c_void {
-        self.inner.sp()
+    /// # Safety
+    ///
+    /// `raw` must meet the requirements checked by `ThreadHandle::new`,
+    /// passing anything else to the tracing functions is undefined behavior.
+    pub unsafe fn from_raw(raw: RawThreadHandle) -> ThreadHandle {
+        ThreadHandle(raw)
     }
 
-    /// Returns the starting symbol address of the frame of this function.
+    /// Returns the wrapped raw handle.
+    pub fn as_raw(&self) -> RawThreadHandle {
+        self.0
+    }
+
+    /// Returns the id of the thread, as reported by `ThreadInfo::id` and
+    /// `BacktraceFrame::thread_id`.
     ///
-    /// This will attempt to rewind the instruction pointer returned by `ip` to
-    /// the start of the function, returning that value. In some cases, however,
-    /// backends will just return `ip` from this function.
+    /// This is only available on Windows, where it's looked up with
+    /// `GetThreadId`.
+    pub fn id(&self) -> Option<u32> {
+        thread_id_imp(self.0)
+    }
+
+    /// Returns the bounds of the stack of the thread.
     ///
-    /// The returned value can sometimes be used if `backtrace::resolve` failed
-    /// on the `ip` given above.
-    pub fn symbol_address(&self) -> *mut c_void {
-        self.inner.symbol_address()
+    /// This is only available on Windows, where the bounds are read from the
+    /// thread's TEB, found with `NtQueryInformationThread`. This needs
+    /// `THREAD_QUERY_INFORMATION` access.
+    pub fn stack_bounds(&self) -> Option<ThreadStackBounds> {
+        stack_bounds_imp(self.0)
     }
 
-    /// Returns the base address of the module to which the frame belongs.
-    pub fn module_base_address(&self) -> Option<*mut c_void> {
-        self.inner.module_base_address()
+    /// Returns the address of the thread environment block (TEB) of the
+    /// thread.
+    ///
+    /// The TEB holds the thread's stack bounds and the slots of its thread
+    /// local storage, and is what the `gs` segment register points to on
+    /// x86_64 (`fs` on x86, `x18` on ARM64). This helps make sense of
+    /// references to thread locals found on the stack or in registers when
+    /// analyzing a crash. It's only available on Windows, where it's found
+    /// with `NtQueryInformationThread`, which needs `THREAD_QUERY_INFORMATION`
+    /// access.
+    pub fn teb_address(&self) -> Option<*mut c_void> {
+        teb_address_imp(self.0)
+    }
+}
+
+/// The address range reserved for the stack of a thread, see
+/// `ThreadHandle::stack_bounds` and `ThreadInfo::stack_bounds`.
+///
+/// Stacks grow downwards, from `base` towards `limit`, so the stack pointer
+/// of a running thread is always between the two.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ThreadStackBounds {
+    pub(crate) base: usize,
+    pub(crate) limit: usize,
+}
+
+impl ThreadStackBounds {
+    /// Returns the highest address of the stack, where it starts. This
+    /// address itself is past the end of the stack.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Returns the lowest address of the stack, which it can't grow beyond.
+    ///
+    /// This is the start of the whole reservation of the stack rather than of
+    /// the part of it which is committed so far.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns the size of the stack in bytes.
+    pub fn size(&self) -> usize {
+        self.base - self.limit
+    }
+
+    /// Returns whether `sp` points into the stack.
+    pub fn contains(&self, sp: usize) -> bool {
+        self.limit <= sp && sp < self.base
+    }
+
+    /// Returns how many bytes of the stack are left below `sp`, or `None` if
+    /// `sp` doesn't point into the stack.
+    pub fn remaining(&self, sp: usize) -> Option<usize> {
+        if self.contains(sp) {
+            Some(sp - self.limit)
+        } else {
+            None
+        }
+    }
+}
+
+/// Traces the stack of another thread of the current process.
+///
+/// The thread is suspended while its stack is walked and resumed afterwards,
+/// including when an error occurs. Frames are yielded to `cb` just like with
+/// `trace`, most recent first.
+///
+/// `thread` may also be the calling thread, in which case nothing is
+/// suspended. On Windows the frames of this crate are left out of the trace
+/// then, so that the first frame is the function which called this one.
+///
+/// On Windows `thread` is a thread handle with `THREAD_SUSPEND_RESUME` and
+/// `THREAD_GET_CONTEXT` access, suspended with `SuspendThread` and walked
+/// with dbghelp. A thread created with `CREATE_SUSPENDED` which hasn't run yet
+/// has no stack to walk, and yields a single frame whose `ip` is its start
+/// routine. As resolving a frame looks up the instruction before `ip`, that
+/// frame is best resolved with `inline_frames_at`.
+///
+/// On macOS `thread` is a Mach thread port, suspended with `thread_suspend`,
+/// and the stack is walked by following frame pointers, which the macOS ABI
+/// requires. The caller of a leaf function which doesn't set up a frame record
+/// may be missing from the trace there.
+///
+/// Suspending a thread risks deadlocks if `cb` takes locks the thread may
+/// hold, and on Windows this is more likely if the process can only run on a
+/// single processor, see `available_processors`.
+///
+/// # Errors
+///
+/// Returns an error if the thread couldn't be suspended or its context
+/// couldn't be read. On Windows `TraceError::AccessDenied` is returned if the
+/// handle lacks the required access rights, in which case enabling
+/// `SeDebugPrivilege` through `enable_debug_privilege` may help. On macOS
+/// `TraceError::SuspendFailed` or `TraceError::GetContextFailed` contain the
+/// `kern_return_t` of the failing call.
+///
+/// On Windows `TraceError::InvalidContext` is returned without walking if the
+/// registers of the thread are implausible, for example because it hasn't
+/// started running yet.
+///
+/// `TraceError::Truncated` is returned if the walk had to be stopped because
+/// the stack looked corrupt, after the frames walked up to that point have
+/// been passed to `cb`. On Windows `TraceError::NoFrames` is returned if not
+/// even the first frame could be walked, in which case `cb` is never called.
+///
+/// On platforms other than Windows and macOS this always returns
+/// `TraceError::Unsupported`.
+///
+/// # Safety
+///
+/// `thread` must stay open for the duration of the call and refer to a thread
+/// of the current process, with the access rights described above on Windows.
+/// `ThreadHandle::new` checks these when the handle is created, but not that
+/// it's still open. Handles to the calling thread are recognized, so it's
+/// never suspended.
+///
+/// `cb` runs while the thread is suspended, so it must not allocate, wait for
+/// the thread or take any lock the thread may be holding, including the lock
+/// of the global allocator, or the call may deadlock. Frames are best copied
+/// into memory set aside beforehand and resolved after this returns.
+///
+/// This doesn't synchronize with other users of dbghelp, see
+/// `trace_unsynchronized`.
+#[inline(never)] // the marker has to be in a frame of its own
+pub unsafe fn trace_thread_unsynchronized<F: FnMut(&Frame) -> bool>(
+    thread: ThreadHandle,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let marker = 0u8;
+    trace_thread_from(thread, &marker, &mut cb)
+}
+
+/// Traces `thread` on behalf of a public entry point of this crate, which
+/// `marker` is a local of.
+///
+/// When a thread traces itself the walk starts inside this crate, so its
+/// frames are skipped up to and including the entry point, making the caller
+/// of the entry point the first frame. The stack grows down on all platforms
+/// which can trace threads, so these are the frames whose stack pointer is
+/// below `marker`. Entry points have to be `#[inline(never)]`, otherwise
+/// `marker` ends up in the frame of their caller, which would be skipped too.
+pub(crate) unsafe fn trace_thread_from(
+    thread: ThreadHandle,
+    marker: *const u8,
+    cb: &mut dyn FnMut(&Frame) -> bool,
+) -> Result<(), TraceError> {
+    skip_own_frames(thread, marker, cb, |cb| {
+        trace_thread_imp(thread.as_raw(), cb)
+    })
+}
+
+/// Runs `walk` with `cb`, skipping the frames of this crate if `thread` is
+/// the calling thread, see `trace_thread_from`.
+///
+/// This is only done on Windows, where frames report their actual stack
+/// pointer. libunwind reports the canonical frame address instead, which is
+/// the stack pointer of the caller.
+unsafe fn skip_own_frames(
+    thread: ThreadHandle,
+    marker: *const u8,
+    cb: &mut dyn FnMut(&Frame) -> bool,
+    walk: impl FnOnce(&mut dyn FnMut(&Frame) -> bool) -> Result<(), TraceError>,
+) -> Result<(), TraceError> {
+    #[cfg(target_os = "windows")]
+    {
+        if is_current_thread_imp(thread.as_raw()) {
+            let limit = marker as usize;
+            return walk(&mut |frame| (frame.sp() as usize) < limit || cb(frame));
+        }
+    }
+    let _ = (thread, marker);
+    walk(cb)
+}
+
+/// Walks the stack at the point an exception was raised, as described by the
+/// `EXCEPTION_POINTERS` passed to an exception filter or vectored exception
+/// handler.
+///
+/// The walk starts at the faulting instruction from the `ContextRecord`, so
+/// none of the frames of the exception dispatching code or of the handler
+/// calling this are included. The context record is copied before the walk
+/// and left untouched. Frames are yielded to `cb` just like with `trace`, most
+/// recent first.
+///
+/// # Errors
+///
+/// Returns `TraceError::DbghelpUnavailable` if dbghelp couldn't be loaded and
+/// `TraceError::Truncated` if the walk was stopped because the stack looked
+/// corrupt.
+///
+/// # Safety
+///
+/// `pointers` must point to a valid `EXCEPTION_POINTERS` whose `ContextRecord`
+/// is valid for reads. This doesn't synchronize with other users of dbghelp,
+/// see `trace_unsynchronized`.
+#[cfg(target_os = "windows")]
+pub unsafe fn trace_exception<F: FnMut(&Frame) -> bool>(
+    pointers: *mut c_void,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    trace_exception_imp(pointers, &mut cb)
+}
+
+/// Same as `trace_thread_unsynchronized`, except that dbghelp is told the stack
+/// belongs to the `machine` architecture instead of detecting it.
+///
+/// `machine` is one of the `IMAGE_FILE_MACHINE_*` values accepted by
+/// `StackWalkEx`:
+///
+/// * `IMAGE_FILE_MACHINE_I386` (`0x014c`) for x86,
+/// * `IMAGE_FILE_MACHINE_AMD64` (`0x8664`) for x86_64,
+/// * `IMAGE_FILE_MACHINE_ARM64` (`0xaa64`) for ARM64,
+/// * `IMAGE_FILE_MACHINE_ARMNT` (`0x01c4`) for ARMv7 in Thumb-2 mode.
+///
+/// Normally the machine type is that of the architecture this crate was
+/// compiled for, which is what `trace_thread_unsynchronized` uses. Overriding
+/// it is only useful for unusual setups such as emulation, where the
+/// automatic choice is wrong. The registers of the thread are still captured
+/// as the native `CONTEXT` record, and dbghelp interprets them according to
+/// `machine`, so a machine type whose `CONTEXT` layout differs from the native
+/// one produces garbage frames. dbghelp rejects unknown values, in which case
+/// no frames are yielded and `TraceError::NoFrames` is returned.
+///
+/// # Errors
+///
+/// See `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized` for the requirements on `thread` and
+/// `cb`. dbghelp reads and updates the captured registers as the `CONTEXT`
+/// record of `machine`, so `machine` must not be a type whose `CONTEXT` is
+/// larger than the native one, such as `IMAGE_FILE_MACHINE_AMD64` on x86.
+#[cfg(target_os = "windows")]
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_with_machine<F: FnMut(&Frame) -> bool>(
+    thread: ThreadHandle,
+    machine: u16,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let marker = 0u8;
+    skip_own_frames(thread, &marker, &mut cb, |cb| {
+        trace_with_machine_imp(thread.as_raw(), machine, cb)
+    })
+}
+
+/// Same as `trace_thread_unsynchronized`, except that `flags` are passed to
+/// `StackWalkEx`, whose flags are otherwise always 0.
+///
+/// The flags dbghelp accepts are:
+///
+/// * `SYM_STKWALK_DEFAULT` (`0`), the default unwinding.
+/// * `SYM_STKWALK_FORCE_FRAMEPTR` (`1`), which makes dbghelp follow the chain
+///   of frame pointers instead of unwinding with the unwind information of
+///   the code. This can get further when unwind information is missing or
+///   wrong, for example in JIT-compiled code, but only as long as all
+///   functions on the stack maintain a frame pointer.
+/// * `SYM_STKWALK_ZEROEXTEND_PTRS` (`2`), which makes dbghelp zero-extend
+///   rather than sign-extend 32-bit addresses, for walking 32-bit stacks.
+///
+/// The flags only have an effect when `StackWalkEx` is used. Stacks are
+/// walked with `StackWalk64`, which doesn't take flags, by dbghelp versions
+/// which lack `StackWalkEx` and while symbol initialization is deferred, see
+/// `set_deferred_symbol_init`, and the flags are ignored then. dbghelp may
+/// also ignore flags it doesn't support for the architecture being walked.
+///
+/// # Errors
+///
+/// See `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized`.
+#[cfg(target_os = "windows")]
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_with_walk_flags<F: FnMut(&Frame) -> bool>(
+    thread: ThreadHandle,
+    flags: u32,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let marker = 0u8;
+    skip_own_frames(thread, &marker, &mut cb, |cb| {
+        trace_with_walk_flags_imp(thread.as_raw(), flags, cb)
+    })
+}
+
+/// The dbghelp function a stack was walked with, as reported by
+/// `trace_thread_best_effort`.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StackWalkApi {
+    /// `StackWalkEx`, which also yields the calls inlined into each frame.
+    StackWalkEx,
+    /// `StackWalk64`, which only yields physical frames.
+    StackWalk64,
+}
+
+/// Same as `trace_thread_unsynchronized`, but falls back to walking with
+/// `StackWalk64` if `StackWalkEx` seems to have stopped early, returning which
+/// of the two the frames passed to `cb` were found with.
+///
+/// `StackWalkEx` is normally preferred as it expands inlined calls, but in
+/// some environments it stops where `StackWalk64` would continue. The walk
+/// with `StackWalkEx` is suspected to have stopped early if it found at most
+/// one frame, or if its last frame is far below the base of the stack, where
+/// the outermost frames of a thread are. In that case the stack is walked
+/// with `StackWalk64` as well and whichever walk found more physical frames
+/// is used. As nothing may be allocated while the thread is suspended the
+/// frames of the walks can't be kept, so the stack is walked up to three
+/// times, once more for `cb`, which makes this slower than
+/// `trace_thread_unsynchronized`.
+///
+/// `StackWalk64` is always used if `StackWalkEx` isn't available or while
+/// symbol initialization is deferred, see `set_deferred_symbol_init`.
+///
+/// # Errors
+///
+/// See `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized`.
+#[cfg(target_os = "windows")]
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_thread_best_effort<F: FnMut(&Frame) -> bool>(
+    thread: ThreadHandle,
+    mut cb: F,
+) -> Result<StackWalkApi, TraceError> {
+    let marker = 0u8;
+    let mut api = StackWalkApi::StackWalk64;
+    skip_own_frames(thread, &marker, &mut cb, |cb| {
+        api = trace_best_effort_imp(thread.as_raw(), cb)?;
+        Ok(())
+    })?;
+    Ok(api)
+}
+
+/// Reusable space for the registers of a thread being traced, see
+/// `trace_thread_with_context`.
+///
+/// Tracing a thread reads its registers into a `CONTEXT` record, which is
+/// over a kilobyte on x86_64 and ARM64. `trace_thread_unsynchronized` sets up
+/// and zeroes a new one on the stack for every trace, which adds up in a
+/// tight sampling loop. A sampler can create a buffer once and pass it to
+/// each trace instead. Since it's large, it's best kept in a long-lived or
+/// boxed struct rather than on the stack.
+#[cfg(target_os = "windows")]
+pub struct ContextBuffer(ContextBufferImp);
+
+#[cfg(target_os = "windows")]
+impl ContextBuffer {
+    /// Creates a zeroed buffer.
+    pub fn new() -> ContextBuffer {
+        ContextBuffer(ContextBufferImp::new())
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Default for ContextBuffer {
+    fn default() -> ContextBuffer {
+        ContextBuffer::new()
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl fmt::Debug for ContextBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextBuffer").finish()
+    }
+}
+
+/// Same as `trace_thread_unsynchronized`, except that the registers of the
+/// thread are read into `buffer` instead of a new `CONTEXT` record.
+///
+/// The buffer isn't cleared between traces, whatever the previous trace left
+/// in it is overwritten. Reusing one buffer for every sample of a sampling
+/// loop saves setting up a record on the stack for each of them, see the
+/// `trace_thread_with_context` benchmark.
+///
+/// # Errors
+///
+/// See `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized`.
+#[cfg(target_os = "windows")]
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_thread_with_context<F: FnMut(&Frame) -> bool>(
+    thread: ThreadHandle,
+    buffer: &mut ContextBuffer,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let marker = 0u8;
+    skip_own_frames(thread, &marker, &mut cb, |cb| {
+        trace_thread_with_context_imp(thread.as_raw(), &mut buffer.0, cb)
+    })
+}
+
+/// How a frame yielded by `trace_thread_reconciled` was found.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FrameConfidence {
+    /// The unwinder found the frame and the chain of frame pointers agrees.
+    Confirmed,
+    /// Only the unwinder found the frame. This is common for functions which
+    /// don't keep a frame pointer, so it doesn't mean the frame is wrong.
+    Unwound,
+    /// The frame was found by following frame pointers after the unwinder
+    /// stopped, and may be wrong if some function on the stack doesn't keep a
+    /// frame pointer.
+    FramePointer,
+}
+
+/// Same as `trace_thread_unsynchronized`, but also follows the chain of frame
+/// pointers of the thread and reconciles it with the frames found by the
+/// unwinder, yielding how each frame was found to `cb`.
+///
+/// The unwinder sometimes stops early, for example at code without unwind
+/// information, while the frame pointer chain is still intact. Frames both
+/// walks agree on are yielded as `FrameConfidence::Confirmed`, the remaining
+/// frames of the unwinder as `FrameConfidence::Unwound`, and frames from the
+/// chain beyond the last one the unwinder found are appended as
+/// `FrameConfidence::FramePointer`. This gives the most complete stacks for
+/// crash reports. The chain is read while the thread is suspended and only
+/// within the bounds of its stack, a corrupt chain just ends the extra frames.
+///
+/// Frame records are the caller's frame pointer followed by the return
+/// address, as laid out by x64 code built with frame pointers and by ARM64.
+/// On 32-bit targets no chain is followed, and all frames are `Unwound`.
+///
+/// # Errors
+///
+/// See `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized`.
+#[cfg(target_os = "windows")]
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_thread_reconciled<F: FnMut(&Frame, FrameConfidence) -> bool>(
+    thread: ThreadHandle,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let marker = 0u8;
+    let limit = if is_current_thread_imp(thread.as_raw()) {
+        &marker as *const u8 as usize
+    } else {
+        0
+    };
+    trace_reconciled_imp(thread.as_raw(), &mut |frame, confidence| {
+        (frame.sp() as usize) < limit || cb(frame, confidence)
+    })
+}
+
+/// A routine reading memory on behalf of `trace_with_memory_reader`.
+///
+/// This has the signature of dbghelp's `PREAD_PROCESS_MEMORY_ROUTINE64`. The
+/// arguments are a process handle, the address to read from, the buffer to
+/// read into, the number of bytes to read and a location to store the number
+/// of bytes actually read in. It must return a nonzero value on success and
+/// zero if the memory couldn't be read.
+#[cfg(target_os = "windows")]
+pub type MemoryReader = unsafe extern "system" fn(
+    process: *mut c_void,
+    addr: u64,
+    buffer: *mut c_void,
+    size: u32,
+    bytes_read: *mut u32,
+) -> i32;
+
+/// Walks the stack described by a captured register context, reading stack
+/// memory through `reader` instead of directly from the current process.
+///
+/// `context` must point to a `CONTEXT` record, aligned to 16 bytes, holding
+/// the registers of the thread at the point the walk should start. It's
+/// updated in place as the walk proceeds. Frames are yielded to `cb` just like
+/// with `trace`, most recent first.
+///
+/// This allows the same walk to run against a copy of a stack, for example one
+/// saved in a buffer or in a minidump, or against memory read with
+/// `ReadProcessMemory`. The process handle passed to `reader` is that of the
+/// current process and should be ignored by readers which access another
+/// memory source.
+///
+/// # Limitations
+///
+/// Unwind information and module base addresses are still looked up in the
+/// current process, so the code on the walked stack must be loaded at the
+/// same addresses here as it was in the memory `reader` reads from. Memory
+/// with code that isn't loaded here is walked on a best-effort basis.
+///
+/// # Errors
+///
+/// Returns `TraceError::DbghelpUnavailable` if dbghelp couldn't be loaded and
+/// `TraceError::Truncated` if the walk was stopped because the stack looked
+/// corrupt.
+///
+/// # Safety
+///
+/// `context` must be valid for reads and writes for the duration of the call,
+/// and `reader` must uphold the contract described on `MemoryReader`.
+#[cfg(target_os = "windows")]
+pub unsafe fn trace_with_memory_reader<F: FnMut(&Frame) -> bool>(
+    reader: MemoryReader,
+    context: *mut c_void,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    trace_with_memory_reader_imp(reader, context, &mut cb)
+}
+
+/// The architecture of a stack walked by `trace_guest_context`.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum GuestArch {
+    /// 32-bit x86, walked as `IMAGE_FILE_MACHINE_I386`.
+    X86,
+    /// x86_64, walked as `IMAGE_FILE_MACHINE_AMD64`.
+    X86_64,
+    /// ARM64, walked as `IMAGE_FILE_MACHINE_ARM64`.
+    Arm64,
+    /// ARMv7 in Thumb-2 mode, walked as `IMAGE_FILE_MACHINE_ARMNT`.
+    Arm,
+}
+
+/// The registers of a guest a walk with `trace_guest_context` starts from.
+///
+/// Only the registers needed to find the first frames are taken, all others
+/// are zero for the walk. Values are truncated to 32 bits for 32-bit
+/// architectures.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct GuestRegisters {
+    pub(crate) pc: u64,
+    pub(crate) sp: u64,
+    pub(crate) fp: u64,
+    pub(crate) lr: u64,
+}
+
+#[cfg(target_os = "windows")]
+impl GuestRegisters {
+    /// Creates the registers from the instruction, stack and frame pointers.
+    ///
+    /// The frame pointer is `ebp` on x86, `rbp` on x86_64, `x29` on ARM64 and
+    /// `r11` on ARM.
+    pub fn new(pc: u64, sp: u64, fp: u64) -> GuestRegisters {
+        GuestRegisters { pc, sp, fp, lr: 0 }
+    }
+
+    /// Sets the link register, which holds the return address of a leaf
+    /// function on ARM and ARM64 and is ignored on x86 and x86_64.
+    pub fn link_register(mut self, lr: u64) -> GuestRegisters {
+        self.lr = lr;
+        self
+    }
+}
+
+/// Walks the stack of a guest of the architecture `arch`, such as a program
+/// run by an emulator or interpreter with a register file of its own,
+/// starting from `registers`.
+///
+/// Unlike `trace_with_memory_reader`, which takes a `CONTEXT` record laid out
+/// for the architecture this crate was compiled for, the registers are laid
+/// out in the `CONTEXT` record of `arch` and the stack is walked as that
+/// machine type. This allows for example walking an x86_64 guest on an ARM64
+/// host. Architectures dbghelp can't walk, such as RISC-V, aren't supported.
+///
+/// Stack memory is read through `reader` if given, see `MemoryReader`, and
+/// from the current process otherwise. Unwind information and module bases
+/// are looked up by dbghelp in the modules it knows of, so frames in code
+/// dbghelp doesn't know about are only found through frame pointers. Frames
+/// are yielded to `cb` just like with `trace`, most recent first.
+///
+/// # Errors
+///
+/// Returns `TraceError::DbghelpUnavailable` if dbghelp couldn't be loaded,
+/// `TraceError::NoFrames` if not even the first frame could be walked, for
+/// example because dbghelp doesn't support walking `arch` on this host, and
+/// `TraceError::Truncated` if the walk was stopped because the stack looked
+/// corrupt.
+///
+/// # Safety
+///
+/// `reader` must uphold the contract described on `MemoryReader`. Without a
+/// reader the stack is read from the current process, in which case the
+/// memory `registers` point to has to stay valid during the walk. This
+/// doesn't synchronize with other users of dbghelp, see
+/// `trace_unsynchronized`.
+#[cfg(target_os = "windows")]
+pub unsafe fn trace_guest_context<F: FnMut(&Frame) -> bool>(
+    arch: GuestArch,
+    registers: &GuestRegisters,
+    reader: Option<MemoryReader>,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    trace_guest_context_imp(arch, registers, reader, &mut cb)
+}
+
+/// Marks a point at which the calling thread may be traced by
+/// `trace_thread_cooperative`.
+///
+/// If a trace of this thread has been requested, the thread captures its
+/// registers, lets the tracer know it's parked, and waits here until the
+/// tracer is done walking its stack. Otherwise this returns right away after
+/// a single atomic load, so it's cheap enough to call from loops and other
+/// hot spots where the thread is known to be in a consistent state.
+///
+/// This must not be called while the thread is itself tracing or resolving
+/// symbols, as the tracer would wait for the lock this thread holds.
+#[cfg(target_os = "windows")]
+#[inline(never)] // see `trace_thread_from`
+pub fn safe_point() {
+    let marker = 0u8;
+    safe_point_imp(&marker)
+}
+
+/// Traces another thread of the current process without suspending it, by
+/// having it park at its next call to `safe_point`.
+///
+/// The thread identified by `thread_id`, as returned by `ThreadHandle::id`
+/// or `ThreadInfo::id`, is asked to park and this waits up to `timeout` for
+/// it to reach a safe point. Its stack is then walked from the registers it
+/// captured there while it waits, and it's released once `cb` has seen all
+/// frames. The first frame is the caller of `safe_point`.
+///
+/// Unlike `trace_thread_unsynchronized` this never interrupts the thread at
+/// an arbitrary instruction, so it can't deadlock on a lock the thread holds
+/// and needs no access rights to it, but only threads which call
+/// `safe_point` regularly can be traced. If `thread_id` is the calling
+/// thread it's traced right away, and the first frame is the function which
+/// called this one.
+///
+/// # Errors
+///
+/// Returns `TraceError::TimedOut` if the thread didn't reach a safe point
+/// within `timeout`, for example because it doesn't call `safe_point`, is
+/// blocked, or doesn't exist. Other errors are those of walking the stack,
+/// see `trace_thread_unsynchronized`.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(all(target_os = "windows", feature = "std"))]
+#[inline(never)] // see `trace_thread_from`
+pub fn trace_thread_cooperative<F: FnMut(&Frame) -> bool>(
+    thread_id: u32,
+    timeout: Duration,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let marker = 0u8;
+    unsafe {
+        let mut parked = park_thread_imp(thread_id, timeout, &marker)?;
+        // The lock is only taken once the thread is parked, so a thread
+        // holding it on its way to the safe point doesn't time out.
+        let _guard = crate::lock::lock();
+        parked.trace(&mut cb)
+    }
+}
+
+// The number of frames `trace_thread_skip_symbols` buffers, allocated before
+// the thread is suspended.
+const SKIP_SYMBOLS_MAX_FRAMES: usize = 1024;
+
+/// Same as `trace_thread_unsynchronized`, except that frames whose symbol name
+/// is in `names` are skipped.
+///
+/// If any of the symbols a frame resolves to has a demangled name (without
+/// the trailing hash) equal to one of `names` then `cb` isn't called for it.
+/// This is useful for hiding runtime trampolines and similar frames to
+/// produce application-only stacks.
+///
+/// Resolving symbols allocates, which would deadlock if the suspended thread
+/// held the heap lock, so the stack is first walked into a buffer allocated up
+/// front, and the frames are only resolved and passed to `cb` once the thread
+/// has been resumed. The buffer holds the 1024 most recent frames. With the
+/// `std` feature the symbols are resolved while holding the same lock as
+/// `resolve`.
+///
+/// # Errors
+///
+/// Returns `TraceError::Truncated` if the stack had more frames than fit into
+/// the buffer, also when `cb` stopped before reaching the last of them.
+/// Otherwise see `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized`. Without the `std` feature the symbols
+/// are resolved without synchronization, see `resolve_unsynchronized`.
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_thread_skip_symbols<F: FnMut(&Frame) -> bool>(
+    thread: ThreadHandle,
+    names: &[&str],
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let mut frames = Vec::with_capacity(SKIP_SYMBOLS_MAX_FRAMES);
+    let mut truncated = false;
+    let marker = 0u8;
+    let result = trace_thread_from(thread, &marker, &mut |frame| {
+        if frames.len() == SKIP_SYMBOLS_MAX_FRAMES {
+            truncated = true;
+            return false;
+        }
+        frames.push(frame.clone());
+        true
+    });
+    let result = match result {
+        Ok(()) if truncated => Err(TraceError::Truncated),
+        result => result,
+    };
+
+    #[cfg(feature = "std")]
+    let _guard = crate::lock::lock();
+    for frame in &frames {
+        let mut skip = false;
+        super::symbolize::resolve_frame_unsynchronized(frame, |symbol| {
+            if let Some(name) = symbol.name() {
+                skip = skip || names.iter().any(|n| name.matches(n));
+            }
+        });
+        if !skip && !cb(frame) {
+            break;
+        }
+    }
+    result
+}
+
+/// Same as `trace_thread_unsynchronized`, except that the first `skip` frames
+/// are discarded before `cb` is called.
+///
+/// This is meant for wrappers around capturing which want to hide their own
+/// frames. If the stack has `skip` frames or fewer then `cb` is never called.
+///
+/// # Errors
+///
+/// See `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized`.
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn capture_thread_skipping<F: FnMut(&Frame) -> bool>(
+    thread: ThreadHandle,
+    skip: usize,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let mut remaining = skip;
+    let mut skipping = |frame: &Frame| {
+        if remaining > 0 {
+            remaining -= 1;
+            return true;
+        }
+        cb(frame)
+    };
+    let marker = 0u8;
+    trace_thread_from(thread, &marker, &mut skipping)
+}
+
+/// Same as `trace_thread_unsynchronized`, except that `cb` is also passed the
+/// index of each frame, starting at zero with the most recent one.
+///
+/// This saves keeping a counter in `cb` for limiting the number of frames or
+/// labeling them in output. Frames of this crate which are left out when the
+/// calling thread is traced aren't counted.
+///
+/// # Errors
+///
+/// See `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized`.
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_thread_indexed<F: FnMut(usize, &Frame) -> bool>(
+    thread: ThreadHandle,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let mut index = 0;
+    let mut indexed = |frame: &Frame| {
+        index += 1;
+        cb(index - 1, frame)
+    };
+    let marker = 0u8;
+    trace_thread_from(thread, &marker, &mut indexed)
+}
+
+/// Same as `trace_thread_unsynchronized`, except that the walk is stopped once
+/// the stack pointer has moved more than `max_stack_bytes` bytes away from the
+/// one of the first frame.
+///
+/// This bounds the part of the stack the walk touches, and with it the time it
+/// takes, regardless of how many frames there are, which protects against
+/// pathological stacks such as those of runaway recursion while still
+/// capturing their interesting top. Frames without a known stack pointer
+/// aren't checked.
+///
+/// # Errors
+///
+/// Returns `TraceError::Truncated` after the frames within the bound have been
+/// passed to `cb` if the walk was stopped because of it. Otherwise see
+/// `trace_thread_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_thread_unsynchronized`.
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_thread_bounded<F: FnMut(&Frame) -> bool>(
+    thread: ThreadHandle,
+    max_stack_bytes: usize,
+    mut cb: F,
+) -> Result<(), TraceError> {
+    let mut start = None;
+    let mut exceeded = false;
+    let mut bounded = |frame: &Frame| {
+        let sp = frame.sp() as usize;
+        if sp != 0 {
+            // The stack grows down, so the callers of a frame are above it.
+            let start = *start.get_or_insert(sp);
+            if sp.saturating_sub(start) > max_stack_bytes {
+                exceeded = true;
+                return false;
+            }
+        }
+        cb(frame)
+    };
+    let marker = 0u8;
+    let result = trace_thread_from(thread, &marker, &mut bounded);
+    if exceeded {
+        return Err(TraceError::Truncated);
+    }
+    result
+}
+
+/// Traces the stacks of all threads of the current process.
+///
+/// The threads are enumerated with `CreateToolhelp32Snapshot` and each one is
+/// suspended and traced in turn, like with `trace_thread_unsynchronized`.
+/// Every frame is passed to `cb` along with information about the thread it
+/// belongs to, and the frames of one thread are all yielded before those of
+/// the next one. Returning `false` from `cb` skips the remaining frames of the
+/// current thread and moves on to the next thread.
+///
+/// The calling thread is included as well, in which case
+/// `ThreadInfo::is_current` is `true` and its innermost frames are those of
+/// this function. Threads which exit before they're traced, or which can't be
+/// opened or suspended, are skipped.
+///
+/// # Errors
+///
+/// Returns `TraceError::EnumerationFailed` if the threads of the process
+/// couldn't be enumerated.
+///
+/// # Safety
+///
+/// Each thread is suspended while `cb` is called with its frames, so `cb` must
+/// not allocate or take any locks another thread might hold. See
+/// `capture_thread_in` for more details.
+pub unsafe fn trace_all_threads_unsynchronized<F: FnMut(&ThreadInfo, &Frame) -> bool>(
+    cb: F,
+) -> Result<(), TraceError> {
+    trace_all_threads_with_options_unsynchronized(&ThreadSnapshotOptions::new(), cb)
+}
+
+/// Same as `trace_all_threads_unsynchronized`, but with `options` controlling
+/// which additional information about each thread is collected.
+///
+/// # Errors
+///
+/// See `trace_all_threads_unsynchronized`.
+///
+/// # Safety
+///
+/// See `trace_all_threads_unsynchronized`.
+pub unsafe fn trace_all_threads_with_options_unsynchronized<F>(
+    options: &ThreadSnapshotOptions,
+    mut cb: F,
+) -> Result<(), TraceError>
+where
+    F: FnMut(&ThreadInfo, &Frame) -> bool,
+{
+    trace_all_threads_imp(options, &mut cb)
+}
+
+/// Lists the threads of the current process without tracing them.
+///
+/// The information about each thread is collected as directed by `options`,
+/// just like for `trace_all_threads_with_options_unsynchronized`, but no
+/// thread is suspended. With `ThreadSnapshotOptions::cpu_times` enabled this
+/// is a cheap way to find the threads worth tracing, see
+/// `HotThreadSampler`.
+///
+/// # Errors
+///
+/// Returns `TraceError::EnumerationFailed` if the threads of the process
+/// couldn't be enumerated.
+#[cfg(target_os = "windows")]
+pub fn list_threads(options: &ThreadSnapshotOptions) -> Result<Vec<ThreadInfo>, TraceError> {
+    unsafe { list_threads_imp(options) }
+}
+
+/// Calls `f` with a handle to the thread `id`, opened with the access needed
+/// to trace it and closed afterwards. Returns `None` if the thread couldn't be
+/// opened, for example because it exited.
+#[cfg(target_os = "windows")]
+pub(crate) fn with_thread<R>(id: u32, f: impl FnOnce(ThreadHandle) -> R) -> Option<R> {
+    let mut f = Some(f);
+    let mut result = None;
+    unsafe {
+        with_thread_imp(id, &mut |raw| {
+            if let Some(f) = f.take() {
+                result = Some(f(ThreadHandle(raw)));
+            }
+        });
+    }
+    result
+}
+
+/// Takes a snapshot of the registers and stacks of all threads of the current
+/// process, to be walked later with `AllThreadsSnapshot::trace`.
+///
+/// Unlike `trace_all_threads_unsynchronized`, which keeps each thread
+/// suspended while it's walked, this suspends all threads at once, copies
+/// their registers and the innermost `ThreadSnapshotOptions::stack_copy_size`
+/// bytes of their stacks, and resumes them straight away. The expensive walk
+/// then runs over the copies while the application keeps running, which
+/// makes the pause much shorter and gives a consistent view of all threads
+/// at a single point in time. `ThreadSnapshotOptions::batch_size` trades that
+/// consistency for shorter pauses in processes with many threads.
+///
+/// All memory needed for the copies is allocated before any thread is
+/// suspended. Threads which can't be opened, suspended or whose registers
+/// can't be read are left out of the snapshot, see
+/// `AllThreadsSnapshot::unstable_threads`, unless they're captured by one of
+/// the attempts allowed with `ThreadSnapshotOptions::retries`. The calling
+/// thread is included, its registers being those of this function.
+///
+/// # Errors
+///
+/// Returns `TraceError::EnumerationFailed` if the threads of the process
+/// couldn't be enumerated.
+///
+/// # Safety
+///
+/// Threads are suspended while their stacks are copied, so the usual caveats
+/// of suspending threads apply, see `capture_thread_in`. No allocations or
+/// locks are taken by this function during that time.
+pub unsafe fn snapshot_all_threads(
+    options: &ThreadSnapshotOptions,
+) -> Result<AllThreadsSnapshot, TraceError> {
+    snapshot_all_threads_imp(options)
+}
+
+/// The registers and stacks of the threads of the process at one point in
+/// time, taken by `snapshot_all_threads`.
+pub struct AllThreadsSnapshot {
+    threads: Vec<CapturedThread>,
+    timestamp: Option<(u64, u64)>,
+    correlation_id: Option<u64>,
+    retries: u32,
+    unstable: Vec<u32>,
+}
+
+impl AllThreadsSnapshot {
+    /// Returns the number of threads in this snapshot.
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Returns whether this snapshot contains no threads.
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    /// Returns the value of the performance counter when the threads started
+    /// being suspended, if requested with `ThreadSnapshotOptions::timestamp`.
+    ///
+    /// This is the value `QueryPerformanceCounter` returned, which counts
+    /// `timestamp_frequency` ticks per second and is what other telemetry on
+    /// Windows is typically timestamped with.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp.map(|t| t.0)
+    }
+
+    /// Returns the number of ticks per second of `timestamp`, as returned by
+    /// `QueryPerformanceFrequency`.
+    pub fn timestamp_frequency(&self) -> Option<u64> {
+        self.timestamp.map(|t| t.1)
+    }
+
+    /// Returns the id set with `ThreadSnapshotOptions::correlation_id`.
+    pub fn correlation_id(&self) -> Option<u64> {
+        self.correlation_id
+    }
+
+    /// Returns how many times the threads of the process were enumerated
+    /// again to capture threads which were missed, at most
+    /// `ThreadSnapshotOptions::retries`.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Returns the ids of the threads which were enumerated but couldn't be
+    /// captured, and were still listed by the last enumeration.
+    ///
+    /// Without retries this includes threads which merely exited before they
+    /// could be suspended. With retries only threads which kept failing are
+    /// left, for example ones which were starting or exiting all along.
+    pub fn unstable_threads(&self) -> &[u32] {
+        &self.unstable
+    }
+
+    /// Walks the stacks of the threads in this snapshot, yielding each frame
+    /// to `cb` along with information about the thread it belongs to.
+    ///
+    /// Frames are yielded in the same way as by
+    /// `trace_all_threads_unsynchronized`, and returning `false` from `cb`
+    /// moves on to the next thread. The stacks are walked over the copies
+    /// taken by `snapshot_all_threads`, so nothing is suspended and `cb` is
+    /// free to allocate. The walk of a thread ends where its copied stack
+    /// ends, and a snapshot can be walked any number of times.
+    ///
+    /// Unwind information is looked up in the modules currently loaded, so
+    /// frames of modules which were unloaded since the snapshot was taken may
+    /// end the walk early.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TraceError::DbghelpUnavailable` if dbghelp couldn't be loaded.
+    pub fn trace<F: FnMut(&ThreadInfo, &Frame) -> bool>(
+        &self,
+        mut cb: F,
+    ) -> Result<(), TraceError> {
+        for thread in &self.threads {
+            let info = thread.info();
+            let result = unsafe { trace_captured_imp(thread, &mut |frame| cb(info, frame)) };
+            if let Err(TraceError::DbghelpUnavailable(_)) = result {
+                return result;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes this snapshot to `w` as a minidump, which can be opened in
+    /// WinDbg or Visual Studio to inspect the threads.
+    ///
+    /// The dump lists the threads with their registers and the copies of
+    /// their stacks, the modules loaded in the process along with what's
+    /// needed to find their symbols, and information about the system. No
+    /// other memory is included, so this is much smaller than a full dump
+    /// but only stacks can be inspected. The modules are those loaded when
+    /// the dump is written rather than when the snapshot was taken.
+    ///
+    /// The dump is assembled in memory and then written to `w` at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns any error writing to `w`, or an error if the dump would exceed
+    /// the 4GiB a minidump can address.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `minidump` feature of the `backtrace` crate
+    /// to be enabled, and the `minidump` feature is not enabled by default.
+    #[cfg(all(feature = "minidump", target_os = "windows"))]
+    pub fn write_minidump<W: std::io::Write>(&self, mut w: W) -> std::io::Result<()> {
+        minidump::write(&self.threads, &mut w)
+    }
+}
+
+/// Options for `trace_all_threads_with_options_unsynchronized` and
+/// `snapshot_all_threads`.
+///
+/// Everything optional is disabled by default to keep snapshots cheap.
+#[derive(Clone)]
+pub struct ThreadSnapshotOptions {
+    pub(crate) scheduling: bool,
+    pub(crate) cpu_times: bool,
+    pub(crate) stack_copy_size: usize,
+    pub(crate) batch_size: usize,
+    pub(crate) frame_pointer_fallback: bool,
+    pub(crate) exception_request: bool,
+    pub(crate) affinity: bool,
+    pub(crate) timestamp: bool,
+    pub(crate) correlation_id: Option<u64>,
+    pub(crate) retries: u32,
+    pub(crate) filter: Option<Arc<dyn Fn(u32) -> bool + Send + Sync>>,
+}
+
+impl fmt::Debug for ThreadSnapshotOptions {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("ThreadSnapshotOptions")
+            .field("scheduling", &self.scheduling)
+            .field("cpu_times", &self.cpu_times)
+            .field("stack_copy_size", &self.stack_copy_size)
+            .field("batch_size", &self.batch_size)
+            .field("frame_pointer_fallback", &self.frame_pointer_fallback)
+            .field("exception_request", &self.exception_request)
+            .field("affinity", &self.affinity)
+            .field("timestamp", &self.timestamp)
+            .field("correlation_id", &self.correlation_id)
+            .field("retries", &self.retries)
+            .field("filter", &self.filter.is_some())
+            .finish()
+    }
+}
+
+impl Default for ThreadSnapshotOptions {
+    fn default() -> ThreadSnapshotOptions {
+        ThreadSnapshotOptions {
+            scheduling: false,
+            cpu_times: false,
+            stack_copy_size: 64 * 1024,
+            batch_size: 0,
+            frame_pointer_fallback: false,
+            exception_request: false,
+            affinity: false,
+            timestamp: false,
+            correlation_id: None,
+            retries: 0,
+            filter: None,
+        }
+    }
+}
+
+impl ThreadSnapshotOptions {
+    /// Creates a new set of options with everything optional disabled.
+    pub fn new() -> ThreadSnapshotOptions {
+        ThreadSnapshotOptions::default()
+    }
+
+    /// Sets the number of bytes copied from the top of each thread's stack by
+    /// `snapshot_all_threads`, 64 KiB by default.
+    ///
+    /// Frames beyond this many bytes from the stack pointer are missing from
+    /// the walk of the snapshot. Larger values capture deeper stacks at the
+    /// cost of memory and a slightly longer pause. This has no effect on
+    /// `trace_all_threads_with_options_unsynchronized`, which walks the live
+    /// stacks.
+    pub fn stack_copy_size(mut self, bytes: usize) -> ThreadSnapshotOptions {
+        self.stack_copy_size = bytes;
+        self
+    }
+
+    /// Sets how many threads `snapshot_all_threads` suspends at once, 0 by
+    /// default to suspend all threads together.
+    ///
+    /// With a batch size the threads are captured in batches: the threads of
+    /// a batch are suspended, their registers and stacks copied, and they're
+    /// resumed before the next batch is suspended. This bounds how long each
+    /// thread is paused by the number of threads in its batch rather than in
+    /// the process, which matters for processes with hundreds of threads. The
+    /// price is that the snapshot is only consistent within a batch, threads
+    /// of different batches are captured at slightly different times. This
+    /// has no effect on `trace_all_threads_with_options_unsynchronized`,
+    /// which suspends one thread at a time anyway.
+    pub fn batch_size(mut self, threads: usize) -> ThreadSnapshotOptions {
+        self.batch_size = threads;
+        self
+    }
+
+    /// Sets whether the priority, scheduling state and wait reason of each
+    /// thread are recorded in its `ThreadInfo`.
+    ///
+    /// This costs a `GetThreadPriority` call per thread and one query of the
+    /// state of all threads before the snapshot starts. The state is read
+    /// just before the threads are suspended, so it describes what each
+    /// thread was doing when the snapshot was requested.
+    pub fn scheduling(mut self, enabled: bool) -> ThreadSnapshotOptions {
+        self.scheduling = enabled;
+        self
+    }
+
+    /// Sets whether the CPU time consumed by each thread is recorded in its
+    /// `ThreadInfo`, see `ThreadInfo::kernel_time` and `ThreadInfo::user_time`.
+    ///
+    /// This costs a `GetThreadTimes` call per thread. `snapshot_all_threads`
+    /// reads the times while the threads are suspended, so they match the
+    /// captured stacks exactly, `trace_all_threads_with_options_unsynchronized`
+    /// reads them just before each thread is suspended.
+    pub fn cpu_times(mut self, enabled: bool) -> ThreadSnapshotOptions {
+        self.cpu_times = enabled;
+        self
+    }
+
+    /// Sets whether the registers of each thread are read with
+    /// `CONTEXT_EXCEPTION_REQUEST`, which reports whether the thread was in a
+    /// system call or dispatching an exception, see
+    /// `ThreadInfo::in_system_call` and `ThreadInfo::in_exception`.
+    ///
+    /// Threads blocked in the kernel, for example in `NtWaitForSingleObject`,
+    /// are suspended on their way back to user mode, and their innermost frame
+    /// is the instruction after the system call in `ntdll.dll`. Knowing that
+    /// tells such threads apart from threads running that code, and shows
+    /// that the innermost frame is the system call stub rather than a
+    /// function the thread was executing. This costs nothing extra, but older
+    /// versions of Windows don't report the state.
+    pub fn exception_request(mut self, enabled: bool) -> ThreadSnapshotOptions {
+        self.exception_request = enabled;
+        self
+    }
+
+    /// Sets whether the ideal processor and the processor affinity of each
+    /// thread are recorded in its `ThreadInfo`, see
+    /// `ThreadInfo::ideal_processor` and `ThreadInfo::affinity`.
+    ///
+    /// This costs a `GetThreadIdealProcessorEx` and a
+    /// `GetThreadGroupAffinity` call per thread, made while the thread is
+    /// opened and before it's suspended.
+    pub fn affinity(mut self, enabled: bool) -> ThreadSnapshotOptions {
+        self.affinity = enabled;
+        self
+    }
+
+    /// Sets whether walks of a snapshot continue along the chain of frame
+    /// pointers once the unwinder gives up, disabled by default.
+    ///
+    /// The unwinder stops at code without unwind information, such as JIT
+    /// compiled or stripped code. On ARM64 such code still links its frames
+    /// through the frame pointer, with the caller's frame pointer and return
+    /// address saved at `[fp]` and `[fp + 8]`, so the rest of the stack can
+    /// often be recovered from the copy taken by `snapshot_all_threads`. The
+    /// chain is only followed while it stays within the copied stack and moves
+    /// towards its base, so a corrupt chain ends the walk rather than reading
+    /// arbitrary memory. Frames found this way may be wrong if some function
+    /// on the stack doesn't keep a frame pointer.
+    ///
+    /// This currently only has an effect on ARM64.
+    pub fn frame_pointer_fallback(mut self, enabled: bool) -> ThreadSnapshotOptions {
+        self.frame_pointer_fallback = enabled;
+        self
+    }
+
+    /// Sets whether `snapshot_all_threads` records the value of the
+    /// performance counter when it starts suspending threads, see
+    /// `AllThreadsSnapshot::timestamp`.
+    ///
+    /// This lets tools writing a time series of snapshots line them up with
+    /// other telemetry. The counter is read after everything has been
+    /// allocated, just before the first thread is suspended.
+    pub fn timestamp(mut self, enabled: bool) -> ThreadSnapshotOptions {
+        self.timestamp = enabled;
+        self
+    }
+
+    /// Sets an id which `snapshot_all_threads` attaches to the snapshot as it
+    /// is, see `AllThreadsSnapshot::correlation_id`.
+    ///
+    /// This is meant for identifiers such as the id of a request or a sample
+    /// which tie the snapshot to data collected elsewhere.
+    pub fn correlation_id(mut self, id: u64) -> ThreadSnapshotOptions {
+        self.correlation_id = Some(id);
+        self
+    }
+
+    /// Sets how many times `snapshot_all_threads` enumerates the threads of
+    /// the process again when some of them couldn't be captured, 0 by
+    /// default.
+    ///
+    /// Threads which exit or start while a snapshot is taken can fail to be
+    /// opened or suspended, which leaves them out of it. Each retry lists the
+    /// threads again and captures those which are still there but weren't
+    /// captured yet, including any which started in the meantime, until all
+    /// threads were captured or the retries are used up. This makes
+    /// snapshots of processes with many short-lived threads more complete, at
+    /// the cost of the retried threads being captured slightly later than the
+    /// others. See `AllThreadsSnapshot::retries` and
+    /// `AllThreadsSnapshot::unstable_threads` for how it went.
+    pub fn retries(mut self, retries: u32) -> ThreadSnapshotOptions {
+        self.retries = retries;
+        self
+    }
+
+    /// Restricts the threads which are traced or snapshotted to those whose
+    /// id `filter` returns `true` for, all threads are included by default.
+    ///
+    /// The filter is called once for each thread of the process while they
+    /// are enumerated, before any of them is opened or suspended, so threads
+    /// which are left out are never stopped. This shortens the pause of the
+    /// application when only some threads are of interest. No thread is
+    /// suspended while the filter runs, so it's free to allocate and take
+    /// locks, for example to look up the name of the thread.
+    pub fn filter<F>(mut self, filter: F) -> ThreadSnapshotOptions
+    where
+        F: Fn(u32) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Returns whether the thread `id` passes the filter set with `filter`.
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    pub(crate) fn includes(&self, id: u32) -> bool {
+        self.filter.as_ref().map_or(true, |filter| filter(id))
+    }
+}
+
+/// Information about a thread traced by `trace_all_threads_unsynchronized`.
+#[derive(Clone, Debug)]
+pub struct ThreadInfo {
+    pub(crate) id: u32,
+    pub(crate) is_current: bool,
+    pub(crate) priority: Option<i32>,
+    pub(crate) state: Option<u32>,
+    pub(crate) wait_reason: Option<u32>,
+    pub(crate) kernel_time: Option<Duration>,
+    pub(crate) user_time: Option<Duration>,
+    pub(crate) stack_bounds: Option<ThreadStackBounds>,
+    pub(crate) teb_address: Option<usize>,
+    pub(crate) in_system_call: Option<bool>,
+    pub(crate) in_exception: Option<bool>,
+    pub(crate) ideal_processor: Option<(u16, u8)>,
+    pub(crate) affinity: Option<(u16, usize)>,
+}
+
+impl ThreadInfo {
+    /// Returns the id of the thread.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns whether this is the thread which is doing the tracing.
+    ///
+    /// The innermost frames of this thread belong to this crate and the code
+    /// which requested the trace, so they typically need to be skipped.
+    pub fn is_current(&self) -> bool {
+        self.is_current
+    }
+
+    /// Returns the priority of the thread as returned by `GetThreadPriority`,
+    /// for example `THREAD_PRIORITY_NORMAL` (0).
+    ///
+    /// This is only available if `ThreadSnapshotOptions::scheduling` is
+    /// enabled.
+    pub fn priority(&self) -> Option<i32> {
+        self.priority
+    }
+
+    /// Returns the scheduling state of the thread, a `KTHREAD_STATE` value
+    /// such as `Running` (2) or `Waiting` (5).
+    ///
+    /// This is only available if `ThreadSnapshotOptions::scheduling` is
+    /// enabled, and relies on the undocumented `NtQuerySystemInformation` so
+    /// may be missing on some systems.
+    pub fn state(&self) -> Option<u32> {
+        self.state
+    }
+
+    /// Returns why the thread is waiting, a `KWAIT_REASON` value such as
+    /// `UserRequest` (6) or `WrQueue` (15).
+    ///
+    /// This is only available if the thread was waiting, see `state`.
+    pub fn wait_reason(&self) -> Option<u32> {
+        self.wait_reason
+    }
+
+    /// Returns the CPU time the thread has spent in kernel mode since it
+    /// started.
+    ///
+    /// This is only available if `ThreadSnapshotOptions::cpu_times` is
+    /// enabled. The difference between two samples of the same thread is the
+    /// CPU time it consumed in between, which can be used to weight its stacks
+    /// by CPU usage rather than wall clock time. Windows accounts CPU time at
+    /// the granularity of the scheduler tick, typically 15.6ms.
+    pub fn kernel_time(&self) -> Option<Duration> {
+        self.kernel_time
+    }
+
+    /// Returns the CPU time the thread has spent in user mode since it
+    /// started, see `kernel_time`.
+    pub fn user_time(&self) -> Option<Duration> {
+        self.user_time
+    }
+
+    /// Returns the bounds of the stack of the thread, read when it was opened
+    /// for tracing, see `ThreadHandle::stack_bounds`.
+    ///
+    /// Together with the stack pointer of its innermost frame this tells how
+    /// much stack space the thread had left when it was traced.
+    pub fn stack_bounds(&self) -> Option<ThreadStackBounds> {
+        self.stack_bounds
+    }
+
+    /// Returns the address of the TEB of the thread, read when it was opened
+    /// for tracing, see `ThreadHandle::teb_address`.
+    pub fn teb_address(&self) -> Option<*mut c_void> {
+        self.teb_address.map(|teb| teb as *mut c_void)
+    }
+
+    /// Returns whether the thread was executing a system call when it was
+    /// suspended, in which case its innermost frame is the system call stub.
+    ///
+    /// This is only available if `ThreadSnapshotOptions::exception_request`
+    /// is enabled and Windows reported the state. It's never available for
+    /// the calling thread, which isn't suspended.
+    pub fn in_system_call(&self) -> Option<bool> {
+        self.in_system_call
+    }
+
+    /// Returns whether the thread was dispatching an exception when it was
+    /// suspended, see `in_system_call`.
+    pub fn in_exception(&self) -> Option<bool> {
+        self.in_exception
+    }
+
+    /// Returns the processor the scheduler prefers to run the thread on, as
+    /// its processor group and its number within that group.
+    ///
+    /// This is only available if `ThreadSnapshotOptions::affinity` is
+    /// enabled.
+    pub fn ideal_processor(&self) -> Option<(u16, u8)> {
+        self.ideal_processor
+    }
+
+    /// Returns the processor group the thread runs in along with the mask of
+    /// the processors within that group it may run on.
+    ///
+    /// This is only available if `ThreadSnapshotOptions::affinity` is
+    /// enabled. Comparing it with `ideal_processor` and the processors of the
+    /// NUMA nodes shows threads pinned to or kept away from the memory they
+    /// work on.
+    pub fn affinity(&self) -> Option<(u16, usize)> {
+        self.affinity
+    }
+}
+
+/// Errors that can occur while tracing another thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceError {
+    /// `dbghelp.dll` couldn't be loaded or initialized, for the contained
+    /// reason.
+    DbghelpUnavailable(DbghelpError),
+    /// Access to the thread was denied (`ERROR_ACCESS_DENIED`).
+    ///
+    /// The thread handle needs `THREAD_SUSPEND_RESUME` and
+    /// `THREAD_GET_CONTEXT` access, and threads of other processes may also
+    /// require `SeDebugPrivilege`.
+    AccessDenied,
+    /// `SuspendThread` failed with the contained Windows error code, or
+    /// `thread_suspend` failed with the contained `kern_return_t` on macOS.
+    SuspendFailed(u32),
+    /// `GetThreadContext` failed with the contained Windows error code, or
+    /// `thread_get_state` failed with the contained `kern_return_t` on macOS.
+    GetContextFailed(u32),
+    /// `CreateToolhelp32Snapshot` failed with the contained Windows error
+    /// code, so the threads of the process couldn't be enumerated.
+    EnumerationFailed(u32),
+    /// The walk was stopped early because the stack appears to be corrupt,
+    /// because it reached the bound given to `trace_thread_bounded`, or
+    /// because the buffer of `trace_thread_skip_symbols` was full.
+    ///
+    /// This is reported after the frames walked so far have been yielded, so
+    /// those are still usable. Note that dbghelp doesn't distinguish between
+    /// failing to unwind a frame and reaching the end of the stack, so a walk
+    /// which stops at a frame it couldn't unwind isn't reported.
+    Truncated,
+    /// The unwinder couldn't produce a single frame from the registers of the
+    /// thread.
+    ///
+    /// Any running thread has at least the frame of the code it's executing,
+    /// so this tells a walk which failed from the start apart from one which
+    /// legitimately ended, and means that an empty list of frames isn't the
+    /// actual stack of the thread. It's returned for example for registers
+    /// dbghelp can't make sense of, or for a machine type it doesn't accept.
+    NoFrames,
+    /// The registers of the suspended thread don't describe a stack which can
+    /// be walked, so no frames were produced.
+    ///
+    /// This typically happens when sampling a thread which has just been
+    /// created and hasn't started running yet, whose instruction or stack
+    /// pointer is zero or whose stack pointer is outside of its stack. Threads
+    /// created suspended are walked as a single frame at their start routine
+    /// instead, see `trace_thread_unsynchronized`.
+    InvalidContext,
+    /// The handle passed to `ThreadHandle::new` isn't a valid thread handle.
+    ///
+    /// This contains the Windows error code returned by `GetHandleInformation`,
+    /// or `KERN_INVALID_ARGUMENT` on macOS.
+    InvalidHandle(u32),
+    /// The thread didn't reach a safe point within the timeout given to
+    /// `trace_thread_cooperative`.
+    TimedOut,
+    /// `CreateEventW` failed with the contained Windows error code, so the
+    /// thread couldn't be asked to park by `trace_thread_cooperative`.
+    RendezvousFailed(u32),
+    /// The operation isn't supported on this platform.
+    ///
+    /// Tracing other threads is only supported on Windows, and on macOS for
+    /// individual threads, elsewhere the cross-thread functions always fail
+    /// with this error.
+    Unsupported,
+}
+
+/// Why `dbghelp.dll`, which is used to walk stacks on Windows, couldn't be
+/// used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DbghelpError {
+    /// The mutex serializing all users of dbghelp in the process couldn't be
+    /// created, `CreateMutexA` failed with the contained Windows error code.
+    LockFailed(u32),
+    /// `dbghelp.dll` couldn't be loaded, `LoadLibraryA` failed with the
+    /// contained Windows error code.
+    ///
+    /// This is typically the case in minimal environments such as WinPE or
+    /// Nano Server, which don't ship dbghelp. Placing a copy of `dbghelp.dll`
+    /// next to the executable makes it available.
+    NotFound(u32),
+    /// The loaded `dbghelp.dll` lacks the named function, which this crate
+    /// requires. This happens with very old versions of dbghelp.
+    MissingFunction(&'static str),
+}
+
+/// Describes the error, including the contained error code if there is one.
+/// On Windows codes are displayed in decimal, as listed in the system error
+/// code documentation.
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TraceError::DbghelpUnavailable(ref err) => write!(f, "dbghelp is unavailable: {}", err),
+            TraceError::AccessDenied => f.write_str("access to the thread was denied"),
+            TraceError::SuspendFailed(code) => {
+                write!(f, "failed to suspend the thread (error {})", code)
+            }
+            TraceError::GetContextFailed(code) => {
+                write!(
+                    f,
+                    "failed to read the registers of the thread (error {})",
+                    code
+                )
+            }
+            TraceError::EnumerationFailed(code) => {
+                write!(
+                    f,
+                    "failed to enumerate the threads of the process (error {})",
+                    code
+                )
+            }
+            TraceError::Truncated => f.write_str("the trace was truncated"),
+            TraceError::NoFrames => f.write_str("no frames could be walked"),
+            TraceError::InvalidContext => {
+                f.write_str("the registers of the thread don't describe a walkable stack")
+            }
+            TraceError::InvalidHandle(code) => write!(f, "invalid thread handle (error {})", code),
+            TraceError::TimedOut => f.write_str("the thread didn't reach a safe point in time"),
+            TraceError::RendezvousFailed(code) => {
+                write!(f, "failed to create the rendezvous events (error {})", code)
+            }
+            TraceError::Unsupported => {
+                f.write_str("tracing threads isn't supported on this platform")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TraceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            TraceError::DbghelpUnavailable(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DbghelpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DbghelpError::LockFailed(code) => {
+                write!(f, "failed to create the dbghelp mutex (error {})", code)
+            }
+            DbghelpError::NotFound(code) => {
+                write!(f, "failed to load dbghelp.dll (error {})", code)
+            }
+            DbghelpError::MissingFunction(name) => {
+                write!(f, "dbghelp.dll lacks the `{}` function", name)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DbghelpError {}
+
+/// Attempts to enable `SeDebugPrivilege` for the current process.
+///
+/// This privilege grants access to threads regardless of their security
+/// descriptors, which may be needed to trace threads belonging to other
+/// processes or users. It's only available if the process is running with
+/// administrator rights.
+///
+/// Returns `true` if the privilege was successfully enabled.
+#[cfg(target_os = "windows")]
+pub fn enable_debug_privilege() -> bool {
+    enable_debug_privilege_imp()
+}
+
+/// Sets whether walking the stack may skip initializing the dbghelp symbol
+/// handler, deferring that until symbols are first resolved.
+///
+/// Initializing the symbol handler makes dbghelp enumerate all modules of the
+/// process, which is a large part of the cost of the first capture. Walking
+/// the stack doesn't need it on 64-bit Windows, so this makes capture-only
+/// workloads, such as `Backtrace::new_unresolved` or sampling profilers which
+/// resolve their samples elsewhere, start faster. On 32-bit Windows, or after
+/// `register_function_entry_callback` was used, walks still rely on the symbol
+/// handler and this has no effect.
+///
+/// Note that without the symbol handler `StackWalkEx` can't expand inlined
+/// calls into frames of their own, so with this enabled inlined calls are
+/// instead reported as additional symbols of the frame they were inlined into
+/// when it's resolved, as on other platforms, and `Frame::is_inline` is
+/// always `false`.
+///
+/// This is disabled by default.
+#[cfg(target_os = "windows")]
+pub fn set_deferred_symbol_init(enabled: bool) {
+    set_deferred_symbol_init_imp(enabled)
+}
+
+/// The `dbghelp.dll` this crate loaded, passed to the closure given to
+/// `with_dbghelp`.
+#[cfg(target_os = "windows")]
+#[derive(Debug)]
+pub struct DbghelpApi {
+    pub(crate) module: *mut c_void,
+}
+
+#[cfg(target_os = "windows")]
+impl DbghelpApi {
+    /// Returns the module handle of the loaded `dbghelp.dll`.
+    pub fn module(&self) -> *mut c_void {
+        self.module
+    }
+
+    /// Looks up the function `name` of `dbghelp.dll` with `GetProcAddress`,
+    /// returning `None` if this version of dbghelp doesn't export it.
+    ///
+    /// The returned pointer has to be transmuted to the `extern "system"`
+    /// function pointer type matching the signature of the function.
+    pub fn function(&self, name: &str) -> Option<*mut c_void> {
+        dbghelp_function_imp(self.module, name)
+    }
+}
+
+/// Runs `f` with the symbol handler session this crate uses, for making
+/// dbghelp calls this crate doesn't wrap, such as `SymEnumSymbolsW` or
+/// `SymSearchW`.
+///
+/// dbghelp allows a single symbol handler session per process, and calling
+/// `SymInitializeW` for the current process again fails or, worse, resets the
+/// session this crate relies on. This hands out the session of this crate
+/// instead, initializing it first if needed: `f` is passed the process handle
+/// it was initialized for, which is the pseudo handle returned by
+/// `GetCurrentProcess`, and the loaded `dbghelp.dll` to look up functions in.
+///
+/// Returns an error if dbghelp couldn't be loaded or initialized, in which
+/// case `f` isn't called.
+///
+/// # Thread safety
+///
+/// dbghelp isn't thread safe. While `f` runs it holds the lock this crate and
+/// the standard library take around all dbghelp calls, so other threads
+/// capturing or resolving backtraces block until it returns. `f` may call
+/// the functions of this crate, which take the same locks reentrantly, but
+/// must not make dbghelp calls from other threads or after it returns, nor
+/// keep the handle or looked up functions around for that. `f` must not call
+/// `SymInitializeW` or `SymCleanup`, and should restore any options it
+/// changes with `SymSetOptions`, as they apply to this crate as well.
+#[cfg(target_os = "windows")]
+pub fn with_dbghelp<R, F: FnOnce(*mut c_void, &DbghelpApi) -> R>(f: F) -> Result<R, DbghelpError> {
+    // The crate lock is always taken before the dbghelp lock elsewhere, so it
+    // has to be here as well for `f` to be able to call into this crate.
+    #[cfg(feature = "std")]
+    let _guard = crate::lock::lock();
+    let mut f = Some(f);
+    let mut ret = None;
+    with_dbghelp_imp(&mut |process, api| ret = f.take().map(|f| f(process, api)))?;
+    Ok(ret.expect("the closure is called on success"))
+}
+
+/// A callback returning the function table entry for an address, see
+/// `register_function_entry_callback`.
+///
+/// The arguments are the process handle, the address being unwound and the
+/// user context passed at registration. It must return a pointer to the
+/// `RUNTIME_FUNCTION` (or `FPO_DATA` on 32-bit x86) covering the address, or
+/// null if it doesn't know about it.
+#[cfg(target_os = "windows")]
+pub type FunctionEntryCallback =
+    unsafe extern "system" fn(process: *mut c_void, addr: u64, user_context: u64) -> *mut c_void;
+
+/// Registers a callback through which dbghelp can find unwind information for
+/// code it otherwise has no function table for, such as JIT-compiled code.
+///
+/// This wraps `SymRegisterFunctionEntryCallback64` for the symbol handler
+/// session of the current process. On 64-bit targets unwind information is
+/// normally found with `RtlLookupFunctionEntry`, which already knows about
+/// tables published with `RtlAddFunctionTable` or
+/// `RtlInstallFunctionTableCallback`, and the callback is only consulted for
+/// addresses that lookup fails for. This is mostly useful for runtimes that
+/// keep their own tables instead of publishing them to the OS.
+///
+/// Returns `false` if dbghelp couldn't be loaded or the registration failed.
+///
+/// # Limitations
+///
+/// * Only the threads of the current process can be traced by this crate, so
+///   the returned pointers must point into the current process' memory. Walks
+///   of other processes, where the callback would have to read the target's
+///   tables with `ReadProcessMemory`, aren't supported.
+/// * dbghelp allows a single callback per process, so this replaces any
+///   callback registered earlier, including by other code sharing the
+///   session.
+/// * The callback is invoked while the traced thread is suspended, so it must
+///   not allocate or take locks that thread might hold.
+///
+/// # Safety
+///
+/// `callback` will be invoked during every subsequent trace and must uphold
+/// the contract described on `FunctionEntryCallback`.
+#[cfg(target_os = "windows")]
+pub unsafe fn register_function_entry_callback(
+    callback: FunctionEntryCallback,
+    user_context: u64,
+) -> bool {
+    register_function_entry_callback_imp(callback, user_context)
+}
+
+/// Counters describing the activity of the cross-thread tracing functions.
+///
+/// These are collected process-wide and are intended for long-running users
+/// of `trace_thread_unsynchronized`, such as sampling profilers, which want to
+/// monitor their own health. Use `trace_stats` to read the current values.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TraceStats {
+    pub(crate) traces: usize,
+    pub(crate) suspensions: usize,
+    pub(crate) suspend_failures: usize,
+    pub(crate) context_failures: usize,
+    pub(crate) frames: usize,
+    pub(crate) single_processor_suspensions: usize,
+}
+
+#[cfg(target_os = "windows")]
+impl TraceStats {
+    /// Returns the number of traces that were attempted.
+    pub fn traces(&self) -> usize {
+        self.traces
+    }
+
+    /// Returns the number of times another thread was successfully suspended.
+    pub fn suspensions(&self) -> usize {
+        self.suspensions
+    }
+
+    /// Returns the number of times suspending another thread failed.
+    pub fn suspend_failures(&self) -> usize {
+        self.suspend_failures
+    }
+
+    /// Returns the number of times the context of a suspended thread couldn't
+    /// be read.
+    pub fn context_failures(&self) -> usize {
+        self.context_failures
+    }
+
+    /// Returns the total number of frames yielded to trace callbacks.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Returns the number of times another thread was suspended while the
+    /// process could only run on a single processor.
+    ///
+    /// A nonzero value means traces ran with an elevated risk of deadlocks,
+    /// see `available_processors`.
+    pub fn single_processor_suspensions(&self) -> usize {
+        self.single_processor_suspensions
+    }
+}
+
+/// Returns the number of processors the current process may run on, or
+/// `None` if it couldn't be determined.
+///
+/// This is the number of processors in the affinity mask of the process, so
+/// it accounts for VMs or containers with a single CPU as well as for
+/// processes restricted to one processor. Only the processor group of the
+/// process is counted.
+///
+/// Suspending another thread is more prone to deadlocks when this is 1: the
+/// suspended thread can't run at all until it's resumed, so any lock it holds,
+/// such as the heap lock, is held throughout the trace, whereas with more
+/// processors it's likely the thread was interrupted outside of such a
+/// section. Callbacks which stick to the rules of
+/// `trace_thread_unsynchronized` are fine either way, but code getting by
+/// with taking locks while another thread is suspended will hang far more
+/// often. `TraceStats::single_processor_suspensions` counts the suspensions
+/// done under these conditions, and `set_yield_on_single_processor` can
+/// reduce the risk.
+#[cfg(target_os = "windows")]
+pub fn available_processors() -> Option<usize> {
+    available_processors_imp()
+}
+
+/// Sets whether the tracing thread yields the rest of its time slice before
+/// suspending another thread when the process can only run on a single
+/// processor, see `available_processors`.
+///
+/// This gives the thread about to be traced the chance to run and leave a
+/// short critical section before it's suspended, making hangs caused by locks
+/// it holds less likely. The yield only happens with a single processor, and
+/// costs one `SwitchToThread` call per suspension there. It doesn't make
+/// taking locks while a thread is suspended safe.
+///
+/// This is disabled by default.
+#[cfg(target_os = "windows")]
+pub fn set_yield_on_single_processor(enabled: bool) {
+    set_yield_on_single_processor_imp(enabled)
+}
+
+/// Returns a snapshot of the process-wide tracing counters.
+///
+/// The counters are updated with relaxed atomics, so values read while other
+/// threads are tracing may be slightly out of sync with one another.
+#[cfg(target_os = "windows")]
+pub fn trace_stats() -> TraceStats {
+    stats_imp()
+}
+
+/// Resets all of the counters returned by `trace_stats` to zero.
+#[cfg(target_os = "windows")]
+pub fn reset_trace_stats() {
+    reset_stats_imp()
+}
+
+/// A trait representing one frame of a backtrace, yielded to the `trace`
+/// function of this crate.
+///
+/// The tracing function's closure will be yielded frames, and the frame is
+/// virtually dispatched as the underlying implementation is not always known
+/// until runtime.
+#[derive(Clone)]
+pub struct Frame {
+    pub(crate) inner: FrameImp,
+}
+
+impl Frame {
+    /// Returns the current instruction pointer of this frame.
+    ///
+    /// This is normally the next instruction to execute in the frame, but not
+    /// all implementations list this with 100% accuracy (but it's generally
+    /// pretty close).
+    ///
+    /// It is recommended to pass this value to `backtrace::resolve` to turn it
+    /// into a symbol name.
+    pub fn ip(&self) -> *mut c_void {
+        self.inner.ip()
+    }
+
+    /// Returns the current stack pointer of this frame.
+    ///
+    /// In the case that a backend cannot recover the stack pointer for this
+    /// frame, a null pointer is returned.
+    pub fn sp(&self) -> *mut c_void {
+        self.inner.sp()
+    }
+
+    /// Returns the starting symbol address of the frame of this function.
+    ///
+    /// This will attempt to rewind the instruction pointer returned by `ip` to
+    /// the start of the function, returning that value. In some cases, however,
+    /// backends will just return `ip` from this function.
+    ///
+    /// The returned value can sometimes be used if `backtrace::resolve` failed
+    /// on the `ip` given above.
+    pub fn symbol_address(&self) -> *mut c_void {
+        self.inner.symbol_address()
+    }
+
+    /// Returns the base address of the module to which the frame belongs.
+    pub fn module_base_address(&self) -> Option<*mut c_void> {
+        self.inner.module_base_address()
+    }
+
+    /// Returns whether the instruction pointer of this frame lies within the
+    /// `size` bytes starting at `base`, for example the range a module is
+    /// loaded at.
+    ///
+    /// This allows keeping only the frames of a particular module without
+    /// resolving any symbols.
+    pub fn is_within(&self, base: *mut c_void, size: usize) -> bool {
+        let ip = self.ip() as usize;
+        let base = base as usize;
+        ip >= base && ip - base < size
+    }
+
+    /// Returns whether this frame is a virtual frame for an inlined call rather
+    /// than a physical frame on the stack.
+    ///
+    /// On Windows `StackWalkEx` expands inlined calls into frames of their
+    /// own, which share the instruction and stack pointers of the physical
+    /// frame they were inlined into and come right before it. Consumers which
+    /// only care about physical frames, such as profilers attributing time to
+    /// stack frames, can skip frames for which this returns `true`.
+    ///
+    /// Other platforms don't expand inlined calls while walking the stack, so
+    /// this always returns `false` there. Inlined calls are instead reported
+    /// as additional symbols when the frame is resolved, innermost first.
+    pub fn is_inline(&self) -> bool {
+        self.inner.is_inline()
+    }
+
+    /// Returns whether this frame was likely entered through a tail call made
+    /// by the function `caller` called, its next frame in the walk.
+    ///
+    /// A function ending in a call to another one may be compiled to jump to
+    /// it instead, reusing its own stack frame. Once the callee runs that
+    /// function is gone from the stack, so the walk goes straight from the
+    /// callee to its caller's caller and a frame is missing in between. This
+    /// is the case if `caller` called a different function than the one this
+    /// frame is in.
+    ///
+    /// This is best-effort. It's only implemented for x86_64 and ARM64
+    /// Windows, where the unwind information of this frame's function gives
+    /// its start, and only direct calls from `caller` can be checked, so
+    /// `false` doesn't mean no frame is missing. Calls through import or
+    /// jump thunks other than a single direct jump may be reported as tail
+    /// calls. Inline frames are never reported as such, and on other
+    /// platforms this always returns `false`.
+    pub fn is_tail_call(&self, caller: &Frame) -> bool {
+        is_tail_call_imp(self, caller)
+    }
+
+    /// Returns whether the instruction pointer of this frame points to
+    /// committed, executable memory.
+    ///
+    /// A walk over a corrupt stack can produce frames whose instruction
+    /// pointer is garbage, pointing into unmapped memory or data. Checking
+    /// this before resolving a frame avoids wasting time on symbol lookups
+    /// which can't succeed, and allows dropping such frames from the output.
+    ///
+    /// On Windows this is a single `VirtualQuery`, checking for `MEM_COMMIT`
+    /// and one of the `PAGE_EXECUTE*` protections. It's not done by any of
+    /// the tracing functions, so the cost is only paid when called. Other
+    /// platforms can't check this, and always return `true`.
+    pub fn address_is_mapped(&self) -> bool {
+        address_is_mapped_imp(self)
+    }
+
+    /// Returns the address of the function table entry the stack walker found
+    /// for this frame, as an opaque value.
+    ///
+    /// This is the `FuncTableEntry` field of the underlying `STACKFRAME64`
+    /// (or `STACKFRAME_EX`), which points to the `RUNTIME_FUNCTION` (or
+    /// `FPO_DATA` on 32-bit x86) describing how to unwind the frame. It's only
+    /// useful for diagnostics, as the pointed-to data is owned by the OS or
+    /// dbghelp, and is mostly interesting when it's zero: in that case no
+    /// unwind information was found and the walker had to guess where the
+    /// caller's frame is, so this frame and all frames after it are less
+    /// reliable.
+    #[cfg(target_os = "windows")]
+    pub fn function_table_entry(&self) -> usize {
+        self.inner.function_table_entry()
+    }
+}
+
+/// Displays the instruction pointer of the frame along with the module it
+/// belongs to and its offset within that module, as in
+/// `0x7ff6e2c71234 app.exe+0x1234`.
+///
+/// This doesn't require symbolization so it's cheap and works without debug
+/// information. If the module base address isn't known, as for JIT-compiled
+/// code or on platforms other than Windows, only the instruction pointer is
+/// displayed.
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ip = self.ip();
+        write!(f, "{:?}", ip)?;
+        let base = match self.module_base_address() {
+            Some(base) if !base.is_null() => base,
+            _ => return Ok(()),
+        };
+        f.write_str(" ")?;
+        #[cfg(all(windows, not(target_vendor = "uwp"), not(miri)))]
+        let named = fmt_module_name(base, f)?;
+        #[cfg(not(all(windows, not(target_vendor = "uwp"), not(miri))))]
+        let named = false;
+        if !named {
+            write!(f, "{:?}", base)?;
+        }
+        write!(f, "+{:#x}", (ip as usize).wrapping_sub(base as usize))
     }
 }
 
@@ -144,6 +2216,16 @@ cfg_if::cfg_if! {
         pub(crate) mod miri;
         use self::miri::trace as trace_imp;
         pub(crate) use self::miri::Frame as FrameImp;
+        mod unsupported;
+        use self::unsupported::trace_thread as trace_thread_imp;
+        use self::unsupported::validate_thread_handle as validate_thread_handle_imp;
+        use self::unsupported::trace_all_threads as trace_all_threads_imp;
+        use self::unsupported::thread_id as thread_id_imp;
+        use self::unsupported::stack_bounds as stack_bounds_imp;
+        use self::unsupported::teb_address as teb_address_imp;
+        use self::unsupported::is_tail_call as is_tail_call_imp;
+        use self::unsupported::address_is_mapped as address_is_mapped_imp;
+        use self::unsupported::{snapshot_all_threads as snapshot_all_threads_imp, trace_captured as trace_captured_imp, CapturedThread};
     } else if #[cfg(
         any(
             all(
@@ -160,9 +2242,56 @@ cfg_if::cfg_if! {
         mod libunwind;
         use self::libunwind::trace as trace_imp;
         pub(crate) use self::libunwind::Frame as FrameImp;
+        #[cfg(target_os = "macos")]
+        mod mach;
+        #[cfg(target_os = "macos")]
+        use self::mach::{trace_thread as trace_thread_imp, validate_thread_handle as validate_thread_handle_imp};
+        mod unsupported;
+        #[cfg(not(target_os = "macos"))]
+        use self::unsupported::{trace_thread as trace_thread_imp, validate_thread_handle as validate_thread_handle_imp};
+        use self::unsupported::trace_all_threads as trace_all_threads_imp;
+        use self::unsupported::thread_id as thread_id_imp;
+        use self::unsupported::stack_bounds as stack_bounds_imp;
+        use self::unsupported::teb_address as teb_address_imp;
+        use self::unsupported::is_tail_call as is_tail_call_imp;
+        use self::unsupported::address_is_mapped as address_is_mapped_imp;
+        use self::unsupported::{snapshot_all_threads as snapshot_all_threads_imp, trace_captured as trace_captured_imp, CapturedThread};
     } else if #[cfg(all(windows, not(target_vendor = "uwp")))] {
         mod dbghelp;
         use self::dbghelp::trace as trace_imp;
+        use self::dbghelp::trace_thread as trace_thread_imp;
+        use self::dbghelp::validate_thread_handle as validate_thread_handle_imp;
+        use self::dbghelp::thread_id as thread_id_imp;
+        use self::dbghelp::stack_bounds as stack_bounds_imp;
+        use self::dbghelp::teb_address as teb_address_imp;
+        use self::dbghelp::is_tail_call as is_tail_call_imp;
+        use self::dbghelp::address_is_mapped as address_is_mapped_imp;
+        use self::dbghelp::is_current_thread as is_current_thread_imp;
+        use self::dbghelp::trace_with_memory_reader as trace_with_memory_reader_imp;
+        use self::dbghelp::trace_guest_context as trace_guest_context_imp;
+        use self::dbghelp::trace_with_machine as trace_with_machine_imp;
+        use self::dbghelp::trace_with_walk_flags as trace_with_walk_flags_imp;
+        use self::dbghelp::trace_lazy_base as trace_lazy_base_imp;
+        use self::dbghelp::trace_best_effort as trace_best_effort_imp;
+        use self::dbghelp::{trace_thread_with_context as trace_thread_with_context_imp, ContextBuffer as ContextBufferImp};
+        use self::dbghelp::trace_reconciled as trace_reconciled_imp;
+        use self::dbghelp::trace_exception as trace_exception_imp;
+        use self::dbghelp::safe_point as safe_point_imp;
+        #[cfg(feature = "std")]
+        use self::dbghelp::park_thread as park_thread_imp;
+        use self::dbghelp::fmt_module_name;
+        use self::dbghelp::trace_all_threads as trace_all_threads_imp;
+        use self::dbghelp::{list_threads as list_threads_imp, with_thread as with_thread_imp};
+        use self::dbghelp::{snapshot_all_threads as snapshot_all_threads_imp, trace_captured as trace_captured_imp, CapturedThread};
+        #[cfg(feature = "minidump")]
+        mod minidump;
+        use self::dbghelp::{reset_stats as reset_stats_imp, stats as stats_imp};
+        use self::dbghelp::available_processors as available_processors_imp;
+        use self::dbghelp::set_yield_on_single_processor as set_yield_on_single_processor_imp;
+        use self::dbghelp::enable_debug_privilege as enable_debug_privilege_imp;
+        use self::dbghelp::set_deferred_symbol_init as set_deferred_symbol_init_imp;
+        use self::dbghelp::register_function_entry_callback as register_function_entry_callback_imp;
+        use self::dbghelp::{dbghelp_function as dbghelp_function_imp, with_dbghelp as with_dbghelp_imp};
         pub(crate) use self::dbghelp::Frame as FrameImp;
         #[cfg(target_env = "msvc")] // only used in dbghelp symbolize
         pub(crate) use self::dbghelp::StackFrame;
@@ -170,5 +2299,15 @@ cfg_if::cfg_if! {
         mod noop;
         use self::noop::trace as trace_imp;
         pub(crate) use self::noop::Frame as FrameImp;
+        mod unsupported;
+        use self::unsupported::trace_thread as trace_thread_imp;
+        use self::unsupported::validate_thread_handle as validate_thread_handle_imp;
+        use self::unsupported::trace_all_threads as trace_all_threads_imp;
+        use self::unsupported::thread_id as thread_id_imp;
+        use self::unsupported::stack_bounds as stack_bounds_imp;
+        use self::unsupported::teb_address as teb_address_imp;
+        use self::unsupported::is_tail_call as is_tail_call_imp;
+        use self::unsupported::address_is_mapped as address_is_mapped_imp;
+        use self::unsupported::{snapshot_all_threads as snapshot_all_threads_imp, trace_captured as trace_captured_imp, CapturedThread};
     }
 }
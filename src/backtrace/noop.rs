@@ -25,4 +25,12 @@ impl Frame {
     pub fn module_base_address(&self) -> Option<*mut c_void> {
         None
     }
+
+    pub fn is_inline(&self) -> bool {
+        false
+    }
+
+    pub fn inline_context(&self) -> u32 {
+        0
+    }
 }
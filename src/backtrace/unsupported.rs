@@ -0,0 +1,70 @@
+//! Stand-ins for the cross-thread tracing functions on platforms which can't
+//! trace other threads, so that code using them still compiles there.
+
+use super::{
+    AllThreadsSnapshot, Frame, RawThreadHandle, ThreadInfo, ThreadSnapshotOptions,
+    ThreadStackBounds, TraceError,
+};
+use core::ffi::c_void;
+
+pub fn is_tail_call(_frame: &Frame, _caller: &Frame) -> bool {
+    false
+}
+
+pub fn address_is_mapped(_frame: &Frame) -> bool {
+    true
+}
+
+pub fn thread_id(_thread: RawThreadHandle) -> Option<u32> {
+    None
+}
+
+pub fn stack_bounds(_thread: RawThreadHandle) -> Option<ThreadStackBounds> {
+    None
+}
+
+pub fn teb_address(_thread: RawThreadHandle) -> Option<*mut c_void> {
+    None
+}
+
+#[allow(dead_code)] // unused on macOS, which can trace single threads
+pub fn validate_thread_handle(_thread: RawThreadHandle) -> Result<(), TraceError> {
+    Err(TraceError::Unsupported)
+}
+
+#[allow(dead_code)] // unused on macOS, which can trace single threads
+pub unsafe fn trace_thread(
+    _thread: RawThreadHandle,
+    _cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    Err(TraceError::Unsupported)
+}
+
+pub unsafe fn trace_all_threads(
+    _options: &ThreadSnapshotOptions,
+    _cb: &mut dyn FnMut(&ThreadInfo, &super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    Err(TraceError::Unsupported)
+}
+
+/// A thread captured by `snapshot_all_threads`, which never succeeds here.
+pub enum CapturedThread {}
+
+impl CapturedThread {
+    pub fn info(&self) -> &ThreadInfo {
+        match *self {}
+    }
+}
+
+pub unsafe fn snapshot_all_threads(
+    _options: &ThreadSnapshotOptions,
+) -> Result<AllThreadsSnapshot, TraceError> {
+    Err(TraceError::Unsupported)
+}
+
+pub unsafe fn trace_captured(
+    thread: &CapturedThread,
+    _cb: &mut dyn FnMut(&super::Frame) -> bool,
+) -> Result<(), TraceError> {
+    match *thread {}
+}
@@ -1,9 +1,22 @@
+use crate::process::{CaptureContext, FrameAction, FrameProcessor};
+use crate::symbolize::{anonymous_module_at, resolve_offline};
 use crate::PrintFmt;
-use crate::{resolve, resolve_frame, trace, BacktraceFmt, Symbol, SymbolName};
-use std::ffi::c_void;
+use crate::{
+    resolve, resolve_frame, trace, BacktraceFmt, BytesOrWideString, DemangleStyle, ModuleMap,
+    Symbol, SymbolKind, SymbolName, SymbolSource,
+};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::ffi::{c_void, OsStr};
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::prelude::v1::*;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
@@ -29,6 +42,9 @@ pub struct Backtrace {
     // The index we believe is the actual start of the backtrace, omitting
     // frames like `Backtrace::new` and `backtrace::trace`.
     actual_start_index: usize,
+    // Whether the frames before `actual_start_index` are listed anyway.
+    #[cfg_attr(feature = "serde", serde(default))]
+    include_internal: bool,
 }
 
 fn _assert_send_sync() {
@@ -41,6 +57,12 @@ fn _assert_send_sync() {
 /// This type is returned as a list from `Backtrace::frames` and represents one
 /// stack frame in a captured backtrace.
 ///
+/// Frames compare equal and hash the same if they have the same instruction
+/// pointer and, on Windows, belong to the same level of inlined calls at that
+/// address. Whether a frame has been resolved is ignored, so frames of two
+/// captures of the same thread can be compared to find where its stack
+/// changed, or deduplicated, regardless of which were symbolized.
+///
 /// # Required features
 ///
 /// This function requires the `std` feature of the `backtrace` crate to be
@@ -49,12 +71,12 @@ fn _assert_send_sync() {
 pub struct BacktraceFrame {
     frame: Frame,
     symbols: Option<Vec<BacktraceSymbol>>,
+    thread_id: Option<u32>,
 }
 
 #[derive(Clone)]
 enum Frame {
     Raw(crate::Frame),
-    #[allow(dead_code)]
     Deserialized {
         ip: usize,
         symbol_address: usize,
@@ -70,6 +92,13 @@ impl Frame {
         }
     }
 
+    fn sp(&self) -> *mut c_void {
+        match *self {
+            Frame::Raw(ref f) => f.sp(),
+            Frame::Deserialized { .. } => ptr::null_mut(),
+        }
+    }
+
     fn symbol_address(&self) -> *mut c_void {
         match *self {
             Frame::Raw(ref f) => f.symbol_address(),
@@ -106,6 +135,12 @@ pub struct BacktraceSymbol {
     filename: Option<PathBuf>,
     lineno: Option<u32>,
     colno: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    kind: Option<SymbolKind>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    inlined_into: Option<Vec<u8>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    inline_depth: u32,
 }
 
 impl Backtrace {
@@ -131,13 +166,16 @@ impl Backtrace {
     /// let current_backtrace = Backtrace::new();
     /// ```
     ///
+    /// At most `BACKTRACE_MAX_FRAMES` frames are captured if that environment
+    /// variable is set to a number, see `max_frames`.
+    ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
     #[inline(never)] // want to make sure there's a frame here to remove
     pub fn new() -> Backtrace {
-        let mut bt = Self::create(Self::new as usize);
+        let mut bt = Self::create(Self::new as fn() -> _ as usize);
         bt.resolve();
         bt
     }
@@ -167,27 +205,179 @@ impl Backtrace {
     /// enabled, and the `std` feature is enabled by default.
     #[inline(never)] // want to make sure there's a frame here to remove
     pub fn new_unresolved() -> Backtrace {
-        Self::create(Self::new_unresolved as usize)
+        Self::create(Self::new_unresolved as fn() -> _ as usize)
+    }
+
+    /// Captures a backtrace of the frames `processor` keeps, and resolves it.
+    ///
+    /// The frames are passed to `processor` while the stack is walked,
+    /// before they're stored, see `FrameProcessor`. At most `max_frames`
+    /// frames are kept.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use backtrace::{Backtrace, FramePipeline, FrameProcessor};
+    ///
+    /// let bt = Backtrace::new_with(FramePipeline::new().skip_crate_internals().limit(16));
+    /// assert!(bt.frames().len() <= 16);
+    /// ```
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    #[inline(never)] // see `trace_thread_from`
+    pub fn new_with<P: FrameProcessor>(processor: P) -> Backtrace {
+        let marker = 0u8;
+        let mut bt = Self::create_with(
+            processor,
+            &marker,
+            Self::new_with::<P> as fn(P) -> _ as usize,
+        );
+        bt.resolve();
+        bt
+    }
+
+    /// Similar to `new_with` except that this does not resolve any symbols.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    #[inline(never)] // see `trace_thread_from`
+    pub fn new_unresolved_with<P: FrameProcessor>(processor: P) -> Backtrace {
+        let marker = 0u8;
+        Self::create_with(
+            processor,
+            &marker,
+            Self::new_unresolved_with::<P> as fn(P) -> _ as usize,
+        )
+    }
+
+    /// Walks the stacks of all threads of a snapshot into a single backtrace.
+    ///
+    /// The frames of each thread follow each other, in the order of the
+    /// threads in the snapshot, and each frame records the id of its thread,
+    /// see `BacktraceFrame::thread_id`. Use `thread_ids` to list the threads
+    /// and filter `frames` by thread id to display them separately. At most
+    /// `max_frames` frames are captured per thread. The frames aren't resolved.
+    ///
+    /// # Errors
+    ///
+    /// See `AllThreadsSnapshot::trace`.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn from_snapshot(
+        snapshot: &crate::AllThreadsSnapshot,
+    ) -> Result<Backtrace, crate::TraceError> {
+        let max = max_frames();
+        let mut frames = Vec::new();
+        let mut current = None;
+        let mut count = 0;
+        snapshot.trace(|info, frame| {
+            if current != Some(info.id()) {
+                current = Some(info.id());
+                count = 0;
+            }
+            frames.push(BacktraceFrame {
+                frame: Frame::Raw(frame.clone()),
+                symbols: None,
+                thread_id: current,
+            });
+            count += 1;
+            count < max
+        })?;
+        Ok(Backtrace::from(frames))
+    }
+
+    /// Captures the stacks of the given threads into a single backtrace.
+    ///
+    /// Each thread is traced in turn like with `capture_thread_in`, and its
+    /// frames are tagged with its id, see `BacktraceFrame::thread_id` and
+    /// `ThreadHandle::id`. The frames of each thread follow each other in the
+    /// order of `threads`. This is a lighter alternative to
+    /// `snapshot_all_threads` when only a known set of threads, such as the
+    /// workers of a pool, is of interest.
+    ///
+    /// Threads which can't be traced are left out, and every thread is
+    /// resumed once its stack was walked, also if that failed. At most
+    /// `max_frames` frames, and no more than 1024, are captured per thread,
+    /// into a buffer allocated up front. The frames aren't resolved.
+    ///
+    /// # Safety
+    ///
+    /// See `capture_thread_in`.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    #[inline(never)] // see `trace_thread_from`
+    pub unsafe fn new_for_threads(threads: &[crate::ThreadHandle]) -> Backtrace {
+        let marker = 0u8;
+        let mut frames = Vec::new();
+        let mut scratch = Vec::with_capacity(max_frames().min(THREAD_FRAMES_CAPACITY));
+        for &thread in threads {
+            scratch.clear();
+            capture_thread_into(thread, &mut scratch, &marker);
+            let thread_id = thread.id();
+            frames.extend(
+                scratch
+                    .drain(..)
+                    .map(|frame| BacktraceFrame { thread_id, ..frame }),
+            );
+        }
+        Backtrace::from(frames)
+    }
+
+    fn create_with<P: FrameProcessor>(
+        mut processor: P,
+        marker: *const u8,
+        entry: usize,
+    ) -> Backtrace {
+        let cx = CaptureContext::new(marker, entry);
+        let max = max_frames();
+        let mut frames = Vec::new();
+        trace(|frame| match processor.process(frame, &cx) {
+            FrameAction::Keep => {
+                frames.push(BacktraceFrame {
+                    frame: Frame::Raw(frame.clone()),
+                    symbols: None,
+                    thread_id: None,
+                });
+                frames.len() < max
+            }
+            FrameAction::Skip => true,
+            FrameAction::Stop => false,
+        });
+        Backtrace::from(frames)
     }
 
     fn create(ip: usize) -> Backtrace {
+        let max = max_frames();
         let mut frames = Vec::new();
         let mut actual_start_index = None;
         trace(|frame| {
             frames.push(BacktraceFrame {
                 frame: Frame::Raw(frame.clone()),
                 symbols: None,
+                thread_id: None,
             });
 
             if frame.symbol_address() as usize == ip && actual_start_index.is_none() {
                 actual_start_index = Some(frames.len());
             }
-            true
+            actual_start_index.map_or(true, |start| frames.len() - start < max)
         });
 
         Backtrace {
             frames,
             actual_start_index: actual_start_index.unwrap_or(0),
+            include_internal: false,
         }
     }
 
@@ -202,247 +392,2722 @@ impl Backtrace {
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
     pub fn frames(&self) -> &[BacktraceFrame] {
-        &self.frames[self.actual_start_index..]
+        &self.frames[self.start_index()..]
     }
 
-    /// If this backtrace was created from `new_unresolved` then this function
-    /// will resolve all addresses in the backtrace to their symbolic names.
+    /// Controls whether the frames of this crate which captured the backtrace,
+    /// such as `Backtrace::new` and `backtrace::trace`, are listed.
     ///
-    /// If this backtrace has been previously resolved or was created through
-    /// `new`, this function does nothing.
+    /// These frames are hidden by default, which is usually what's wanted
+    /// when printing a backtrace, but they can be useful when debugging the
+    /// capture itself. This affects `frames`, `len`, `into_frames` and the
+    /// non-alternate `Debug` output.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn resolve(&mut self) {
-        for frame in self.frames.iter_mut().filter(|f| f.symbols.is_none()) {
-            let mut symbols = Vec::new();
-            {
-                let sym = |symbol: &Symbol| {
-                    symbols.push(BacktraceSymbol {
-                        name: symbol.name().map(|m| m.as_bytes().to_vec()),
-                        addr: symbol.addr().map(|a| a as usize),
-                        filename: symbol.filename().map(|m| m.to_owned()),
-                        lineno: symbol.lineno(),
-                        colno: symbol.colno(),
-                    });
-                };
-                match frame.frame {
-                    Frame::Raw(ref f) => resolve_frame(f, sym),
-                    Frame::Deserialized { ip, .. } => {
-                        resolve(ip as *mut c_void, sym);
-                    }
-                }
-            }
-            frame.symbols = Some(symbols);
-        }
+    pub fn include_internal_frames(&mut self, include: bool) {
+        self.include_internal = include;
     }
-}
 
-impl From<Vec<BacktraceFrame>> for Backtrace {
-    fn from(frames: Vec<BacktraceFrame>) -> Self {
-        Backtrace {
-            frames,
-            actual_start_index: 0,
-        }
+    /// Returns a value which displays this backtrace exactly the way the
+    /// standard library displays a `std::backtrace::Backtrace`.
+    ///
+    /// This eases moving from `std::backtrace` to this crate without changing
+    /// what ends up in logs. Unlike the `Debug` output, each inlined call gets
+    /// a number of its own, and paths within the current directory are
+    /// printed as `./src/main.rs`. The alternate format, `{:#}`, prints all
+    /// frames along with their instruction pointers and full paths, like
+    /// std's does.
+    ///
+    /// Nothing is resolved by this, frames which aren't resolved yet are
+    /// printed as `<unknown>`. Call `resolve` first if needed.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn display_std(&self) -> impl fmt::Display + '_ {
+        StdDisplay(self)
     }
-}
 
-impl From<crate::Frame> for BacktraceFrame {
-    fn from(frame: crate::Frame) -> BacktraceFrame {
-        BacktraceFrame {
-            frame: Frame::Raw(frame),
-            symbols: None,
+    fn start_index(&self) -> usize {
+        if self.include_internal {
+            0
+        } else {
+            self.actual_start_index
         }
     }
-}
 
-impl Into<Vec<BacktraceFrame>> for Backtrace {
-    fn into(self) -> Vec<BacktraceFrame> {
-        self.frames
+    /// Returns the number of frames in this backtrace, the same as
+    /// `frames().len()`.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn len(&self) -> usize {
+        self.frames().len()
     }
-}
 
-impl BacktraceFrame {
-    /// Same as `Frame::ip`
+    /// Returns whether this backtrace has no frames, for example because the
+    /// thread it was captured from couldn't be suspended.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn ip(&self) -> *mut c_void {
-        self.frame.ip() as *mut c_void
+    pub fn is_empty(&self) -> bool {
+        self.frames().is_empty()
     }
 
-    /// Same as `Frame::symbol_address`
+    /// Returns the frame at `index` in `frames`, or `None` if there are fewer
+    /// frames.
+    ///
+    /// This is meant for looking at a single frame of an unresolved backtrace,
+    /// such as the one selected in a UI, which can then be resolved on its own
+    /// with `resolve_address` instead of resolving the whole backtrace.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn symbol_address(&self) -> *mut c_void {
-        self.frame.symbol_address() as *mut c_void
+    pub fn frame(&self, index: usize) -> Option<&BacktraceFrame> {
+        self.frames().get(index)
     }
 
-    /// Same as `Frame::module_base_address`
+    /// Consumes this backtrace, returning its frames.
+    ///
+    /// The returned frames are the same as those listed by `frames`, but are
+    /// moved out rather than borrowed so any resolved symbol data doesn't have
+    /// to be cloned.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn module_base_address(&self) -> Option<*mut c_void> {
-        self.frame
-            .module_base_address()
-            .map(|addr| addr as *mut c_void)
+    pub fn into_frames(mut self) -> Vec<BacktraceFrame> {
+        let start = self.start_index();
+        self.frames.drain(..start);
+        self.frames
     }
 
-    /// Returns the list of symbols that this frame corresponds to.
-    ///
-    /// Normally there is only one symbol per frame, but sometimes if a number
-    /// of functions are inlined into one frame then multiple symbols will be
-    /// returned. The first symbol listed is the "innermost function", whereas
-    /// the last symbol is the outermost (last caller).
+    /// Returns the distinct thread ids of the frames of this backtrace, in the
+    /// order they first appear.
     ///
-    /// Note that if this frame came from an unresolved backtrace then this will
-    /// return an empty list.
+    /// This is empty unless the backtrace combines the stacks of several
+    /// threads, see `BacktraceFrame::thread_id`.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn symbols(&self) -> &[BacktraceSymbol] {
-        self.symbols.as_ref().map(|s| &s[..]).unwrap_or(&[])
+    pub fn thread_ids(&self) -> Vec<u32> {
+        let mut ids = Vec::new();
+        for id in self.frames().iter().filter_map(|f| f.thread_id) {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        ids
     }
-}
 
-impl BacktraceSymbol {
-    /// Same as `Symbol::name`
+    /// Returns a backtrace of only the frames of this one which belong to one
+    /// of the modules named in `keep`, such as `myapp.exe` or `mylib.dll`.
+    ///
+    /// Modules are named by their file name like in
+    /// `BacktraceFrame::module_offset`, and compared ignoring ASCII case as
+    /// Windows does. This is meant for trimming reports down to the code of
+    /// interest after the fact, and leaves this backtrace untouched, so the
+    /// full capture remains available. Use `Backtrace::new_with` to filter
+    /// frames while capturing instead.
+    ///
+    /// Module names are only known on Windows and for code registered with
+    /// `register_anonymous_module`, elsewhere no frames are kept.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn name(&self) -> Option<SymbolName<'_>> {
-        self.name.as_ref().map(|s| SymbolName::new(s))
+    pub fn filter_modules(&self, keep: &[&str]) -> Backtrace {
+        // Frames of the same module share its base address, so every module
+        // is only looked up once.
+        let mut kept = HashMap::new();
+        let frames = self
+            .frames()
+            .iter()
+            .filter(|frame| {
+                let base = match frame.module_base_address() {
+                    Some(base) if !base.is_null() => base,
+                    _ => {
+                        return anonymous_module_at(frame.ip() as usize)
+                            .map_or(false, |m| keep.iter().any(|k| k.eq_ignore_ascii_case(&m.1)))
+                    }
+                };
+                *kept.entry(base as usize).or_insert_with(|| {
+                    module_name(base).map_or(false, |name| {
+                        keep.iter().any(|k| k.eq_ignore_ascii_case(&name))
+                    })
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        Backtrace::from(frames)
     }
 
-    /// Same as `Symbol::addr`
+    /// Returns the size in bytes of the stack frame of each frame of this
+    /// backtrace, in the same order as `frames`.
+    ///
+    /// The size of a frame is the distance from its stack pointer to that of
+    /// the next frame, its caller. This shows which functions use the most
+    /// stack, for example because of large locals, when looking for the cause
+    /// of a stack overflow. Inline frames share the stack frame of the
+    /// function they were inlined into and have a size of 0.
+    ///
+    /// The size is `None` for the outermost frame of each thread, which has
+    /// no caller, for frames whose stack pointer or that of their caller
+    /// isn't known, see `BacktraceFrame::sp`, and where the caller's stack
+    /// pointer is below the frame's own, which means the walk went wrong.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn addr(&self) -> Option<*mut c_void> {
-        self.addr.map(|s| s as *mut c_void)
+    pub fn frame_sizes(&self) -> Vec<Option<usize>> {
+        let frames = self.frames();
+        let mut sizes = Vec::with_capacity(frames.len());
+        for (i, frame) in frames.iter().enumerate() {
+            let sp = frame.sp() as usize;
+            let size = frames.get(i + 1).and_then(|next| {
+                let next_sp = next.sp() as usize;
+                if sp == 0 || next_sp == 0 || next.thread_id != frame.thread_id {
+                    return None;
+                }
+                next_sp.checked_sub(sp)
+            });
+            sizes.push(size);
+        }
+        sizes
     }
 
-    /// Same as `Symbol::filename`
+    /// Returns whether each frame of this backtrace was likely entered through
+    /// a tail call, in the same order as `frames`.
+    ///
+    /// A frame entered through a tail call directly follows the caller of the
+    /// function which made it, so a frame may be missing right after it. See
+    /// `Frame::is_tail_call` for how this is detected. This is best-effort:
+    /// `false` doesn't mean no frame is missing, and it's always `false` for
+    /// the outermost frame of each thread and for frames created with
+    /// `from_parts` or deserialized.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn filename(&self) -> Option<&Path> {
-        self.filename.as_ref().map(|p| &**p)
+    pub fn tail_calls(&self) -> Vec<bool> {
+        let frames = self.frames();
+        let mut tail_calls = Vec::with_capacity(frames.len());
+        for (i, frame) in frames.iter().enumerate() {
+            let tail_call = match (&frame.frame, frames.get(i + 1)) {
+                (Frame::Raw(f), Some(next)) if next.thread_id == frame.thread_id => {
+                    match next.frame {
+                        Frame::Raw(ref caller) => f.is_tail_call(caller),
+                        Frame::Deserialized { .. } => false,
+                    }
+                }
+                _ => false,
+            };
+            tail_calls.push(tail_call);
+        }
+        tail_calls
     }
 
-    /// Same as `Symbol::lineno`
+    /// If this backtrace was created from `new_unresolved` then this function
+    /// will resolve all addresses in the backtrace to their symbolic names.
+    ///
+    /// If this backtrace has been previously resolved or was created through
+    /// `new`, this function does nothing.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn lineno(&self) -> Option<u32> {
-        self.lineno
+    pub fn resolve(&mut self) {
+        for frame in self.frames.iter_mut().filter(|f| f.symbols.is_none()) {
+            frame.resolve();
+        }
     }
 
-    /// Same as `Symbol::colno`
+    /// Same as `resolve`, but looks up frames in a process-wide `SymbolCache`
+    /// first and remembers the symbols of the frames it had to resolve.
+    ///
+    /// This makes resolving the same addresses over and over again, as a
+    /// sampling profiler does for its hot frames, much cheaper. The cache
+    /// holds the symbols of up to 4096 frames, evicting the oldest ones
+    /// beyond that. It's emptied by `clear_symbol_cache` and invalidated by
+    /// `refresh_modules`. Use `resolve_with_cache` to manage the cache
+    /// yourself instead.
     ///
     /// # Required features
     ///
     /// This function requires the `std` feature of the `backtrace` crate to be
     /// enabled, and the `std` feature is enabled by default.
-    pub fn colno(&self) -> Option<u32> {
-        self.colno
+    pub fn resolve_cached(&mut self) {
+        with_global_symbol_cache(|cache| self.resolve_with_cache(cache));
     }
-}
-
-impl fmt::Debug for Backtrace {
-    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let full = fmt.alternate();
-        let (frames, style) = if full {
-            (&self.frames[..], PrintFmt::Full)
-        } else {
-            (&self.frames[self.actual_start_index..], PrintFmt::Short)
-        };
 
-        // When printing paths we try to strip the cwd if it exists, otherwise
-        // we just print the path as-is. Note that we also only do this for the
-        // short format, because if it's full we presumably want to print
-        // everything.
-        let cwd = std::env::current_dir();
-        let mut print_path =
-            move |fmt: &mut fmt::Formatter<'_>, path: crate::BytesOrWideString<'_>| {
-                let path = path.into_path_buf();
-                if !full {
-                    if let Ok(cwd) = &cwd {
-                        if let Ok(suffix) = path.strip_prefix(cwd) {
-                            return fmt::Display::fmt(&suffix.display(), fmt);
-                        }
-                    }
-                }
-                fmt::Display::fmt(&path.display(), fmt)
-            };
-
-        let mut f = BacktraceFmt::new(fmt, style, &mut print_path);
-        f.add_context()?;
-        for frame in frames {
-            f.frame().backtrace_frame(frame)?;
+    /// Same as `resolve`, but looks up frames in `cache` first and adds the
+    /// symbols of the frames it had to resolve to it.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn resolve_with_cache(&mut self, cache: &mut SymbolCache) {
+        cache.invalidate_if_stale();
+        for frame in self.frames.iter_mut().filter(|f| f.symbols.is_none()) {
+            let key = frame.key();
+            if let Some(symbols) = cache.symbols.get(&key) {
+                frame.symbols = Some(symbols.clone());
+                continue;
+            }
+            frame.resolve();
+            cache.insert(key, frame.symbols().to_vec());
         }
-        f.finish()?;
-        Ok(())
     }
-}
 
-impl Default for Backtrace {
-    fn default() -> Backtrace {
-        Backtrace::new()
+    /// Same as `resolve`, additionally returning how well the frames of this
+    /// backtrace resolved.
+    ///
+    /// The statistics cover all frames returned by `frames`, including ones
+    /// which were already resolved before this call. They help judge how
+    /// much debug information is available, for example whether it's worth
+    /// fetching more PDBs for backtraces reported from the field.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn resolve_with_stats(&mut self) -> ResolveStats {
+        self.resolve();
+        let mut stats = ResolveStats::default();
+        for frame in self.frames() {
+            let symbols = frame.symbols();
+            if symbols.iter().any(|s| s.lineno().is_some()) {
+                stats.lines += 1;
+            } else if symbols.iter().any(|s| s.name().is_some()) {
+                stats.symbols += 1;
+            } else if frame.module_base_address().is_some() {
+                stats.modules += 1;
+            } else {
+                stats.unresolved += 1;
+            }
+            let debug_info = |kind| matches!(kind, SymbolKind::Pdb | SymbolKind::Dwarf);
+            if symbols.iter().filter_map(|s| s.kind()).any(debug_info) {
+                stats.debug_info += 1;
+            }
+        }
+        stats
     }
-}
 
-impl fmt::Debug for BacktraceFrame {
-    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("BacktraceFrame")
-            .field("ip", &self.ip())
-            .field("symbol_address", &self.symbol_address())
-            .finish()
+    /// Same as `resolve`, except that no further frames are resolved once
+    /// `timeout` has elapsed.
+    ///
+    /// Symbolization can be slow, for example when dbghelp has to download
+    /// symbols from a symbol server, so this can be used to bound the time
+    /// spent on it. Frames that weren't resolved in time are left unresolved,
+    /// which can be checked with `BacktraceFrame::is_resolved`, and a later
+    /// call to `resolve` or `resolve_with_timeout` picks up where this one
+    /// left off. The timeout is only checked between frames, so resolving a
+    /// single frame can still exceed it.
+    ///
+    /// Returns `true` if all frames are now resolved.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn resolve_with_timeout(&mut self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        for frame in self.frames.iter_mut().filter(|f| f.symbols.is_none()) {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            frame.resolve();
+        }
+        true
     }
-}
 
-impl fmt::Debug for BacktraceSymbol {
-    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("BacktraceSymbol")
-            .field("name", &self.name())
-            .field("addr", &self.addr())
-            .field("filename", &self.filename())
-            .field("lineno", &self.lineno())
-            .field("colno", &self.colno())
-            .finish()
+    /// Resolves the frames of this backtrace using the binaries listed in
+    /// `source` instead of the modules loaded in the current process.
+    ///
+    /// Frames are matched to a module of `source` by their
+    /// `module_base_address`, and resolved from that module's debug
+    /// information alone. Only the offset of each frame into its module, see
+    /// `BacktraceFrame::rva`, is looked up, relative to the base address the
+    /// binary prefers, so it doesn't matter where the module was loaded when
+    /// the backtrace was captured. This makes the result independent of the system's
+    /// symbolication libraries and allows resolving backtraces captured on
+    /// another machine, for example ones rebuilt with
+    /// `BacktraceFrame::from_parts`. Frames which are already resolved, whose
+    /// module isn't in `source` or whose binary can't be read are left as they
+    /// are.
+    ///
+    /// This reads DWARF debug information from ELF, Mach-O and PE binaries.
+    /// PDB files aren't supported, so nothing is resolved on MSVC targets.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn resolve_offline(&mut self, source: &SymbolSource) {
+        for (base, path) in source.modules() {
+            self.resolve_in_binary(path, true, |frame| {
+                if frame.module_base_address() != Some(base as _) {
+                    return None;
+                }
+                frame.rva().map(|rva| rva as usize)
+            });
+        }
     }
-}
-
-#[cfg(feature = "serialize-rustc")]
-mod rustc_serialize_impls {
-    use super::*;
-    use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
 
-    #[derive(RustcEncodable, RustcDecodable)]
+    /// Resolves the frames of this backtrace against the modules of `map`,
+    /// which was captured when the backtrace was.
+    ///
+    /// This is meant for backtraces captured a while ago, after which modules
+    /// may have been unloaded or loaded at different addresses. Frames are
+    /// matched to the module of `map` their `ip` lies within, and resolved
+    /// from that module's binary on disk, like with `resolve_offline`, rather
+    /// than from whatever is loaded at that address now. Frames which are
+    /// already resolved, aren't within any module of `map` or whose binary
+    /// can't be read are left as they are.
+    ///
+    /// This reads DWARF debug information from ELF, Mach-O and PE binaries.
+    /// PDB files aren't supported, so nothing is resolved on MSVC targets.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn resolve_with_module_map(&mut self, map: &ModuleMap) {
+        for module in map.modules() {
+            self.resolve_in_binary(module.path(), false, |frame| {
+                if !module.contains(frame.ip()) {
+                    return None;
+                }
+                Some(module.stated_address(frame.ip()))
+            });
+        }
+    }
+
+    /// Resolves each frame of this backtrace to a single symbol named after
+    /// the module of `map` its `ip` lies within and the offset into it, such
+    /// as `app.exe!0x1a2b`.
+    ///
+    /// This is the cheapest way of resolving a backtrace, meant for logging
+    /// large numbers of them: no debug information or export table is read
+    /// and no symbolication library is called, the frames are only looked up
+    /// in `map`. Captures of `ModuleMap` with the same version can share a
+    /// single map. Frames outside of any module of `map` fall back to the
+    /// ranges registered with `register_anonymous_module`, and otherwise end
+    /// up without symbols. Frames which are already resolved are left as
+    /// they are.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn resolve_offset_only(&mut self, map: &ModuleMap) {
+        for frame in self.frames.iter_mut().filter(|f| f.symbols.is_none()) {
+            let ip = frame.ip() as usize;
+            let module = map.module_at(ip as *mut c_void).map(|m| {
+                let path = m.path();
+                let name = path.file_name().unwrap_or(path.as_os_str());
+                (m.base() as usize, name.to_string_lossy().into_owned())
+            });
+            let symbols = match module.or_else(|| anonymous_module_at(ip)) {
+                Some((base, name)) => {
+                    let name = format!("{}!{:#x}", name, ip - base);
+                    vec![BacktraceSymbol::anonymous(name)]
+                }
+                None => Vec::new(),
+            };
+            frame.symbols = Some(symbols);
+        }
+    }
+
+    /// Writes the frames of this backtrace to `w` in a compact binary format,
+    /// meant for shipping many captured stacks, such as the samples of a
+    /// profiler, over the network.
+    ///
+    /// Only the addresses of the frames are written, along with the ids of the
+    /// threads they belong to if any frame has one. The base addresses of
+    /// modules are written once and referred to by index, instruction
+    /// pointers are written as the difference to the one of the previous
+    /// frame and symbol addresses as the difference to the instruction
+    /// pointer, all of them as variable-length integers. Frames typically take
+    /// 4 to 8 bytes this way, compared to hundreds with serde and JSON.
+    ///
+    /// Symbols aren't written, resolve the frames after decoding them with
+    /// `Backtrace::decode_compact`, for example with
+    /// `Backtrace::resolve_offline`.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn encode_compact<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        let frames = self.frames();
+        let mut modules = Vec::new();
+        let mut indices = HashMap::new();
+        for frame in frames {
+            if let Some(base) = frame.module_base_address() {
+                indices.entry(base as usize).or_insert_with(|| {
+                    modules.push(base as u64);
+                    modules.len() as u64
+                });
+            }
+        }
+        let thread_ids = frames.iter().any(|frame| frame.thread_id.is_some());
+
+        let mut buf = vec![COMPACT_VERSION, thread_ids as u8];
+        write_varint(&mut buf, modules.len() as u64);
+        for base in modules {
+            write_varint(&mut buf, base);
+        }
+        write_varint(&mut buf, frames.len() as u64);
+        let mut prev_ip = 0u64;
+        for frame in frames {
+            let ip = frame.ip() as u64;
+            let module = frame
+                .module_base_address()
+                .map_or(0, |base| indices[&(base as usize)]);
+            write_varint(&mut buf, module);
+            write_varint(&mut buf, zigzag(ip.wrapping_sub(prev_ip)));
+            write_varint(
+                &mut buf,
+                zigzag(ip.wrapping_sub(frame.symbol_address() as u64)),
+            );
+            if thread_ids {
+                write_varint(&mut buf, frame.thread_id.map_or(0, |id| u64::from(id) + 1));
+            }
+            prev_ip = ip;
+        }
+        w.write_all(&buf)
+    }
+
+    /// Reads a backtrace written by `Backtrace::encode_compact` from `r`.
+    ///
+    /// Exactly the bytes of one backtrace are read, so several backtraces
+    /// encoded one after the other can be decoded from the same reader. The
+    /// frames are unresolved.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the input isn't a backtrace
+    /// in the compact format, and any error reading from `r`, including
+    /// `UnexpectedEof` if the input ends early.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn decode_compact<R: io::Read>(mut r: R) -> io::Result<Backtrace> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut header = [0; 2];
+        r.read_exact(&mut header)?;
+        if header[0] != COMPACT_VERSION {
+            return Err(invalid("unsupported compact backtrace version"));
+        }
+        let thread_ids = header[1] & 1 != 0;
+
+        // The counts aren't trusted for preallocating, as the input may be
+        // corrupt.
+        let count = read_varint(&mut r)?;
+        let mut modules = Vec::with_capacity(count.min(64) as usize);
+        for _ in 0..count {
+            modules.push(read_varint(&mut r)? as usize);
+        }
+        let count = read_varint(&mut r)?;
+        let mut frames = Vec::with_capacity(count.min(1024) as usize);
+        let mut prev_ip = 0u64;
+        for _ in 0..count {
+            let module_base_address = match read_varint(&mut r)? {
+                0 => None,
+                i => Some(
+                    *modules
+                        .get(i as usize - 1)
+                        .ok_or_else(|| invalid("module index out of range"))?,
+                ),
+            };
+            let ip = prev_ip.wrapping_add(unzigzag(read_varint(&mut r)?));
+            let symbol_address = ip.wrapping_sub(unzigzag(read_varint(&mut r)?));
+            let thread_id = match if thread_ids { read_varint(&mut r)? } else { 0 } {
+                0 => None,
+                id if id <= 1 << 32 => Some((id - 1) as u32),
+                _ => return Err(invalid("thread id out of range")),
+            };
+            prev_ip = ip;
+            frames.push(BacktraceFrame {
+                frame: Frame::Deserialized {
+                    ip: ip as usize,
+                    symbol_address: symbol_address as usize,
+                    module_base_address,
+                },
+                symbols: None,
+                thread_id,
+            });
+        }
+        Ok(Backtrace::from(frames))
+    }
+
+    /// Resolves the unresolved frames `addr` returns an address for from the
+    /// binary at `path`, see `symbolize::resolve_offline` for what
+    /// `image_relative` means.
+    fn resolve_in_binary(
+        &mut self,
+        path: &Path,
+        image_relative: bool,
+        addr: impl Fn(&BacktraceFrame) -> Option<usize>,
+    ) {
+        let mut indices = Vec::new();
+        let mut addrs = Vec::new();
+        for (i, frame) in self.frames.iter().enumerate() {
+            if frame.symbols.is_some() {
+                continue;
+            }
+            // Like `resolve`, look up the call instruction rather than the
+            // return address.
+            if let Some(addr) = addr(frame).filter(|&addr| addr > 0) {
+                indices.push(i);
+                addrs.push(addr - 1);
+            }
+        }
+        if indices.is_empty() {
+            return;
+        }
+
+        let mut symbols = vec![Vec::new(); indices.len()];
+        let found = resolve_offline(path, &addrs, image_relative, &mut |i, symbol| {
+            symbols[i].push(BacktraceSymbol::new(symbol));
+        });
+        if found {
+            for (i, mut symbols) in indices.into_iter().zip(symbols) {
+                number_inlined_calls(&mut symbols);
+                self.frames[i].symbols = Some(symbols);
+            }
+        }
+    }
+}
+
+/// A backtrace which is only resolved once it's formatted.
+///
+/// Capturing a backtrace is cheap compared to resolving its symbols, and
+/// backtraces captured for logging are often never printed. This captures
+/// the addresses like `Backtrace::new_unresolved`, and resolves them the
+/// first time the backtrace is formatted with `{}` or `{:?}`, as the
+/// backtraces of the standard library do. The resolved symbols are kept for
+/// later formatting.
+///
+/// Both `Display` and `Debug` print the backtrace like the `Debug`
+/// implementation of `Backtrace`.
+///
+/// # Examples
+///
+/// ```
+/// use backtrace::LazyBacktrace;
+///
+/// let bt = LazyBacktrace::new(); // only the addresses are captured here
+/// println!("{}", bt); // symbols are resolved here
+/// ```
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+pub struct LazyBacktrace {
+    // Formatting only has a shared reference, so the backtrace is resolved
+    // behind a lock.
+    inner: Mutex<Backtrace>,
+}
+
+impl LazyBacktrace {
+    /// Captures a backtrace at the callsite of this function, without
+    /// resolving it.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    #[inline(never)] // want to make sure there's a frame here to remove
+    pub fn new() -> LazyBacktrace {
+        LazyBacktrace::from(Backtrace::create(Self::new as fn() -> _ as usize))
+    }
+
+    /// Returns the backtrace, which is resolved only if this has been
+    /// formatted before.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn into_backtrace(self) -> Backtrace {
+        self.inner.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn resolved(&self) -> std::sync::MutexGuard<'_, Backtrace> {
+        // Resolving only fills in symbols, so a panic while doing so leaves
+        // nothing inconsistent behind.
+        let mut bt = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        bt.resolve();
+        bt
+    }
+}
+
+impl Default for LazyBacktrace {
+    fn default() -> LazyBacktrace {
+        LazyBacktrace::new()
+    }
+}
+
+impl From<Backtrace> for LazyBacktrace {
+    fn from(bt: Backtrace) -> LazyBacktrace {
+        LazyBacktrace {
+            inner: Mutex::new(bt),
+        }
+    }
+}
+
+impl fmt::Display for LazyBacktrace {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.resolved(), fmt)
+    }
+}
+
+impl fmt::Debug for LazyBacktrace {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.resolved(), fmt)
+    }
+}
+
+/// Captures the stack of another thread into storage provided by the caller.
+///
+/// Frames are appended to `frames` without ever growing it: once the vector's
+/// spare capacity is used up the walk stops. Callers are expected to reserve
+/// enough room up front (e.g. with `Vec::with_capacity`) *before* calling this
+/// function.
+///
+/// Returns the number of frames that were appended along with the error that
+/// interrupted the trace, if any. Frames collected before an error occurred
+/// are kept, which makes truncated stacks available for best-effort crash
+/// reporting. No frames may have been appended at all, for example with
+/// `TraceError::NoFrames` if the stack couldn't be walked from the thread's
+/// registers, so don't assume a capture is non-empty.
+///
+/// The target thread is suspended for the duration of the walk. If it happened
+/// to be suspended while holding the lock of the global allocator (e.g. while
+/// inside `malloc`) then any allocation made on this thread before the target
+/// is resumed would deadlock the process. Collecting into pre-allocated
+/// storage means this crate performs no allocations while the target is
+/// stopped, avoiding that whole class of deadlocks. Note that this can't
+/// account for allocations made internally by `dbghelp.dll` itself.
+///
+/// The captured frames are unresolved, use `Backtrace::from` followed by
+/// `Backtrace::resolve` (after this function returns) to symbolize them. The
+/// walk also stops after `max_frames` frames.
+///
+/// # Safety
+///
+/// `thread` must be a valid thread handle with `THREAD_SUSPEND_RESUME` and
+/// `THREAD_GET_CONTEXT` access. See `trace_thread_unsynchronized` for more
+/// information about the risks of suspending another thread.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn capture_thread_in(
+    thread: crate::ThreadHandle,
+    frames: &mut Vec<BacktraceFrame>,
+) -> (usize, Option<crate::TraceError>) {
+    let marker = 0u8;
+    capture_thread_into(thread, frames, &marker)
+}
+
+/// Implementation of `capture_thread_in`, with `marker` belonging to the
+/// public entry point, see `trace_thread_from`.
+unsafe fn capture_thread_into(
+    thread: crate::ThreadHandle,
+    frames: &mut Vec<BacktraceFrame>,
+    marker: *const u8,
+) -> (usize, Option<crate::TraceError>) {
+    let max = max_frames();
+    let _guard = crate::lock::lock();
+    let start = frames.len();
+    let result = crate::backtrace::trace_thread_from(thread, marker, &mut |frame| {
+        if frames.len() == frames.capacity() {
+            return false;
+        }
+        frames.push(BacktraceFrame {
+            frame: Frame::Raw(frame.clone()),
+            symbols: None,
+            thread_id: None,
+        });
+        frames.len() - start < max
+    });
+    (frames.len() - start, result.err())
+}
+
+/// Captures the stack of another thread, pre-allocating room for `capacity`
+/// frames.
+///
+/// The frame vector is allocated before the target thread is suspended so no
+/// reallocation happens while it's stopped, keeping the suspension as short as
+/// possible. Since the vector is never grown at most `capacity` frames are
+/// captured, so pick a value large enough for the deepest stack of interest.
+/// See `capture_thread_in` for more details, including about the returned
+/// error.
+///
+/// # Safety
+///
+/// See `capture_thread_in`.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn capture_thread_with_capacity(
+    thread: crate::ThreadHandle,
+    capacity: usize,
+) -> (Backtrace, Option<crate::TraceError>) {
+    let marker = 0u8;
+    let mut frames = Vec::with_capacity(capacity);
+    let (_, err) = capture_thread_into(thread, &mut frames, &marker);
+    (Backtrace::from(frames), err)
+}
+
+/// A fixed-size ring of the most recent stacks sampled from other threads, for
+/// continuous low-overhead profiling.
+///
+/// All memory is allocated up front by `SampleRing::new`, after which
+/// `sample_thread` captures into the preallocated slots without allocating,
+/// overwriting the oldest sample once the ring is full. A typical use is a
+/// background thread sampling the threads of interest periodically, with the
+/// ring being dumped through `drain` when something goes wrong. The ring isn't
+/// synchronized itself, wrap it in a `Mutex` to share it between threads.
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn thread_handle() -> backtrace::ThreadHandle { unimplemented!() }
+/// use backtrace::SampleRing;
+///
+/// let mut ring = SampleRing::new(64, 128);
+/// let thread = thread_handle();
+/// for _ in 0..1000 {
+///     let _ = unsafe { ring.sample_thread(thread) };
+///     std::thread::sleep(std::time::Duration::from_millis(10));
+/// }
+/// for mut sample in ring.drain() {
+///     sample.resolve();
+///     println!("{:?}", sample);
+/// }
+/// ```
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[derive(Clone)]
+pub struct SampleRing {
+    // One more slot than the capacity, the slot at `next` isn't part of the
+    // ring so a failed sample doesn't destroy the oldest one.
+    slots: Vec<Vec<BacktraceFrame>>,
+    next: usize,
+    len: usize,
+}
+
+impl SampleRing {
+    /// Creates a ring holding up to `samples` stacks of at most `depth`
+    /// frames each.
+    ///
+    /// Frames beyond `depth` are dropped from a sample, so pick a value large
+    /// enough for the deepest stack of interest.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn new(samples: usize, depth: usize) -> SampleRing {
+        SampleRing {
+            slots: (0..samples + 1)
+                .map(|_| Vec::with_capacity(depth))
+                .collect(),
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the maximum number of samples held by the ring.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn capacity(&self) -> usize {
+        self.slots.len() - 1
+    }
+
+    /// Returns the number of samples currently held by the ring.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the ring holds no samples.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Captures the stack of `thread` into the ring, overwriting the oldest
+    /// sample if the ring is full.
+    ///
+    /// No memory is allocated, see `capture_thread_in` for why that matters
+    /// while another thread is suspended. A sample is only recorded if at
+    /// least one frame was captured, so a failed capture leaves the ring as it
+    /// was. Frames collected before an error are kept as a truncated sample,
+    /// and the error is returned.
+    ///
+    /// # Safety
+    ///
+    /// See `capture_thread_in`.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    #[inline(never)] // see `trace_thread_from`
+    pub unsafe fn sample_thread(
+        &mut self,
+        thread: crate::ThreadHandle,
+    ) -> Result<(), crate::TraceError> {
+        let marker = 0u8;
+        let slot = &mut self.slots[self.next];
+        slot.clear();
+        let (count, err) = capture_thread_into(thread, slot, &marker);
+        if count > 0 && self.slots.len() > 1 {
+            self.next = (self.next + 1) % self.slots.len();
+            self.len = (self.len + 1).min(self.capacity());
+        }
+        match err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes all samples from the ring, returning them oldest first.
+    ///
+    /// The samples are copied out so the ring keeps its preallocated slots
+    /// and can continue sampling without allocating. The returned backtraces
+    /// are unresolved, use `Backtrace::resolve` to symbolize them.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn drain(&mut self) -> Vec<Backtrace> {
+        let n = self.slots.len();
+        let start = (self.next + n - self.len) % n;
+        let samples = (0..self.len)
+            .map(|i| Backtrace::from(self.slots[(start + i) % n].clone()))
+            .collect();
+        self.len = 0;
+        samples
+    }
+}
+
+/// Samples the CPU usage of the threads of the process to trace the busiest
+/// ones first, for profilers with a limited time budget for each sample.
+///
+/// Each call to `snapshot_hot_threads` ranks the threads by the CPU time they
+/// consumed since the previous call, and traces the busiest threads one after
+/// the other until either enough of them were traced or the time budget is
+/// used up. Threads which are idle, such as the workers of a pool waiting for
+/// work, are ranked last, so the time spent with threads suspended goes to the
+/// threads actually running code. The first call ranks threads by the CPU time
+/// they consumed since they started.
+///
+/// # Examples
+///
+/// ```no_run
+/// use backtrace::HotThreadSampler;
+/// use std::time::Duration;
+///
+/// let mut sampler = HotThreadSampler::new();
+/// loop {
+///     let hot = unsafe { sampler.snapshot_hot_threads(Duration::from_millis(2), 4) }.unwrap();
+///     for (id, backtrace) in hot.traces() {
+///         println!("{}: {} frames", id, backtrace.frames().len());
+///     }
+///     std::thread::sleep(Duration::from_millis(100));
+/// }
+/// ```
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Debug, Default)]
+pub struct HotThreadSampler {
+    // Total CPU time of each thread at the previous sample.
+    previous: HashMap<u32, Duration>,
+}
+
+#[cfg(target_os = "windows")]
+impl HotThreadSampler {
+    /// Creates a sampler with no previous sample.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn new() -> HotThreadSampler {
+        HotThreadSampler::default()
+    }
+
+    /// Ranks the threads of the process by their recent CPU usage and traces
+    /// up to `n` of the busiest ones within `budget`.
+    ///
+    /// The calling thread, which is busy sampling, is left out. The budget is
+    /// checked before each thread is traced, so a single slow trace can
+    /// exceed it. Threads which can't be traced, for example because they
+    /// exited in the meantime, are skipped without counting towards `n`. Each
+    /// trace is captured like with `capture_thread_with_capacity`, into a
+    /// buffer of at most 1024 frames, and isn't resolved.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TraceError::EnumerationFailed` if the threads of the process
+    /// couldn't be enumerated.
+    ///
+    /// # Safety
+    ///
+    /// See `capture_thread_in`.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    #[inline(never)] // see `trace_thread_from`
+    pub unsafe fn snapshot_hot_threads(
+        &mut self,
+        budget: Duration,
+        n: usize,
+    ) -> Result<HotThreads, crate::TraceError> {
+        let marker = 0u8;
+        let start = Instant::now();
+        let options = crate::ThreadSnapshotOptions::new().cpu_times(true);
+        let mut previous = HashMap::new();
+        let mut ranking = Vec::new();
+        for info in crate::list_threads(&options)? {
+            let total = match (info.kernel_time(), info.user_time()) {
+                (Some(kernel), Some(user)) => kernel + user,
+                _ => continue,
+            };
+            previous.insert(info.id(), total);
+            if info.is_current() {
+                continue;
+            }
+            let before = self.previous.get(&info.id()).cloned().unwrap_or_default();
+            ranking.push(ThreadCpuUsage {
+                id: info.id(),
+                recent: total.checked_sub(before).unwrap_or_default(),
+                total,
+            });
+        }
+        self.previous = previous;
+        ranking.sort_by(|a, b| b.recent.cmp(&a.recent));
+
+        let mut traces = Vec::new();
+        let mut scratch = Vec::with_capacity(max_frames().min(THREAD_FRAMES_CAPACITY));
+        for usage in &ranking {
+            if traces.len() == n || start.elapsed() >= budget {
+                break;
+            }
+            scratch.clear();
+            let traced = crate::backtrace::with_thread(usage.id, |thread| {
+                capture_thread_into(thread, &mut scratch, &marker)
+            });
+            if let Some((count, _)) = traced {
+                if count > 0 {
+                    let id = Some(usage.id);
+                    let frames = scratch.drain(..).map(|frame| BacktraceFrame {
+                        thread_id: id,
+                        ..frame
+                    });
+                    traces.push((usage.id, Backtrace::from(frames.collect::<Vec<_>>())));
+                }
+            }
+        }
+        Ok(HotThreads { ranking, traces })
+    }
+}
+
+/// The CPU usage of a thread, as ranked by `HotThreadSampler`.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThreadCpuUsage {
+    id: u32,
+    recent: Duration,
+    total: Duration,
+}
+
+#[cfg(target_os = "windows")]
+impl ThreadCpuUsage {
+    /// Returns the id of the thread.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the CPU time the thread consumed since the previous sample,
+    /// which it's ranked by.
+    pub fn recent(&self) -> Duration {
+        self.recent
+    }
+
+    /// Returns the CPU time the thread consumed since it started, in kernel
+    /// and user mode.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+}
+
+/// The result of `HotThreadSampler::snapshot_hot_threads`.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(target_os = "windows")]
+#[derive(Clone)]
+pub struct HotThreads {
+    ranking: Vec<ThreadCpuUsage>,
+    traces: Vec<(u32, Backtrace)>,
+}
+
+#[cfg(target_os = "windows")]
+impl HotThreads {
+    /// Returns all threads of the process except the calling one, busiest
+    /// first, in the order they were considered for tracing.
+    pub fn ranking(&self) -> &[ThreadCpuUsage] {
+        &self.ranking
+    }
+
+    /// Returns the id and stack of each thread which was traced, in the order
+    /// of `ranking`. The frames of each stack are tagged with the thread id,
+    /// see `BacktraceFrame::thread_id`.
+    pub fn traces(&self) -> &[(u32, Backtrace)] {
+        &self.traces
+    }
+
+    /// Consumes this snapshot, returning the traces.
+    pub fn into_traces(self) -> Vec<(u32, Backtrace)> {
+        self.traces
+    }
+}
+
+// Size of the fields of a frame record written by `trace_thread_to_writer`,
+// not counting the length prefix.
+const FRAME_RECORD_LEN: usize = 32;
+
+/// Streams the stack of another thread to `w` as it's walked, without
+/// collecting the frames in memory.
+///
+/// This is meant for capturing very deep stacks or many threads where memory
+/// is tight. Each frame is written as one record, a length byte followed by
+/// that many bytes holding the instruction pointer, stack pointer, symbol
+/// address and module base address of the frame as little-endian 64-bit
+/// integers, the module base being zero if unknown. Readers skip any bytes
+/// past the fields they know, so records may grow in the future. Use
+/// `FrameReader` to turn the records back into frames for resolving.
+///
+/// Returns the number of frames written along with the error that
+/// interrupted the trace, if any, see `capture_thread_in`. If writing fails
+/// the walk is stopped and the I/O error is returned instead. The walk also
+/// stops after `max_frames` frames.
+///
+/// # Safety
+///
+/// See `capture_thread_in`. In addition, `thread` is suspended while `w` is
+/// written to, so `w` must not take locks or allocate memory, which the
+/// suspended thread might be holding the lock of. An unbuffered `File` is
+/// fine, a `BufWriter` is once its buffer has been allocated.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn trace_thread_to_writer<W: io::Write>(
+    thread: crate::ThreadHandle,
+    mut w: W,
+) -> io::Result<(usize, Option<crate::TraceError>)> {
+    let marker = 0u8;
+    let max = max_frames();
+    let mut written = 0;
+    let mut io_error = None;
+    let _guard = crate::lock::lock();
+    let result = crate::backtrace::trace_thread_from(thread, &marker, &mut |frame| {
+        let fields = [
+            frame.ip() as usize,
+            frame.sp() as usize,
+            frame.symbol_address() as usize,
+            frame.module_base_address().map_or(0, |base| base as usize),
+        ];
+        let mut record = [0; 1 + FRAME_RECORD_LEN];
+        record[0] = FRAME_RECORD_LEN as u8;
+        for (chunk, field) in record[1..].chunks_mut(8).zip(&fields) {
+            chunk.copy_from_slice(&(*field as u64).to_le_bytes());
+        }
+        if let Err(e) = w.write_all(&record) {
+            io_error = Some(e);
+            return false;
+        }
+        written += 1;
+        written < max
+    });
+    drop(_guard);
+    match io_error {
+        Some(e) => Err(e),
+        None => Ok((written, result.err())),
+    }
+}
+
+/// Reads back the frames written by `trace_thread_to_writer`.
+///
+/// This is an iterator over the frames in the order they were written, most
+/// recent first, ending at the end of the input. The frames are unresolved,
+/// collect them into a `Backtrace` with `Backtrace::from` and use
+/// `Backtrace::resolve` or `Backtrace::resolve_offline` to symbolize them.
+/// Stack pointers aren't part of `BacktraceFrame` and are dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// use backtrace::{Backtrace, FrameReader};
+///
+/// let file = std::fs::File::open("stack.bin")?;
+/// let frames = FrameReader::new(std::io::BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+/// let mut bt = Backtrace::from(frames);
+/// bt.resolve();
+/// println!("{:?}", bt);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+}
+
+impl<R: io::Read> FrameReader<R> {
+    /// Creates a reader of the frame records in `inner`.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn new(inner: R) -> FrameReader<R> {
+        FrameReader { inner }
+    }
+
+    /// Returns the underlying reader.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_frame(&mut self) -> io::Result<Option<BacktraceFrame>> {
+        let mut len = [0];
+        loop {
+            match self.inner.read(&mut len) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut record = [0; 255];
+        let record = &mut record[..len[0] as usize];
+        self.inner.read_exact(record)?;
+        if record.len() < FRAME_RECORD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame record too short",
+            ));
+        }
+        let mut fields = [0; 4];
+        for (field, chunk) in fields.iter_mut().zip(record.chunks(8)) {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(chunk);
+            *field = u64::from_le_bytes(bytes) as usize;
+        }
+        let [ip, _sp, symbol_address, module_base] = fields;
+        Ok(Some(BacktraceFrame::from_parts(
+            ip as *mut c_void,
+            symbol_address as *mut c_void,
+            if module_base == 0 {
+                None
+            } else {
+                Some(module_base as *mut c_void)
+            },
+        )))
+    }
+}
+
+impl<R: io::Read> Iterator for FrameReader<R> {
+    type Item = io::Result<BacktraceFrame>;
+
+    fn next(&mut self) -> Option<io::Result<BacktraceFrame>> {
+        self.read_frame().transpose()
+    }
+}
+
+// The first byte written by `Backtrace::encode_compact`, bumped whenever the
+// format changes.
+const COMPACT_VERSION: u8 = 1;
+
+// Appends `value` to `buf` as a LEB128 variable-length integer, seven bits
+// per byte starting with the lowest ones.
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint<R: io::Read>(r: &mut R) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        r.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "variable-length integer too long",
+    ))
+}
+
+// Maps differences close to zero in either direction to small integers, so
+// they're encoded in few bytes.
+fn zigzag(value: u64) -> u64 {
+    let value = value as i64;
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> u64 {
+    (value >> 1) ^ (value & 1).wrapping_neg()
+}
+
+/// Resolves a single address to its symbols, without capturing a backtrace.
+///
+/// This is useful to symbolize addresses collected some other way, such as
+/// from a log or a sampling profiler. Like `BacktraceFrame::symbols` this
+/// returns a symbol for each function inlined at `ip`, innermost first,
+/// followed by the function actually containing it. The returned list is
+/// empty if `ip` couldn't be resolved.
+///
+/// Like with `resolve`, `ip` is assumed to be a return address as found on
+/// the stack, so the instruction just before it is looked up.
+///
+/// # Examples
+///
+/// ```
+/// let ip = backtrace::resolve_address as *mut std::ffi::c_void;
+/// for symbol in backtrace::resolve_address(ip) {
+///     println!("{:?}", symbol.name());
+/// }
+/// ```
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+pub fn resolve_address(ip: *mut c_void) -> Vec<BacktraceSymbol> {
+    let mut symbols = Vec::new();
+    resolve(ip, |symbol| symbols.push(BacktraceSymbol::new(symbol)));
+    number_inlined_calls(&mut symbols);
+    symbols
+}
+
+/// Sets the inline depth of `symbols`, all of which one address resolved to,
+/// innermost first.
+///
+/// Backends only know the depth of an inlined call resolved on its own, like
+/// one of the frames `StackWalkEx` expands them into. When an address
+/// resolves to a chain of calls, their depth follows from their position.
+fn number_inlined_calls(symbols: &mut [BacktraceSymbol]) {
+    let outermost = symbols.len().saturating_sub(1);
+    if outermost > 0 {
+        for (i, symbol) in symbols.iter_mut().enumerate() {
+            symbol.inline_depth = (outermost - i) as u32;
+        }
+    }
+}
+
+/// Returns the chain of inlined calls at the instruction at `ip`, innermost
+/// first, followed by the function actually containing it.
+///
+/// Unlike `resolve_address`, `ip` is the address of the instruction of
+/// interest itself rather than a return address, which is what a disassembler
+/// or profiler annotating arbitrary instructions has at hand. On Windows the
+/// chain comes from dbghelp's inline trace, elsewhere from the inlining
+/// information of the DWARF debug info. The returned list is empty if `ip`
+/// couldn't be resolved.
+///
+/// # Examples
+///
+/// ```
+/// let ip = backtrace::inline_frames_at as *mut std::ffi::c_void;
+/// for symbol in backtrace::inline_frames_at(ip) {
+///     println!("{:?} at {:?}:{:?}", symbol.name(), symbol.filename(), symbol.lineno());
+/// }
+/// ```
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+pub fn inline_frames_at(ip: *mut c_void) -> Vec<BacktraceSymbol> {
+    // Resolution always looks up the instruction before the address it's
+    // given, see `adjust_ip`, so pass the address of the next byte.
+    resolve_address((ip as usize).wrapping_add(1) as *mut c_void)
+}
+
+/// Captures the innermost `head` and outermost `tail` frames of another
+/// thread's stack, leaving out the frames in between.
+///
+/// This keeps crash reports of very deep stacks compact while preserving both
+/// where the thread currently is and how it got started. The whole stack is
+/// still walked to find its outermost frames, but only `head + tail` frames
+/// are kept, in buffers allocated before the thread is suspended. The number
+/// of frames left out is available through `HeadTailBacktrace::omitted` and
+/// shown as a marker when the backtrace is printed. `max_frames` doesn't apply
+/// to this function.
+///
+/// Returns the error that interrupted the trace, if any, along with the frames
+/// collected before it occurred. See `capture_thread_in` for more details.
+///
+/// # Safety
+///
+/// See `capture_thread_in`.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[inline(never)] // see `trace_thread_from`
+pub unsafe fn capture_thread_head_tail(
+    thread: crate::ThreadHandle,
+    head: usize,
+    tail: usize,
+) -> (HeadTailBacktrace, Option<crate::TraceError>) {
+    let mut head_frames = Vec::with_capacity(head);
+    // Once full this is used as a ring buffer holding the last `tail` frames.
+    let mut tail_frames = Vec::with_capacity(tail);
+    let mut total = 0;
+    let marker = 0u8;
+    let _guard = crate::lock::lock();
+    let result = crate::backtrace::trace_thread_from(thread, &marker, &mut |frame| {
+        let frame = BacktraceFrame {
+            frame: Frame::Raw(frame.clone()),
+            symbols: None,
+            thread_id: None,
+        };
+        if head_frames.len() < head {
+            head_frames.push(frame);
+        } else if tail_frames.len() < tail {
+            tail_frames.push(frame);
+        } else if tail > 0 {
+            tail_frames[(total - head) % tail] = frame;
+        }
+        total += 1;
+        true
+    });
+    drop(_guard);
+
+    let walked_tail = total - head_frames.len();
+    if tail > 0 && walked_tail > tail {
+        tail_frames.rotate_left(walked_tail % tail);
+    }
+    let head = head_frames.len();
+    let omitted = total - head - tail_frames.len();
+    head_frames.extend(tail_frames);
+    let bt = HeadTailBacktrace {
+        frames: Backtrace::from(head_frames),
+        head,
+        omitted,
+    };
+    (bt, result.err())
+}
+
+/// The innermost and outermost frames of a thread's stack, as captured by
+/// `capture_thread_head_tail`.
+///
+/// The `Debug` implementation prints the frames like `Backtrace` does, with a
+/// marker in place of the omitted frames.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[derive(Clone)]
+pub struct HeadTailBacktrace {
+    frames: Backtrace,
+    head: usize,
+    omitted: usize,
+}
+
+impl HeadTailBacktrace {
+    /// Returns the innermost frames, most recent first.
+    pub fn head(&self) -> &[BacktraceFrame] {
+        &self.frames.frames()[..self.head]
+    }
+
+    /// Returns the outermost frames, ending with the thread's entry point.
+    pub fn tail(&self) -> &[BacktraceFrame] {
+        &self.frames.frames()[self.head..]
+    }
+
+    /// Returns the number of frames left out between `head` and `tail`.
+    pub fn omitted(&self) -> usize {
+        self.omitted
+    }
+
+    /// Resolves the symbols of the captured frames, see `Backtrace::resolve`.
+    pub fn resolve(&mut self) {
+        self.frames.resolve();
+    }
+}
+
+// Bounds the walk of the exception record chain, which could loop forever if
+// the records were corrupted by the crash.
+#[cfg(target_os = "windows")]
+const MAX_NESTED_EXCEPTIONS: usize = 64;
+
+// The number of instruction bytes recorded on each side of the faulting
+// instruction, see `CrashInfo::code_bytes`.
+#[cfg(target_os = "windows")]
+const CODE_BYTES_BEFORE: usize = 16;
+#[cfg(target_os = "windows")]
+const CODE_BYTES_AFTER: usize = 16;
+
+/// Captures the stack and the exception records of an exception, from the
+/// `EXCEPTION_POINTERS` passed to an exception filter or vectored exception
+/// handler.
+///
+/// The backtrace starts at the faulting instruction, see
+/// `trace_exception`. Besides the exception being raised, the chain of records
+/// linked through `EXCEPTION_RECORD::ExceptionRecord` is recorded too, which
+/// describes the exceptions that were being handled when this one was raised,
+/// for example when a destructor faults during unwinding. The instruction
+/// bytes around the faulting address are copied as well, so the fault site
+/// can be disassembled without the binary. The frames aren't resolved, call
+/// `CrashInfo::resolve` once it's safe to do so.
+///
+/// If the walk stopped early the error is returned alongside the frames which
+/// were collected up to that point.
+///
+/// # Safety
+///
+/// `pointers` must point to a valid `EXCEPTION_POINTERS` whose records are
+/// valid for reads.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(target_os = "windows")]
+pub unsafe fn capture_exception(pointers: *mut c_void) -> (CrashInfo, Option<crate::TraceError>) {
+    use crate::windows::EXCEPTION_POINTERS;
+
+    let mut frames = Vec::new();
+    let result = {
+        let _guard = crate::lock::lock();
+        crate::trace_exception(pointers, |frame| {
+            frames.push(BacktraceFrame {
+                frame: Frame::Raw(frame.clone()),
+                symbols: None,
+                thread_id: None,
+            });
+            true
+        })
+    };
+
+    let mut exceptions = Vec::new();
+    let mut record = (*(pointers as *const EXCEPTION_POINTERS)).ExceptionRecord;
+    while !record.is_null() && exceptions.len() <= MAX_NESTED_EXCEPTIONS {
+        exceptions.push(NestedException {
+            code: (*record).ExceptionCode,
+            address: (*record).ExceptionAddress as usize,
+        });
+        record = (*record).ExceptionRecord;
+    }
+    let outer = if exceptions.is_empty() {
+        NestedException {
+            code: 0,
+            address: 0,
+        }
+    } else {
+        exceptions.remove(0)
+    };
+    let (code_start, code_bytes) = read_code_window(outer.address);
+
+    let info = CrashInfo {
+        backtrace: Backtrace::from(frames),
+        exception_code: outer.code,
+        exception_address: outer.address,
+        nested: exceptions,
+        code_start,
+        code_bytes,
+    };
+    (info, result.err())
+}
+
+/// Copies up to `CODE_BYTES_BEFORE` bytes before and `CODE_BYTES_AFTER` bytes
+/// from `ip`, returning the address of the first byte copied.
+///
+/// The faulting address may be bogus or sit next to an unmapped page, so the
+/// memory is read with `ReadProcessMemory` rather than dereferenced, and each
+/// half is dropped if it isn't fully readable.
+#[cfg(target_os = "windows")]
+fn read_code_window(ip: usize) -> (usize, Vec<u8>) {
+    use crate::windows::{GetCurrentProcess, ReadProcessMemory, LPCVOID, SIZE_T, TRUE};
+
+    let read = |addr: usize, buf: &mut [u8]| {
+        let mut n = 0;
+        let ok = unsafe {
+            ReadProcessMemory(
+                GetCurrentProcess(),
+                addr as LPCVOID,
+                buf.as_mut_ptr().cast(),
+                buf.len() as SIZE_T,
+                &mut n,
+            )
+        };
+        ok == TRUE && n == buf.len() as SIZE_T
+    };
+
+    let before = CODE_BYTES_BEFORE.min(ip);
+    let mut head = [0; CODE_BYTES_BEFORE];
+    let mut tail = [0; CODE_BYTES_AFTER];
+    let mut start = ip;
+    let mut bytes = Vec::with_capacity(CODE_BYTES_BEFORE + CODE_BYTES_AFTER);
+    if read(ip - before, &mut head[..before]) {
+        start = ip - before;
+        bytes.extend_from_slice(&head[..before]);
+    }
+    if read(ip, &mut tail) {
+        bytes.extend_from_slice(&tail);
+    }
+    (start, bytes)
+}
+
+/// The stack and exception records of an exception, as captured by
+/// `capture_exception`.
+///
+/// The `Debug` implementation prints the exception codes and addresses
+/// followed by the backtrace.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(target_os = "windows")]
+#[derive(Clone)]
+pub struct CrashInfo {
+    backtrace: Backtrace,
+    exception_code: u32,
+    exception_address: usize,
+    nested: Vec<NestedException>,
+    code_start: usize,
+    code_bytes: Vec<u8>,
+}
+
+#[cfg(target_os = "windows")]
+impl CrashInfo {
+    /// Returns the stack at the point the exception was raised.
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+
+    /// Returns the code of the exception, for example `0xc0000005` for an
+    /// access violation.
+    pub fn exception_code(&self) -> u32 {
+        self.exception_code
+    }
+
+    /// Returns the address of the instruction which raised the exception.
+    pub fn exception_address(&self) -> *mut c_void {
+        self.exception_address as *mut c_void
+    }
+
+    /// Returns the exceptions which were being handled when this one was
+    /// raised, the most recent first.
+    pub fn nested(&self) -> &[NestedException] {
+        &self.nested
+    }
+
+    /// Returns the instruction bytes around the faulting instruction, at
+    /// most 16 before and 16 from `exception_address`.
+    ///
+    /// The bytes are read while handling the exception, so the fault site
+    /// can be disassembled later even if the exact binary isn't available.
+    /// Either side is left out if it couldn't be read, and the slice is
+    /// empty if none of it could.
+    pub fn code_bytes(&self) -> &[u8] {
+        &self.code_bytes
+    }
+
+    /// Returns the address of the first of the `code_bytes`.
+    pub fn code_start(&self) -> *mut c_void {
+        self.code_start as *mut c_void
+    }
+
+    /// Resolves the symbols of the backtrace, see `Backtrace::resolve`.
+    pub fn resolve(&mut self) {
+        self.backtrace.resolve();
+    }
+}
+
+/// An exception from the chain of records of a `CrashInfo`.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NestedException {
+    code: u32,
+    address: usize,
+}
+
+#[cfg(target_os = "windows")]
+impl NestedException {
+    /// Returns the code of the exception.
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    /// Returns the address of the instruction which raised the exception.
+    pub fn address(&self) -> *mut c_void {
+        self.address as *mut c_void
+    }
+}
+
+/// A bounded cache of the symbols frames resolved to, see
+/// `Backtrace::resolve_with_cache`.
+///
+/// Frames are keyed by their instruction pointer, and for inline frames by
+/// their position in the inline stack. Once the cache is full the oldest
+/// entries are evicted first. The cache is emptied automatically when
+/// `refresh_modules` is called.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[derive(Clone, Debug)]
+pub struct SymbolCache {
+    symbols: HashMap<(usize, u32), Vec<BacktraceSymbol>>,
+    // Keys in insertion order, the oldest first.
+    order: VecDeque<(usize, u32)>,
+    capacity: usize,
+    generation: usize,
+}
+
+impl SymbolCache {
+    /// Creates an empty cache holding the symbols of up to `capacity`
+    /// frames.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn new(capacity: usize) -> SymbolCache {
+        SymbolCache {
+            symbols: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            generation: crate::symbolize::modules_generation(),
+        }
+    }
+
+    /// Returns the maximum number of frames this cache holds.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of frames currently cached.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Returns whether no frames are cached.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Removes all frames from the cache.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn clear(&mut self) {
+        self.symbols = HashMap::new();
+        self.order = VecDeque::new();
+    }
+
+    fn invalidate_if_stale(&mut self) {
+        let generation = crate::symbolize::modules_generation();
+        if self.generation != generation {
+            self.clear();
+            self.generation = generation;
+        }
+    }
+
+    fn insert(&mut self, key: (usize, u32), symbols: Vec<BacktraceSymbol>) {
+        if self.capacity == 0 || self.symbols.insert(key, symbols).is_some() {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.symbols.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+    }
+}
+
+// Frames held by the cache used by `Backtrace::resolve_cached`.
+const GLOBAL_SYMBOL_CACHE_CAPACITY: usize = 4096;
+
+static mut GLOBAL_SYMBOL_CACHE: *mut Mutex<SymbolCache> = 0 as *mut _;
+static GLOBAL_SYMBOL_CACHE_INIT: Once = Once::new();
+
+fn with_global_symbol_cache<R>(f: impl FnOnce(&mut SymbolCache) -> R) -> R {
+    let cache = unsafe {
+        GLOBAL_SYMBOL_CACHE_INIT.call_once(|| {
+            let cache = SymbolCache::new(GLOBAL_SYMBOL_CACHE_CAPACITY);
+            GLOBAL_SYMBOL_CACHE = Box::into_raw(Box::new(Mutex::new(cache)));
+        });
+        &*GLOBAL_SYMBOL_CACHE
+    };
+    // A panic while resolving leaves the cache consistent, there's no need
+    // to propagate the poison.
+    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut cache)
+}
+
+/// Empties the cache used by `Backtrace::resolve_cached`, for
+/// `clear_symbol_cache`.
+///
+/// This must not be called with the crate lock held, `resolve_cached` takes
+/// that lock while holding the cache's.
+pub(crate) fn clear_global_symbol_cache() {
+    with_global_symbol_cache(|cache| cache.clear());
+}
+
+/// How well the frames of a backtrace resolved, see
+/// `Backtrace::resolve_with_stats`.
+///
+/// Every frame is counted in exactly one of `lines`, `symbols_only`,
+/// `modules_only` and `unresolved`, according to the most detailed
+/// information found for it.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResolveStats {
+    lines: usize,
+    symbols: usize,
+    modules: usize,
+    unresolved: usize,
+    debug_info: usize,
+}
+
+impl ResolveStats {
+    /// Returns the number of frames with a symbol carrying a line number.
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// Returns the number of frames resolved to a symbol name, but without
+    /// line information.
+    pub fn symbols_only(&self) -> usize {
+        self.symbols
+    }
+
+    /// Returns the number of frames which didn't resolve to a symbol, but
+    /// whose module is known.
+    pub fn modules_only(&self) -> usize {
+        self.modules
+    }
+
+    /// Returns the number of frames nothing is known about.
+    pub fn unresolved(&self) -> usize {
+        self.unresolved
+    }
+
+    /// Returns the number of frames whose symbols came from debug
+    /// information, `SymbolKind::Pdb` or `SymbolKind::Dwarf`, rather than
+    /// from an export or symbol table.
+    pub fn from_debug_info(&self) -> usize {
+        self.debug_info
+    }
+
+    /// Returns the total number of frames.
+    pub fn total(&self) -> usize {
+        self.lines + self.symbols + self.modules + self.unresolved
+    }
+}
+
+// Frames captured per thread by `Backtrace::new_for_threads`.
+const THREAD_FRAMES_CAPACITY: usize = 1024;
+
+/// Returns the maximum number of frames captured by `Backtrace::new`,
+/// `Backtrace::new_unresolved` and `capture_thread_in`.
+///
+/// The limit is read from the `BACKTRACE_MAX_FRAMES` environment variable the
+/// first time it's needed and cached for the rest of the process, so changing
+/// the variable afterwards has no effect. If it's unset or not a number
+/// captures are unbounded, except for the safety cap each unwinder applies
+/// to guard against corrupt stacks. Frames skipped at the top of a `Backtrace`
+/// don't count towards the limit.
+///
+/// This only affects the `Backtrace` capture APIs, `trace` and the other
+/// callback based functions always pass every frame to their callback.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+pub fn max_frames() -> usize {
+    // 0 means the variable hasn't been read yet, otherwise the limit plus one.
+    static MAX_FRAMES: AtomicUsize = AtomicUsize::new(0);
+    match MAX_FRAMES.load(Ordering::Relaxed) {
+        0 => {}
+        n => return n - 1,
+    }
+    let max = parse_max_frames(env::var_os("BACKTRACE_MAX_FRAMES").as_deref());
+    MAX_FRAMES.store(max.saturating_add(1), Ordering::Relaxed);
+    max.min(usize::MAX - 1)
+}
+
+fn parse_max_frames(var: Option<&OsStr>) -> usize {
+    var.and_then(|s| s.to_str())
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(usize::MAX)
+}
+
+/// Formats `bt` as a single line of the folded stack format consumed by
+/// flamegraph tools such as `flamegraph.pl` and `inferno`.
+///
+/// The line lists the function names of the stack from the outermost caller
+/// to the innermost frame, separated by `;`, followed by a space and a sample
+/// count of `1`. Inlined functions are expanded into separate entries, and
+/// names are demangled without their trailing hash. Frames which are
+/// unresolved or have no symbol name are written as their instruction
+/// pointer. Any `;` within a name, as can appear in array types, is replaced
+/// with `:` so it isn't taken for a separator.
+///
+/// Lines for multiple samples can be concatenated, and identical stacks are
+/// summed by the flamegraph tools themselves. A backtrace without any frames
+/// yields an empty string rather than a sample with an empty stack.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+pub fn fold_stack(bt: &Backtrace) -> String {
+    let mut folded = String::new();
+    if bt.frames().is_empty() {
+        return folded;
+    }
+    for frame in bt.frames().iter().rev() {
+        let mut named = false;
+        for symbol in frame.symbols().iter().rev() {
+            if let Some(name) = symbol.demangled(DemangleStyle::NoHash) {
+                if !folded.is_empty() {
+                    folded.push(';');
+                }
+                folded.push_str(&name.replace(';', ":"));
+                named = true;
+            }
+        }
+        if !named {
+            if !folded.is_empty() {
+                folded.push(';');
+            }
+            folded.push_str(&format!("{:?}", frame.ip()));
+        }
+    }
+    folded.push_str(" 1");
+    folded
+}
+
+impl From<Vec<BacktraceFrame>> for Backtrace {
+    fn from(frames: Vec<BacktraceFrame>) -> Self {
+        Backtrace {
+            frames,
+            actual_start_index: 0,
+            include_internal: false,
+        }
+    }
+}
+
+impl From<crate::Frame> for BacktraceFrame {
+    fn from(frame: crate::Frame) -> BacktraceFrame {
+        BacktraceFrame {
+            frame: Frame::Raw(frame),
+            symbols: None,
+            thread_id: None,
+        }
+    }
+}
+
+impl Into<Vec<BacktraceFrame>> for Backtrace {
+    fn into(self) -> Vec<BacktraceFrame> {
+        self.frames
+    }
+}
+
+/// Iterates over the frames listed by `Backtrace::frames`.
+impl<'a> IntoIterator for &'a Backtrace {
+    type Item = &'a BacktraceFrame;
+    type IntoIter = std::slice::Iter<'a, BacktraceFrame>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.frames().iter()
+    }
+}
+
+/// Iterates over the frames returned by `Backtrace::into_frames`.
+impl IntoIterator for Backtrace {
+    type Item = BacktraceFrame;
+    type IntoIter = std::vec::IntoIter<BacktraceFrame>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_frames().into_iter()
+    }
+}
+
+impl BacktraceFrame {
+    /// Creates an unresolved frame from raw addresses.
+    ///
+    /// This is useful for reconstructing a backtrace from a source other than
+    /// this crate, for example a log, a minidump or the stack walker of a
+    /// managed runtime. The resulting frame can be resolved with
+    /// `Backtrace::resolve` like any other, which will succeed if `ip` is a
+    /// valid address in the current process.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn from_parts(
+        ip: *mut c_void,
+        symbol_address: *mut c_void,
+        module_base_address: Option<*mut c_void>,
+    ) -> BacktraceFrame {
+        BacktraceFrame {
+            frame: Frame::Deserialized {
+                ip: ip as usize,
+                symbol_address: symbol_address as usize,
+                module_base_address: module_base_address.map(|addr| addr as usize),
+            },
+            symbols: None,
+            thread_id: None,
+        }
+    }
+
+    /// Same as `Frame::ip`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn ip(&self) -> *mut c_void {
+        self.frame.ip() as *mut c_void
+    }
+
+    /// Same as `Frame::sp`
+    ///
+    /// Frames created with `from_parts` or deserialized don't carry this
+    /// information and return a null pointer.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn sp(&self) -> *mut c_void {
+        self.frame.sp()
+    }
+
+    /// Same as `Frame::symbol_address`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn symbol_address(&self) -> *mut c_void {
+        self.frame.symbol_address() as *mut c_void
+    }
+
+    /// Same as `Frame::module_base_address`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn module_base_address(&self) -> Option<*mut c_void> {
+        self.frame
+            .module_base_address()
+            .map(|addr| addr as *mut c_void)
+    }
+
+    /// Same as `Frame::is_inline`
+    ///
+    /// Frames created with `from_parts` or deserialized don't carry this
+    /// information and are never inline.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn is_inline(&self) -> bool {
+        match self.frame {
+            Frame::Raw(ref f) => f.is_inline(),
+            Frame::Deserialized { .. } => false,
+        }
+    }
+
+    /// Returns the name of the module this frame belongs to along with the
+    /// offset of `ip` from the start of that module.
+    ///
+    /// This is the cheapest form of symbolication available: it only requires
+    /// the module's base address and doesn't consult any debug information, so
+    /// it works even when no symbols are available. The result is typically
+    /// printed in the `module!0x1234` form.
+    ///
+    /// Code outside of any module loaded by the OS, such as JIT-compiled code,
+    /// is attributed to the range registered for it with
+    /// `register_anonymous_module`, if any.
+    ///
+    /// Returns `None` if the module base address of this frame isn't known or
+    /// no module is loaded at that address in the current process, and no
+    /// range is registered for `ip` either.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn module_offset(&self) -> Option<(String, u64)> {
+        let ip = self.ip() as u64;
+        if let Some(base) = self.module_base_address().filter(|b| !b.is_null()) {
+            if let Some(name) = module_name(base) {
+                return Some((name, ip.wrapping_sub(base as u64)));
+            }
+        }
+        let (base, name) = anonymous_module_at(ip as usize)?;
+        Some((name, ip - base as u64))
+    }
+
+    /// Returns the offset of `ip` from the base address of its module, its
+    /// relative virtual address (RVA).
+    ///
+    /// Unlike `ip`, this doesn't depend on where address space layout
+    /// randomization placed the module, so it's what identifies the
+    /// instruction across processes and machines. `Backtrace::resolve_offline`
+    /// looks frames up by their RVA, and serialized frames carry it along with
+    /// `ip` for other tools to use.
+    ///
+    /// Returns `None` if the module base address of this frame isn't known or
+    /// `ip` is below it.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn rva(&self) -> Option<u64> {
+        let base = self.module_base_address()?;
+        if base.is_null() {
+            return None;
+        }
+        (self.ip() as u64).checked_sub(base as u64)
+    }
+
+    /// Resolves both the call instruction of this frame and the instruction
+    /// its `ip` returns to, returning the symbols of the call followed by
+    /// those of the return address.
+    ///
+    /// `ip` of a frame other than the innermost one is a return address, the
+    /// instruction after the call which is still executing, and its line is
+    /// typically the one after the call or even that of a different statement.
+    /// Resolution therefore looks up the instruction before `ip`, so the
+    /// symbols of the call are what `symbols` holds as well. This is right in
+    /// most cases, but tools mapping frames precisely to source code may want
+    /// to see both and pick one themselves.
+    ///
+    /// Like with `symbols`, there is one symbol for each function inlined at
+    /// either address, innermost first, and the lists are empty if the address
+    /// couldn't be resolved. The frame itself is left unresolved.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn call_and_return_symbols(&self) -> (Vec<BacktraceSymbol>, Vec<BacktraceSymbol>) {
+        let ip = self.ip();
+        if ip.is_null() {
+            return (Vec::new(), Vec::new());
+        }
+        (resolve_address(ip), inline_frames_at(ip))
+    }
+
+    /// Returns whether the symbols of this frame have been resolved.
+    ///
+    /// Frames of a backtrace created with `new_unresolved` are unresolved
+    /// until `Backtrace::resolve` is called.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn is_resolved(&self) -> bool {
+        self.symbols.is_some()
+    }
+
+    fn resolve(&mut self) {
+        let symbols = match self.frame {
+            Frame::Raw(ref f) => {
+                let mut symbols = Vec::new();
+                resolve_frame(f, |symbol| symbols.push(BacktraceSymbol::new(symbol)));
+                number_inlined_calls(&mut symbols);
+                if symbols.is_empty() {
+                    if let Some((_, name)) = anonymous_module_at(f.ip() as usize) {
+                        symbols.push(BacktraceSymbol::anonymous(name));
+                    }
+                }
+                symbols
+            }
+            Frame::Deserialized { ip, .. } => resolve_address(ip as *mut c_void),
+        };
+        self.symbols = Some(symbols);
+    }
+
+    /// Returns the list of symbols that this frame corresponds to.
+    ///
+    /// Normally there is only one symbol per frame, but sometimes if a number
+    /// of functions are inlined into one frame then multiple symbols will be
+    /// returned. The first symbol listed is the "innermost function", whereas
+    /// the last symbol is the outermost (last caller).
+    ///
+    /// Note that if this frame came from an unresolved backtrace then this will
+    /// return an empty list.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn symbols(&self) -> &[BacktraceSymbol] {
+        self.symbols.as_ref().map(|s| &s[..]).unwrap_or(&[])
+    }
+
+    /// Returns the id of the thread this frame belongs to, if it's part of a
+    /// backtrace combining the stacks of several threads such as one created
+    /// by `Backtrace::from_snapshot`.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn thread_id(&self) -> Option<u32> {
+        self.thread_id
+    }
+}
+
+/// Returns the file name of the module loaded at `base` in this process.
+#[cfg(windows)]
+fn module_name(base: *mut c_void) -> Option<String> {
+    use crate::windows::*;
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+
+    let mut buf = vec![0u16; MAX_PATH];
+    loop {
+        let len =
+            unsafe { GetModuleFileNameW(base as HMODULE, buf.as_mut_ptr(), buf.len() as DWORD) };
+        if len == 0 {
+            return None;
+        }
+        // A return value equal to the buffer size means the path was truncated,
+        // so try again with a larger buffer.
+        if (len as usize) < buf.len() {
+            buf.truncate(len as usize);
+            break;
+        }
+        if buf.len() >= 0x8000 {
+            return None;
+        }
+        let new_len = buf.len() * 2;
+        buf.resize(new_len, 0);
+    }
+    let path = PathBuf::from(OsString::from_wide(&buf));
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(windows))]
+fn module_name(_base: *mut c_void) -> Option<String> {
+    None
+}
+
+impl BacktraceSymbol {
+    fn new(symbol: &Symbol) -> BacktraceSymbol {
+        BacktraceSymbol {
+            name: symbol.name().map(|m| m.as_bytes().to_vec()),
+            addr: symbol.addr().map(|a| a as usize),
+            filename: symbol.filename().map(|m| m.to_owned()),
+            lineno: symbol.lineno(),
+            colno: symbol.colno(),
+            kind: symbol.kind(),
+            inlined_into: symbol.inlined_into().map(|m| m.as_bytes().to_vec()),
+            inline_depth: symbol.inline_depth(),
+        }
+    }
+
+    // A symbol naming a range registered with `register_anonymous_module`, or
+    // a module and offset for `Backtrace::resolve_offset_only`.
+    fn anonymous(name: String) -> BacktraceSymbol {
+        BacktraceSymbol {
+            name: Some(name.into_bytes()),
+            addr: None,
+            filename: None,
+            lineno: None,
+            colno: None,
+            kind: None,
+            inlined_into: None,
+            inline_depth: 0,
+        }
+    }
+
+    /// Same as `Symbol::name`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn name(&self) -> Option<SymbolName<'_>> {
+        self.name.as_ref().map(|s| SymbolName::new(s))
+    }
+
+    /// Returns the demangled name of this symbol rendered in the given style.
+    ///
+    /// See `SymbolName::display` for more information.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn demangled(&self, style: DemangleStyle) -> Option<String> {
+        self.name().map(|name| name.display(style).to_string())
+    }
+
+    /// Same as `Symbol::addr`
+    ///
+    /// On MSVC this is the start of the function the frame is in, which
+    /// together with `BacktraceFrame::ip` gives the offset into the function.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn addr(&self) -> Option<*mut c_void> {
+        self.addr.map(|s| s as *mut c_void)
+    }
+
+    /// Same as `Symbol::filename`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn filename(&self) -> Option<&Path> {
+        self.filename.as_ref().map(|p| &**p)
+    }
+
+    /// Same as `Symbol::lineno`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn lineno(&self) -> Option<u32> {
+        self.lineno
+    }
+
+    /// Same as `Symbol::colno`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn colno(&self) -> Option<u32> {
+        self.colno
+    }
+
+    /// Same as `Symbol::kind`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn kind(&self) -> Option<SymbolKind> {
+        self.kind
+    }
+
+    /// Same as `Symbol::inlined_into`
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn inlined_into(&self) -> Option<SymbolName<'_>> {
+        self.inlined_into.as_ref().map(|s| SymbolName::new(s))
+    }
+
+    /// Returns whether this symbol is a call inlined into its physical frame
+    /// rather than the function that frame belongs to.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn is_inline(&self) -> bool {
+        self.inline_depth > 0
+    }
+
+    /// Returns how deeply this symbol's call is nested within the function of
+    /// its physical frame, 0 for that function itself, 1 for a call inlined
+    /// into it, 2 for a call inlined into that call and so on.
+    ///
+    /// This holds both when a frame resolves to several symbols, innermost
+    /// first, and when `StackWalkEx` expanded each inlined call into a frame
+    /// of its own, see `Frame::is_inline`. Consumers can use it to indent or
+    /// annotate inlined calls when rendering a backtrace.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `std` feature of the `backtrace` crate to be
+    /// enabled, and the `std` feature is enabled by default.
+    pub fn inline_depth(&self) -> u32 {
+        self.inline_depth
+    }
+
+    /// Reads the source code surrounding this symbol's location from disk.
+    ///
+    /// Returns up to `context_lines` lines before and after the symbol's line
+    /// number along with the line itself, so the first returned line is line
+    /// number `lineno - context_lines` (or line 1 if that would be before the
+    /// start of the file).
+    ///
+    /// This returns `None` if the symbol has no filename or line number, or if
+    /// the file can't be read, which is typically the case when a backtrace is
+    /// printed on a machine other than the one it was built on.
+    ///
+    /// # Required features
+    ///
+    /// This function requires the `source-snippet` feature of the `backtrace`
+    /// crate to be enabled, and the `source-snippet` feature is not enabled by
+    /// default.
+    #[cfg(feature = "source-snippet")]
+    pub fn source_snippet(&self, context_lines: usize) -> Option<Vec<String>> {
+        use std::fs::File;
+        use std::io::{BufRead, BufReader};
+
+        let filename = self.filename.as_ref()?;
+        let lineno = self.lineno? as usize;
+        if lineno == 0 {
+            return None;
+        }
+        let first = lineno.saturating_sub(context_lines).max(1);
+        let last = lineno.saturating_add(context_lines);
+
+        let file = File::open(filename).ok()?;
+        let lines = BufReader::new(file)
+            .lines()
+            .skip(first - 1)
+            .take(last - first + 1)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+
+        // If the file is shorter than we expected it's probably not the file
+        // this symbol was compiled from.
+        if lines.len() <= lineno - first {
+            return None;
+        }
+        Some(lines)
+    }
+}
+
+impl fmt::Debug for Backtrace {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frames = if fmt.alternate() {
+            &self.frames[..]
+        } else {
+            self.frames()
+        };
+        fmt_frames(fmt, frames, 0, &[])
+    }
+}
+
+impl fmt::Debug for HeadTailBacktrace {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_frames(fmt, self.head(), self.omitted, self.tail())
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl fmt::Debug for CrashInfo {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            fmt,
+            "exception {:#010x} at {:?}",
+            self.exception_code,
+            self.exception_address()
+        )?;
+        for nested in &self.nested {
+            writeln!(
+                fmt,
+                "while handling exception {:#010x} at {:?}",
+                nested.code,
+                nested.address()
+            )?;
+        }
+        if !self.code_bytes.is_empty() {
+            write!(fmt, "code at {:?}:", self.code_start())?;
+            for byte in &self.code_bytes {
+                write!(fmt, " {:02x}", byte)?;
+            }
+            writeln!(fmt)?;
+        }
+        fmt::Debug::fmt(&self.backtrace, fmt)
+    }
+}
+
+/// Prints `head` and `tail` with a marker for the `omitted` frames between
+/// them, if any.
+fn fmt_frames(
+    fmt: &mut fmt::Formatter<'_>,
+    head: &[BacktraceFrame],
+    omitted: usize,
+    tail: &[BacktraceFrame],
+) -> fmt::Result {
+    let full = fmt.alternate();
+    let style = if full {
+        PrintFmt::Full
+    } else {
+        PrintFmt::Short
+    };
+
+    // When printing paths we try to strip the cwd if it exists, otherwise
+    // we just print the path as-is. Note that we also only do this for the
+    // short format, because if it's full we presumably want to print
+    // everything.
+    let cwd = std::env::current_dir();
+    let mut print_path = move |fmt: &mut fmt::Formatter<'_>, path: crate::BytesOrWideString<'_>| {
+        let path = path.into_path_buf();
+        if !full {
+            if let Ok(cwd) = &cwd {
+                if let Ok(suffix) = path.strip_prefix(cwd) {
+                    return fmt::Display::fmt(&suffix.display(), fmt);
+                }
+            }
+        }
+        fmt::Display::fmt(&path.display(), fmt)
+    };
+
+    let mut f = BacktraceFmt::new(fmt, style, &mut print_path);
+    f.add_context()?;
+    for frame in head {
+        f.frame().backtrace_frame(frame)?;
+    }
+    if omitted > 0 {
+        f.omitted_frames(omitted)?;
+    }
+    for frame in tail {
+        f.frame().backtrace_frame(frame)?;
+    }
+    f.finish()
+}
+
+// See `Backtrace::display_std`, this mirrors std's `Display` implementation.
+struct StdDisplay<'a>(&'a Backtrace);
+
+impl fmt::Display for StdDisplay<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let full = fmt.alternate();
+        let (frames, style) = if full {
+            (&self.0.frames[..], PrintFmt::Full)
+        } else {
+            (self.0.frames(), PrintFmt::Short)
+        };
+
+        let cwd = std::env::current_dir();
+        let mut print_path = move |fmt: &mut fmt::Formatter<'_>, path: BytesOrWideString<'_>| {
+            let path = path.into_path_buf();
+            if let (false, Ok(cwd)) = (full, &cwd) {
+                if let Some(suffix) = path.strip_prefix(cwd).ok().and_then(|p| p.to_str()) {
+                    return write!(fmt, ".{}{}", std::path::MAIN_SEPARATOR, suffix);
+                }
+            }
+            fmt::Display::fmt(&path.display(), fmt)
+        };
+
+        let mut f = BacktraceFmt::new(fmt, style, &mut print_path);
+        f.add_context()?;
+        for frame in frames {
+            let symbols = frame.symbols();
+            if symbols.is_empty() {
+                f.frame().print_raw(frame.ip(), None, None, None)?;
+            }
+            for symbol in symbols {
+                f.frame().backtrace_symbol(frame, symbol)?;
+            }
+        }
+        f.finish()
+    }
+}
+
+impl Default for Backtrace {
+    fn default() -> Backtrace {
+        Backtrace::new()
+    }
+}
+
+impl BacktraceFrame {
+    // The values frames are compared and hashed by, see the type's docs.
+    fn key(&self) -> (usize, u32) {
+        match self.frame {
+            Frame::Raw(ref f) => (f.ip() as usize, f.inner.inline_context()),
+            Frame::Deserialized { ip, .. } => (ip, 0),
+        }
+    }
+}
+
+impl PartialEq for BacktraceFrame {
+    fn eq(&self, other: &BacktraceFrame) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for BacktraceFrame {}
+
+impl Hash for BacktraceFrame {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl fmt::Debug for BacktraceFrame {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("BacktraceFrame")
+            .field("ip", &self.ip())
+            .field("symbol_address", &self.symbol_address())
+            .finish()
+    }
+}
+
+impl fmt::Debug for BacktraceSymbol {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("BacktraceSymbol")
+            .field("name", &self.name())
+            .field("addr", &self.addr())
+            .field("filename", &self.filename())
+            .field("lineno", &self.lineno())
+            .field("colno", &self.colno())
+            .field("kind", &self.kind())
+            .field("inlined_into", &self.inlined_into())
+            .field("inline_depth", &self.inline_depth())
+            .finish()
+    }
+}
+
+#[cfg(feature = "serialize-rustc")]
+mod rustc_serialize_impls {
+    use super::*;
+    use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+
+    #[derive(RustcEncodable, RustcDecodable)]
     struct SerializedFrame {
         ip: usize,
         symbol_address: usize,
         module_base_address: Option<usize>,
         symbols: Option<Vec<BacktraceSymbol>>,
+        thread_id: Option<u32>,
+        rva: Option<u64>,
     }
 
     impl Decodable for BacktraceFrame {
@@ -458,6 +3123,7 @@ mod rustc_serialize_impls {
                     module_base_address: frame.module_base_address,
                 },
                 symbols: frame.symbols,
+                thread_id: frame.thread_id,
             })
         }
     }
@@ -467,12 +3133,18 @@ mod rustc_serialize_impls {
         where
             E: Encoder,
         {
-            let BacktraceFrame { frame, symbols } = self;
+            let BacktraceFrame {
+                frame,
+                symbols,
+                thread_id,
+            } = self;
             SerializedFrame {
                 ip: frame.ip() as usize,
                 symbol_address: frame.symbol_address() as usize,
                 module_base_address: frame.module_base_address().map(|addr| addr as usize),
                 symbols: symbols.clone(),
+                thread_id: *thread_id,
+                rva: self.rva(),
             }
             .encode(e)
         }
@@ -492,6 +3164,10 @@ mod serde_impls {
         symbol_address: usize,
         module_base_address: Option<usize>,
         symbols: Option<Vec<BacktraceSymbol>>,
+        #[serde(default)]
+        thread_id: Option<u32>,
+        #[serde(default)]
+        rva: Option<u64>,
     }
 
     impl Serialize for BacktraceFrame {
@@ -499,12 +3175,18 @@ mod serde_impls {
         where
             S: Serializer,
         {
-            let BacktraceFrame { frame, symbols } = self;
+            let BacktraceFrame {
+                frame,
+                symbols,
+                thread_id,
+            } = self;
             SerializedFrame {
                 ip: frame.ip() as usize,
                 symbol_address: frame.symbol_address() as usize,
                 module_base_address: frame.module_base_address().map(|addr| addr as usize),
                 symbols: symbols.clone(),
+                thread_id: *thread_id,
+                rva: self.rva(),
             }
             .serialize(s)
         }
@@ -523,6 +3205,7 @@ mod serde_impls {
                     module_base_address: frame.module_base_address,
                 },
                 symbols: frame.symbols,
+                thread_id: frame.thread_id,
             })
         }
     }
@@ -532,6 +3215,138 @@ mod serde_impls {
 mod tests {
     use super::*;
 
+    /// Returns an unresolved frame with `ip` as its instruction pointer and
+    /// symbol address.
+    fn frame(ip: usize) -> BacktraceFrame {
+        BacktraceFrame::from_parts(ip as *mut c_void, ip as *mut c_void, None)
+    }
+
+    #[test]
+    fn test_frame_eq_ignores_resolution() {
+        use std::collections::HashSet;
+
+        let bt = Backtrace::new_unresolved();
+        let mut resolved = bt.clone();
+        resolved.resolve();
+        assert_eq!(bt.frames(), resolved.frames());
+
+        let set = bt.frames().iter().collect::<HashSet<_>>();
+        assert!(resolved.frames().iter().all(|f| set.contains(f)));
+
+        let ip = bt.frames()[0].ip() as usize;
+        let other = BacktraceFrame {
+            frame: Frame::Deserialized {
+                ip: ip + 1,
+                symbol_address: 0,
+                module_base_address: None,
+            },
+            symbols: None,
+            thread_id: None,
+        };
+        assert_ne!(bt.frames()[0], other);
+    }
+
+    #[test]
+    fn test_include_internal_frames() {
+        let mut bt = Backtrace::new_unresolved();
+        let hidden = bt.frames().to_vec();
+        bt.include_internal_frames(true);
+        assert_eq!(bt.len(), hidden.len() + bt.actual_start_index);
+        assert_eq!(&bt.frames()[bt.actual_start_index..], &hidden[..]);
+        assert_eq!(bt.clone().into_frames(), bt.frames());
+        assert!((&bt).into_iter().eq(bt.frames()));
+        assert!(bt.clone().into_iter().eq(bt.frames().iter().cloned()));
+
+        assert_eq!(bt.frame(0), bt.frames().first());
+
+        bt.include_internal_frames(false);
+        assert_eq!(bt.frames(), &hidden[..]);
+        assert_eq!(bt.frame(0), hidden.first());
+        assert_eq!(bt.frame(hidden.len()), None);
+    }
+
+    #[test]
+    fn test_thread_ids() {
+        let frame = |ip, thread_id| BacktraceFrame {
+            frame: Frame::Deserialized {
+                ip,
+                symbol_address: ip,
+                module_base_address: None,
+            },
+            symbols: None,
+            thread_id,
+        };
+        let bt = Backtrace::from(vec![
+            frame(1, Some(7)),
+            frame(2, Some(7)),
+            frame(1, Some(3)),
+            frame(3, None),
+            frame(4, Some(7)),
+        ]);
+        assert_eq!(bt.thread_ids(), [7, 3]);
+        assert!(Backtrace::new_unresolved().thread_ids().is_empty());
+    }
+
+    #[test]
+    fn test_encode_compact() {
+        let frame = |ip: usize, module: Option<usize>, thread_id| BacktraceFrame {
+            frame: Frame::Deserialized {
+                ip,
+                symbol_address: ip - ip % 0x40,
+                module_base_address: module,
+            },
+            symbols: None,
+            thread_id,
+        };
+        let exe = 0x7ff6_1234_0000;
+        let dll = 0x7ffb_8000_0000;
+        let frames = (0..100)
+            .map(|i| match i % 3 {
+                0 => frame(dll + 0x9000 - i * 0x13, Some(dll), None),
+                _ => frame(exe + 0x40000 + i * 0x117, Some(exe), None),
+            })
+            .chain(vec![frame(0x1000, None, Some(7))])
+            .collect::<Vec<_>>();
+        let bt = Backtrace::from(frames);
+
+        let mut buf = Vec::new();
+        bt.encode_compact(&mut buf).unwrap();
+        Backtrace::new_unresolved()
+            .encode_compact(&mut buf)
+            .unwrap();
+        let mut r = &buf[..];
+        let decoded = Backtrace::decode_compact(&mut r).unwrap();
+        assert!(!Backtrace::decode_compact(&mut r).unwrap().is_empty());
+        assert!(r.is_empty());
+        assert_eq!(decoded.len(), bt.len());
+        for (a, b) in decoded.frames().iter().zip(bt.frames()) {
+            assert_eq!(a.ip(), b.ip());
+            assert_eq!(a.symbol_address(), b.symbol_address());
+            assert_eq!(a.module_base_address(), b.module_base_address());
+            assert_eq!(a.thread_id(), b.thread_id());
+        }
+
+        // Much smaller than the records of `trace_thread_to_writer`, let alone
+        // JSON.
+        let mut compact = Vec::new();
+        bt.encode_compact(&mut compact).unwrap();
+        assert!(compact.len() * 4 < bt.len() * (1 + FRAME_RECORD_LEN));
+
+        let err = Backtrace::decode_compact(&compact[..compact.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = Backtrace::decode_compact(&[2, 0][..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_max_frames() {
+        assert_eq!(parse_max_frames(None), usize::MAX);
+        assert_eq!(parse_max_frames(Some(OsStr::new("32"))), 32);
+        assert_eq!(parse_max_frames(Some(OsStr::new(" 8 "))), 8);
+        assert_eq!(parse_max_frames(Some(OsStr::new("lots"))), usize::MAX);
+        assert_eq!(parse_max_frames(Some(OsStr::new("-1"))), usize::MAX);
+    }
+
     #[test]
     fn test_frame_conversion() {
         let mut frames = vec![];
@@ -552,4 +3367,323 @@ mod tests {
             println!("{:?}", frame.symbols());
         }
     }
+
+    #[test]
+    fn test_display_std() {
+        let cwd = std::env::current_dir().unwrap();
+        let symbol = |name: &str, filename, lineno, colno| BacktraceSymbol {
+            name: Some(name.as_bytes().to_vec()),
+            addr: None,
+            filename,
+            lineno,
+            colno,
+            kind: None,
+            inlined_into: None,
+            inline_depth: 0,
+        };
+
+        let mut inlined = frame(0x1000);
+        inlined.symbols = Some(vec![
+            symbol("app::inner", Some(cwd.join("lib.rs")), Some(3), Some(5)),
+            symbol("app::outer", Some(cwd.join("main.rs")), Some(10), Some(9)),
+        ]);
+        let mut elsewhere = frame(0x2000);
+        let rt = PathBuf::from("/rustc/library/std/src/rt.rs");
+        elsewhere.symbols = Some(vec![symbol("std::rt::lang_start", Some(rt), Some(1), None)]);
+        let bt = Backtrace::from(vec![inlined, elsewhere, frame(0x3000)]);
+
+        let sep = std::path::MAIN_SEPARATOR;
+        let expected = [
+            "   0: app::inner".to_string(),
+            format!("             at .{}lib.rs:3:5", sep),
+            "   1: app::outer".to_string(),
+            format!("             at .{}main.rs:10:9", sep),
+            "   2: std::rt::lang_start".to_string(),
+            "             at /rustc/library/std/src/rt.rs:1".to_string(),
+            "   3: <unknown>".to_string(),
+        ];
+        assert_eq!(bt.display_std().to_string(), expected.join("\n") + "\n");
+
+        let full = format!("{:#}", bt.display_std());
+        assert_eq!(full.lines().filter(|l| l.contains(" - ")).count(), 4);
+        assert!(full.contains(&format!("at {}:3:5\n", cwd.join("lib.rs").display())));
+    }
+
+    #[test]
+    #[cfg(feature = "source-snippet")]
+    fn test_source_snippet() {
+        let symbol = BacktraceSymbol {
+            name: None,
+            addr: None,
+            filename: Some(PathBuf::from(file!())),
+            lineno: Some(line!()),
+            colno: None,
+            kind: None,
+            inlined_into: None,
+            inline_depth: 0,
+        };
+        let snippet = symbol.source_snippet(1).unwrap();
+        assert_eq!(snippet.len(), 3);
+        assert!(snippet[1].contains("line!()"));
+
+        let missing = BacktraceSymbol {
+            filename: Some(PathBuf::from("this/file/does/not/exist.rs")),
+            ..symbol
+        };
+        assert!(missing.source_snippet(1).is_none());
+    }
+
+    #[test]
+    fn test_into_frames() {
+        let bt = Backtrace::new_unresolved();
+        let ips = bt.frames().iter().map(|f| f.ip()).collect::<Vec<_>>();
+        let frames = bt.into_frames();
+        assert_eq!(frames.iter().map(|f| f.ip()).collect::<Vec<_>>(), ips);
+    }
+
+    #[test]
+    fn test_resolve_with_timeout() {
+        let mut bt = Backtrace::new_unresolved();
+        assert!(bt.frames().iter().all(|f| !f.is_resolved()));
+        if !bt.frames().is_empty() {
+            assert!(!bt.resolve_with_timeout(Duration::from_secs(0)));
+            assert!(bt.frames().iter().all(|f| !f.is_resolved()));
+        }
+        assert!(bt.resolve_with_timeout(Duration::from_secs(3600)));
+        assert!(bt.frames().iter().all(|f| f.is_resolved()));
+    }
+
+    #[test]
+    fn test_empty_backtrace() {
+        let mut bt = Backtrace::from(Vec::new());
+        bt.resolve();
+        assert!(bt.frames().is_empty());
+        assert_eq!(bt.resolve_with_stats().total(), 0);
+        assert!(bt.thread_ids().is_empty());
+        bt.include_internal_frames(true);
+        assert!(bt.frames().is_empty());
+        format!("{:?} {:#?}", bt, bt);
+        assert!(bt.into_frames().is_empty());
+    }
+
+    #[test]
+    fn test_len() {
+        let bt = Backtrace::new_unresolved();
+        assert_eq!(bt.len(), bt.frames().len());
+        assert!(!bt.is_empty());
+        assert!(Backtrace::from(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_fold_stack() {
+        let bt = Backtrace::from(vec![frame(0x10), frame(0x20)]);
+        assert_eq!(fold_stack(&bt), "0x20;0x10 1");
+        assert_eq!(fold_stack(&Backtrace::from(Vec::new())), "");
+    }
+
+    #[test]
+    fn test_frame_reader() {
+        let mut bytes = Vec::new();
+        for &(ip, base) in &[(0x1234usize, 0x1000usize), (0x5678, 0)] {
+            bytes.push(FRAME_RECORD_LEN as u8 + 2);
+            for field in &[ip, 0x7ff0, ip - 4, base] {
+                bytes.extend_from_slice(&(*field as u64).to_le_bytes());
+            }
+            bytes.extend_from_slice(&[0xaa, 0xbb]);
+        }
+        let frames = FrameReader::new(&bytes[..])
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].ip() as usize, 0x1234);
+        assert_eq!(frames[0].symbol_address() as usize, 0x1230);
+        assert_eq!(frames[0].module_base_address(), Some(0x1000 as *mut c_void));
+        assert_eq!(frames[1].module_base_address(), None);
+
+        let truncated = &bytes[..10];
+        assert!(FrameReader::new(truncated).next().unwrap().is_err());
+        let short = [4, 0, 0, 0, 0];
+        assert!(FrameReader::new(&short[..]).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_resolve_with_stats() {
+        let mut bt = Backtrace::from(vec![BacktraceFrame::from_parts(
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            None,
+        )]);
+        let stats = bt.resolve_with_stats();
+        assert_eq!(stats.unresolved(), 1);
+        assert_eq!(stats.total(), 1);
+
+        let mut bt = Backtrace::new_unresolved();
+        let stats = bt.resolve_with_stats();
+        assert_eq!(stats.total(), bt.frames().len());
+    }
+
+    #[test]
+    fn test_symbol_cache() {
+        let mut cache = SymbolCache::new(2);
+        let mut bt = Backtrace::from(vec![frame(0x10), frame(0x20), frame(0x10)]);
+        bt.resolve_with_cache(&mut cache);
+        assert!(bt.frames().iter().all(|f| f.is_resolved()));
+        assert_eq!(cache.len(), 2);
+
+        let mut bt = Backtrace::from(vec![frame(0x30)]);
+        bt.resolve_with_cache(&mut cache);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.symbols.contains_key(&(0x10, 0)));
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.capacity(), 2);
+
+        let mut bt = Backtrace::new_unresolved();
+        bt.resolve_cached();
+        assert!(bt.frames().iter().all(|f| f.is_resolved()));
+    }
+
+    #[test]
+    fn test_frame_sizes() {
+        let bt = Backtrace::new_unresolved();
+        let sizes = bt.frame_sizes();
+        assert_eq!(sizes.len(), bt.frames().len());
+        assert_eq!(sizes.last(), bt.frames().last().map(|_| &None));
+
+        let bt = Backtrace::from(vec![frame(0x10), frame(0x20)]);
+        assert_eq!(bt.frame_sizes(), vec![None, None]);
+    }
+
+    #[test]
+    fn test_module_map() {
+        let map = ModuleMap::capture();
+        assert_eq!(ModuleMap::capture().version(), map.version());
+        let addr = test_module_map as usize as *mut c_void;
+        if let Some(module) = map.module_at(addr) {
+            assert!(module.contains(addr));
+            assert!(module.base() <= addr);
+        }
+
+        let mut bt = Backtrace::new_unresolved();
+        let len = bt.frames().len();
+        bt.resolve_with_module_map(&map);
+        assert_eq!(bt.frames().len(), len);
+    }
+
+    #[test]
+    fn test_resolve_offset_only() {
+        let map = ModuleMap::capture();
+        let mut bt = Backtrace::new_unresolved();
+        bt.resolve_offset_only(&map);
+        assert!(bt.frames().iter().all(|f| f.is_resolved()));
+        for frame in bt.frames() {
+            let module = map.module_at(frame.ip());
+            let symbols = frame.symbols();
+            if let Some(module) = module {
+                let name = symbols[0].name().unwrap().to_string();
+                let offset = frame.ip() as usize - module.base() as usize;
+                assert!(name.ends_with(&format!("!{:#x}", offset)), "{}", name);
+                assert!(symbols[0].filename().is_none());
+            }
+        }
+
+        let mut bt = Backtrace::from(vec![frame(0x10)]);
+        bt.resolve_offset_only(&ModuleMap::capture());
+        assert_eq!(bt.frames()[0].symbols().len(), 0);
+    }
+
+    #[test]
+    fn test_lazy_backtrace() {
+        let lazy = LazyBacktrace::new();
+        let printed = lazy.to_string();
+        assert_eq!(format!("{:?}", lazy), printed);
+        let bt = lazy.into_backtrace();
+        assert!(bt.frames().iter().all(|f| f.is_resolved()));
+    }
+
+    #[test]
+    fn test_filter_modules() {
+        let bt = Backtrace::new_unresolved();
+        assert!(bt.filter_modules(&[]).is_empty());
+
+        let name = bt
+            .frames()
+            .iter()
+            .find_map(|f| f.module_offset())
+            .map(|m| m.0);
+        if let Some(name) = name {
+            let kept = bt.filter_modules(&[&name.to_uppercase()]);
+            assert!(!kept.is_empty());
+            assert!(kept.len() <= bt.len());
+            assert!(kept
+                .frames()
+                .iter()
+                .all(|f| f.module_offset().map(|m| m.0) == Some(name.clone())));
+        }
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let bt = Backtrace::new_unresolved();
+        let tail_calls = bt.tail_calls();
+        assert_eq!(tail_calls.len(), bt.frames().len());
+        assert_eq!(tail_calls.last(), bt.frames().last().map(|_| &false));
+
+        let bt = Backtrace::from(vec![frame(0x10), frame(0x20)]);
+        assert_eq!(bt.tail_calls(), vec![false, false]);
+    }
+
+    #[test]
+    fn test_frame_pipeline() {
+        use crate::process::FramePipeline;
+
+        let all = Backtrace::new_unresolved_with(FramePipeline::new())
+            .frames()
+            .len();
+        let bt = Backtrace::new_unresolved_with(FramePipeline::new().limit(1));
+        assert!(bt.frames().len() <= 1);
+        let bt = Backtrace::new_unresolved_with(FramePipeline::new().skip(1));
+        assert_eq!(bt.frames().len(), all.saturating_sub(1));
+        let bt = Backtrace::new_unresolved_with(FramePipeline::new().filter(|_| false));
+        assert!(bt.frames().is_empty());
+
+        // Inline frames share the address of their physical frame, but frames
+        // compare their inline context too, like `Dedupe` does.
+        let bt = Backtrace::new_unresolved_with(FramePipeline::new().dedupe());
+        assert!(bt.frames().windows(2).all(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let bt = Backtrace::new();
+        let frames = bt
+            .frames()
+            .iter()
+            .map(|f| {
+                BacktraceFrame::from_parts(f.ip(), f.symbol_address(), f.module_base_address())
+            })
+            .collect::<Vec<_>>();
+
+        let mut synthesized = Backtrace::from(frames);
+        synthesized.resolve();
+        for (a, b) in bt.frames().iter().zip(synthesized.frames()) {
+            assert_eq!(a.ip(), b.ip());
+            assert_eq!(a.module_base_address(), b.module_base_address());
+            // Inlined frames may be attributed differently when resolving a
+            // bare address, but the frame should still resolve to something.
+            assert_eq!(a.symbols().is_empty(), b.symbols().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_rva() {
+        let frame = |ip: usize, base: Option<usize>| {
+            BacktraceFrame::from_parts(ip as *mut c_void, ip as *mut c_void, base.map(|b| b as _))
+        };
+        assert_eq!(frame(0x1234, Some(0x1000)).rva(), Some(0x234));
+        assert_eq!(frame(0x1234, None).rva(), None);
+        assert_eq!(frame(0x1234, Some(0)).rva(), None);
+        assert_eq!(frame(0x0fff, Some(0x1000)).rva(), None);
+    }
 }
@@ -24,6 +24,7 @@
 #![allow(non_snake_case)]
 
 use super::windows::*;
+use crate::backtrace::DbghelpError;
 use core::mem;
 use core::ptr;
 
@@ -55,6 +56,22 @@ mod dbghelp {
             pdwDisplacement: PDWORD,
             Line: PIMAGEHLP_LINEW64,
         ) -> BOOL;
+        pub fn SymRegisterFunctionEntryCallback64(
+            hProcess: HANDLE,
+            CallbackFunction: PSYMBOL_FUNCENTRY_CALLBACK64,
+            UserContext: ULONG64,
+        ) -> BOOL;
+        pub fn SymAddrIncludeInlineTrace(hProcess: HANDLE, Address: DWORD64) -> DWORD;
+        pub fn SymQueryInlineTrace(
+            hProcess: HANDLE,
+            StartAddress: DWORD64,
+            StartContext: DWORD,
+            StartRetAddress: DWORD64,
+            CurAddress: DWORD64,
+            CurContext: LPDWORD,
+            CurFrameIndex: LPDWORD,
+        ) -> BOOL;
+        pub fn SymRefreshModuleList(hProcess: HANDLE) -> BOOL;
     }
 
     pub fn assert_equal_types<T>(a: T, _b: T) -> T {
@@ -89,10 +106,8 @@ macro_rules! dbghelp {
 
         impl Dbghelp {
             /// Attempts to open `dbghelp.dll`. Returns success if it works or
-            /// error if `LoadLibraryW` fails.
-            ///
-            /// Panics if library is already loaded.
-            fn ensure_open(&mut self) -> Result<(), ()> {
+            /// the error code of `LoadLibraryA` if it fails.
+            fn ensure_open(&mut self) -> Result<(), DbghelpError> {
                 if !self.dll.is_null() {
                     return Ok(())
                 }
@@ -100,7 +115,7 @@ macro_rules! dbghelp {
                 unsafe {
                     self.dll = LoadLibraryA(lib.as_ptr() as *const i8);
                     if self.dll.is_null() {
-                        Err(())
+                        Err(DbghelpError::NotFound(GetLastError()))
                     }  else {
                         Ok(())
                     }
@@ -123,6 +138,12 @@ macro_rules! dbghelp {
                 }
             })*
 
+            /// Returns the handle of the loaded `dbghelp.dll`, which is null
+            /// until it's been opened.
+            pub fn dll(&self) -> HMODULE {
+                self.dll
+            }
+
             fn symbol(&self, symbol: &[u8]) -> Option<usize> {
                 unsafe {
                     match GetProcAddress(self.dll, symbol.as_ptr() as *const _) as usize {
@@ -223,6 +244,27 @@ dbghelp! {
             pdwDisplacement: PDWORD,
             Line: PIMAGEHLP_LINEW64
         ) -> BOOL;
+        fn SymRegisterFunctionEntryCallback64(
+            hProcess: HANDLE,
+            CallbackFunction: PSYMBOL_FUNCENTRY_CALLBACK64,
+            UserContext: ULONG64
+        ) -> BOOL;
+        fn SymAddrIncludeInlineTrace(
+            hProcess: HANDLE,
+            Address: DWORD64
+        ) -> DWORD;
+        fn SymQueryInlineTrace(
+            hProcess: HANDLE,
+            StartAddress: DWORD64,
+            StartContext: DWORD,
+            StartRetAddress: DWORD64,
+            CurAddress: DWORD64,
+            CurContext: LPDWORD,
+            CurFrameIndex: LPDWORD
+        ) -> BOOL;
+        fn SymRefreshModuleList(
+            hProcess: HANDLE
+        ) -> BOOL;
     }
 }
 
@@ -231,12 +273,30 @@ pub struct Init {
 }
 
 /// Initialize all support necessary to access `dbghelp` API functions from this
-/// crate.
+/// crate, including the symbol handler.
 ///
 /// Note that this function is **safe**, it internally has its own
 /// synchronization. Also note that it is safe to call this function multiple
 /// times recursively.
-pub fn init() -> Result<Init, ()> {
+pub fn init() -> Result<Init, DbghelpError> {
+    let ret = load()?;
+    unsafe {
+        init_symbol_handler();
+    }
+    Ok(ret)
+}
+
+/// Same as `init`, except that the symbol handler isn't initialized.
+///
+/// This is enough to walk stacks with callbacks which don't rely on the symbol
+/// handler, and avoids the cost of `SymInitializeW` enumerating all modules of
+/// the process until symbols are actually needed.
+///
+/// The functions which are always used are checked to be present, so they
+/// can be called through `Init` without further checks. Functions which are
+/// only used when available, such as `StackWalkEx`, must still be checked
+/// with `Init::dbghelp` first.
+pub fn load() -> Result<Init, DbghelpError> {
     use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
 
     unsafe {
@@ -283,7 +343,7 @@ pub fn init() -> Result<Init, ()> {
                 "Local\\RustBacktraceMutex\0".as_ptr() as _,
             ) as usize;
             if lock == 0 {
-                return Err(());
+                return Err(DbghelpError::LockFailed(GetLastError()));
             }
             if let Err(other) = LOCK.compare_exchange(0, lock, SeqCst, SeqCst) {
                 debug_assert!(other != 0);
@@ -306,38 +366,86 @@ pub fn init() -> Result<Init, ()> {
         // utility.
         //
         // Once we've opened `dbghelp.dll` we need to call some initialization
-        // functions in it, and that's detailed more below. We only do this
-        // once, though, so we've got a global boolean indicating whether we're
-        // done yet or not.
+        // functions in it, and that's detailed more in `init_symbol_handler`.
+        // We only do this once, though, so we've got global booleans
+        // indicating whether we're done yet or not.
         DBGHELP.ensure_open()?;
 
-        static mut INITIALIZED: bool = false;
-        if INITIALIZED {
+        static mut LOADED: bool = false;
+        if LOADED {
             return Ok(ret);
         }
 
-        let orig = DBGHELP.SymGetOptions().unwrap()();
+        // Old versions of dbghelp, like the one shipped with Windows XP, lack
+        // some of the functions we use unconditionally. Check for those up
+        // front rather than panicking when one of them is called.
+        macro_rules! require {
+            ($($name:ident),*) => ($(
+                if DBGHELP.$name().is_none() {
+                    return Err(DbghelpError::MissingFunction(stringify!($name)));
+                }
+            )*)
+        }
+        require!(
+            SymGetOptions,
+            SymSetOptions,
+            SymInitializeW,
+            StackWalk64,
+            SymFunctionTableAccess64,
+            SymGetModuleBase64,
+            SymFromAddrW,
+            SymGetLineFromAddrW64
+        );
+        LOADED = true;
+        Ok(ret)
+    }
+}
 
-        // Ensure that the `SYMOPT_DEFERRED_LOADS` flag is set, because
-        // according to MSVC's own docs about this: "This is the fastest, most
-        // efficient way to use the symbol handler.", so let's do that!
-        DBGHELP.SymSetOptions().unwrap()(orig | SYMOPT_DEFERRED_LOADS);
+/// Initializes the symbol handler of dbghelp, which must already be loaded,
+/// unless that's been done before.
+///
+/// Must be called with the dbghelp lock held.
+unsafe fn init_symbol_handler() {
+    static mut INITIALIZED: bool = false;
+    if INITIALIZED {
+        return;
+    }
 
-        // Actually initialize symbols with MSVC. Note that this can fail, but we
-        // ignore it. There's not a ton of prior art for this per se, but LLVM
-        // internally seems to ignore the return value here and one of the
-        // sanitizer libraries in LLVM prints a scary warning if this fails but
-        // basically ignores it in the long run.
-        //
-        // One case this comes up a lot for Rust is that the standard library and
-        // this crate on crates.io both want to compete for `SymInitializeW`. The
-        // standard library historically wanted to initialize then cleanup most of
-        // the time, but now that it's using this crate it means that someone will
-        // get to initialization first and the other will pick up that
-        // initialization.
-        DBGHELP.SymInitializeW().unwrap()(GetCurrentProcess(), ptr::null_mut(), TRUE);
-        INITIALIZED = true;
-        Ok(ret)
+    let orig = DBGHELP.SymGetOptions().unwrap()();
+
+    // Ensure that the `SYMOPT_DEFERRED_LOADS` flag is set, because
+    // according to MSVC's own docs about this: "This is the fastest, most
+    // efficient way to use the symbol handler.", so let's do that!
+    DBGHELP.SymSetOptions().unwrap()(orig | SYMOPT_DEFERRED_LOADS);
+
+    // Actually initialize symbols with MSVC. Note that this can fail
+    // because the process was already initialized by someone else, which
+    // we ignore. There's not a ton of prior art for this per se, but LLVM
+    // internally seems to ignore the return value here and one of the
+    // sanitizer libraries in LLVM prints a scary warning if this fails but
+    // basically ignores it in the long run.
+    //
+    // One case this comes up a lot for Rust is that the standard library and
+    // this crate on crates.io both want to compete for `SymInitializeW`. The
+    // standard library historically wanted to initialize then cleanup most of
+    // the time, but now that it's using this crate it means that someone will
+    // get to initialization first and the other will pick up that
+    // initialization.
+    DBGHELP.SymInitializeW().unwrap()(GetCurrentProcess(), ptr::null_mut(), TRUE);
+    INITIALIZED = true;
+}
+
+/// Makes dbghelp load the modules loaded into the process since it was
+/// initialized, and forget about those which were unloaded.
+///
+/// Does nothing if dbghelp is unavailable or too old to support this.
+pub fn refresh_module_list() {
+    if let Ok(dbghelp) = init() {
+        unsafe {
+            if let Some(refresh) = (*dbghelp.dbghelp()).SymRefreshModuleList() {
+                refresh(GetCurrentProcess());
+            }
+        }
     }
 }
 
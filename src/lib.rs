@@ -111,19 +111,36 @@ extern crate std;
 extern crate alloc;
 
 #[cfg(target_os = "windows")]
-pub use self::backtrace::trace_thread_unsynchronized;
+pub use self::backtrace::{
+    available_processors, enable_debug_privilege, list_threads, register_function_entry_callback,
+    reset_trace_stats, safe_point, set_deferred_symbol_init, set_yield_on_single_processor,
+    trace_exception, trace_guest_context, trace_lazy_base_unsynchronized, trace_stats,
+    trace_thread_best_effort, trace_thread_reconciled, trace_thread_with_context,
+    trace_with_machine, trace_with_memory_reader, trace_with_walk_flags, with_dbghelp,
+    ContextBuffer, DbghelpApi, FrameConfidence, FunctionEntryCallback, GuestArch, GuestRegisters,
+    MemoryReader, StackWalkApi, TraceStats,
+};
+pub use self::backtrace::{
+    capture_thread_skipping, snapshot_all_threads, trace_all_threads_unsynchronized,
+    trace_all_threads_with_options_unsynchronized, trace_thread_bounded, trace_thread_indexed,
+    trace_thread_skip_symbols, trace_thread_unsynchronized, AllThreadsSnapshot, DbghelpError,
+    RawThreadHandle, ThreadHandle, ThreadInfo, ThreadSnapshotOptions, ThreadStackBounds,
+    TraceError,
+};
+#[cfg(all(target_os = "windows", feature = "std"))]
+pub use self::backtrace::{trace_lazy_base, trace_thread_cooperative};
 pub use self::backtrace::{trace_unsynchronized, Frame};
 mod backtrace;
 
 pub use self::symbolize::resolve_frame_unsynchronized;
-pub use self::symbolize::{resolve_unsynchronized, Symbol, SymbolName};
+pub use self::symbolize::{resolve_unsynchronized, DemangleStyle, Symbol, SymbolKind, SymbolName};
 mod symbolize;
 
 pub use self::types::BytesOrWideString;
 mod types;
 
 #[cfg(feature = "std")]
-pub use self::symbolize::clear_symbol_cache;
+pub use self::symbolize::{clear_symbol_cache, refresh_modules};
 
 mod print;
 pub use print::{BacktraceFmt, BacktraceFrameFmt, PrintFmt};
@@ -131,9 +148,29 @@ pub use print::{BacktraceFmt, BacktraceFrameFmt, PrintFmt};
 cfg_if::cfg_if! {
     if #[cfg(feature = "std")] {
         pub use self::backtrace::trace;
-        pub use self::symbolize::{resolve, resolve_frame};
-        pub use self::capture::{Backtrace, BacktraceFrame, BacktraceSymbol};
+        pub use self::symbolize::{
+            register_anonymous_module, resolve, resolve_frame, unregister_anonymous_module,
+            LoadedModule, ModuleMap, SymbolSource,
+        };
+        pub use self::capture::{
+            fold_stack, inline_frames_at, max_frames, resolve_address, Backtrace, BacktraceFrame,
+            BacktraceSymbol, LazyBacktrace, ResolveStats, SymbolCache,
+        };
+        pub use self::capture::{
+            capture_thread_head_tail, capture_thread_in, capture_thread_with_capacity,
+            trace_thread_to_writer, FrameReader, HeadTailBacktrace, SampleRing,
+        };
+        #[cfg(target_os = "windows")]
+        pub use self::capture::{
+            capture_exception, CrashInfo, HotThreadSampler, HotThreads, NestedException,
+            ThreadCpuUsage,
+        };
+        pub use self::process::{
+            CaptureContext, Dedupe, Filter, FrameAction, FramePipeline, FrameProcessor, Limit,
+            Skip, SkipCrateInternals, Then,
+        };
         mod capture;
+        mod process;
     }
 }
 
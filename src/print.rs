@@ -78,6 +78,17 @@ impl<'a, 'b> BacktraceFmt<'a, 'b> {
         }
     }
 
+    /// Prints a marker in place of `count` frames left out of the output, such
+    /// as the middle of a very deep stack.
+    ///
+    /// Frames added afterwards are numbered as if the omitted frames had been
+    /// printed.
+    pub fn omitted_frames(&mut self, count: usize) -> fmt::Result {
+        writeln!(self.fmt, "      ... {} frames omitted ...", count)?;
+        self.frame_index += count;
+        Ok(())
+    }
+
     /// Completes the backtrace output.
     ///
     /// This is currently a no-op but is added for future compatibility with
@@ -0,0 +1,241 @@
+//! Composable stages filtering the frames of a capture, see `FrameProcessor`.
+
+use crate::Frame;
+
+/// What a `FrameProcessor` decides to do with a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameAction {
+    /// Keeps the frame, passing it on to the next stage.
+    Keep,
+    /// Leaves the frame out and continues with the next one.
+    Skip,
+    /// Leaves the frame out and ends the capture.
+    Stop,
+}
+
+/// Information about the capture a `FrameProcessor` is applied to.
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureContext {
+    // Address of a local of the capturing function, the frames of this crate
+    // have their stack pointer below it.
+    marker: usize,
+    // Address of the capturing function.
+    entry: usize,
+}
+
+impl CaptureContext {
+    pub(crate) fn new(marker: *const u8, entry: usize) -> CaptureContext {
+        CaptureContext {
+            marker: marker as usize,
+            entry,
+        }
+    }
+
+    /// Returns whether `frame` belongs to this crate rather than to the code
+    /// which requested the capture.
+    ///
+    /// Depending on the platform frames report either their own stack pointer
+    /// or that of their caller, so a frame is internal if its stack pointer is
+    /// below a local of the capturing function, or if it's the frame of the
+    /// capturing function itself.
+    pub fn is_internal(&self, frame: &Frame) -> bool {
+        let sp = frame.sp() as usize;
+        (sp != 0 && sp <= self.marker) || frame.symbol_address() as usize == self.entry
+    }
+}
+
+/// A stage transforming the stream of frames of a capture, before any of
+/// them is stored.
+///
+/// Stages are chained with the provided methods, starting from
+/// `FramePipeline::new`, and applied with `Backtrace::new_with` or
+/// `Backtrace::new_unresolved_with`. A frame reaches a stage only if all
+/// stages before it kept it, and the first stage returning
+/// `FrameAction::Stop` ends the capture.
+///
+/// # Examples
+///
+/// ```
+/// use backtrace::{Backtrace, FramePipeline, FrameProcessor};
+///
+/// let pipeline = FramePipeline::new().skip_crate_internals().dedupe().limit(64);
+/// let bt = Backtrace::new_with(pipeline);
+/// assert!(bt.frames().len() <= 64);
+/// ```
+///
+/// # Required features
+///
+/// This trait requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+pub trait FrameProcessor {
+    /// Decides what to do with `frame`.
+    fn process(&mut self, frame: &Frame, cx: &CaptureContext) -> FrameAction;
+
+    /// Passes the frames kept by this stage on to `next`.
+    fn then<P: FrameProcessor>(self, next: P) -> Then<Self, P>
+    where
+        Self: Sized,
+    {
+        Then {
+            first: self,
+            second: next,
+        }
+    }
+
+    /// Leaves out the frames of this crate, see `CaptureContext::is_internal`.
+    fn skip_crate_internals(self) -> Then<Self, SkipCrateInternals>
+    where
+        Self: Sized,
+    {
+        self.then(SkipCrateInternals)
+    }
+
+    /// Leaves out frames repeating the frame kept before them, such as the
+    /// frames of a directly recursive function.
+    fn dedupe(self) -> Then<Self, Dedupe>
+    where
+        Self: Sized,
+    {
+        self.then(Dedupe { last: None })
+    }
+
+    /// Leaves out the first `n` frames reaching this stage.
+    fn skip(self, n: usize) -> Then<Self, Skip>
+    where
+        Self: Sized,
+    {
+        self.then(Skip { remaining: n })
+    }
+
+    /// Ends the capture once `n` frames reached this stage.
+    fn limit(self, n: usize) -> Then<Self, Limit>
+    where
+        Self: Sized,
+    {
+        self.then(Limit { remaining: n })
+    }
+
+    /// Keeps only the frames `predicate` returns `true` for.
+    fn filter<F: FnMut(&Frame) -> bool>(self, predicate: F) -> Then<Self, Filter<F>>
+    where
+        Self: Sized,
+    {
+        self.then(Filter { predicate })
+    }
+}
+
+/// The start of a chain of stages, which keeps all frames.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FramePipeline {
+    _private: (),
+}
+
+impl FramePipeline {
+    /// Creates a pipeline keeping all frames, to add stages to.
+    pub fn new() -> FramePipeline {
+        FramePipeline::default()
+    }
+}
+
+impl FrameProcessor for FramePipeline {
+    fn process(&mut self, _frame: &Frame, _cx: &CaptureContext) -> FrameAction {
+        FrameAction::Keep
+    }
+}
+
+/// Two stages applied one after the other, see `FrameProcessor::then`.
+#[derive(Clone, Debug)]
+pub struct Then<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: FrameProcessor, B: FrameProcessor> FrameProcessor for Then<A, B> {
+    fn process(&mut self, frame: &Frame, cx: &CaptureContext) -> FrameAction {
+        match self.first.process(frame, cx) {
+            FrameAction::Keep => self.second.process(frame, cx),
+            action => action,
+        }
+    }
+}
+
+/// See `FrameProcessor::skip_crate_internals`.
+#[derive(Clone, Copy, Debug)]
+pub struct SkipCrateInternals;
+
+impl FrameProcessor for SkipCrateInternals {
+    fn process(&mut self, frame: &Frame, cx: &CaptureContext) -> FrameAction {
+        if cx.is_internal(frame) {
+            FrameAction::Skip
+        } else {
+            FrameAction::Keep
+        }
+    }
+}
+
+/// See `FrameProcessor::dedupe`.
+#[derive(Clone, Copy, Debug)]
+pub struct Dedupe {
+    // Instruction pointer and inline context of the last frame kept, inline
+    // frames share the address of the frame they were inlined into.
+    last: Option<(usize, u32)>,
+}
+
+impl FrameProcessor for Dedupe {
+    fn process(&mut self, frame: &Frame, _cx: &CaptureContext) -> FrameAction {
+        let key = (frame.ip() as usize, frame.inner.inline_context());
+        if self.last == Some(key) {
+            return FrameAction::Skip;
+        }
+        self.last = Some(key);
+        FrameAction::Keep
+    }
+}
+
+/// See `FrameProcessor::skip`.
+#[derive(Clone, Copy, Debug)]
+pub struct Skip {
+    remaining: usize,
+}
+
+impl FrameProcessor for Skip {
+    fn process(&mut self, _frame: &Frame, _cx: &CaptureContext) -> FrameAction {
+        if self.remaining == 0 {
+            return FrameAction::Keep;
+        }
+        self.remaining -= 1;
+        FrameAction::Skip
+    }
+}
+
+/// See `FrameProcessor::limit`.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    remaining: usize,
+}
+
+impl FrameProcessor for Limit {
+    fn process(&mut self, _frame: &Frame, _cx: &CaptureContext) -> FrameAction {
+        if self.remaining == 0 {
+            return FrameAction::Stop;
+        }
+        self.remaining -= 1;
+        FrameAction::Keep
+    }
+}
+
+/// See `FrameProcessor::filter`.
+#[derive(Clone, Debug)]
+pub struct Filter<F> {
+    predicate: F,
+}
+
+impl<F: FnMut(&Frame) -> bool> FrameProcessor for Filter<F> {
+    fn process(&mut self, frame: &Frame, _cx: &CaptureContext) -> FrameAction {
+        if (self.predicate)(frame) {
+            FrameAction::Keep
+        } else {
+            FrameAction::Skip
+        }
+    }
+}
@@ -18,7 +18,7 @@
 #![allow(bad_style)]
 
 use super::super::{backtrace::StackFrame, dbghelp, windows::*};
-use super::{BytesOrWideString, ResolveWhat, SymbolName};
+use super::{BytesOrWideString, ResolveWhat, SymbolKind, SymbolName};
 use core::char;
 use core::ffi::c_void;
 use core::marker;
@@ -31,6 +31,9 @@ pub struct Symbol<'a> {
     addr: *mut c_void,
     line: Option<u32>,
     filename: Option<*const [u16]>,
+    kind: SymbolKind,
+    inlined_into: Option<*const [u8]>,
+    inline_depth: u32,
     #[cfg(feature = "std")]
     _filename_cache: Option<::std::ffi::OsString>,
     #[cfg(not(feature = "std"))]
@@ -60,6 +63,19 @@ impl Symbol<'_> {
         self.line
     }
 
+    pub fn kind(&self) -> Option<SymbolKind> {
+        Some(self.kind)
+    }
+
+    pub fn inlined_into(&self) -> Option<SymbolName<'_>> {
+        self.inlined_into
+            .map(|name| SymbolName::new(unsafe { &*name }))
+    }
+
+    pub fn inline_depth(&self) -> u32 {
+        self.inline_depth
+    }
+
     #[cfg(feature = "std")]
     pub fn filename(&self) -> Option<&::std::path::Path> {
         use std::path::Path;
@@ -71,18 +87,97 @@ impl Symbol<'_> {
 #[repr(C, align(8))]
 struct Aligned8<T>(T);
 
+// Set in `SYMBOL_INFOW::Flags` for symbols from the export table, which
+// dbghelp falls back to for modules without a PDB.
+const SYMFLAG_EXPORT: ULONG = 0x200;
+
+const INFO_SIZE: usize = 2 * MAX_SYM_NAME + mem::size_of::<SYMBOL_INFOW>();
+// A UTF-16 code unit takes at most 3 bytes when reencoded as UTF-8.
+const NAME_SIZE: usize = 3 * MAX_SYM_NAME;
+
+/// Scratch space for `do_resolve`: the `SYMBOL_INFOW` dbghelp writes into and
+/// the buffers its name is reencoded to, the second one holding the name of
+/// the function an inlined call was inlined into.
+struct Buffers {
+    info: Aligned8<[u8; INFO_SIZE]>,
+    name: [u8; NAME_SIZE],
+    outer_name: [u8; NAME_SIZE],
+    initialized: bool,
+}
+
+impl Buffers {
+    const fn new() -> Buffers {
+        Buffers {
+            info: Aligned8([0; INFO_SIZE]),
+            name: [0; NAME_SIZE],
+            outer_name: [0; NAME_SIZE],
+            initialized: false,
+        }
+    }
+
+    unsafe fn get(
+        &mut self,
+    ) -> (
+        &mut SYMBOL_INFOW,
+        &mut [u8; NAME_SIZE],
+        &mut [u8; NAME_SIZE],
+    ) {
+        let info = &mut *(self.info.0.as_mut_ptr() as *mut SYMBOL_INFOW);
+        // These are only read by dbghelp, so they only need to be set once.
+        if !self.initialized {
+            info.MaxNameLen = MAX_SYM_NAME as ULONG;
+            // the struct size in C.  the value is different to
+            // `size_of::<SYMBOL_INFOW>() - MAX_SYM_NAME + 1` (== 81)
+            // due to struct alignment.
+            info.SizeOfStruct = 88;
+            self.initialized = true;
+        }
+        (info, &mut self.name, &mut self.outer_name)
+    }
+}
+
+// The buffers are large enough that setting them up for every frame shows up
+// when resolving deep stacks, so a single set is reused by all calls. They're
+// protected by the dbghelp lock, which is held throughout `resolve`, but that
+// lock is reentrant so a `resolve` from within the callback of another on the
+// same thread finds them in use and falls back to buffers on its stack.
+static mut BUFFERS: Buffers = Buffers::new();
+static mut BUFFERS_IN_USE: bool = false;
+
 pub unsafe fn resolve(what: ResolveWhat<'_>, cb: &mut dyn FnMut(&super::Symbol)) {
     // Ensure this process's symbols are initialized
     let dbghelp = match dbghelp::init() {
         Ok(dbghelp) => dbghelp,
-        Err(()) => return, // oh well...
+        Err(_) => return, // oh well...
     };
 
+    if BUFFERS_IN_USE {
+        let mut buffers = Buffers::new();
+        resolve_with_buffers(&dbghelp, what, &mut buffers, cb);
+    } else {
+        // Should `cb` panic the flag is never reset, after which every call
+        // uses its own buffers. That's just slower, not incorrect.
+        BUFFERS_IN_USE = true;
+        resolve_with_buffers(&dbghelp, what, &mut BUFFERS, cb);
+        BUFFERS_IN_USE = false;
+    }
+}
+
+unsafe fn resolve_with_buffers(
+    dbghelp: &dbghelp::Init,
+    what: ResolveWhat<'_>,
+    buffers: &mut Buffers,
+    cb: &mut dyn FnMut(&super::Symbol),
+) {
     match what {
-        ResolveWhat::Address(_) => resolve_without_inline(&dbghelp, what.address_or_ip(), cb),
+        ResolveWhat::Address(_) => resolve_address(dbghelp, what.address_or_ip(), buffers, cb),
         ResolveWhat::Frame(frame) => match &frame.inner.stack_frame {
-            StackFrame::New(frame) => resolve_with_inline(&dbghelp, frame, cb),
-            StackFrame::Old(_) => resolve_without_inline(&dbghelp, frame.ip(), cb),
+            StackFrame::New(stack_frame) => {
+                resolve_with_inline(dbghelp, stack_frame, frame.is_inline(), buffers, cb)
+            }
+            // `StackWalk64` doesn't expand inlined calls, so they're looked up
+            // by address like for `ResolveWhat::Address`.
+            StackFrame::Old(_) => resolve_address(dbghelp, what.address_or_ip(), buffers, cb),
         },
     }
 }
@@ -90,90 +185,158 @@ pub unsafe fn resolve(what: ResolveWhat<'_>, cb: &mut dyn FnMut(&super::Symbol))
 unsafe fn resolve_with_inline(
     dbghelp: &dbghelp::Init,
     frame: &STACKFRAME_EX,
+    is_inline: bool,
+    buffers: &mut Buffers,
     cb: &mut dyn FnMut(&super::Symbol),
 ) {
+    let process = GetCurrentProcess();
+    let addr = super::adjust_ip(frame.AddrPC.Offset as *mut _) as DWORD64;
+    // All frames `StackWalkEx` expands from one physical frame share its
+    // address. The virtual frames of the inlined calls carry the context
+    // identifying their level, but the context of the physical frame doesn't,
+    // and looking up its line by address would find the line of the innermost
+    // inlined call. Its level comes after all of the inlined ones, where the
+    // line is the call site of the outermost inlined call.
+    let has_inline_trace = (*dbghelp.dbghelp()).SymAddrIncludeInlineTrace().is_some();
+    let (first, inlined) = if has_inline_trace {
+        inline_trace(dbghelp, process, addr)
+    } else {
+        (0, 0)
+    };
+    let context = if is_inline || !has_inline_trace {
+        frame.InlineFrameContext
+    } else {
+        first + inlined
+    };
+    // Each inlined call is one level deeper than the call it was inlined
+    // into, the physical frame being at depth 0.
+    let (inlined_into, inline_depth) = if is_inline {
+        let depth = if context >= first && context < first + inlined {
+            first + inlined - context
+        } else {
+            1
+        };
+        (containing_function(dbghelp, process, addr, buffers), depth)
+    } else {
+        (None, 0)
+    };
     do_resolve(
-        |info| {
-            dbghelp.SymFromInlineContextW()(
-                GetCurrentProcess(),
-                super::adjust_ip(frame.AddrPC.Offset as *mut _) as u64,
-                frame.InlineFrameContext,
-                &mut 0,
-                info,
-            )
-        },
-        |line| {
-            dbghelp.SymGetLineFromInlineContextW()(
-                GetCurrentProcess(),
-                super::adjust_ip(frame.AddrPC.Offset as *mut _) as u64,
-                frame.InlineFrameContext,
-                0,
-                &mut 0,
-                line,
-            )
-        },
+        buffers,
+        |info| dbghelp.SymFromInlineContextW()(process, addr, context, &mut 0, info),
+        |line| dbghelp.SymGetLineFromInlineContextW()(process, addr, context, 0, &mut 0, line),
+        inlined_into,
+        inline_depth,
         cb,
     )
 }
 
+// Returns the name of the function actually containing `addr`, which for the
+// symbols of calls inlined there is the function they were inlined into.
+// `SymFromAddrW` doesn't look at inline contexts, so it always finds that one.
+unsafe fn containing_function(
+    dbghelp: &dbghelp::Init,
+    process: HANDLE,
+    addr: DWORD64,
+    buffers: &mut Buffers,
+) -> Option<*const [u8]> {
+    let (info, _, outer_name) = buffers.get();
+    if dbghelp.SymFromAddrW()(process, addr, &mut 0, info) != TRUE {
+        return None;
+    }
+    Some(reencode_name(info, outer_name))
+}
+
+// Returns the inline context of the innermost call inlined at `addr` and the
+// number of inlined calls there. The levels of the inlined calls follow the
+// innermost one, and the level of the function actually containing `addr`
+// comes last.
+unsafe fn inline_trace(dbghelp: &dbghelp::Init, process: HANDLE, addr: DWORD64) -> (DWORD, DWORD) {
+    let inlined = dbghelp.SymAddrIncludeInlineTrace()(process, addr);
+    let mut context = 0;
+    if inlined == 0
+        || dbghelp.SymQueryInlineTrace()(process, addr, 0, addr, addr, &mut context, &mut 0) != TRUE
+    {
+        return (0, 0);
+    }
+    (context, inlined)
+}
+
+unsafe fn resolve_address(
+    dbghelp: &dbghelp::Init,
+    addr: *mut c_void,
+    buffers: &mut Buffers,
+    cb: &mut dyn FnMut(&super::Symbol),
+) {
+    // The inline trace functions were added to dbghelp together with
+    // `StackWalkEx`, older versions can't expand inlined calls.
+    if (*dbghelp.dbghelp()).SymAddrIncludeInlineTrace().is_some() {
+        resolve_address_with_inline(dbghelp, addr, buffers, cb)
+    } else {
+        resolve_without_inline(dbghelp, addr, buffers, cb)
+    }
+}
+
+// Yields a symbol for each call inlined at `addr`, innermost first, followed
+// by the function actually containing it. Without a frame from `StackWalkEx`
+// there's no inline context to start from, so it's looked up with
+// `SymQueryInlineTrace` instead.
+unsafe fn resolve_address_with_inline(
+    dbghelp: &dbghelp::Init,
+    addr: *mut c_void,
+    buffers: &mut Buffers,
+    cb: &mut dyn FnMut(&super::Symbol),
+) {
+    let process = GetCurrentProcess();
+    let addr = addr as DWORD64;
+    let (first, inlined) = inline_trace(dbghelp, process, addr);
+    let outer = if inlined > 0 {
+        containing_function(dbghelp, process, addr, buffers)
+    } else {
+        None
+    };
+    for context in first..=first + inlined {
+        let inlined_into = outer.filter(|_| context < first + inlined);
+        do_resolve(
+            buffers,
+            |info| dbghelp.SymFromInlineContextW()(process, addr, context, &mut 0, info),
+            |line| dbghelp.SymGetLineFromInlineContextW()(process, addr, context, 0, &mut 0, line),
+            inlined_into,
+            first + inlined - context,
+            cb,
+        )
+    }
+}
+
 unsafe fn resolve_without_inline(
     dbghelp: &dbghelp::Init,
     addr: *mut c_void,
+    buffers: &mut Buffers,
     cb: &mut dyn FnMut(&super::Symbol),
 ) {
     do_resolve(
+        buffers,
         |info| dbghelp.SymFromAddrW()(GetCurrentProcess(), addr as DWORD64, &mut 0, info),
         |line| dbghelp.SymGetLineFromAddrW64()(GetCurrentProcess(), addr as DWORD64, &mut 0, line),
+        None,
+        0,
         cb,
     )
 }
 
 unsafe fn do_resolve(
+    buffers: &mut Buffers,
     sym_from_addr: impl FnOnce(*mut SYMBOL_INFOW) -> BOOL,
     get_line_from_addr: impl FnOnce(&mut IMAGEHLP_LINEW64) -> BOOL,
+    inlined_into: Option<*const [u8]>,
+    inline_depth: DWORD,
     cb: &mut dyn FnMut(&super::Symbol),
 ) {
-    const SIZE: usize = 2 * MAX_SYM_NAME + mem::size_of::<SYMBOL_INFOW>();
-    let mut data = Aligned8([0u8; SIZE]);
-    let data = &mut data.0;
-    let info = &mut *(data.as_mut_ptr() as *mut SYMBOL_INFOW);
-    info.MaxNameLen = MAX_SYM_NAME as ULONG;
-    // the struct size in C.  the value is different to
-    // `size_of::<SYMBOL_INFOW>() - MAX_SYM_NAME + 1` (== 81)
-    // due to struct alignment.
-    info.SizeOfStruct = 88;
+    let (info, name_buffer, _) = buffers.get();
 
     if sym_from_addr(info) != TRUE {
         return;
     }
-
-    // If the symbol name is greater than MaxNameLen, SymFromAddrW will
-    // give a buffer of (MaxNameLen - 1) characters and set NameLen to
-    // the real value.
-    let name_len = ::core::cmp::min(info.NameLen as usize, info.MaxNameLen as usize - 1);
-    let name_ptr = info.Name.as_ptr() as *const u16;
-    let name = slice::from_raw_parts(name_ptr, name_len);
-
-    // Reencode the utf-16 symbol to utf-8 so we can use `SymbolName::new` like
-    // all other platforms
-    let mut name_len = 0;
-    let mut name_buffer = [0; 256];
-    {
-        let mut remaining = &mut name_buffer[..];
-        for c in char::decode_utf16(name.iter().cloned()) {
-            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
-            let len = c.len_utf8();
-            if len < remaining.len() {
-                c.encode_utf8(remaining);
-                let tmp = remaining;
-                remaining = &mut tmp[len..];
-                name_len += len;
-            } else {
-                break;
-            }
-        }
-    }
-    let name = &name_buffer[..name_len] as *const [u8];
+    let name = reencode_name(info, name_buffer);
 
     let mut line = mem::zeroed::<IMAGEHLP_LINEW64>();
     line.SizeOfStruct = mem::size_of::<IMAGEHLP_LINEW64>() as DWORD;
@@ -200,12 +363,48 @@ unsafe fn do_resolve(
             addr: info.Address as *mut _,
             line: lineno,
             filename,
+            kind: if info.Flags & SYMFLAG_EXPORT != 0 {
+                SymbolKind::Export
+            } else {
+                SymbolKind::Pdb
+            },
+            inlined_into,
+            inline_depth,
             _filename_cache: cache(filename),
             _marker: marker::PhantomData,
         },
     })
 }
 
+// Reencodes the utf-16 name in `info` to utf-8 in `buffer` so we can use
+// `SymbolName::new` like all other platforms.
+unsafe fn reencode_name(info: &SYMBOL_INFOW, buffer: &mut [u8; NAME_SIZE]) -> *const [u8] {
+    // If the symbol name is greater than MaxNameLen, SymFromAddrW will
+    // give a buffer of (MaxNameLen - 1) characters and set NameLen to
+    // the real value.
+    let name_len = ::core::cmp::min(info.NameLen as usize, info.MaxNameLen as usize - 1);
+    let name_ptr = info.Name.as_ptr() as *const u16;
+    let name = slice::from_raw_parts(name_ptr, name_len);
+
+    let mut name_len = 0;
+    {
+        let mut remaining = &mut buffer[..];
+        for c in char::decode_utf16(name.iter().cloned()) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            let len = c.len_utf8();
+            if len < remaining.len() {
+                c.encode_utf8(remaining);
+                let tmp = remaining;
+                remaining = &mut tmp[len..];
+                name_len += len;
+            } else {
+                break;
+            }
+        }
+    }
+    &buffer[..name_len] as *const [u8]
+}
+
 #[cfg(feature = "std")]
 unsafe fn cache(filename: Option<*const [u16]>) -> Option<::std::ffi::OsString> {
     use std::os::windows::ffi::OsStringExt;
@@ -216,3 +415,51 @@ unsafe fn cache(filename: Option<*const [u16]>) -> Option<::std::ffi::OsString>
 unsafe fn cache(_filename: Option<*const [u16]>) {}
 
 pub unsafe fn clear_symbol_cache() {}
+
+// `refresh_modules` refreshes the module list of dbghelp itself.
+pub unsafe fn refresh_modules() {}
+
+// Reading PDBs without dbghelp would require a PDB parser, which this crate
+// doesn't have, so offline resolution isn't supported with this backend.
+#[cfg(feature = "std")]
+pub unsafe fn resolve_offline(
+    _path: &::std::path::Path,
+    _addrs: &[usize],
+    _image_relative: bool,
+    _cb: &mut dyn FnMut(usize, &super::Symbol),
+) -> bool {
+    false
+}
+
+/// Lists the modules of this process with the Toolhelp API. Their stated
+/// addresses are taken to be offsets from their base, as nothing can be
+/// resolved offline with this backend anyway.
+#[cfg(feature = "std")]
+pub unsafe fn loaded_modules() -> alloc::vec::Vec<super::LoadedModule> {
+    use alloc::vec::Vec;
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+
+    let mut modules = Vec::new();
+    let snap = CreateToolhelp32Snapshot(TH32CS_SNAPMODULE, 0);
+    if snap == INVALID_HANDLE_VALUE {
+        return modules;
+    }
+    let mut entry: MODULEENTRY32W = mem::zeroed();
+    entry.dwSize = mem::size_of_val(&entry) as DWORD;
+    let mut more = Module32FirstW(snap, &mut entry) == TRUE;
+    while more {
+        let len = entry.szExePath.iter().position(|&c| c == 0);
+        let path = OsString::from_wide(&entry.szExePath[..len.unwrap_or(entry.szExePath.len())]);
+        let base = entry.modBaseAddr as usize;
+        modules.push(super::LoadedModule {
+            base,
+            size: entry.modBaseSize as usize,
+            bias: base,
+            path: path.into(),
+        });
+        more = Module32NextW(snap, &mut entry) == TRUE;
+    }
+    CloseHandle(snap);
+    modules
+}
@@ -8,6 +8,7 @@ use self::mmap::Mmap;
 use self::stash::Stash;
 use super::BytesOrWideString;
 use super::ResolveWhat;
+use super::SymbolKind;
 use super::SymbolName;
 use addr2line::gimli;
 use core::convert::TryInto;
@@ -241,6 +242,14 @@ pub unsafe fn clear_symbol_cache() {
     Cache::with_global(|cache| cache.mappings.clear());
 }
 
+pub unsafe fn refresh_modules() {
+    Cache::with_global(|cache| {
+        // Mappings refer to libraries by index, which the new list changes.
+        cache.mappings.clear();
+        cache.libraries = native_libraries();
+    });
+}
+
 impl Cache {
     fn new() -> Cache {
         Cache {
@@ -355,44 +364,107 @@ pub unsafe fn resolve(what: ResolveWhat<'_>, cb: &mut dyn FnMut(&super::Symbol))
             Some(cx) => cx,
             None => return,
         };
-        let mut any_frames = false;
-        if let Ok(mut frames) = cx.dwarf.find_frames(addr as u64) {
-            while let Ok(Some(frame)) = frames.next() {
-                any_frames = true;
-                let name = match frame.function {
-                    Some(f) => Some(f.name.slice()),
-                    None => cx.object.search_symtab(addr as u64),
-                };
-                call(Symbol::Frame {
-                    addr: addr as *mut c_void,
-                    location: frame.location,
-                    name,
-                });
-            }
+        resolve_in(cx, addr as usize, &mut call);
+    });
+}
+
+/// Resolves addresses within the binary at `path` rather than within the
+/// current process, see `super::resolve_offline`.
+///
+/// The mapping for `path` is created for just this call and isn't added to the
+/// global cache. Returns `false` if `path` couldn't be loaded.
+#[cfg(feature = "std")]
+pub unsafe fn resolve_offline(
+    path: &Path,
+    addrs: &[usize],
+    image_relative: bool,
+    cb: &mut dyn FnMut(usize, &super::Symbol),
+) -> bool {
+    let mut mapping = match Mapping::new(path) {
+        Some(mapping) => mapping,
+        None => return false,
+    };
+    let image_base = if image_relative {
+        mapping.cx.object.image_base() as usize
+    } else {
+        0
+    };
+    for (i, offset) in addrs.iter().enumerate() {
+        let addr = image_base.wrapping_add(*offset);
+        resolve_in(&mut mapping.cx, addr, &mut |sym| {
+            // See `resolve` above.
+            let sym = mem::transmute::<Symbol<'_>, Symbol<'static>>(sym);
+            cb(i, &super::Symbol { inner: sym });
+        });
+    }
+    true
+}
+
+/// Lists the libraries currently loaded in this process, freshly enumerated
+/// rather than taken from the global cache.
+#[cfg(feature = "std")]
+pub unsafe fn loaded_modules() -> Vec<super::LoadedModule> {
+    native_libraries()
+        .into_iter()
+        .filter(|lib| !lib.name.is_empty())
+        .filter_map(|lib| {
+            let segments = lib.segments.iter().filter(|s| s.len > 0);
+            let start = segments
+                .clone()
+                .map(|s| s.stated_virtual_memory_address)
+                .min()?;
+            let end = segments
+                .map(|s| s.stated_virtual_memory_address.wrapping_add(s.len))
+                .max()?;
+            Some(super::LoadedModule {
+                base: lib.bias.wrapping_add(start),
+                size: end.wrapping_sub(start),
+                bias: lib.bias,
+                path: lib.name.into(),
+            })
+        })
+        .collect()
+}
+
+/// Looks up the stated virtual memory address `addr` in `cx`.
+fn resolve_in(cx: &mut Context<'_>, addr: usize, call: &mut dyn FnMut(Symbol<'_>)) {
+    let mut any_frames = false;
+    if let Ok(mut frames) = cx.dwarf.find_frames(addr as u64) {
+        while let Ok(Some(frame)) = frames.next() {
+            any_frames = true;
+            let name = match frame.function {
+                Some(f) => Some(f.name.slice()),
+                None => cx.object.search_symtab(addr as u64),
+            };
+            call(Symbol::Frame {
+                addr: addr as *mut c_void,
+                location: frame.location,
+                name,
+            });
         }
-        if !any_frames {
-            if let Some((object_cx, object_addr)) = cx.object.search_object_map(addr as u64) {
-                if let Ok(mut frames) = object_cx.dwarf.find_frames(object_addr) {
-                    while let Ok(Some(frame)) = frames.next() {
-                        any_frames = true;
-                        call(Symbol::Frame {
-                            addr: addr as *mut c_void,
-                            location: frame.location,
-                            name: frame.function.map(|f| f.name.slice()),
-                        });
-                    }
+    }
+    if !any_frames {
+        if let Some((object_cx, object_addr)) = cx.object.search_object_map(addr as u64) {
+            if let Ok(mut frames) = object_cx.dwarf.find_frames(object_addr) {
+                while let Ok(Some(frame)) = frames.next() {
+                    any_frames = true;
+                    call(Symbol::Frame {
+                        addr: addr as *mut c_void,
+                        location: frame.location,
+                        name: frame.function.map(|f| f.name.slice()),
+                    });
                 }
             }
         }
-        if !any_frames {
-            if let Some(name) = cx.object.search_symtab(addr as u64) {
-                call(Symbol::Symtab {
-                    addr: addr as *mut c_void,
-                    name,
-                });
-            }
+    }
+    if !any_frames {
+        if let Some(name) = cx.object.search_symtab(addr as u64) {
+            call(Symbol::Symtab {
+                addr: addr as *mut c_void,
+                name,
+            });
         }
-    });
+    }
 }
 
 pub enum Symbol<'a> {
@@ -459,4 +531,21 @@ impl Symbol<'_> {
             Symbol::Symtab { .. } => None,
         }
     }
+
+    pub fn kind(&self) -> Option<SymbolKind> {
+        match self {
+            Symbol::Frame { .. } => Some(SymbolKind::Dwarf),
+            Symbol::Symtab { .. } => Some(SymbolKind::SymbolTable),
+        }
+    }
+
+    pub fn inlined_into(&self) -> Option<SymbolName<'_>> {
+        None
+    }
+
+    // The symbols of a frame are all yielded together, so their depth follows
+    // from their order.
+    pub fn inline_depth(&self) -> u32 {
+        0
+    }
 }
@@ -24,6 +24,7 @@ pub struct Object<'a> {
     sections: SectionTable<'a>,
     symbols: Vec<(usize, &'a ImageSymbol)>,
     strings: StringTable<'a>,
+    image_base: usize,
 }
 
 pub fn get_image_base(data: &[u8]) -> Option<usize> {
@@ -71,9 +72,14 @@ impl<'a> Object<'a> {
             sections,
             strings,
             symbols,
+            image_base,
         })
     }
 
+    pub fn image_base(&self) -> u64 {
+        self.image_base as u64
+    }
+
     pub fn section(&self, _: &Stash, name: &str) -> Option<&'a [u8]> {
         Some(
             self.sections
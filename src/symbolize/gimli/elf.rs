@@ -7,7 +7,9 @@ use super::{Context, Mapping, Stash, Vec};
 use core::convert::{TryFrom, TryInto};
 use core::str;
 use object::elf::{ELFCOMPRESS_ZLIB, ELF_NOTE_GNU, NT_GNU_BUILD_ID, SHF_COMPRESSED};
-use object::read::elf::{CompressionHeader, FileHeader, SectionHeader, SectionTable, Sym};
+use object::read::elf::{
+    CompressionHeader, FileHeader, ProgramHeader, SectionHeader, SectionTable, Sym,
+};
 use object::read::StringTable;
 use object::{BigEndian, Bytes, NativeEndian};
 
@@ -84,6 +86,8 @@ pub struct Object<'a> {
     strings: StringTable<'a>,
     /// List of pre-parsed and sorted symbols by base address.
     syms: Vec<ParsedSym>,
+    /// Address of the lowest loadable segment.
+    image_base: u64,
 }
 
 impl<'a> Object<'a> {
@@ -128,15 +132,29 @@ impl<'a> Object<'a> {
             })
             .collect::<Vec<_>>();
         syms.sort_unstable_by_key(|s| s.address);
+        let image_base = match elf.program_headers(endian, data) {
+            Ok(headers) => headers
+                .iter()
+                .filter(|ph| ph.p_type(endian) == object::elf::PT_LOAD)
+                .map(|ph| u64::from(ph.p_vaddr(endian)))
+                .min()
+                .unwrap_or(0),
+            Err(_) => 0,
+        };
         Some(Object {
             endian,
             data,
             sections,
             strings,
             syms,
+            image_base,
         })
     }
 
+    pub fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
     pub fn section(&self, stash: &'a Stash, name: &str) -> Option<&'a [u8]> {
         if let Some(section) = self.section_header(name) {
             let mut data = Bytes(section.data(self.endian, self.data).ok()?);
@@ -166,6 +166,8 @@ pub struct Object<'a> {
     object_map: Option<object::ObjectMap<'a>>,
     // The outer Option is for lazy loading, and the inner Option allows load errors to be cached.
     object_mappings: Box<[Option<Option<Mapping>>]>,
+    // Address of the `__TEXT` segment, which starts with the Mach-O header.
+    image_base: u64,
 }
 
 impl<'a> Object<'a> {
@@ -177,8 +179,12 @@ impl<'a> Object<'a> {
         let mut commands = mach.load_commands(endian, data, 0).ok()?;
         let mut object_map = None;
         let mut object_mappings = Vec::new();
+        let mut image_base = 0;
         while let Ok(Some(command)) = commands.next() {
             if let Some((segment, section_data)) = MachSegment::from_command(command).ok()? {
+                if segment.name() == b"__TEXT" {
+                    image_base = u64::from(segment.vmaddr(endian));
+                }
                 // Object files should have all sections in a single unnamed segment load command.
                 if segment.name() == b"__DWARF" || (is_object && segment.name() == b"") {
                     dwarf = segment.sections(endian, section_data).ok();
@@ -219,9 +225,14 @@ impl<'a> Object<'a> {
             syms_sort_by_name,
             object_map,
             object_mappings: object_mappings.into_boxed_slice(),
+            image_base,
         })
     }
 
+    pub fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
     pub fn section(&self, _: &Stash, name: &str) -> Option<&'a [u8]> {
         let name = name.as_bytes();
         let dwarf = self.dwarf?;
@@ -3,7 +3,7 @@ use core::marker::PhantomData;
 
 use super::super::backtrace::miri::{resolve_addr, Frame};
 use super::BytesOrWideString;
-use super::{ResolveWhat, SymbolName};
+use super::{ResolveWhat, SymbolKind, SymbolName};
 
 pub unsafe fn resolve(what: ResolveWhat<'_>, cb: &mut dyn FnMut(&super::Symbol)) {
     let sym = match what {
@@ -45,6 +45,18 @@ impl<'a> Symbol<'a> {
         Some(self.inner.inner.colno)
     }
 
+    pub fn kind(&self) -> Option<SymbolKind> {
+        None
+    }
+
+    pub fn inlined_into(&self) -> Option<SymbolName<'_>> {
+        None
+    }
+
+    pub fn inline_depth(&self) -> u32 {
+        0
+    }
+
     #[cfg(feature = "std")]
     pub fn filename(&self) -> Option<&std::path::Path> {
         Some(std::path::Path::new(
@@ -54,3 +66,20 @@ impl<'a> Symbol<'a> {
 }
 
 pub unsafe fn clear_symbol_cache() {}
+
+pub unsafe fn refresh_modules() {}
+
+#[cfg(feature = "std")]
+pub unsafe fn resolve_offline(
+    _path: &::std::path::Path,
+    _addrs: &[usize],
+    _image_relative: bool,
+    _cb: &mut dyn FnMut(usize, &super::Symbol),
+) -> bool {
+    false
+}
+
+#[cfg(feature = "std")]
+pub unsafe fn loaded_modules() -> alloc::vec::Vec<super::LoadedModule> {
+    alloc::vec::Vec::new()
+}
@@ -2,7 +2,9 @@ use core::{fmt, str};
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "std")] {
-        use std::path::Path;
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        use std::path::{Path, PathBuf};
         use std::prelude::v1::*;
     }
 }
@@ -10,6 +12,8 @@ cfg_if::cfg_if! {
 use super::backtrace::Frame;
 use super::types::BytesOrWideString;
 use core::ffi::c_void;
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 use rustc_demangle::{try_demangle, Demangle};
 
 /// Resolve an address to a symbol, passing the symbol to the specified
@@ -105,6 +109,262 @@ pub fn resolve_frame<F: FnMut(&Symbol)>(frame: &Frame, cb: F) {
     unsafe { resolve_frame_unsynchronized(frame, cb) }
 }
 
+/// A map from the modules of a captured backtrace to binaries on the local
+/// file system, used by `Backtrace::resolve_offline`.
+///
+/// Each module is identified by the base address it was loaded at when the
+/// backtrace was captured, as reported by `Frame::module_base_address`, which
+/// is the address its lowest segment (and typically its headers) were mapped
+/// at. The binary at the associated path must be the same build as the one
+/// that was loaded, but it doesn't have to be loaded in the current process,
+/// so backtraces can be symbolized on a different host than they were
+/// captured on.
+///
+/// # Required features
+///
+/// This type requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct SymbolSource {
+    modules: Vec<(usize, PathBuf)>,
+}
+
+#[cfg(feature = "std")]
+impl SymbolSource {
+    /// Creates an empty map.
+    pub fn new() -> SymbolSource {
+        SymbolSource::default()
+    }
+
+    /// Adds the module that was loaded at `base`, whose debug information is
+    /// read from the binary at `path`.
+    pub fn module(mut self, base: *mut c_void, path: impl Into<PathBuf>) -> SymbolSource {
+        self.modules.push((base as usize, path.into()));
+        self
+    }
+
+    pub(crate) fn modules(&self) -> impl Iterator<Item = (usize, &Path)> {
+        self.modules
+            .iter()
+            .map(|(base, path)| (*base, path.as_path()))
+    }
+}
+
+/// A snapshot of the modules loaded in the process, used by
+/// `Backtrace::resolve_with_module_map`.
+///
+/// Modules can be unloaded and others loaded at the same addresses while a
+/// process runs, so addresses captured some time ago may no longer belong to
+/// the module they were in. A profiler which captures a map along with its
+/// samples can still resolve them against the modules that were loaded when
+/// they were taken, long after the layout changed.
+///
+/// Each map has a version, which increases whenever a capture finds a
+/// different set of modules than the capture before it, and stays the same
+/// otherwise. Samples taken with maps of the same version can therefore share
+/// a single map.
+///
+/// # Required features
+///
+/// This type requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct ModuleMap {
+    version: u64,
+    modules: Vec<LoadedModule>,
+}
+
+#[cfg(feature = "std")]
+impl ModuleMap {
+    /// Lists the modules currently loaded in the process.
+    ///
+    /// Modules are enumerated with the Toolhelp API on Windows, and like the
+    /// gimli symbolizer does elsewhere, for example with `dl_iterate_phdr` on
+    /// Linux. The map is empty on platforms where modules can't be
+    /// enumerated.
+    pub fn capture() -> ModuleMap {
+        // The last version handed out along with a hash of its modules, only
+        // accessed under the lock.
+        static VERSION: AtomicUsize = AtomicUsize::new(0);
+        static LAST_HASH: AtomicUsize = AtomicUsize::new(0);
+
+        let _guard = crate::lock::lock();
+        let modules = unsafe { imp::loaded_modules() };
+        let mut hasher = DefaultHasher::new();
+        modules.hash(&mut hasher);
+        let hash = hasher.finish() as usize;
+        let mut version = VERSION.load(Ordering::SeqCst);
+        if version == 0 || hash != LAST_HASH.load(Ordering::SeqCst) {
+            version += 1;
+            VERSION.store(version, Ordering::SeqCst);
+            LAST_HASH.store(hash, Ordering::SeqCst);
+        }
+        ModuleMap {
+            version: version as u64,
+            modules,
+        }
+    }
+
+    /// Returns the version of the layout this map describes.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the modules of this map.
+    pub fn modules(&self) -> &[LoadedModule] {
+        &self.modules
+    }
+
+    /// Returns the module `addr` was in when this map was captured.
+    pub fn module_at(&self, addr: *mut c_void) -> Option<&LoadedModule> {
+        self.modules.iter().find(|m| m.contains(addr))
+    }
+}
+
+/// A module listed in a `ModuleMap`.
+///
+/// # Required features
+///
+/// This type requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LoadedModule {
+    pub(crate) base: usize,
+    pub(crate) size: usize,
+    // Subtracted from addresses within the module to get the addresses its
+    // debug information uses.
+    pub(crate) bias: usize,
+    pub(crate) path: PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl LoadedModule {
+    /// Returns the lowest address the module was mapped at.
+    pub fn base(&self) -> *mut c_void {
+        self.base as *mut c_void
+    }
+
+    /// Returns the size in bytes of the address range the module spans,
+    /// starting at `base`.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the path of the module's binary.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether `addr` is within the module.
+    pub fn contains(&self, addr: *mut c_void) -> bool {
+        let addr = addr as usize;
+        addr >= self.base && addr - self.base < self.size
+    }
+
+    /// Returns the address the debug information of the module uses for
+    /// `addr`.
+    pub(crate) fn stated_address(&self, addr: *mut c_void) -> usize {
+        (addr as usize).wrapping_sub(self.bias)
+    }
+}
+
+// Ranges registered with `register_anonymous_module` as `(base, size, name)`,
+// allocated on first use and guarded by `crate::lock`.
+#[cfg(feature = "std")]
+static mut ANONYMOUS_MODULES: *mut Vec<(usize, usize, String)> = 0 as *mut _;
+
+#[cfg(feature = "std")]
+fn with_anonymous_modules<R>(f: impl FnOnce(&mut Vec<(usize, usize, String)>) -> R) -> R {
+    let _guard = crate::lock::lock();
+    unsafe {
+        if ANONYMOUS_MODULES.is_null() {
+            ANONYMOUS_MODULES = Box::into_raw(Box::new(Vec::new()));
+        }
+        f(&mut *ANONYMOUS_MODULES)
+    }
+}
+
+/// Registers `name` for the `size` bytes of code starting at `base`, which
+/// don't belong to any module the OS knows about.
+///
+/// This is meant for managed runtimes which generate code at runtime or map
+/// modules by hand, such as .NET or V8 hosts, and know what that code is but
+/// have no symbols for it. Frames within the range are labeled with `name`
+/// when a `Backtrace` is resolved and no symbol is found for them, and
+/// `BacktraceFrame::module_offset` reports `name` and the offset from `base`
+/// for them if their address isn't within a module loaded by the OS.
+///
+/// Registering `base` again replaces its previous registration. If ranges
+/// overlap the one registered last takes precedence.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(feature = "std")]
+pub fn register_anonymous_module(base: *mut c_void, size: usize, name: &str) {
+    with_anonymous_modules(|modules| {
+        modules.retain(|m| m.0 != base as usize);
+        modules.push((base as usize, size, name.to_string()));
+    })
+}
+
+/// Removes the range starting at `base` registered with
+/// `register_anonymous_module`, for example when the code in it is freed.
+///
+/// Returns whether a range was registered at `base`.
+///
+/// # Required features
+///
+/// This function requires the `std` feature of the `backtrace` crate to be
+/// enabled, and the `std` feature is enabled by default.
+#[cfg(feature = "std")]
+pub fn unregister_anonymous_module(base: *mut c_void) -> bool {
+    with_anonymous_modules(|modules| {
+        let len = modules.len();
+        modules.retain(|m| m.0 != base as usize);
+        modules.len() != len
+    })
+}
+
+/// Returns the base address and name of the range registered with
+/// `register_anonymous_module` which contains `addr`.
+#[cfg(feature = "std")]
+pub(crate) fn anonymous_module_at(addr: usize) -> Option<(usize, String)> {
+    with_anonymous_modules(|modules| {
+        modules
+            .iter()
+            .rev()
+            .find(|m| addr >= m.0 && addr - m.0 < m.1)
+            .map(|m| (m.0, m.2.clone()))
+    })
+}
+
+/// Resolves addresses within the binary at `path` using only its own debug
+/// information, independent of what's loaded in the current process.
+///
+/// If `image_relative` is set each of `addrs` is relative to the address the
+/// binary's lowest segment is loaded at, otherwise they're the addresses the
+/// binary's debug information uses. They're looked up as-is, without the
+/// adjustment `resolve` makes for return addresses. `cb` is called with the
+/// index into `addrs` of each symbol found.
+///
+/// This is only supported by the gimli backend, and otherwise or if `path`
+/// couldn't be read `false` is returned.
+#[cfg(feature = "std")]
+pub(crate) fn resolve_offline(
+    path: &Path,
+    addrs: &[usize],
+    image_relative: bool,
+    cb: &mut dyn FnMut(usize, &Symbol),
+) -> bool {
+    let _guard = crate::lock::lock();
+    unsafe { imp::resolve_offline(path, addrs, image_relative, cb) }
+}
+
 pub enum ResolveWhat<'a> {
     Address(*mut c_void),
     Frame(&'a Frame),
@@ -209,6 +469,11 @@ impl Symbol {
     }
 
     /// Returns the starting address of this function.
+    ///
+    /// On MSVC this is the `Address` dbghelp reports for the symbol, so the
+    /// offset of an instruction into its function is the difference between
+    /// the two. The gimli backend reports the address which was looked up
+    /// instead, and nothing for symbols only found in the symbol table.
     pub fn addr(&self) -> Option<*mut c_void> {
         self.inner.addr().map(|p| p as *mut _)
     }
@@ -251,6 +516,34 @@ impl Symbol {
     pub fn filename(&self) -> Option<&Path> {
         self.inner.filename()
     }
+
+    /// Returns where the information about this symbol came from.
+    ///
+    /// With dbghelp this tells symbols from a PDB apart from symbols found in
+    /// the export table of a DLL, which dbghelp falls back to when it can't
+    /// find a PDB. With gimli it tells DWARF information apart from the
+    /// symbol table. Returns `None` if the backend doesn't know.
+    pub fn kind(&self) -> Option<SymbolKind> {
+        self.inner.kind()
+    }
+
+    /// Returns the name of the function this symbol's call was inlined into.
+    ///
+    /// When a frame expands to several symbols because of inlining, this is
+    /// `Some` for each inlined call and names the function that physically
+    /// contains the code, allowing display like `inlined_fn (in outer_fn)`.
+    /// It's `None` for the outer function itself and for symbols which weren't
+    /// inlined. Only dbghelp currently provides a value here.
+    pub fn inlined_into(&self) -> Option<SymbolName<'_>> {
+        self.inner.inlined_into()
+    }
+
+    /// Returns how many calls deep this symbol was inlined into its physical
+    /// frame, if the backend resolved it on its own rather than along with
+    /// the calls it was inlined into, and 0 otherwise.
+    pub(crate) fn inline_depth(&self) -> u32 {
+        self.inner.inline_depth()
+    }
 }
 
 impl fmt::Debug for Symbol {
@@ -354,6 +647,368 @@ impl<'a> SymbolName<'a> {
     pub fn as_bytes(&self) -> &'a [u8] {
         self.bytes
     }
+
+    /// Returns a value which displays the demangled name in the given style.
+    ///
+    /// `DemangleStyle::Full` displays the same as the `Display`
+    /// implementation of this type, and the other styles trade detail for
+    /// more concise names.
+    pub fn display(&self, style: DemangleStyle) -> impl fmt::Display + '_ {
+        struct Styled<'a, 'b> {
+            name: &'a SymbolName<'b>,
+            style: DemangleStyle,
+        }
+
+        impl fmt::Display for Styled<'_, '_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self.style {
+                    DemangleStyle::Full => write!(f, "{}", self.name),
+                    DemangleStyle::NoHash => write!(f, "{:#}", self.name),
+                    DemangleStyle::NoGenerics => {
+                        let mut strip = StripGenerics::new(f);
+                        fmt::write(&mut strip, format_args!("{:#}", self.name))?;
+                        strip.finish()
+                    }
+                    DemangleStyle::Friendly => {
+                        let mut friendly = FriendlyNames::new(f);
+                        fmt::write(&mut friendly, format_args!("{:#}", self.name))?;
+                        friendly.finish()
+                    }
+                }
+            }
+        }
+
+        Styled { name: self, style }
+    }
+
+    /// Returns whether the demangled name, without any trailing hash, is equal
+    /// to `name`.
+    ///
+    /// This doesn't allocate, which makes it usable while other threads are
+    /// suspended.
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        struct Matcher<'a> {
+            rest: &'a str,
+        }
+
+        impl fmt::Write for Matcher<'_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                if self.rest.starts_with(s) {
+                    self.rest = &self.rest[s.len()..];
+                    Ok(())
+                } else {
+                    Err(fmt::Error)
+                }
+            }
+        }
+
+        let mut matcher = Matcher { rest: name };
+        fmt::write(&mut matcher, format_args!("{:#}", self)).is_ok() && matcher.rest.is_empty()
+    }
+}
+
+/// Where the information about a symbol came from, see `Symbol::kind`.
+///
+/// This gives an idea of how reliable a symbol is. Symbols from full debug
+/// information are precise, while `Export` and `SymbolTable` symbols only
+/// name the closest preceding symbol known to the binary. When debug
+/// information is missing that can be a function other than the one actually
+/// executing, and there's no file or line information either.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-rustc", derive(RustcDecodable, RustcEncodable))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[non_exhaustive]
+pub enum SymbolKind {
+    /// Debug information loaded by dbghelp, typically from a PDB.
+    Pdb,
+    /// The export table of a DLL, used by dbghelp when no PDB is available.
+    Export,
+    /// DWARF debug information.
+    Dwarf,
+    /// The symbol table of the binary, used when no DWARF information covers
+    /// the address.
+    SymbolTable,
+}
+
+/// How much detail to include when displaying a demangled symbol name, see
+/// `SymbolName::display`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DemangleStyle {
+    /// The full demangled name, which is what the `Display` implementation of
+    /// `SymbolName` prints.
+    Full,
+    /// The demangled name without the hash legacy Rust symbols end with, which
+    /// is what the alternate (`{:#}`) format of `SymbolName` prints.
+    NoHash,
+    /// Like `NoHash`, but also without any generic arguments, so for example
+    /// `alloc::vec::Vec<T>::push` is displayed as `alloc::vec::Vec::push`.
+    /// Qualified paths such as `<T as Trait>::method` are kept.
+    NoGenerics,
+    /// Like `NoHash`, but tidied up for reading: closures are displayed as
+    /// `{closure}` rather than `{{closure}}` or `{closure#0}`, and paths
+    /// within generic arguments are shortened to their last segment. For
+    /// example `core::ptr::drop_in_place<std::rt::lang_start<()>::{{closure}}>`
+    /// is displayed as `core::ptr::drop_in_place<lang_start<()>::{closure}>`,
+    /// and `<alloc::string::String as core::fmt::Display>::fmt` as
+    /// `<String as Display>::fmt`.
+    ///
+    /// This is meant for Rust crash stacks read by people, where deeply
+    /// generic names are otherwise hard to make sense of. The names are no
+    /// longer unique, so they shouldn't be used to identify functions.
+    Friendly,
+}
+
+impl Default for DemangleStyle {
+    fn default() -> DemangleStyle {
+        DemangleStyle::Full
+    }
+}
+
+/// A `fmt::Write` adapter removing generic arguments from a symbol name.
+///
+/// Written text is streamed through rather than buffered so this doesn't
+/// allocate. A `<` opens a list of generic arguments if it follows an
+/// identifier or a `::` (as in `f::<T>`), any other `<` starts a qualified
+/// path and is kept.
+struct StripGenerics<'a, 'b> {
+    out: &'a mut fmt::Formatter<'b>,
+    depth: usize,
+    prev: char,
+    // Number of `:` at the top level which haven't been written yet because
+    // they may be part of a `::<` which is removed.
+    colons: usize,
+}
+
+impl<'a, 'b> StripGenerics<'a, 'b> {
+    fn new(out: &'a mut fmt::Formatter<'b>) -> StripGenerics<'a, 'b> {
+        StripGenerics {
+            out,
+            depth: 0,
+            prev: ' ',
+            colons: 0,
+        }
+    }
+
+    fn flush_colons(&mut self) -> fmt::Result {
+        for _ in 0..self.colons {
+            self.out.write_str(":")?;
+        }
+        self.colons = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> fmt::Result {
+        self.flush_colons()
+    }
+}
+
+impl fmt::Write for StripGenerics<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let prev = self.prev;
+            self.prev = c;
+            if self.depth > 0 {
+                match c {
+                    '<' => self.depth += 1,
+                    // Not the arrow of a function pointer type.
+                    '>' if prev != '-' => self.depth -= 1,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                ':' => self.colons += 1,
+                '<' if self.colons == 2 || prev.is_alphanumeric() || prev == '_' => {
+                    self.colons = 0;
+                    self.depth = 1;
+                }
+                _ => {
+                    self.flush_colons()?;
+                    self.out.write_char(c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A `fmt::Write` adapter tidying up a symbol name, see
+/// `DemangleStyle::Friendly`.
+///
+/// Like `StripGenerics` this streams the name through without allocating, so
+/// text which may have to be changed is held back in fixed buffers. Should a
+/// name not fit, the rest of it is written as it is.
+struct FriendlyNames<'a, 'b> {
+    out: &'a mut fmt::Formatter<'b>,
+    depth: usize,
+    prev: char,
+    // Number of `:` written since the last segment of a path within angle
+    // brackets, which isn't written yet either.
+    colons: usize,
+    // The path segment being written within angle brackets. It's held back
+    // until it's known whether a `::` follows, which makes it a prefix to
+    // leave out.
+    segment: Pending,
+    // A `{...}` group being written, such as `{{closure}}`, and how many of
+    // its braces are open.
+    group: Pending,
+    braces: usize,
+    plain: bool,
+}
+
+/// Text held back by `FriendlyNames`.
+#[derive(Clone, Copy)]
+struct Pending {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Pending {
+    fn new() -> Pending {
+        Pending {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole characters are pushed.
+        str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    fn push(&mut self, c: char) -> bool {
+        if self.len + c.len_utf8() > self.buf.len() {
+            return false;
+        }
+        self.len += c.encode_utf8(&mut self.buf[self.len..]).len();
+        true
+    }
+}
+
+impl<'a, 'b> FriendlyNames<'a, 'b> {
+    fn new(out: &'a mut fmt::Formatter<'b>) -> FriendlyNames<'a, 'b> {
+        FriendlyNames {
+            out,
+            depth: 0,
+            prev: ' ',
+            colons: 0,
+            segment: Pending::new(),
+            group: Pending::new(),
+            braces: 0,
+            plain: false,
+        }
+    }
+
+    /// Writes `c`, shortening paths within angle brackets.
+    fn put(&mut self, c: char) -> fmt::Result {
+        use core::fmt::Write;
+
+        let prev = self.prev;
+        self.prev = c;
+        if self.plain {
+            return self.out.write_char(c);
+        }
+        if self.depth == 0 {
+            if c == '<' {
+                self.depth = 1;
+            }
+            return self.out.write_char(c);
+        }
+        if c == ':' {
+            self.colons += 1;
+            return Ok(());
+        }
+        if c.is_alphanumeric() || matches!(c, '_' | '{' | '}' | '#' | '$' | '.') {
+            if self.colons == 2 && self.segment.len > 0 {
+                self.segment.len = 0;
+                self.colons = 0;
+            } else if self.colons > 0 {
+                self.flush_segment()?;
+            }
+            if !self.segment.push(c) {
+                self.flush_segment()?;
+                self.plain = true;
+                self.out.write_char(c)?;
+            }
+            return Ok(());
+        }
+        self.flush_segment()?;
+        match c {
+            '<' => self.depth += 1,
+            // Not the arrow of a function pointer type.
+            '>' if prev != '-' => self.depth -= 1,
+            _ => {}
+        }
+        self.out.write_char(c)
+    }
+
+    fn flush_segment(&mut self) -> fmt::Result {
+        self.out.write_str(self.segment.as_str())?;
+        self.segment.len = 0;
+        for _ in 0..self.colons {
+            self.out.write_str(":")?;
+        }
+        self.colons = 0;
+        Ok(())
+    }
+
+    /// Writes the completed `{...}` group, as `{closure}` for both the
+    /// `{{closure}}` of legacy symbols and the `{closure#0}` of v0 symbols.
+    /// Later closures of a function keep their index.
+    fn flush_group(&mut self) -> fmt::Result {
+        let group = self.group;
+        self.group.len = 0;
+        let mut inner = group.as_str();
+        if inner.starts_with("{{") && inner.ends_with("}}") && inner.len() >= 4 {
+            inner = &inner[2..inner.len() - 2];
+        } else if inner.starts_with('{') && inner.ends_with('}') && inner.len() >= 2 {
+            inner = &inner[1..inner.len() - 1];
+        }
+        if inner.ends_with("#0") {
+            inner = &inner[..inner.len() - 2];
+        }
+        self.put('{')?;
+        for c in inner.chars() {
+            self.put(c)?;
+        }
+        self.put('}')
+    }
+
+    fn finish(mut self) -> fmt::Result {
+        // An unterminated group is written as it is.
+        let group = self.group;
+        for c in group.as_str().chars() {
+            self.put(c)?;
+        }
+        self.flush_segment()
+    }
+}
+
+impl fmt::Write for FriendlyNames<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.plain || (self.braces == 0 && c != '{') {
+                self.put(c)?;
+                continue;
+            }
+            match c {
+                '{' => self.braces += 1,
+                '}' => self.braces -= 1,
+                _ => {}
+            }
+            if !self.group.push(c) {
+                let group = self.group;
+                self.group.len = 0;
+                self.flush_segment()?;
+                self.plain = true;
+                self.out.write_str(group.as_str())?;
+                self.out.write_char(c)?;
+            } else if self.braces == 0 {
+                self.flush_group()?;
+            }
+        }
+        Ok(())
+    }
 }
 
 fn format_symbol_name(
@@ -450,19 +1105,61 @@ cfg_if::cfg_if! {
 ///
 /// # Caveats
 ///
-/// While this function is always available it doesn't actually do anything on
-/// most implementations. Libraries like dbghelp or libbacktrace do not provide
-/// facilities to deallocate state and manage the allocated memory. For now the
-/// `gimli-symbolize` feature of this crate is the only feature where this
-/// function has any effect.
+/// The symbols cached by `Backtrace::resolve_cached` are dropped on every
+/// platform. Beyond that this doesn't do anything on most implementations, as
+/// libraries like dbghelp or libbacktrace do not provide facilities to
+/// deallocate state and manage the allocated memory. For now the
+/// `gimli-symbolize` feature of this crate is the only feature where the
+/// parsed debug information is released as well.
 #[cfg(feature = "std")]
 pub fn clear_symbol_cache() {
+    crate::capture::clear_global_symbol_cache();
     let _guard = crate::lock::lock();
     unsafe {
         imp::clear_symbol_cache();
     }
 }
 
+/// Updates the list of modules loaded into the process, which is used to walk
+/// stacks and resolve symbols.
+///
+/// The list is built the first time it's needed and not updated afterwards,
+/// so frames of libraries loaded later on, for example with `dlopen` or
+/// `LoadLibrary`, can't be resolved and may not be unwound until this is
+/// called. Long running processes which load libraries dynamically should
+/// call this after doing so.
+///
+/// On Windows this calls `SymRefreshModuleList`, with the `gimli-symbolize`
+/// feature the libraries are enumerated again and the debug info parsed so
+/// far is dropped, as with `clear_symbol_cache`. Other implementations pick
+/// up new modules on their own and this does nothing.
+///
+/// All `SymbolCache`s are invalidated as well, as the addresses they hold may
+/// now belong to different modules.
+#[cfg(feature = "std")]
+pub fn refresh_modules() {
+    MODULES_GENERATION.fetch_add(1, Ordering::SeqCst);
+    let _guard = crate::lock::lock();
+    unsafe {
+        imp::refresh_modules();
+    }
+    // dbghelp is also used to walk stacks when symbols are resolved with
+    // gimli, so its list is refreshed regardless of `imp`.
+    #[cfg(all(windows, not(target_vendor = "uwp")))]
+    crate::dbghelp::refresh_module_list();
+}
+
+// Bumped by `refresh_modules`, so caches of resolved symbols can tell that
+// they're stale.
+#[cfg(feature = "std")]
+static MODULES_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns how often the list of modules has been refreshed so far.
+#[cfg(feature = "std")]
+pub(crate) fn modules_generation() -> usize {
+    MODULES_GENERATION.load(Ordering::SeqCst)
+}
+
 cfg_if::cfg_if! {
     if #[cfg(miri)] {
         mod miri;
@@ -483,3 +1180,115 @@ cfg_if::cfg_if! {
         use noop as imp;
     }
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn concise(name: &str) -> String {
+        SymbolName::new(name.as_bytes())
+            .display(DemangleStyle::NoGenerics)
+            .to_string()
+    }
+
+    #[test]
+    fn demangle_styles() {
+        let legacy = SymbolName::new(b"_ZN4core3ptr85drop_in_place$LT$std..rt..lang_start$LT$$LP$$RP$$GT$..$u7b$$u7b$closure$u7d$$u7d$$GT$17h1234567890abcdefE");
+        assert_eq!(
+            legacy.display(DemangleStyle::Full).to_string(),
+            "core::ptr::drop_in_place<std::rt::lang_start<()>::{{closure}}>::h1234567890abcdef"
+        );
+        assert_eq!(
+            legacy.display(DemangleStyle::NoHash).to_string(),
+            "core::ptr::drop_in_place<std::rt::lang_start<()>::{{closure}}>"
+        );
+        assert_eq!(
+            legacy.display(DemangleStyle::NoGenerics).to_string(),
+            "core::ptr::drop_in_place"
+        );
+        assert_eq!(
+            legacy.display(DemangleStyle::Friendly).to_string(),
+            "core::ptr::drop_in_place<lang_start<()>::{closure}>"
+        );
+        assert_eq!(DemangleStyle::default(), DemangleStyle::Full,);
+    }
+
+    #[test]
+    fn friendly_names() {
+        let friendly = |name: &str| {
+            SymbolName::new(name.as_bytes())
+                .display(DemangleStyle::Friendly)
+                .to_string()
+        };
+        assert_eq!(
+            friendly("<alloc::string::String as core::fmt::Display>::fmt"),
+            "<String as Display>::fmt"
+        );
+        assert_eq!(
+            friendly("std::panicking::try::do_call::{{closure}}"),
+            "std::panicking::try::do_call::{closure}"
+        );
+        assert_eq!(
+            friendly("app::main::{closure#0}::{closure#1}"),
+            "app::main::{closure}::{closure#1}"
+        );
+        assert_eq!(
+            friendly("core::ops::function::FnOnce::call_once<app::main::{closure#0}, ()>"),
+            "core::ops::function::FnOnce::call_once<{closure}, ()>"
+        );
+        assert_eq!(
+            friendly(
+                "alloc::boxed::Box<dyn core::ops::function::Fn(&str) -> core::option::Option<u8>>"
+            ),
+            "alloc::boxed::Box<dyn Fn(&str) -> Option<u8>>"
+        );
+        assert_eq!(
+            friendly("std::collections::HashMap<[u8; 4], alloc::vec::Vec<i32>>::get"),
+            "std::collections::HashMap<[u8; 4], Vec<i32>>::get"
+        );
+        assert_eq!(friendly("main"), "main");
+        assert_eq!(friendly("unterminated::{closure"), "unterminated::{closure");
+
+        let long = format!("Wrapper<{}::Inner>", "a".repeat(300));
+        assert_eq!(friendly(&long), long);
+    }
+
+    #[test]
+    fn anonymous_modules() {
+        let code = [0u8; 64];
+        let base = code.as_ptr() as *mut c_void;
+        let addr = base as usize + 16;
+        assert_eq!(anonymous_module_at(addr), None);
+
+        register_anonymous_module(base, 64, "jit");
+        register_anonymous_module((addr + 8) as *mut c_void, 8, "stub");
+        assert_eq!(
+            anonymous_module_at(addr),
+            Some((base as usize, "jit".to_string()))
+        );
+        assert_eq!(anonymous_module_at(addr + 8).unwrap().1, "stub");
+        assert_eq!(anonymous_module_at(base as usize + 64), None);
+
+        register_anonymous_module(base, 32, "renamed");
+        assert_eq!(anonymous_module_at(addr).unwrap().1, "renamed");
+        assert!(unregister_anonymous_module(base));
+        assert!(!unregister_anonymous_module(base));
+        assert!(unregister_anonymous_module((addr + 8) as *mut c_void));
+        assert_eq!(anonymous_module_at(addr), None);
+    }
+
+    #[test]
+    fn strip_generics() {
+        assert_eq!(concise("alloc::vec::Vec<T>::push"), "alloc::vec::Vec::push");
+        assert_eq!(concise("core::mem::drop::<u32>"), "core::mem::drop");
+        assert_eq!(
+            concise("<alloc::vec::Vec<T> as core::ops::Drop>::drop"),
+            "<alloc::vec::Vec as core::ops::Drop>::drop"
+        );
+        assert_eq!(concise("foo<fn() -> u8>::bar"), "foo::bar");
+        assert_eq!(
+            concise("std::rt::lang_start::{{closure}}"),
+            "std::rt::lang_start::{{closure}}"
+        );
+    }
+}
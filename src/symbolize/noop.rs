@@ -1,7 +1,7 @@
 //! Empty symbolication strategy used to compile for platforms that have no
 //! support.
 
-use super::{BytesOrWideString, ResolveWhat, SymbolName};
+use super::{BytesOrWideString, ResolveWhat, SymbolKind, SymbolName};
 use core::ffi::c_void;
 use core::marker;
 
@@ -36,6 +36,35 @@ impl Symbol<'_> {
     pub fn colno(&self) -> Option<u32> {
         None
     }
+
+    pub fn kind(&self) -> Option<SymbolKind> {
+        None
+    }
+
+    pub fn inlined_into(&self) -> Option<SymbolName<'_>> {
+        None
+    }
+
+    pub fn inline_depth(&self) -> u32 {
+        0
+    }
 }
 
 pub unsafe fn clear_symbol_cache() {}
+
+pub unsafe fn refresh_modules() {}
+
+#[cfg(feature = "std")]
+pub unsafe fn resolve_offline(
+    _path: &::std::path::Path,
+    _addrs: &[usize],
+    _image_relative: bool,
+    _cb: &mut dyn FnMut(usize, &super::Symbol),
+) -> bool {
+    false
+}
+
+#[cfg(feature = "std")]
+pub unsafe fn loaded_modules() -> alloc::vec::Vec<super::LoadedModule> {
+    alloc::vec::Vec::new()
+}
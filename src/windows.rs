@@ -24,6 +24,7 @@ cfg_if::cfg_if! {
             pub use winapi::ctypes::*;
             pub use winapi::shared::basetsd::*;
             pub use winapi::shared::minwindef::*;
+            pub use winapi::shared::winerror::*;
             pub use winapi::um::dbghelp::*;
             pub use winapi::um::fileapi::*;
             pub use winapi::um::handleapi::*;
@@ -31,6 +32,8 @@ cfg_if::cfg_if! {
             pub use winapi::um::memoryapi::*;
             pub use winapi::um::minwinbase::*;
             pub use winapi::um::processthreadsapi::*;
+            pub use winapi::um::processtopologyapi::*;
+            pub use winapi::um::securitybaseapi::*;
             pub use winapi::um::synchapi::*;
             pub use winapi::um::tlhelp32::*;
             pub use winapi::um::winbase::*;
@@ -187,6 +190,32 @@ macro_rules! ffi {
         ffi!($($rest)*);
     );
 
+    (#[link(name = $lib:literal)] extern "system" { $(pub fn $name:ident($($args:tt)*) -> $ret:ty;)* } $($rest:tt)*) => (
+        #[link(name = $lib)]
+        extern "system" {
+            $(pub fn $name($($args)*) -> $ret;)*
+        }
+
+        $(
+            #[cfg(feature = "verify-winapi")]
+            mod $name {
+                #[test]
+                fn assert_same() {
+                    use super::*;
+
+                    assert_eq!($name as usize, winapi::$name as usize);
+                    let mut x: unsafe extern "system" fn($($args)*) -> $ret;
+                    x = $name;
+                    drop(x);
+                    x = winapi::$name;
+                    drop(x);
+                }
+            }
+        )*
+
+        ffi!($($rest)*);
+    );
+
     (impl $name:ident { $($i:tt)* } $($rest:tt)*) => (
         #[cfg(not(feature = "verify-winapi"))]
         impl $name {
@@ -273,6 +302,9 @@ ffi! {
         Option<unsafe extern "system" fn(hProcess: HANDLE, Address: DWORD64) -> DWORD64>;
     pub type PFUNCTION_TABLE_ACCESS_ROUTINE64 =
         Option<unsafe extern "system" fn(ahProcess: HANDLE, AddrBase: DWORD64) -> PVOID>;
+    pub type PSYMBOL_FUNCENTRY_CALLBACK64 = Option<
+        unsafe extern "system" fn(hProcess: HANDLE, AddrBase: ULONG64, UserContext: ULONG64) -> PVOID,
+    >;
     pub type PREAD_PROCESS_MEMORY_ROUTINE64 = Option<
         unsafe extern "system" fn(
             hProcess: HANDLE,
@@ -326,6 +358,104 @@ ffi! {
         pub szExePath: [WCHAR; MAX_PATH],
     }
 
+    #[repr(C)]
+    pub struct THREADENTRY32 {
+        pub dwSize: DWORD,
+        pub cntUsage: DWORD,
+        pub th32ThreadID: DWORD,
+        pub th32OwnerProcessID: DWORD,
+        pub tpBasePri: LONG,
+        pub tpDeltaPri: LONG,
+        pub dwFlags: DWORD,
+    }
+
+    #[repr(C)]
+    pub struct MEMORY_BASIC_INFORMATION {
+        pub BaseAddress: PVOID,
+        pub AllocationBase: PVOID,
+        pub AllocationProtect: DWORD,
+        pub RegionSize: SIZE_T,
+        pub State: DWORD,
+        pub Protect: DWORD,
+        pub Type: DWORD,
+    }
+
+    pub type PMEMORY_BASIC_INFORMATION = *mut MEMORY_BASIC_INFORMATION;
+
+    #[repr(C)]
+    pub struct PROCESSOR_NUMBER {
+        pub Group: WORD,
+        pub Number: BYTE,
+        pub Reserved: BYTE,
+    }
+
+    pub type PPROCESSOR_NUMBER = *mut PROCESSOR_NUMBER;
+
+    #[repr(C)]
+    pub struct GROUP_AFFINITY {
+        pub Mask: KAFFINITY,
+        pub Group: WORD,
+        pub Reserved: [WORD; 3],
+    }
+
+    pub type PGROUP_AFFINITY = *mut GROUP_AFFINITY;
+
+    #[repr(C)]
+    pub struct FILETIME {
+        pub dwLowDateTime: DWORD,
+        pub dwHighDateTime: DWORD,
+    }
+
+    pub type LPFILETIME = *mut FILETIME;
+
+    #[repr(C)]
+    pub struct EXCEPTION_RECORD {
+        pub ExceptionCode: DWORD,
+        pub ExceptionFlags: DWORD,
+        pub ExceptionRecord: PEXCEPTION_RECORD,
+        pub ExceptionAddress: PVOID,
+        pub NumberParameters: DWORD,
+        pub ExceptionInformation: [ULONG_PTR; EXCEPTION_MAXIMUM_PARAMETERS],
+    }
+
+    pub type PEXCEPTION_RECORD = *mut EXCEPTION_RECORD;
+
+    #[repr(C)]
+    pub struct EXCEPTION_POINTERS {
+        pub ExceptionRecord: PEXCEPTION_RECORD,
+        pub ContextRecord: PCONTEXT,
+    }
+
+    #[repr(C)]
+    pub struct LUID {
+        pub LowPart: DWORD,
+        pub HighPart: LONG,
+    }
+
+    pub type PLUID = *mut LUID;
+
+    #[repr(C)]
+    pub struct RTL_SRWLOCK {
+        pub Ptr: PVOID,
+    }
+
+    pub type SRWLOCK = RTL_SRWLOCK;
+    pub type PSRWLOCK = *mut RTL_SRWLOCK;
+
+    #[repr(C)]
+    pub struct LUID_AND_ATTRIBUTES {
+        pub Luid: LUID,
+        pub Attributes: DWORD,
+    }
+
+    #[repr(C)]
+    pub struct TOKEN_PRIVILEGES {
+        pub PrivilegeCount: DWORD,
+        pub Privileges: [LUID_AND_ATTRIBUTES; 1],
+    }
+
+    pub type PTOKEN_PRIVILEGES = *mut TOKEN_PRIVILEGES;
+
     pub const MAX_SYM_NAME: usize = 2000;
     pub const AddrModeFlat: ADDRESS_MODE = 3;
     pub const TRUE: BOOL = 1;
@@ -341,11 +471,31 @@ ffi! {
     pub const GENERIC_READ: DWORD = 0x80000000;
     pub const INFINITE: DWORD = !0;
     pub const PAGE_READONLY: DWORD = 2;
+    pub const PAGE_EXECUTE: DWORD = 0x10;
+    pub const PAGE_EXECUTE_READ: DWORD = 0x20;
+    pub const PAGE_EXECUTE_READWRITE: DWORD = 0x40;
+    pub const PAGE_EXECUTE_WRITECOPY: DWORD = 0x80;
+    pub const PAGE_GUARD: DWORD = 0x100;
+    pub const MEM_COMMIT: DWORD = 0x1000;
     pub const FILE_MAP_READ: DWORD = 4;
     pub const TH32CS_SNAPMODULE: DWORD = 0x00000008;
+    pub const TH32CS_SNAPTHREAD: DWORD = 0x00000004;
     pub const INVALID_HANDLE_VALUE: HANDLE = -1isize as HANDLE;
     pub const MAX_MODULE_NAME32: usize = 255;
     pub const MAX_PATH: usize = 260;
+    pub const EXCEPTION_MAXIMUM_PARAMETERS: usize = 15;
+    pub const ERROR_ACCESS_DENIED: DWORD = 5;
+    pub const ERROR_INVALID_HANDLE: DWORD = 6;
+    pub const ERROR_NOT_ALL_ASSIGNED: DWORD = 1300;
+    pub const TOKEN_QUERY: DWORD = 0x0008;
+    pub const TOKEN_ADJUST_PRIVILEGES: DWORD = 0x0020;
+    pub const SE_PRIVILEGE_ENABLED: DWORD = 0x00000002;
+    pub const THREAD_SUSPEND_RESUME: DWORD = 0x0002;
+    pub const THREAD_GET_CONTEXT: DWORD = 0x0008;
+    pub const THREAD_QUERY_INFORMATION: DWORD = 0x0040;
+    pub const THREAD_PRIORITY_ERROR_RETURN: DWORD = 0x7fffffff;
+    pub const WAIT_OBJECT_0: DWORD = 0;
+    pub const WAIT_TIMEOUT: DWORD = 258;
     pub const CONTEXT_i386: u32 = 0x10000;
     pub const CONTEXT_CONTROL: u32 = CONTEXT_i386 | 0x01; // SS:SP, CS:IP, FLAGS, B;
     pub const CONTEXT_INTEGER: u32 = CONTEXT_i386 | 0x02; // AX, BX, CX, DX, SI, D;
@@ -353,20 +503,29 @@ ffi! {
     pub const CONTEXT_FLOATING_POINT: u32 = CONTEXT_i386 | 0x08; // 387 stat;
     pub const CONTEXT_DEBUG_REGISTERS: u32 = CONTEXT_i386 | 0x10; // DB 0-3,6,;
     pub const CONTEXT_EXTENDED_REGISTERS: u32 = CONTEXT_i386 | 0x20; // cpu specific extension;
+    pub const CONTEXT_EXCEPTION_ACTIVE: u32 = 0x08000000;
+    pub const CONTEXT_SERVICE_ACTIVE: u32 = 0x10000000;
+    pub const CONTEXT_EXCEPTION_REQUEST: u32 = 0x40000000;
+    pub const CONTEXT_EXCEPTION_REPORTING: u32 = 0x80000000;
     pub const CONTEXT_ALL: u32 = CONTEXT_CONTROL | CONTEXT_INTEGER | CONTEXT_SEGMENTS |  CONTEXT_FLOATING_POINT | CONTEXT_DEBUG_REGISTERS |  CONTEXT_EXTENDED_REGISTERS;
 
 
     pub type DWORD = u32;
+    pub type LONG = i32;
     pub type PDWORD = *mut u32;
     pub type BOOL = i32;
     pub type DWORD64 = u64;
     pub type PDWORD64 = *mut u64;
     pub type HANDLE = *mut c_void;
+    pub type PHANDLE = *mut HANDLE;
     pub type PVOID = HANDLE;
     pub type PCWSTR = *const u16;
+    pub type LPCWSTR = *const u16;
     pub type LPSTR = *mut i8;
     pub type LPCSTR = *const i8;
     pub type PWSTR = *mut u16;
+    pub type LPWSTR = *mut u16;
+    pub type BYTE = u8;
     pub type WORD = u16;
     pub type ULONG = u32;
     pub type ULONG64 = u64;
@@ -376,13 +535,20 @@ ffi! {
     pub type DWORDLONG = u64;
     pub type HMODULE = HINSTANCE;
     pub type SIZE_T = usize;
+    pub type ULONG_PTR = usize;
+    pub type DWORD_PTR = ULONG_PTR;
+    pub type PDWORD_PTR = *mut DWORD_PTR;
+    pub type KAFFINITY = ULONG_PTR;
     pub type LPVOID = *mut c_void;
     pub type LPCVOID = *const c_void;
     pub type LPMODULEENTRY32W = *mut MODULEENTRY32W;
+    pub type LPTHREADENTRY32 = *mut THREADENTRY32;
+    pub type PSIZE_T = *mut SIZE_T;
 
     extern "system" {
         pub fn GetCurrentProcess() -> HANDLE;
         pub fn GetCurrentThread() -> HANDLE;
+        pub fn GetLastError() -> DWORD;
         pub fn RtlCaptureContext(ContextRecord: PCONTEXT) -> ();
         pub fn GetThreadContext(ThreadHandle: HANDLE, ContextRecord: PCONTEXT) -> DWORD;
         pub fn SuspendThread(ThreadHandle: HANDLE) -> DWORD;
@@ -390,13 +556,45 @@ ffi! {
         pub fn LoadLibraryA(a: *const i8) -> HMODULE;
         pub fn GetProcAddress(h: HMODULE, name: *const i8) -> FARPROC;
         pub fn GetModuleHandleA(name: *const i8) -> HMODULE;
+        pub fn GetModuleFileNameW(
+            hModule: HMODULE,
+            lpFilename: LPWSTR,
+            nSize: DWORD,
+        ) -> DWORD;
         pub fn OpenProcess(
             dwDesiredAccess: DWORD,
             bInheitHandle: BOOL,
             dwProcessId: DWORD,
         ) -> HANDLE;
         pub fn GetCurrentProcessId() -> DWORD;
+        pub fn GetCurrentThreadId() -> DWORD;
+        pub fn GetThreadId(Thread: HANDLE) -> DWORD;
+        pub fn GetThreadPriority(hThread: HANDLE) -> i32;
+        pub fn GetThreadIdealProcessorEx(
+            hThread: HANDLE,
+            lpIdealProcessor: PPROCESSOR_NUMBER,
+        ) -> BOOL;
+        pub fn GetThreadGroupAffinity(hThread: HANDLE, GroupAffinity: PGROUP_AFFINITY) -> BOOL;
+        pub fn GetProcessAffinityMask(
+            hProcess: HANDLE,
+            lpProcessAffinityMask: PDWORD_PTR,
+            lpSystemAffinityMask: PDWORD_PTR,
+        ) -> BOOL;
+        pub fn SwitchToThread() -> BOOL;
+        pub fn GetThreadTimes(
+            hThread: HANDLE,
+            lpCreationTime: LPFILETIME,
+            lpExitTime: LPFILETIME,
+            lpKernelTime: LPFILETIME,
+            lpUserTime: LPFILETIME,
+        ) -> BOOL;
+        pub fn OpenThread(
+            dwDesiredAccess: DWORD,
+            bInheritHandle: BOOL,
+            dwThreadId: DWORD,
+        ) -> HANDLE;
         pub fn CloseHandle(h: HANDLE) -> BOOL;
+        pub fn GetHandleInformation(hObject: HANDLE, lpdwFlags: LPDWORD) -> BOOL;
         pub fn CreateFileA(
             lpFileName: LPCSTR,
             dwDesiredAccess: DWORD,
@@ -417,6 +615,22 @@ ffi! {
             dwMilliseconds: DWORD,
             bAlertable: BOOL,
         ) -> DWORD;
+        pub fn WaitForSingleObject(hHandle: HANDLE, dwMilliseconds: DWORD) -> DWORD;
+        pub fn CreateEventW(
+            lpEventAttributes: LPSECURITY_ATTRIBUTES,
+            bManualReset: BOOL,
+            bInitialState: BOOL,
+            lpName: LPCWSTR,
+        ) -> HANDLE;
+        pub fn SetEvent(hEvent: HANDLE) -> BOOL;
+        pub fn SignalObjectAndWait(
+            hObjectToSignal: HANDLE,
+            hObjectToWaitOn: HANDLE,
+            dwMilliseconds: DWORD,
+            bAlertable: BOOL,
+        ) -> DWORD;
+        pub fn AcquireSRWLockExclusive(SRWLock: PSRWLOCK) -> ();
+        pub fn ReleaseSRWLockExclusive(SRWLock: PSRWLOCK) -> ();
         pub fn CreateFileMappingA(
             hFile: HANDLE,
             lpFileMappingAttributes: LPSECURITY_ATTRIBUTES,
@@ -445,17 +659,50 @@ ffi! {
             hSnapshot: HANDLE,
             lpme: LPMODULEENTRY32W,
         ) -> BOOL;
+        pub fn Thread32First(
+            hSnapshot: HANDLE,
+            lpte: LPTHREADENTRY32,
+        ) -> BOOL;
+        pub fn Thread32Next(
+            hSnapshot: HANDLE,
+            lpte: LPTHREADENTRY32,
+        ) -> BOOL;
+        pub fn VirtualQuery(
+            lpAddress: LPCVOID,
+            lpBuffer: PMEMORY_BASIC_INFORMATION,
+            dwLength: SIZE_T,
+        ) -> SIZE_T;
+        pub fn ReadProcessMemory(
+            hProcess: HANDLE,
+            lpBaseAddress: LPCVOID,
+            lpBuffer: LPVOID,
+            nSize: SIZE_T,
+            lpNumberOfBytesRead: PSIZE_T,
+        ) -> BOOL;
     }
 }
 
-#[cfg(target_pointer_width = "64")]
 ffi! {
+    #[link(name = "advapi32")]
     extern "system" {
-        pub fn RtlLookupFunctionEntry(
-            ControlPc: DWORD64,
-            ImageBase: PDWORD64,
-            HistoryTable: PUNWIND_HISTORY_TABLE,
-        ) -> PRUNTIME_FUNCTION;
+        pub fn OpenProcessToken(
+            ProcessHandle: HANDLE,
+            DesiredAccess: DWORD,
+            TokenHandle: PHANDLE,
+        ) -> BOOL;
+        pub fn LookupPrivilegeValueW(
+            lpSystemName: PCWSTR,
+            lpName: PCWSTR,
+            lpLuid: PLUID,
+        ) -> BOOL;
+        pub fn AdjustTokenPrivileges(
+            TokenHandle: HANDLE,
+            DisableAllPrivileges: BOOL,
+            NewState: PTOKEN_PRIVILEGES,
+            BufferLength: DWORD,
+            PreviousState: PTOKEN_PRIVILEGES,
+            ReturnLength: PDWORD,
+        ) -> BOOL;
     }
 }
 
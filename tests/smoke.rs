@@ -218,6 +218,1447 @@ fn many_threads() {
     }
 }
 
+#[test]
+fn frame_display() {
+    backtrace::trace(|frame| {
+        let s = frame.to_string();
+        assert!(s.starts_with(&format!("{:?}", frame.ip())), "{}", s);
+        if frame
+            .module_base_address()
+            .map_or(false, |base| !base.is_null())
+        {
+            assert!(s.contains("+0x"), "{}", s);
+        }
+        false
+    });
+}
+
+#[test]
+fn symbol_kind() {
+    use backtrace::SymbolKind;
+
+    // Miri symbolizes through the interpreter, which doesn't say how.
+    if cfg!(miri) {
+        return;
+    }
+
+    // Only full debug information provides file names.
+    let bt = backtrace::Backtrace::new();
+    for symbol in bt.frames().iter().flat_map(|f| f.symbols()) {
+        if symbol.filename().is_some() {
+            match symbol.kind() {
+                Some(SymbolKind::Pdb) | Some(SymbolKind::Dwarf) => {}
+                kind => panic!("{:?} for {:?}", kind, symbol),
+            }
+        }
+    }
+}
+
+#[test]
+fn inline_frames_precede_physical_frame() {
+    // An inline frame is always followed by more frames of the same physical
+    // frame, ending with the physical one.
+    let bt = backtrace::Backtrace::new_unresolved();
+    let frames = bt.frames();
+    for (i, frame) in frames.iter().enumerate() {
+        if frame.is_inline() {
+            let next = frames.get(i + 1).expect("inline frame is the last frame");
+            assert_eq!(frame.ip(), next.ip());
+        }
+    }
+    if let Some(last) = frames.last() {
+        assert!(!last.is_inline());
+    }
+}
+
+/// Returns a handle to the calling thread, which is traced without being
+/// suspended.
+#[cfg(windows)]
+fn current_thread() -> backtrace::ThreadHandle {
+    extern "system" {
+        fn GetCurrentThread() -> *mut std::ffi::c_void;
+    }
+    backtrace::ThreadHandle::new(unsafe { GetCurrentThread() }).unwrap()
+}
+
+/// A spawned thread which stays blocked until this is dropped, for tracing
+/// another thread, which suspends and resumes it.
+///
+/// Nothing may be allocated while it's suspended, as it could be holding the
+/// heap lock, so the callbacks tracing it only count.
+#[cfg(windows)]
+struct BlockedThread {
+    thread: Option<thread::JoinHandle<()>>,
+    release: Option<std::sync::mpsc::Sender<()>>,
+}
+
+#[cfg(windows)]
+impl BlockedThread {
+    fn spawn() -> BlockedThread {
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let (release, wait_release) = std::sync::mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            started.send(()).unwrap();
+            let _ = wait_release.recv();
+        });
+        wait_started.recv().unwrap();
+        BlockedThread {
+            thread: Some(thread),
+            release: Some(release),
+        }
+    }
+
+    fn handle(&self) -> backtrace::ThreadHandle {
+        use std::os::windows::io::AsRawHandle;
+        let raw = self.thread.as_ref().unwrap().as_raw_handle();
+        backtrace::ThreadHandle::new(raw).unwrap()
+    }
+}
+
+#[cfg(windows)]
+impl Drop for BlockedThread {
+    fn drop(&mut self) {
+        drop(self.release.take());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+#[test]
+#[cfg(windows)]
+fn capture_thread_skipping() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let count = |skip| {
+        let mut frames = 0;
+        unsafe {
+            backtrace::capture_thread_skipping(thread, skip, |_| {
+                frames += 1;
+                true
+            })
+            .unwrap();
+        }
+        frames
+    };
+    let all = count(0);
+    assert!(all > 2);
+    assert_eq!(count(2), all - 2);
+    assert_eq!(count(all + 10), 0);
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_thread_indexed() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let mut indices = [0; 3];
+    let mut calls = 0;
+    unsafe {
+        backtrace::trace_thread_indexed(thread, |i, _| {
+            indices[calls] = i;
+            calls += 1;
+            i < 2
+        })
+        .unwrap();
+    }
+    assert_eq!(calls, 3);
+    assert_eq!(indices, [0, 1, 2]);
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_thread_bounded() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let count = |max_stack_bytes| {
+        let mut frames = 0;
+        let result = unsafe {
+            backtrace::trace_thread_bounded(thread, max_stack_bytes, |_| {
+                frames += 1;
+                true
+            })
+        };
+        (frames, result)
+    };
+    let (all, result) = count(usize::max_value());
+    assert!(all > 1);
+    assert_eq!(result, Ok(()));
+    // Only the first frame and any inlined into it share its stack pointer.
+    let (bounded, result) = count(0);
+    assert!(bounded >= 1 && bounded < all);
+    assert_eq!(result, Err(backtrace::TraceError::Truncated));
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_thread_best_effort() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let mut frames = 0;
+    let api = unsafe {
+        backtrace::trace_thread_best_effort(thread, |_| {
+            frames += 1;
+            true
+        })
+    };
+    assert!(api.is_ok());
+    assert!(frames > 1);
+}
+
+#[test]
+#[cfg(windows)]
+fn with_dbghelp() {
+    const SYMOPT_DEFERRED_LOADS: u32 = 0x4;
+
+    let options = backtrace::with_dbghelp(|_process, api| {
+        let get_options = api.function("SymGetOptions").unwrap();
+        let get_options: unsafe extern "system" fn() -> u32 =
+            unsafe { std::mem::transmute(get_options) };
+        assert!(api.function("NotADbghelpFunction").is_none());
+
+        // Calling back into the crate doesn't deadlock.
+        backtrace::resolve(with_dbghelp as usize as *mut std::ffi::c_void, |_| {});
+        unsafe { get_options() }
+    })
+    .unwrap();
+    assert_ne!(options & SYMOPT_DEFERRED_LOADS, 0);
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_thread_created_suspended() {
+    use std::ffi::c_void;
+    use std::ptr;
+
+    const CREATE_SUSPENDED: u32 = 0x4;
+    const INFINITE: u32 = 0xffff_ffff;
+    extern "system" {
+        fn CreateThread(
+            attributes: *mut c_void,
+            stack_size: usize,
+            start: unsafe extern "system" fn(*mut c_void) -> u32,
+            parameter: *mut c_void,
+            flags: u32,
+            id: *mut u32,
+        ) -> *mut c_void;
+        fn ResumeThread(thread: *mut c_void) -> u32;
+        fn WaitForSingleObject(handle: *mut c_void, millis: u32) -> u32;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+    unsafe extern "system" fn start(_: *mut c_void) -> u32 {
+        0
+    }
+
+    unsafe {
+        let raw = CreateThread(
+            ptr::null_mut(),
+            0,
+            start,
+            ptr::null_mut(),
+            CREATE_SUSPENDED,
+            ptr::null_mut(),
+        );
+        assert!(!raw.is_null());
+        let thread = backtrace::ThreadHandle::new(raw).unwrap();
+        let mut ips = Vec::new();
+        let result = backtrace::trace_thread_unsynchronized(thread, |frame| {
+            ips.push(frame.ip() as usize);
+            true
+        });
+        ResumeThread(raw);
+        WaitForSingleObject(raw, INFINITE);
+        CloseHandle(raw);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(ips, [start as usize]);
+    }
+}
+
+#[test]
+#[cfg(windows)]
+fn capture_current_thread_starts_at_caller() {
+    #[inline(never)]
+    fn caller() -> backtrace::Backtrace {
+        let current = current_thread();
+        let (bt, err) = unsafe { backtrace::capture_thread_with_capacity(current, 64) };
+        assert!(err.is_none());
+        bt
+    }
+
+    let mut bt = caller();
+    bt.resolve();
+    let first = &bt.frames()[0];
+    assert!(first
+        .symbols()
+        .iter()
+        .any(|s| s.name().map_or(false, |n| n.to_string().contains("caller"))));
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_thread_to_writer() {
+    let current = current_thread();
+
+    // The current thread isn't suspended, so writing to a `Vec` is fine.
+    let mut out = Vec::new();
+    let (written, err) = unsafe { backtrace::trace_thread_to_writer(current, &mut out) }.unwrap();
+    assert!(err.is_none());
+    assert!(written > 0);
+    let frames = backtrace::FrameReader::new(&out[..])
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(frames.len(), written);
+    assert!(frames.iter().all(|f| !f.ip().is_null()));
+}
+
+#[test]
+#[cfg(windows)]
+fn single_processor_diagnostics() {
+    let processors = backtrace::available_processors();
+    assert!(processors.map_or(true, |n| n >= 1));
+    let stats = backtrace::trace_stats();
+    assert!(stats.single_processor_suspensions() <= stats.suspensions());
+    if processors.map_or(false, |n| n > 1) {
+        assert_eq!(stats.single_processor_suspensions(), 0);
+    }
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_thread_reconciled() {
+    use backtrace::FrameConfidence;
+    let current = current_thread();
+
+    let mut unwound = 0;
+    unsafe {
+        backtrace::trace_unsynchronized(|_| {
+            unwound += 1;
+            true
+        });
+    }
+    let mut confidences = Vec::new();
+    unsafe {
+        backtrace::trace_thread_reconciled(current, |_, confidence| {
+            confidences.push(confidence);
+            true
+        })
+        .unwrap();
+    }
+    let found = confidences
+        .iter()
+        .filter(|&&c| c != FrameConfidence::FramePointer)
+        .count();
+    assert!(found > 0 && found <= unwound);
+    // Frame pointer frames only ever follow the unwinder's.
+    let first_fp = confidences
+        .iter()
+        .position(|&c| c == FrameConfidence::FramePointer)
+        .unwrap_or(confidences.len());
+    assert!(confidences[first_fp..]
+        .iter()
+        .all(|&c| c == FrameConfidence::FramePointer));
+}
+
+#[test]
+#[cfg(windows)]
+fn capture_thread_head_tail() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let (full, err) = unsafe { backtrace::capture_thread_head_tail(thread, 1024, 0) };
+    assert!(err.is_none());
+    let total = full.head().len();
+    assert!(total > 4);
+
+    let (bt, err) = unsafe { backtrace::capture_thread_head_tail(thread, 1, 2) };
+    assert!(err.is_none());
+    assert_eq!(bt.head().len(), 1);
+    assert_eq!(bt.tail().len(), 2);
+    assert_eq!(bt.omitted(), total - 3);
+    let ips =
+        |frames: &[backtrace::BacktraceFrame]| frames.iter().map(|f| f.ip()).collect::<Vec<_>>();
+    assert_eq!(ips(bt.tail()), ips(&full.head()[total - 2..]));
+    assert!(format!("{:?}", bt).contains(&format!("... {} frames omitted ...", total - 3)));
+
+    let (bt, _) = unsafe { backtrace::capture_thread_head_tail(thread, total, 5) };
+    assert!(bt.tail().is_empty());
+    assert_eq!(bt.omitted(), 0);
+
+    // Without a tail everything past the head is omitted.
+    let (bt, err) = unsafe { backtrace::capture_thread_head_tail(thread, 1, 0) };
+    assert!(err.is_none());
+    assert_eq!(bt.head().len(), 1);
+    assert!(bt.tail().is_empty());
+    assert_eq!(bt.omitted(), total - 1);
+}
+
+#[test]
+#[cfg(windows)]
+fn thread_handle_validation() {
+    use backtrace::{ThreadHandle, TraceError};
+    use std::ffi::c_void;
+
+    extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn GetCurrentThreadId() -> u32;
+        fn OpenThread(access: u32, inherit: i32, id: u32) -> *mut c_void;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+    const THREAD_SUSPEND_RESUME: u32 = 0x0002;
+    const THREAD_GET_CONTEXT: u32 = 0x0008;
+    const THREAD_QUERY_INFORMATION: u32 = 0x0040;
+
+    assert!(ThreadHandle::new(unsafe { GetCurrentThread() }).is_ok());
+    let open = |access| unsafe { OpenThread(access, 0, GetCurrentThreadId()) };
+
+    let raw = open(THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT);
+    assert_eq!(ThreadHandle::new(raw).map(|h| h.as_raw()), Ok(raw));
+    unsafe { CloseHandle(raw) };
+
+    let raw = open(THREAD_QUERY_INFORMATION);
+    assert_eq!(ThreadHandle::new(raw), Err(TraceError::AccessDenied));
+    unsafe { CloseHandle(raw) };
+
+    assert!(matches!(
+        ThreadHandle::new(std::ptr::null_mut()),
+        Err(TraceError::InvalidHandle(_))
+    ));
+    assert!(matches!(
+        ThreadHandle::new(0x1234 as *mut c_void),
+        Err(TraceError::InvalidHandle(_))
+    ));
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_real_handle_to_current_thread() {
+    use backtrace::ThreadHandle;
+    use std::ffi::c_void;
+
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+        fn OpenThread(access: u32, inherit: i32, id: u32) -> *mut c_void;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+    const THREAD_SUSPEND_RESUME: u32 = 0x0002;
+    const THREAD_GET_CONTEXT: u32 = 0x0008;
+    const THREAD_QUERY_LIMITED_INFORMATION: u32 = 0x0800;
+
+    // A real handle isn't the pseudo handle, suspending through it would
+    // deadlock unless the calling thread is recognized by its id.
+    let id = unsafe { GetCurrentThreadId() };
+    let access = THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_LIMITED_INFORMATION;
+    let raw = unsafe { OpenThread(access, 0, id) };
+    assert!(!raw.is_null());
+    let handle = ThreadHandle::new(raw).unwrap();
+
+    let mut frames = 0;
+    let result = unsafe {
+        backtrace::trace_thread_unsynchronized(handle, |_| {
+            frames += 1;
+            true
+        })
+    };
+    assert_eq!(result, Ok(()));
+    assert!(frames > 0);
+
+    let bt = unsafe { backtrace::Backtrace::new_for_threads(&[handle]) };
+    assert!(!bt.frames().is_empty());
+    assert!(bt.frames().iter().all(|f| f.thread_id() == Some(id)));
+
+    unsafe { CloseHandle(raw) };
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_with_machine() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let native = if cfg!(target_arch = "x86_64") {
+        0x8664
+    } else if cfg!(target_arch = "aarch64") {
+        0xaa64
+    } else if cfg!(target_arch = "x86") {
+        0x014c
+    } else {
+        0x01c4
+    };
+    let count = |machine| {
+        let mut frames = 0;
+        let _ = unsafe {
+            backtrace::trace_with_machine(thread, machine, |_| {
+                frames += 1;
+                true
+            })
+        };
+        frames
+    };
+    assert!(count(native) > 2);
+    assert_eq!(count(0x1234), 0);
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_with_walk_flags() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let mut frames = 0;
+    unsafe {
+        backtrace::trace_with_walk_flags(thread, 0, |_| {
+            frames += 1;
+            true
+        })
+        .unwrap();
+    }
+    assert!(frames > 0);
+    // Forcing frame pointers may stop at the first frame without one, but the
+    // innermost frame comes from the registers either way.
+    let mut first = None;
+    let _ = unsafe {
+        backtrace::trace_with_walk_flags(thread, 1, |frame| {
+            first = Some(frame.ip());
+            false
+        })
+    };
+    assert!(first.is_some());
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_lazy_base() {
+    let mut eager = Vec::new();
+    backtrace::trace(|frame| {
+        eager.push((frame.ip(), frame.module_base_address()));
+        true
+    });
+    let mut lazy = Vec::new();
+    backtrace::trace_lazy_base(|frame| {
+        lazy.push(frame.clone());
+        true
+    });
+
+    // The callers of this test are the same for both walks.
+    let mut matched = 0;
+    for frame in &lazy {
+        let base = frame.module_base_address();
+        assert_eq!(frame.module_base_address(), base);
+        if let Some(&(_, expected)) = eager.iter().find(|(ip, _)| *ip == frame.ip()) {
+            assert_eq!(base, expected);
+            matched += 1;
+        }
+    }
+    assert!(matched > 0);
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_thread_with_context() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let count = |buffer: Option<&mut backtrace::ContextBuffer>| {
+        let mut frames = 0;
+        let cb = |_: &backtrace::Frame| {
+            frames += 1;
+            true
+        };
+        unsafe {
+            match buffer {
+                Some(buffer) => backtrace::trace_thread_with_context(thread, buffer, cb),
+                None => backtrace::trace_thread_unsynchronized(thread, cb),
+            }
+            .unwrap();
+        }
+        frames
+    };
+    let mut buffer = Box::new(backtrace::ContextBuffer::new());
+    let expected = count(None);
+    assert_eq!(count(Some(&mut buffer)), expected);
+    assert_eq!(count(Some(&mut buffer)), expected);
+}
+
+#[test]
+#[cfg(windows)]
+fn capture_exception() {
+    use std::ffi::c_void;
+    use std::ptr;
+
+    #[repr(C)]
+    struct ExceptionRecord {
+        code: u32,
+        flags: u32,
+        record: *mut ExceptionRecord,
+        address: *mut c_void,
+        parameters: u32,
+        information: [usize; 15],
+    }
+    #[repr(C)]
+    struct ExceptionPointers {
+        record: *mut ExceptionRecord,
+        context: *mut c_void,
+    }
+    #[repr(C, align(16))]
+    struct Context([u8; 4096]);
+    extern "system" {
+        fn RtlCaptureContext(context: *mut c_void);
+    }
+
+    let record = |code, address, record| ExceptionRecord {
+        code,
+        flags: 0,
+        record,
+        address: address as *mut c_void,
+        parameters: 0,
+        information: [0; 15],
+    };
+    let mut inner = record(0xe06d7363, 0x2000, ptr::null_mut());
+    let mut outer = record(0xc0000005, 0x1000, &mut inner);
+    let mut context = Context([0; 4096]);
+    unsafe { RtlCaptureContext(context.0.as_mut_ptr().cast()) };
+    let mut pointers = ExceptionPointers {
+        record: &mut outer,
+        context: context.0.as_mut_ptr().cast(),
+    };
+
+    let (info, err) =
+        unsafe { backtrace::capture_exception(&mut pointers as *mut _ as *mut c_void) };
+    assert!(err.is_none());
+    assert_eq!(info.exception_code(), 0xc0000005);
+    assert_eq!(info.exception_address() as usize, 0x1000);
+    assert_eq!(info.nested().len(), 1);
+    assert_eq!(info.nested()[0].code(), 0xe06d7363);
+    assert_eq!(info.nested()[0].address() as usize, 0x2000);
+    assert!(info.backtrace().len() > 2);
+    assert!(info.code_bytes().is_empty());
+    assert!(format!("{:?}", info).starts_with("exception 0xc0000005 at 0x1000\n"));
+
+    // A fault inside real code records the bytes on both sides of it.
+    let function = capture_exception as fn() as usize;
+    let address = function + 32;
+    outer.address = address as *mut c_void;
+    let (info, _) = unsafe { backtrace::capture_exception(&mut pointers as *mut _ as *mut c_void) };
+    assert_eq!(info.code_start() as usize, address - 16);
+    assert_eq!(info.code_bytes().len(), 32);
+    let code = unsafe { std::slice::from_raw_parts((address - 16) as *const u8, 32) };
+    assert_eq!(info.code_bytes(), code);
+    assert!(format!("{:?}", info).contains(&format!("code at {:#x}:", address - 16)));
+}
+
+#[test]
+#[cfg(windows)]
+fn deferred_symbol_init() {
+    backtrace::set_deferred_symbol_init(true);
+    let mut frames = Vec::new();
+    backtrace::trace(|frame| {
+        frames.push(frame.clone());
+        true
+    });
+    backtrace::set_deferred_symbol_init(false);
+
+    assert!(frames.len() > 1);
+    assert!(frames.iter().all(|f| !f.is_inline()));
+    let mut resolved = 0;
+    for frame in &frames {
+        backtrace::resolve_frame(frame, |sym| {
+            if sym.name().is_some() {
+                resolved += 1;
+            }
+        });
+    }
+    assert!(resolved > 0);
+}
+
+#[test]
+#[cfg(windows)]
+fn sample_ring() {
+    let blocked = BlockedThread::spawn();
+    let thread = blocked.handle();
+
+    let mut ring = backtrace::SampleRing::new(2, 256);
+    assert_eq!(ring.capacity(), 2);
+    for _ in 0..3 {
+        unsafe { ring.sample_thread(thread).unwrap() };
+    }
+    assert_eq!(ring.len(), 2);
+    let samples = ring.drain();
+    assert_eq!(samples.len(), 2);
+    assert!(samples.iter().all(|s| !s.frames().is_empty()));
+    assert!(ring.is_empty());
+    assert!(ring.drain().is_empty());
+}
+
+#[test]
+#[cfg(windows)]
+fn all_threads() {
+    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+    use std::sync::Arc;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let worker = {
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(SeqCst) {
+                thread::yield_now();
+            }
+        })
+    };
+
+    // No allocations in the callback, the other threads are suspended.
+    let mut threads = 0;
+    let mut current = 0;
+    let mut last_id = None;
+    unsafe {
+        backtrace::trace_all_threads_unsynchronized(|info, _frame| {
+            if last_id != Some(info.id()) {
+                last_id = Some(info.id());
+                threads += 1;
+                if info.is_current() {
+                    current += 1;
+                }
+            }
+            true
+        })
+        .unwrap();
+    }
+    done.store(true, SeqCst);
+    worker.join().unwrap();
+
+    assert!(threads >= 2);
+    assert_eq!(current, 1);
+}
+
+#[test]
+#[cfg(windows)]
+fn new_for_threads() {
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+    let current = current_thread();
+    let id = unsafe { GetCurrentThreadId() };
+    assert_eq!(current.id(), Some(id));
+
+    let bt = unsafe { backtrace::Backtrace::new_for_threads(&[current, current]) };
+    assert!(!bt.frames().is_empty());
+    assert!(bt.frames().iter().all(|f| f.thread_id() == Some(id)));
+    assert_eq!(bt.thread_ids(), vec![id]);
+}
+
+#[test]
+#[cfg(windows)]
+fn thread_stack_bounds() {
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+    let local = 0u8;
+    let sp = &local as *const u8 as usize;
+    let current = current_thread();
+    let bounds = current.stack_bounds().unwrap();
+    assert!(bounds.contains(sp));
+    assert!(bounds.remaining(sp).unwrap() < bounds.size());
+
+    let id = unsafe { GetCurrentThreadId() };
+    let options = backtrace::ThreadSnapshotOptions::new().filter(move |tid| tid == id);
+    let mut traced = false;
+    unsafe {
+        backtrace::trace_all_threads_with_options_unsynchronized(&options, |info, _| {
+            assert_eq!(info.stack_bounds(), Some(bounds));
+            traced = true;
+            false
+        })
+        .unwrap();
+    }
+    assert!(traced);
+}
+
+#[test]
+#[cfg(windows)]
+fn thread_teb_address() {
+    let current = current_thread();
+    let teb = current.teb_address().unwrap() as *const usize;
+    // The TEB starts with an `NT_TIB`, whose `StackBase` is its second field
+    // and whose `Self` field, the seventh, points back to it.
+    unsafe {
+        assert_eq!(*teb.add(6), teb as usize);
+        assert_eq!(*teb.add(1), current.stack_bounds().unwrap().base());
+    }
+}
+
+#[test]
+#[cfg(all(windows, feature = "minidump"))]
+fn write_minidump() {
+    let snapshot =
+        unsafe { backtrace::snapshot_all_threads(&backtrace::ThreadSnapshotOptions::new()) }
+            .unwrap();
+    let mut dump = Vec::new();
+    snapshot.write_minidump(&mut dump).unwrap();
+
+    let u32_at =
+        |at: usize| u32::from_le_bytes([dump[at], dump[at + 1], dump[at + 2], dump[at + 3]]);
+    assert_eq!(&dump[..4], b"MDMP");
+    let streams = u32_at(8) as usize;
+    let directory = u32_at(12) as usize;
+    let threads = (0..streams)
+        .map(|i| directory + i * 12)
+        .find(|&entry| u32_at(entry) == 3)
+        .map(|entry| u32_at(u32_at(entry + 8) as usize))
+        .unwrap();
+    assert_eq!(threads as usize, snapshot.len());
+}
+
+#[test]
+#[cfg(windows)]
+fn snapshot_hot_threads() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = stop.clone();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let busy = std::thread::spawn(move || {
+        tx.send(unsafe { GetCurrentThreadId() }).unwrap();
+        while !stop2.load(Ordering::Relaxed) {}
+    });
+    let busy_id = rx.recv().unwrap();
+
+    let mut sampler = backtrace::HotThreadSampler::new();
+    let hot = unsafe { sampler.snapshot_hot_threads(Duration::from_secs(5), 1) }.unwrap();
+    let current = unsafe { GetCurrentThreadId() };
+    assert!(hot.ranking().iter().all(|usage| usage.id() != current));
+    assert!(hot.ranking().iter().any(|usage| usage.id() == busy_id));
+    assert!(hot
+        .ranking()
+        .windows(2)
+        .all(|w| w[0].recent() >= w[1].recent()));
+    assert!(hot.traces().len() <= 1);
+    for (id, bt) in hot.traces() {
+        assert!(bt.frames().iter().all(|f| f.thread_id() == Some(*id)));
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    busy.join().unwrap();
+}
+
+#[test]
+#[cfg(windows)]
+fn thread_in_system_call() {
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    let blocked = std::thread::spawn(move || {
+        tx.send(unsafe { GetCurrentThreadId() }).unwrap();
+        done_rx.recv().unwrap();
+    });
+    let id = rx.recv().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    let options = backtrace::ThreadSnapshotOptions::new()
+        .exception_request(true)
+        .filter(move |tid| tid == id);
+    let mut state = None;
+    unsafe {
+        backtrace::trace_all_threads_with_options_unsynchronized(&options, |info, _| {
+            state = Some(info.in_system_call());
+            false
+        })
+        .unwrap();
+    }
+    // Older versions of Windows don't report the state.
+    assert!(matches!(state, Some(Some(true)) | Some(None)));
+
+    done_tx.send(()).unwrap();
+    blocked.join().unwrap();
+}
+
+#[test]
+#[cfg(windows)]
+fn snapshot_in_batches() {
+    let workers = (0..4)
+        .map(|_| std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_millis(500))))
+        .collect::<Vec<_>>();
+
+    let options = backtrace::ThreadSnapshotOptions::new().batch_size(2);
+    let snapshot = unsafe { backtrace::snapshot_all_threads(&options) }.unwrap();
+    assert!(snapshot.len() > workers.len());
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+#[test]
+#[cfg(windows)]
+fn snapshot_affinity() {
+    let snapshot =
+        unsafe { backtrace::snapshot_all_threads(&backtrace::ThreadSnapshotOptions::new()) }
+            .unwrap();
+    snapshot
+        .trace(|info, _| {
+            assert_eq!(info.ideal_processor(), None);
+            assert_eq!(info.affinity(), None);
+            false
+        })
+        .unwrap();
+
+    let options = backtrace::ThreadSnapshotOptions::new().affinity(true);
+    let threads = backtrace::list_threads(&options).unwrap();
+    let current = threads.iter().find(|t| t.is_current()).unwrap();
+    let (group, mask) = current.affinity().unwrap();
+    assert_ne!(mask, 0);
+    let (ideal_group, ideal) = current.ideal_processor().unwrap();
+    assert_eq!(ideal_group, group);
+    assert!(usize::from(ideal) < 8 * std::mem::size_of::<usize>());
+}
+
+#[test]
+#[cfg(windows)]
+fn snapshot_metadata() {
+    let snapshot =
+        unsafe { backtrace::snapshot_all_threads(&backtrace::ThreadSnapshotOptions::new()) }
+            .unwrap();
+    assert_eq!(snapshot.timestamp(), None);
+    assert_eq!(snapshot.correlation_id(), None);
+
+    let options = backtrace::ThreadSnapshotOptions::new()
+        .timestamp(true)
+        .correlation_id(42);
+    let first = unsafe { backtrace::snapshot_all_threads(&options) }.unwrap();
+    let second = unsafe { backtrace::snapshot_all_threads(&options) }.unwrap();
+    assert_eq!(first.correlation_id(), Some(42));
+    assert!(first.timestamp_frequency().unwrap() > 0);
+    assert!(second.timestamp().unwrap() >= first.timestamp().unwrap());
+}
+
+#[test]
+#[cfg(windows)]
+fn snapshot_retries_under_thread_churn() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let churn = (0..4)
+        .map(|_| {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    std::thread::spawn(|| {}).join().unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let options = backtrace::ThreadSnapshotOptions::new().retries(3);
+    for _ in 0..20 {
+        let snapshot = unsafe { backtrace::snapshot_all_threads(&options) }.unwrap();
+        assert!(snapshot.retries() <= 3);
+        if snapshot.retries() < 3 {
+            assert!(snapshot.unstable_threads().is_empty());
+        }
+        let mut ids = Vec::new();
+        snapshot
+            .trace(|info, _| {
+                ids.push(info.id());
+                false
+            })
+            .unwrap();
+        // Retries never capture a thread a second time.
+        let len = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), len);
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for thread in churn {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+#[cfg(windows)]
+fn filter_threads() {
+    use backtrace::ThreadSnapshotOptions;
+
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+    let current = unsafe { GetCurrentThreadId() };
+
+    let options = ThreadSnapshotOptions::new().filter(move |id| id == current);
+    let mut ids = Vec::new();
+    let snapshot = unsafe { backtrace::snapshot_all_threads(&options) }.unwrap();
+    assert_eq!(snapshot.len(), 1);
+    snapshot
+        .trace(|info, _frame| {
+            ids.push(info.id());
+            true
+        })
+        .unwrap();
+    assert!(!ids.is_empty());
+    assert!(ids.iter().all(|&id| id == current));
+
+    let options = ThreadSnapshotOptions::new().filter(|_| false);
+    unsafe {
+        backtrace::trace_all_threads_with_options_unsynchronized(&options, |_, _| {
+            panic!("no thread should be traced")
+        })
+        .unwrap();
+    }
+}
+
+#[test]
+#[cfg(windows)]
+fn trace_thread_cooperative_stress() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+
+    #[inline(never)]
+    fn cooperate(stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            backtrace::safe_point();
+        }
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    let threads = (0..32)
+        .map(|_| {
+            let stop = stop.clone();
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                tx.send(unsafe { GetCurrentThreadId() }).unwrap();
+                cooperate(&stop);
+            })
+        })
+        .collect::<Vec<_>>();
+    let ids = rx.iter().take(threads.len()).collect::<Vec<_>>();
+
+    for _ in 0..10 {
+        for &id in &ids {
+            let mut ips = Vec::new();
+            backtrace::trace_thread_cooperative(id, Duration::from_secs(10), |frame| {
+                ips.push(frame.ip());
+                true
+            })
+            .unwrap();
+            let mut found = false;
+            for ip in ips {
+                backtrace::resolve(ip, |sym| {
+                    found |= sym
+                        .name()
+                        .map_or(false, |n| n.to_string().contains("cooperate"));
+                });
+            }
+            assert!(found, "cooperate not found on thread {}", id);
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // A thread which never reaches a safe point isn't waited for forever.
+    let (tx, rx) = mpsc::channel::<()>();
+    let (id_tx, id_rx) = mpsc::channel();
+    let blocked = std::thread::spawn(move || {
+        id_tx.send(unsafe { GetCurrentThreadId() }).unwrap();
+        let _ = rx.recv();
+    });
+    let id = id_rx.recv().unwrap();
+    let result = backtrace::trace_thread_cooperative(id, Duration::from_millis(50), |_| true);
+    assert_eq!(result, Err(backtrace::TraceError::TimedOut));
+    drop(tx);
+    blocked.join().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn resolve_offline() {
+    use backtrace::{Backtrace, BacktraceFrame, SymbolSource};
+    use std::ffi::c_void;
+
+    #[inline(never)]
+    fn target() {}
+
+    // Describe `target` the way a capture from another process would, by its
+    // module base address and the path of the binary.
+    let addr = target as *const c_void;
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    assert_ne!(unsafe { libc::dladdr(addr, &mut info) }, 0);
+    let base = info.dli_fbase;
+    let ip = (addr as usize + 1) as *mut c_void;
+    let frame = BacktraceFrame::from_parts(ip, addr as *mut c_void, Some(base));
+
+    let mut bt = Backtrace::from(vec![frame]);
+    let source = SymbolSource::new().module(base, std::env::current_exe().unwrap());
+    bt.resolve_offline(&source);
+
+    let frame = &bt.frames()[0];
+    assert!(frame.is_resolved());
+    let name = frame.symbols()[0].name().unwrap().to_string();
+    assert!(name.contains("resolve_offline::target"), "{}", name);
+}
+
+#[test]
+#[cfg(windows)]
+fn snapshot_all_threads() {
+    use backtrace::ThreadSnapshotOptions;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+    use std::sync::Arc;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let worker = {
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(SeqCst) {
+                thread::yield_now();
+            }
+        })
+    };
+
+    let options = ThreadSnapshotOptions::new().cpu_times(true);
+    let snapshot = unsafe { backtrace::snapshot_all_threads(&options) };
+    done.store(true, SeqCst);
+    worker.join().unwrap();
+    let snapshot = snapshot.unwrap();
+    assert!(snapshot.len() >= 2);
+
+    // The walk runs after the threads were resumed, so it's free to allocate.
+    let mut frames = HashMap::new();
+    snapshot
+        .trace(|info, _frame| {
+            assert!(info.kernel_time().is_some());
+            assert!(info.user_time().is_some());
+            *frames.entry((info.id(), info.is_current())).or_insert(0) += 1;
+            true
+        })
+        .unwrap();
+    assert!(frames.len() >= 2);
+    assert!(frames.iter().any(|(&(_, current), &n)| current && n > 1));
+
+    let bt = backtrace::Backtrace::from_snapshot(&snapshot).unwrap();
+    let ids = bt.thread_ids();
+    assert_eq!(ids.len(), frames.len());
+    for (&(id, _), &n) in &frames {
+        assert!(ids.contains(&id));
+        let count = bt.frames().iter().filter(|f| f.thread_id() == Some(id));
+        assert_eq!(count.count(), n);
+    }
+}
+
+#[test]
+#[cfg_attr(all(target_arch = "x86", target_env = "msvc"), ignore)]
+#[rustfmt::skip] // we care about line numbers here
+fn inline_frames_have_call_site_lines() {
+    inline_physical(line!());
+    #[inline(never)] fn inline_physical(start_line: u32) { inline_outer(start_line) }
+    #[inline(always)] fn inline_outer(start_line: u32) { inline_inner(start_line) }
+    #[inline(always)] fn inline_inner(start_line: u32) {
+        let bt = backtrace::Backtrace::new();
+        let symbols = bt
+            .frames()
+            .iter()
+            .flat_map(|frame| frame.symbols())
+            .map(|sym| (sym.name().map(|n| n.to_string()).unwrap_or_default(), sym.lineno()))
+            .collect::<Vec<_>>();
+        println!("{:#?}", symbols);
+
+        // Platforms without debug info for inlined calls report neither the
+        // inlined functions nor their lines.
+        let start = match symbols.iter().position(|(name, _)| name.contains("inline_inner")) {
+            Some(start) => start,
+            None => return,
+        };
+        let expected = [
+            ("inline_inner", start_line + 4),
+            ("inline_outer", start_line + 2),
+            ("inline_physical", start_line + 1),
+        ];
+        for (i, &(name, line)) in expected.iter().enumerate() {
+            let (actual_name, actual_line) = &symbols[start + i];
+            assert!(actual_name.contains(name), "{} isn't {}", actual_name, name);
+            if let Some(actual_line) = actual_line {
+                assert_eq!(*actual_line, line, "wrong line for {}", name);
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(all(windows, target_env = "msvc"))]
+#[cfg_attr(target_arch = "x86", ignore)]
+fn inline_frames_name_containing_function() {
+    inline_physical();
+    #[inline(never)]
+    fn inline_physical() {
+        inline_outer()
+    }
+    #[inline(always)]
+    fn inline_outer() {
+        inline_inner()
+    }
+    #[inline(always)]
+    fn inline_inner() {
+        let bt = backtrace::Backtrace::new();
+        let symbols = bt
+            .frames()
+            .iter()
+            .flat_map(|frame| frame.symbols())
+            .map(|sym| {
+                let name = |n: backtrace::SymbolName<'_>| n.to_string();
+                (
+                    sym.name().map(name).unwrap_or_default(),
+                    sym.inlined_into().map(name),
+                )
+            })
+            .collect::<Vec<_>>();
+        println!("{:#?}", symbols);
+
+        let start = match symbols
+            .iter()
+            .position(|(name, _)| name.contains("inline_inner"))
+        {
+            Some(start) => start,
+            None => return,
+        };
+        for (name, outer) in &symbols[start..start + 2] {
+            let outer = outer
+                .as_ref()
+                .unwrap_or_else(|| panic!("{} isn't inlined", name));
+            assert!(
+                outer.contains("inline_physical"),
+                "{} isn't inline_physical",
+                outer
+            );
+        }
+        assert_eq!(symbols[start + 2].1, None);
+    }
+}
+
+#[test]
+fn inline_depth_of_symbols() {
+    depth_physical();
+    #[inline(never)]
+    fn depth_physical() {
+        depth_outer()
+    }
+    #[inline(always)]
+    fn depth_outer() {
+        depth_inner()
+    }
+    #[inline(always)]
+    fn depth_inner() {
+        let bt = backtrace::Backtrace::new();
+        let symbols = bt
+            .frames()
+            .iter()
+            .flat_map(|frame| frame.symbols())
+            .map(|sym| {
+                let name = sym.name().map(|n| n.to_string()).unwrap_or_default();
+                (name, sym.is_inline(), sym.inline_depth())
+            })
+            .collect::<Vec<_>>();
+        println!("{:#?}", symbols);
+
+        // Inlined calls may not be reported, for example without debug info.
+        let start = match symbols
+            .iter()
+            .position(|(name, ..)| name.contains("depth_inner"))
+        {
+            Some(start) => start,
+            None => return,
+        };
+        if !symbols[start + 1].0.contains("depth_outer") {
+            return;
+        }
+        let depths = symbols[start..start + 3]
+            .iter()
+            .map(|s| (s.1, s.2))
+            .collect::<Vec<_>>();
+        assert_eq!(depths, [(true, 2), (true, 1), (false, 0)]);
+    }
+}
+
+#[test]
+#[rustfmt::skip] // we care about line numbers here
+fn call_and_return_symbols() {
+    #[inline(never)]
+    fn capture(bt: &mut Option<backtrace::Backtrace>) {
+        *bt = Some(backtrace::Backtrace::new_unresolved());
+    }
+
+    let mut bt = None;
+    let call_line = line!(); capture(&mut bt);
+    assert!(bt.is_some());
+    let bt = bt.unwrap();
+
+    let lines = |symbols: &[backtrace::BacktraceSymbol]| {
+        symbols
+            .iter()
+            .filter(|sym| sym.name().map_or(false, |n| format!("{:#}", n).ends_with("::call_and_return_symbols")))
+            .filter_map(|sym| sym.lineno())
+            .next()
+    };
+    // Platforms without debug info don't report lines.
+    let (call, ret) = match bt
+        .frames()
+        .iter()
+        .map(|frame| frame.call_and_return_symbols())
+        .find(|(call, _)| lines(call).is_some())
+    {
+        Some(symbols) => symbols,
+        None => return,
+    };
+    assert_eq!(lines(&call), Some(call_line));
+    if let Some(ret_line) = lines(&ret) {
+        assert!(ret_line >= call_line);
+    }
+}
+
+#[test]
+fn refresh_modules() {
+    if cfg!(miri) {
+        return;
+    }
+    let name = |ip| {
+        let mut name = None;
+        backtrace::resolve(ip, |sym| name = sym.name().map(|n| n.to_string()));
+        name
+    };
+    let ip = (refresh_modules as usize + 1) as *mut std::ffi::c_void;
+    let before = name(ip);
+    backtrace::refresh_modules();
+    assert_eq!(name(ip), before);
+}
+
+#[test]
+fn frame_is_within() {
+    backtrace::trace(|frame| {
+        let ip = frame.ip();
+        assert!(frame.is_within(ip, 1));
+        assert!(!frame.is_within(ip, 0));
+        assert!(!frame.is_within((ip as usize).wrapping_add(1) as *mut _, usize::MAX));
+        if let Some(base) = (ip as usize).checked_sub(16) {
+            assert!(frame.is_within(base as *mut _, 17));
+            assert!(!frame.is_within(base as *mut _, 16));
+        }
+        false
+    });
+}
+
+#[test]
+fn frame_address_is_mapped() {
+    backtrace::trace(|frame| {
+        assert!(frame.address_is_mapped(), "{:?} isn't mapped", frame.ip());
+        false
+    });
+}
+
+#[test]
+fn resolve_address() {
+    #[inline(never)]
+    fn target() -> usize {
+        target as usize
+    }
+
+    if cfg!(miri) {
+        return;
+    }
+    // Somewhere inside the function, as a return address would be.
+    let ip = (target() + 1) as *mut std::ffi::c_void;
+    let symbols = backtrace::resolve_address(ip);
+    assert!(symbols
+        .iter()
+        .any(|s| s.name().map_or(false, |n| n.to_string().contains("target"))));
+}
+
+#[test]
+fn inline_frames_at() {
+    #[inline(never)]
+    fn target() -> usize {
+        target as usize
+    }
+
+    if cfg!(miri) {
+        return;
+    }
+    // The very first instruction, which a return address can't point at.
+    let ip = target() as *mut std::ffi::c_void;
+    let symbols = backtrace::inline_frames_at(ip);
+    assert!(symbols
+        .last()
+        .and_then(|s| s.name())
+        .map_or(false, |n| n.to_string().contains("target")));
+}
+
+#[test]
+#[cfg(not(any(windows, target_os = "macos")))]
+fn cross_thread_unsupported() {
+    use backtrace::{ThreadHandle, TraceError};
+
+    let raw = std::ptr::null_mut();
+    assert_eq!(ThreadHandle::new(raw), Err(TraceError::Unsupported));
+    let handle = unsafe { ThreadHandle::from_raw(raw) };
+
+    let mut frames = 0;
+    let result = unsafe {
+        backtrace::trace_thread_unsynchronized(handle, |_| {
+            frames += 1;
+            true
+        })
+    };
+    assert_eq!(result, Err(TraceError::Unsupported));
+    assert_eq!(frames, 0);
+
+    let options = backtrace::ThreadSnapshotOptions::new();
+    let snapshot = unsafe { backtrace::snapshot_all_threads(&options) };
+    assert_eq!(snapshot.err(), Some(TraceError::Unsupported));
+
+    let mut ring = backtrace::SampleRing::new(4, 32);
+    let result = unsafe { ring.sample_thread(handle) };
+    assert_eq!(result, Err(TraceError::Unsupported));
+    assert!(ring.is_empty());
+}
+
+#[test]
+fn trace_error_display() {
+    use backtrace::{DbghelpError, TraceError};
+    use std::error::Error;
+
+    fn assert_send_sync<T: Send + Sync + 'static>(_: &T) {}
+
+    let err = TraceError::SuspendFailed(5);
+    assert_send_sync(&err);
+    assert_eq!(err.to_string(), "failed to suspend the thread (error 5)");
+    assert!(err.source().is_none());
+
+    let err = TraceError::DbghelpUnavailable(DbghelpError::NotFound(126));
+    assert_eq!(
+        err.to_string(),
+        "dbghelp is unavailable: failed to load dbghelp.dll (error 126)"
+    );
+    assert!(err.source().is_some());
+
+    let boxed: Box<dyn Error + Send + Sync> = TraceError::Unsupported.into();
+    assert!(!boxed.to_string().is_empty());
+}
+
 #[test]
 #[cfg(feature = "rustc-serialize")]
 fn is_rustc_serialize() {